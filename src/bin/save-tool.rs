@@ -0,0 +1,28 @@
+extern crate roguelike_tutorial;
+
+use roguelike_tutorial::SaveCommand;
+
+const USAGE: &'static str = "\
+Usage: save-tool <command>
+
+Looks after part 13's saved games without starting the game.
+
+    list       show what each slot has, and the save format of each file
+    check      try to load every save and tell which won't
+    upgrade    bring the saves from older versions up to date, keeping the old files
+    prune      remove half-written saves, stale autosaves and journals without a save";
+
+// The saves are read and upgraded by the game's own code, in src/lib.rs.
+fn main() {
+    match std::env::args().nth(1).and_then(|command| SaveCommand::parse(&command)) {
+        Some(command) => {
+            if !roguelike_tutorial::run_save_tool(command) {
+                std::process::exit(1);
+            }
+        }
+        None => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
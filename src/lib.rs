@@ -0,0 +1,13819 @@
+//! Part 13's game, everything but the command lines of the programs built
+//! from it: the game itself (src/bin/part-13-adventure-gear.rs) and the save
+//! tool (src/bin/save-tool.rs), which reads and upgrades saves with the
+//! game's own code.
+
+extern crate tcod;
+extern crate rand;
+extern crate serde;
+#[macro_use] extern crate serde_derive;
+extern crate serde_json;
+extern crate bincode;
+extern crate flate2;
+extern crate toml;
+#[macro_use] extern crate lazy_static;
+#[macro_use] extern crate log;
+extern crate fern;
+extern crate rhai;
+extern crate crossterm;
+extern crate once_cell;
+
+use std::cmp;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use std::ascii::AsciiExt;
+use std::io::{Read, Write};
+use std::fs::File;
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tcod::console::*;
+use tcod::colors::{self, Color};
+use tcod::input::{self, Event, Key, Mouse};
+use tcod::map::{Map as FovMap, FovAlgorithm};
+use tcod::pathfinding::AStar;
+use rand::{Rng, SeedableRng};
+use once_cell::sync::OnceCell;
+
+// the size of the window and the map, the FPS limit, spells and colors are
+// in `Config`, they can be changed in config.toml
+
+// sizes and coordinates relevant for the GUI
+const BAR_WIDTH: i32 = 20;
+const PANEL_HEIGHT: i32 = 7;
+const MSG_X: i32 = BAR_WIDTH + 2;
+const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+
+// how much of its status' color a creature's character takes on
+const STATUS_TINT: f32 = 0.6;
+// how red a tile gets, sneaking, where a monster is sure to notice the
+// player, down to half that where it only might
+const DETECTION_HINT_TINT: f32 = 0.35;
+
+// thrown noisemakers: how far they fly and how many steps away they are heard
+const THROW_RANGE: f32 = 6.0;
+const ROCK_LOUDNESS: i32 = 8;
+const BELL_LOUDNESS: i32 = 16;
+// smoke hides whatever is behind it until it clears: the cloud of a smoke
+// bomb, and the puff a fireball leaves where it went off
+const SMOKE_RADIUS: i32 = 2;
+const SMOKE_TURNS: u32 = 10;
+const FIRE_SMOKE_TURNS: u32 = 4;
+// digging: a pickaxe is heard this far, a scroll of digging bores a tunnel
+// this long
+const DIG_LOUDNESS: i32 = 10;
+const DIG_TUNNEL_LENGTH: i32 = 8;
+// how long monsters look for whatever made a noise before giving up
+const INVESTIGATE_TURNS: u32 = 20;
+// most monsters are found asleep: one wakes up to a noise, to getting hurt,
+// and now and then to the player coming close, half as often when their
+// torch is out; a fight is heard this many steps away
+const SLEEP_CHANCE: f32 = 0.7;
+const WAKE_DISTANCE: f32 = 4.0;
+const WAKE_CHANCE: f32 = 0.3;
+const COMBAT_LOUDNESS: i32 = 5;
+// a fallen pack leader scares its faction this far around, its own
+// followers more than the rest, and those who run keep running this long
+const MORALE_RADIUS: f32 = 8.0;
+const FOLLOWER_FLEE_CHANCE: f32 = 0.7;
+const FACTION_FLEE_CHANCE: f32 = 0.3;
+const FLEE_TURNS: u32 = 10;
+// a monster that spotted the player loses its nerve when the share of its
+// hit points it lost, and OUTMATCHED_FEAR more if the player could finish
+// it with a blow, goes over its morale; it comes back MORALE_RALLY braver
+const OUTMATCHED_FEAR: i32 = 30;
+const MORALE_RALLY: i32 = 40;
+// monsters that see a blast go off among others react up to this far past
+// its edge: the faint-hearted run, the rest go round the caster to come at
+// them from every side, and give up on that after a while
+const BLAST_WITNESS_RADIUS: f32 = 6.0;
+const BLAST_SCATTER_MORALE: i32 = 50;
+const FLANK_TURNS: u32 = 8;
+// a pack's followers keep this close to their leader while there's nobody
+// to chase
+const PACK_LEASH: f32 = 3.0;
+// a monster with nothing better to do goes for better gear this close by
+const GEAR_SIGHT: f32 = 6.0;
+// the player's companion goes for foes this close to it, and hurries back
+// once the player is further away than COMPANION_LEASH. Every
+// COMPANION_LEVEL_XP experience it gets gives it COMPANION_LEVEL_HP more
+// hit points and a point of attack
+const COMPANION_SIGHT: f32 = 6.0;
+const COMPANION_LEASH: f32 = 3.0;
+const COMPANION_LEVEL_XP: i32 = 100;
+const COMPANION_LEVEL_HP: i32 = 5;
+// the share of a monster's experience for killing it with the terrain or a trap
+const HAZARD_XP_PERCENT: i32 = 50;
+// traps: what they do to whoever sets them off, and the player's chance
+// of disarming one (half of the failures set it off)
+const SPIKE_DAMAGE: i32 = 10;
+const DART_DAMAGE: i32 = 3;
+const POISON_DAMAGE: i32 = 2;
+const POISON_TURNS: u32 = 6;
+const ALARM_LOUDNESS: i32 = 30;
+const DISARM_CHANCE: f32 = 0.6;
+// what the ground does to whoever ends their turn on it without flying
+// (or, for deep water, swimming)
+const LAVA_DAMAGE: i32 = 15;
+const DROWNING_DAMAGE: i32 = 4;
+const DROWNING_CHANCE: f32 = 0.5;
+// the town's economy: what a new character has, how much of an item's price
+// a shop pays for it and what the healer asks for each hit point and for
+// each turn of poison left
+const STARTING_GOLD: u32 = 50;
+const SELL_PRICE_PERCENT: u32 = 50;
+const HEALING_PRICE_PER_HP: u32 = 1;
+const CURE_PRICE_PER_TURN: u32 = 5;
+// the trainer's first lesson costs TRAINING_PRICE gold and every one after
+// that TRAINING_PRICE more, taking back a level-up costs half a lesson
+const TRAINING_PRICE: u32 = 100;
+// the hunter asks for BOUNTY_MIN_TROPHIES to BOUNTY_MAX_TROPHIES trophies of
+// a kind at a time and pays BOUNTY_PRICE_PERCENT of their price for them,
+// cutting one from a corpse takes BUTCHER_COST energy
+const BOUNTY_MIN_TROPHIES: u32 = 2;
+const BOUNTY_MAX_TROPHIES: u32 = 4;
+const BOUNTY_PRICE_PERCENT: u32 = 200;
+const BUTCHER_COST: i32 = ACTION_COST * 2;
+// the score: SCORE_PER_LEVEL for each level of difficulty the player got
+// down to and SCORE_PER_CLEARED_LEVEL for each level they cleared, the
+// experience of their kills and the gold they earned. A point for every
+// SPEED_BONUS_TURNS turns they were quicker than SPEED_PAR_TURNS a level,
+// CONDUCT_BONUS_PER_LEVEL a level for every conduct kept,
+// MUTATOR_BONUS_PER_LEVEL a level for every mutator played with, and
+// DEBUG_COMMAND_PENALTY off for each cheat
+const SCORE_PER_LEVEL: i32 = 100;
+const SCORE_PER_CLEARED_LEVEL: i32 = 50;
+const SPEED_PAR_TURNS: i32 = 1000;
+const SPEED_BONUS_TURNS: i32 = 10;
+const CONDUCT_BONUS_PER_LEVEL: i32 = 25;
+const MUTATOR_BONUS_PER_LEVEL: i32 = 25;
+const DEBUG_COMMAND_PENALTY: i32 = 500;
+// both ends of a portal to town go by this name
+const PORTAL_NAME: &'static str = "magic portal";
+// town events: how likely one is going on when the player arrives and how
+// long until the next one can, how long a festival lasts and how much less
+// the shop asks meanwhile, how many orcs raid the town, at least how far
+// from the player they show up and what the town pays for each
+const TOWN_EVENT_CHANCE: f32 = 0.25;
+const TOWN_EVENT_COOLDOWN: u32 = 500;
+const FESTIVAL_TURNS: u32 = 300;
+const FESTIVAL_DISCOUNT_PERCENT: u32 = 25;
+const RAIDERS: usize = 4;
+const RAID_MIN_DISTANCE: f32 = 8.0;
+const RAID_BOUNTY: u32 = 30;
+// how often the town looks whether the raiders are gone
+const RAID_CHECK_INTERVAL: u32 = 5;
+const RAIDER_NAME: &'static str = "orc raider";
+// luck: an altar blesses whoever first kneels at it with ALTAR_LUCK, and
+// each point of luck makes a critical hit LUCK_CRITICAL_PERCENT likelier and
+// a find LUCK_LOOT_PERCENT likelier to be the better of two rolls
+const ALTAR_NAME: &'static str = "altar";
+const ALTAR_LUCK: i32 = 1;
+const LUCK_CRITICAL_PERCENT: i32 = 2;
+const LUCK_LOOT_PERCENT: i32 = 10;
+// cover: how likely a creature in the line of fire takes a shot meant for
+// someone behind it, and how likely a shot squeezing past a corner goes wide
+const COVER_HIT_CHANCE: f32 = 0.5;
+const CORNER_MISS_CHANCE: f32 = 0.25;
+// monsters that shoot back off from a player closer than RANGED_MIN_DISTANCE,
+// and their shots fly a tile every SHOT_FRAME_MS milliseconds on screen
+const RANGED_MIN_DISTANCE: f32 = 3.0;
+const SHOT_FRAME_MS: u64 = 25;
+// after ATTRACT_IDLE_SECS on the title screen without a key press the bot
+// plays a demo behind it, a turn every ATTRACT_FRAME_MS for ATTRACT_TURNS
+const ATTRACT_IDLE_SECS: u64 = 30;
+const ATTRACT_FRAME_MS: u64 = 100;
+const ATTRACT_TURNS: u32 = 300;
+// a monster with a few spells casts one on a turn with CAST_CHANCE, and
+// heals the others as far as ALLY_HEAL_RANGE away
+const CAST_CHANCE: f32 = 0.4;
+const ALLY_HEAL_RANGE: f32 = 5.0;
+// how much a script gets to do before it's stopped, so one stuck in a loop
+// or recursing away can't hang the game
+const SCRIPT_MAX_OPERATIONS: u64 = 100_000;
+const SCRIPT_MAX_CALL_LEVELS: usize = 32;
+const SCRIPT_MAX_SIZE: usize = 10_000;
+const INVENTORY_WIDTH: i32 = 50;
+// how many items the player can carry, one for each letter
+const INVENTORY_SIZE: usize = 26;
+const STASH_SCREEN_WIDTH: i32 = 70;
+// how many items the town's stash chest holds
+const STASH_CAPACITY: usize = 100;
+const CHARACTER_SCREEN_WIDTH: i32 = 30;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+
+// down staircases: how many, and how far from the entry point and each other
+const MAX_STAIRS: usize = 2;
+const STAIRS_MIN_DISTANCE: f32 = 20.0;
+const STAIRS_MIN_SEPARATION: f32 = 15.0;
+// nothing is put this close to where the player starts a new level
+const START_CLEARANCE: i32 = 1;
+
+// hand-made rooms: where they are, and how likely a room is one of them
+const PREFAB_DIR: &'static str = "prefabs";
+const PREFAB_CHANCE: f32 = 0.15;
+
+// what spawns in the rooms of each rule set, see `rules`
+const SPAWN_TABLES_PATH: &'static str = "spawns.toml";
+// what can be cut from the remains of each species, see `Trophy`
+const TROPHIES_PATH: &'static str = "trophies.toml";
+
+// every BOSS_LEVEL_INTERVAL'th level of the main dungeon is an arena with a
+// boss in it, the bosses take turns in the order of BOSSES
+const BOSS_LEVEL_INTERVAL: u32 = 5;
+const BOSSES: &'static [&'static str] = &["bone king", "troll queen"];
+// halfway between the arenas, on the MINI_BOSS_LEVEL'th of every
+// BOSS_LEVEL_INTERVAL levels, a mini-boss guards a room of its own; they take
+// turns in the order of MINI_BOSSES
+const MINI_BOSS_LEVEL: u32 = 3;
+const MINI_BOSSES: &'static [MiniBoss] = &[
+    MiniBoss {
+        species: "orc warchief",
+        prefab: "warchief's hall",
+        feeling: "War drums echo through the halls. An orc warchief holds court on this level.",
+    },
+    MiniBoss {
+        species: "troll matriarch",
+        prefab: "matriarch's den",
+        feeling: "The air reeks of troll. Something big is nesting on this level.",
+    },
+];
+const ARENA_WIDTH: i32 = 50;
+const ARENA_HEIGHT: i32 = 20;
+const ARENA_PILLAR_SPACING: i32 = 6;
+
+// the town's houses, one for each service
+const TOWN_HOUSE_WIDTH: i32 = 10;
+const TOWN_HOUSE_HEIGHT: i32 = 6;
+// the smallest map the town fits on: the houses side by side, half a house
+// apart, with the street and the way down below them
+const TOWN_MIN_MAP_WIDTH: i32 = 4 * TOWN_HOUSE_WIDTH + 3 * (TOWN_HOUSE_WIDTH / 2) + 8;
+const TOWN_MIN_MAP_HEIGHT: i32 = TOWN_HOUSE_HEIGHT + 13;
+
+// how likely a level has a passage behind secret doors, and how likely the
+// player notices a secret door next to them without searching
+const SECRET_PASSAGE_CHANCE: f32 = 0.4;
+// how likely a tunnel coming into a room ends in a door, how likely a door
+// is locked and how likely the player forces a lock with each try
+const DOOR_CHANCE: f32 = 0.5;
+const LOCKED_DOOR_CHANCE: f32 = 0.1;
+const FORCE_LOCK_CHANCE: f32 = 0.3;
+// how likely a room has a heap of rubble in it, and how far the heap spreads
+const RUBBLE_CHANCE: f32 = 0.25;
+const RUBBLE_RADIUS: i32 = 2;
+// water and lava: how likely a level has a stream running through it, a
+// room has a pool of water and, from LAVA_MIN_LEVEL on, a pool of lava
+const RIVER_CHANCE: f32 = 0.3;
+const POOL_CHANCE: f32 = 0.15;
+const POOL_RADIUS: i32 = 2;
+const LAVA_CHANCE: f32 = 0.1;
+const LAVA_MIN_LEVEL: u32 = 4;
+// the drunkard's walk caves: a walker gives up after this many steps for
+// every tile it still has to dig, and the caves are split into cells of this
+// size, those with enough floor count as rooms
+const CAVE_WALK_LIMIT: usize = 20;
+const CAVE_CELL_SIZE: i32 = 10;
+const CAVE_CELL_MIN_FLOOR: usize = 12;
+// scenery: how likely a room has some strewn about its floor and how much at
+// most, and how likely a room at least DECORATED_ROOM_SIZE tiles across has
+// pillars or braziers in its corners
+const STREWN_CHANCE: f32 = 0.5;
+const MAX_STREWN: i32 = 5;
+const CORNERS_CHANCE: f32 = 0.3;
+const DECORATED_ROOM_SIZE: i32 = 7;
+const PASSIVE_SEARCH_CHANCE: f32 = 0.1;
+// now and then a monster from OUT_OF_DEPTH_LEVELS deeper wanders up, and
+// items worth VALUABLE_PRICE or more make the player feel there's treasure
+// a level counts as cleared with all its monsters dead and at least
+// CLEARED_EXPLORED_PERCENT of it seen, that's worth CLEAR_BONUS_XP per
+// level of difficulty
+const CLEARED_EXPLORED_PERCENT: usize = 95;
+const CLEAR_BONUS_XP: i32 = 10;
+const OUT_OF_DEPTH_CHANCE: f32 = 0.03;
+const OUT_OF_DEPTH_LEVELS: u32 = 3;
+const VALUABLE_PRICE: u32 = 60;
+
+const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic;  // default FOV algorithm
+const FOV_LIGHT_WALLS: bool = true;  // light walls or not
+// how far the player sees with their torch doused
+const DOUSED_TORCH_RADIUS: i32 = 2;
+// with the low light mutator the light reaches this percentage of as far
+const LOW_LIGHT_PERCENT: i32 = 50;
+// with the fragile items mutator, the chance one of the player's items
+// breaks on a turn they got hurt
+const FRAGILE_BREAK_CHANCE: f32 = 0.2;
+
+// turn scheduling: every tick each fighter gains `speed` energy, and acting
+// costs ACTION_COST energy. So speed 20 acts twice per turn, 5 every other one.
+// Actions other than a plain step or attack can cost more or less than that.
+const ACTION_COST: i32 = 100;
+const NORMAL_SPEED: i32 = 10;
+const QUICK_ACTION_COST: i32 = ACTION_COST / 2;
+// what a step through rock costs on top of the step itself
+const BURROW_EXTRA_COST: i32 = ACTION_COST;
+// monsters follow a path at most this many steps long, anything longer
+// and they just head straight for where they want to go
+const MONSTER_PATH_LIMIT: i32 = 25;
+
+// remains rot away after a while; every CLEANUP_INTERVAL turns the oldest
+// ones are removed so that only MAX_REMAINS are kept around at most
+const CORPSE_DECAY_TURNS: u32 = 300;
+const JUNK_DECAY_TURNS: u32 = 1000;
+const FLIES_CHANCE: f32 = 0.2;
+const CLEANUP_INTERVAL: u32 = 50;
+const MAX_REMAINS: usize = 40;
+
+// levels further than this from the player are only kept serialized, in
+// files of their own in the system's temporary directory
+const LEVEL_CACHE_RADIUS: u32 = 1;
+
+// how many seeds --validate-data and the tests try the map generator with
+const MAPGEN_CHECK_SEEDS: usize = 500;
+// how many times a level is made over before the game gives up on the
+// map settings in config.toml
+const MAX_MAP_TRIES: u32 = 100;
+
+// where the player's key bindings are kept
+const KEYMAP_PATH: &'static str = "keys.toml";
+
+// the game's log file, it's rotated once it grows past LOG_MAX_SIZE bytes and
+// LOG_FILES old logs are kept as game.log.1, game.log.2...
+const LOG_PATH: &'static str = "game.log";
+const LOG_MAX_SIZE: u64 = 1024 * 1024;
+const LOG_FILES: u32 = 3;
+// when the game crashes it writes what went wrong here, with the last
+// RECENT_COMMANDS things the player did
+const CRASH_REPORT_PATH: &'static str = "crash-report.txt";
+const RECENT_COMMANDS: usize = 20;
+
+// what a dead monster is called, before its species
+const REMAINS_PREFIX: &'static str = "remains of ";
+
+// player will always be the first object
+const PLAYER: usize = 0;
+
+/// What was asked for on the command line, `play` sets it before anything
+/// else runs. Whatever runs without `play`, like the tests, gets the defaults.
+static OPTIONS: OnceCell<Options> = OnceCell::new();
+
+lazy_static! {
+    /// The game's settings, read from config.toml (or `--config`) at startup.
+    static ref CONFIG: Config = Config::load(&options().config_path);
+}
+
+fn options() -> &'static Options {
+    OPTIONS.get_or_init(Options::default)
+}
+
+/// The command line options
+pub struct Options {
+    pub seed: Option<usize>,
+    /// the save slot to load straight away
+    pub load_slot: Option<usize>,
+    pub fullscreen: bool,
+    // there's no sound yet, the flag is accepted so launchers can pass it
+    pub sound: bool,
+    pub font: String,
+    pub debug: bool,
+    pub config_path: String,
+    pub terminal: bool,
+    pub binary_saves: bool,
+    pub headless: bool,
+    pub validate_data: bool,
+    pub help: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            seed: None,
+            load_slot: None,
+            fullscreen: false,
+            sound: true,
+            font: "arial10x10.png".into(),
+            debug: false,
+            config_path: "config.toml".into(),
+            terminal: false,
+            binary_saves: false,
+            headless: false,
+            validate_data: false,
+            help: false,
+        }
+    }
+}
+
+impl Options {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut options = Options::default();
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--seed" => {
+                    let seed = try! { args.next().ok_or("--seed needs a number") };
+                    options.seed = Some(try! {
+                        seed.parse().map_err(|_| format!("'{}' is not a valid seed", seed))
+                    });
+                }
+                "--load" => {
+                    let slot = try! { args.next().ok_or("--load needs a slot number") };
+                    match slot.parse::<usize>() {
+                        Ok(slot) if slot >= 1 && slot <= SAVE_SLOTS => options.load_slot = Some(slot - 1),
+                        _ => return Err(format!("'{}' is not a save slot, pick 1 to {}", slot, SAVE_SLOTS)),
+                    }
+                }
+                "--fullscreen" => options.fullscreen = true,
+                "--no-sound" => options.sound = false,
+                "--font" => options.font = try! { args.next().ok_or("--font needs a path") },
+                "--debug" => options.debug = true,
+                "--config" => options.config_path = try! { args.next().ok_or("--config needs a path") },
+                "--terminal" => options.terminal = true,
+                "--binary-saves" => options.binary_saves = true,
+                "--headless" => options.headless = true,
+                "--validate-data" => options.validate_data = true,
+                "--help" | "-h" => options.help = true,
+                _ => return Err(format!("Unknown option '{}'", arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+thread_local! {
+    /// Where all the game's randomness comes from, so `--seed` can make it
+    /// repeatable.
+    static RNG: RefCell<rand::StdRng> = RefCell::new(rand::StdRng::new().expect("No source of randomness"));
+    /// the seed the randomness last started over from, for crash reports
+    static SEED: std::cell::Cell<Option<usize>> = std::cell::Cell::new(None);
+    /// what the last panic said, where and how it got there
+    static PANIC_REPORT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+/// A handle to the game's random number generator, used like `rand::thread_rng()`.
+struct GameRng;
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        RNG.with(|rng| rng.borrow_mut().next_u32())
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        RNG.with(|rng| rng.borrow_mut().next_u64())
+    }
+}
+
+fn game_rng() -> GameRng {
+    GameRng
+}
+
+/// make the game's randomness start over from `seed`
+pub fn seed_rng(seed: usize) {
+    RNG.with(|rng| *rng.borrow_mut() = rand::StdRng::from_seed(&[seed][..]));
+    SEED.with(|current| current.set(Some(seed)));
+}
+
+/// Settings that can be changed in config.toml without recompiling. Anything
+/// the file doesn't mention keeps its default.
+struct Config {
+    // actual size of the window
+    screen_width: i32,
+    screen_height: i32,
+    limit_fps: i32,
+
+    // size of the map
+    map_width: i32,
+    map_height: i32,
+
+    //parameters for dungeon generator
+    room_max_size: i32,
+    room_min_size: i32,
+    max_rooms: i32,
+    /// the drunkard's walk caves: how much of the map they dig out (in
+    /// percent) and how many walkers share the digging
+    cave_coverage: i32,
+    cave_walkers: i32,
+    /// from which level on a level may be caves instead of rooms, and how
+    /// likely that is (in percent); the Caves branch is always caves
+    cave_min_level: i32,
+    cave_chance: i32,
+    /// the rooms are joined by the fewest, shortest tunnels that reach them
+    /// all; on top of that this many percent more make loops
+    loop_corridors: i32,
+
+    torch_radius: i32,
+
+    /// spell settings that override the ones of every rule set
+    heal_amount: Option<i32>,
+    lightning_damage: Option<i32>,
+    lightning_range: Option<i32>,
+    confuse_range: Option<i32>,
+    confuse_num_turns: Option<i32>,
+    fireball_radius: Option<i32>,
+    fireball_damage: Option<i32>,
+
+    color_dark_wall: Color,
+    color_light_wall: Color,
+    color_dark_ground: Color,
+    color_light_ground: Color,
+
+    /// how many monsters of a species may live on one level
+    population_caps: Vec<(String, usize)>,
+    default_population_cap: usize,
+
+    /// without the mouse everything is done with the keyboard; with it each
+    /// button does what it's set to
+    mouse_enabled: bool,
+    mouse_left: MouseAction,
+    mouse_right: MouseAction,
+
+    /// doors the player walks through close behind them on their own
+    close_doors_behind: bool,
+    /// with the torch doused, show where the monsters that haven't seen the
+    /// player yet might, see `show_detection_hints`
+    detection_hints: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            screen_width: 80,
+            screen_height: 50,
+            limit_fps: 20,  // 20 frames-per-second maximum
+            map_width: 80,
+            map_height: 43,
+            room_max_size: 10,
+            room_min_size: 6,
+            max_rooms: 30,
+            cave_coverage: 40,
+            cave_walkers: 4,
+            cave_min_level: 3,
+            cave_chance: 20,
+            loop_corridors: 20,
+            torch_radius: 10,
+            heal_amount: None,
+            lightning_damage: None,
+            lightning_range: None,
+            confuse_range: None,
+            confuse_num_turns: None,
+            fireball_radius: None,
+            fireball_damage: None,
+            color_dark_wall: Color { r: 0, g: 0, b: 100 },
+            color_light_wall: Color { r: 130, g: 110, b: 50 },
+            color_dark_ground: Color { r: 50, g: 50, b: 150 },
+            color_light_ground: Color { r: 200, g: 180, b: 50 },
+            population_caps: vec![
+                ("swarm of flies".into(), 6),
+                ("cloud of gnats".into(), 8),
+                ("bat".into(), 8),
+                ("troll".into(), 10),
+                ("zombie".into(), 10),
+                ("giant".into(), 2),
+                ("dragon".into(), 1),
+            ],
+            default_population_cap: 20,
+            mouse_enabled: true,
+            mouse_left: MouseAction::Select,
+            mouse_right: MouseAction::Cancel,
+            close_doors_behind: false,
+            detection_hints: true,
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file. A missing file means the defaults, a broken one
+    /// is reported and ignored.
+    pub fn load(path: &str) -> Self {
+        let mut config = Config::default();
+        let mut text = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut text)).is_err() {
+            return config;
+        }
+        match text.parse::<toml::Value>() {
+            Ok(doc) => config.apply(&doc),
+            Err(e) => eprintln!("Ignoring {}, it's not valid TOML: {}", path, e),
+        }
+        config.fix_up();
+        config
+    }
+
+    fn apply(&mut self, doc: &toml::Value) {
+        /// the value of `key` in the `[section]` table
+        fn get<'a>(doc: &'a toml::Value, section: &str, key: &str) -> Option<&'a toml::Value> {
+            doc.as_table()
+                .and_then(|table| table.get(section))
+                .and_then(|section| section.as_table())
+                .and_then(|section| section.get(key))
+        }
+        fn set_int(doc: &toml::Value, section: &str, key: &str, setting: &mut i32) {
+            if let Some(value) = get(doc, section, key).and_then(|v| v.as_integer()) {
+                *setting = value as i32;
+            }
+        }
+        fn set_bool(doc: &toml::Value, section: &str, key: &str, setting: &mut bool) {
+            if let Some(value) = get(doc, section, key).and_then(|v| v.as_bool()) {
+                *setting = value;
+            }
+        }
+        fn set_override(doc: &toml::Value, section: &str, key: &str, setting: &mut Option<i32>) {
+            if let Some(value) = get(doc, section, key).and_then(|v| v.as_integer()) {
+                *setting = Some(value as i32);
+            }
+        }
+        fn set_color(doc: &toml::Value, key: &str, setting: &mut Color) {
+            let rgb: Vec<u8> = get(doc, "colors", key)
+                .and_then(|v| v.as_array())
+                .map(|rgb| rgb.iter().filter_map(|c| c.as_integer()).map(|c| c as u8).collect())
+                .unwrap_or(vec![]);
+            if rgb.len() == 3 {
+                *setting = Color::new(rgb[0], rgb[1], rgb[2]);
+            }
+        }
+        fn set_mouse_action(doc: &toml::Value, key: &str, setting: &mut MouseAction) {
+            if let Some(action) = get(doc, "mouse", key).and_then(|v| v.as_str()).and_then(MouseAction::from_id) {
+                *setting = action;
+            }
+        }
+
+        set_int(doc, "screen", "width", &mut self.screen_width);
+        set_int(doc, "screen", "height", &mut self.screen_height);
+        set_int(doc, "screen", "fps", &mut self.limit_fps);
+        set_int(doc, "map", "width", &mut self.map_width);
+        set_int(doc, "map", "height", &mut self.map_height);
+        set_int(doc, "map", "room_max_size", &mut self.room_max_size);
+        set_int(doc, "map", "room_min_size", &mut self.room_min_size);
+        set_int(doc, "map", "max_rooms", &mut self.max_rooms);
+        set_int(doc, "map", "cave_coverage", &mut self.cave_coverage);
+        set_int(doc, "map", "cave_walkers", &mut self.cave_walkers);
+        set_int(doc, "map", "cave_min_level", &mut self.cave_min_level);
+        set_int(doc, "map", "cave_chance", &mut self.cave_chance);
+        set_int(doc, "map", "loop_corridors", &mut self.loop_corridors);
+        set_int(doc, "fov", "torch_radius", &mut self.torch_radius);
+        set_override(doc, "spells", "heal_amount", &mut self.heal_amount);
+        set_override(doc, "spells", "lightning_damage", &mut self.lightning_damage);
+        set_override(doc, "spells", "lightning_range", &mut self.lightning_range);
+        set_override(doc, "spells", "confuse_range", &mut self.confuse_range);
+        set_override(doc, "spells", "confuse_num_turns", &mut self.confuse_num_turns);
+        set_override(doc, "spells", "fireball_radius", &mut self.fireball_radius);
+        set_override(doc, "spells", "fireball_damage", &mut self.fireball_damage);
+        set_color(doc, "dark_wall", &mut self.color_dark_wall);
+        set_color(doc, "light_wall", &mut self.color_light_wall);
+        set_color(doc, "dark_ground", &mut self.color_dark_ground);
+        set_color(doc, "light_ground", &mut self.color_light_ground);
+        set_bool(doc, "mouse", "enabled", &mut self.mouse_enabled);
+        set_mouse_action(doc, "left", &mut self.mouse_left);
+        set_mouse_action(doc, "right", &mut self.mouse_right);
+        set_bool(doc, "doors", "close_behind", &mut self.close_doors_behind);
+        set_bool(doc, "stealth", "detection_hints", &mut self.detection_hints);
+
+        // [population] has a `default` and a cap for any species by name
+        let population = doc.as_table().and_then(|t| t.get("population")).and_then(|p| p.as_table());
+        for (species, cap) in population.into_iter().flat_map(|p| p.iter()) {
+            let cap = match cap.as_integer() {
+                Some(cap) if cap >= 0 => cap as usize,
+                _ => continue,
+            };
+            if species == "default" {
+                self.default_population_cap = cap;
+            } else {
+                self.population_caps.retain(|&(ref name, _)| name != species);
+                self.population_caps.push((species.clone(), cap));
+            }
+        }
+    }
+
+    /// Everything wrong with the config file at `path`: unknown settings
+    /// (most likely typos), values of the wrong kind and caps for species
+    /// that don't exist. A missing file is fine.
+    pub fn check(path: &str) -> Vec<String> {
+        // keep in sync with `apply`
+        const SETTINGS: &'static [(&'static str, &'static [&'static str])] = &[
+            ("screen", &["width", "height", "fps"]),
+            ("map", &["width", "height", "room_max_size", "room_min_size", "max_rooms",
+                      "cave_coverage", "cave_walkers", "cave_min_level", "cave_chance",
+                      "loop_corridors"]),
+            ("fov", &["torch_radius"]),
+            ("spells", &["heal_amount", "lightning_damage", "lightning_range", "confuse_range",
+                         "confuse_num_turns", "fireball_radius", "fireball_damage"]),
+            ("colors", &["dark_wall", "light_wall", "dark_ground", "light_ground"]),
+            ("mouse", &["enabled", "left", "right"]),
+            ("doors", &["close_behind"]),
+            ("stealth", &["detection_hints"]),
+        ];
+        // the settings that are switched on or off
+        const SWITCHES: &'static [(&'static str, &'static str)] = &[("mouse", "enabled"), ("doors", "close_behind"),
+                                                                  ("stealth", "detection_hints")];
+
+        let mut text = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut text)).is_err() {
+            return vec![];
+        }
+        let doc = match text.parse::<toml::Value>() {
+            Ok(doc) => doc,
+            Err(e) => return vec![format!("{}: not valid TOML: {}", path, e)],
+        };
+        let mut problems = vec![];
+        for (name, section) in doc.as_table().into_iter().flat_map(|doc| doc.iter()) {
+            let section = match section.as_table() {
+                Some(section) => section,
+                None => {
+                    problems.push(format!("{}: '{}' should be a [section]", path, name));
+                    continue;
+                }
+            };
+            if name == "population" {
+                for (species, cap) in section {
+                    if species != "default" && create_monster(species, 0, 0).is_none() {
+                        problems.push(format!("{}: there is no monster called '{}'", path, species));
+                    }
+                    if cap.as_integer().map_or(true, |cap| cap < 0) {
+                        problems.push(format!("{}: the cap for '{}' should be a number of at least 0",
+                                              path, species));
+                    }
+                }
+                continue;
+            }
+            let known = match SETTINGS.iter().find(|&&(known, _)| known == name) {
+                Some(&(_, known)) => known,
+                None => {
+                    problems.push(format!("{}: unknown section [{}]", path, name));
+                    continue;
+                }
+            };
+            for (key, value) in section {
+                if !known.contains(&&key[..]) {
+                    problems.push(format!("{}: unknown setting '{}' in [{}]", path, key, name));
+                } else if name == "colors" {
+                    let is_color = value.as_array().map_or(false, |rgb| {
+                        rgb.len() == 3 && rgb.iter().all(|c| c.as_integer().map_or(false, |c| c >= 0 && c <= 255))
+                    });
+                    if !is_color {
+                        problems.push(format!("{}: colors.{} should be [r, g, b] with each from 0 to 255",
+                                              path, key));
+                    }
+                } else if SWITCHES.contains(&(&name[..], &key[..])) {
+                    if value.as_bool().is_none() {
+                        problems.push(format!("{}: {}.{} should be true or false", path, name, key));
+                    }
+                } else if name == "mouse" {
+                    if value.as_str().and_then(MouseAction::from_id).is_none() {
+                        let ids: Vec<_> = MouseAction::all().iter().map(|action| action.id()).collect();
+                        problems.push(format!("{}: mouse.{} should be one of {}", path, key, ids.join(", ")));
+                    }
+                } else if value.as_integer().is_none() {
+                    problems.push(format!("{}: {}.{} should be a whole number", path, name, key));
+                }
+            }
+        }
+        problems
+    }
+
+    /// keep the settings to what the game can work with
+    fn fix_up(&mut self) {
+        self.screen_width = cmp::max(self.screen_width, BAR_WIDTH + 20);
+        self.screen_height = cmp::max(self.screen_height, PANEL_HEIGHT + 10);
+        self.limit_fps = cmp::max(self.limit_fps, 1);
+        // a map larger than the screen scrolls with the player, a smaller
+        // one than the town won't do
+        self.map_width = cmp::max(TOWN_MIN_MAP_WIDTH, self.map_width);
+        self.map_height = cmp::max(TOWN_MIN_MAP_HEIGHT, self.map_height);
+        self.room_max_size = cmp::min(self.room_max_size, cmp::min(self.map_width, self.map_height) - 2);
+        self.room_min_size = cmp::max(3, cmp::min(self.room_min_size, self.room_max_size));
+        self.cave_coverage = cmp::max(10, cmp::min(self.cave_coverage, 80));
+        self.cave_walkers = cmp::max(1, self.cave_walkers);
+        self.cave_chance = cmp::max(0, cmp::min(self.cave_chance, 100));
+        self.loop_corridors = cmp::max(0, cmp::min(self.loop_corridors, 100));
+    }
+
+    /// where the GUI panel starts
+    pub fn panel_y(&self) -> i32 {
+        self.screen_height - PANEL_HEIGHT
+    }
+
+    /// how much of the map fits on the screen, above the panel
+    pub fn view_width(&self) -> i32 {
+        cmp::min(self.map_width, self.screen_width)
+    }
+
+    pub fn view_height(&self) -> i32 {
+        cmp::min(self.map_height, self.panel_y())
+    }
+
+    pub fn msg_width(&self) -> i32 {
+        self.screen_width - BAR_WIDTH - 2
+    }
+
+    /// what the buttons pressed in a mouse event ask for
+    pub fn mouse_action(&self, mouse: &Mouse) -> MouseAction {
+        if !self.mouse_enabled {
+            MouseAction::Nothing
+        } else if mouse.lbutton_pressed {
+            self.mouse_left
+        } else if mouse.rbutton_pressed {
+            self.mouse_right
+        } else {
+            MouseAction::Nothing
+        }
+    }
+
+    /// which mouse button does `action`, if one does
+    pub fn mouse_button(&self, action: MouseAction) -> Option<&'static str> {
+        if !self.mouse_enabled {
+            None
+        } else if self.mouse_left == action {
+            Some("left")
+        } else if self.mouse_right == action {
+            Some("right")
+        } else {
+            None
+        }
+    }
+}
+
+/// What a mouse button does, set in config.toml's [mouse].
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MouseAction {
+    /// pick the target under the mouse
+    Select,
+    /// stop picking a target
+    Cancel,
+    /// describe what's on the tile under the mouse
+    Examine,
+    Nothing,
+}
+
+impl MouseAction {
+    pub fn all() -> Vec<MouseAction> {
+        vec![MouseAction::Select, MouseAction::Cancel, MouseAction::Examine, MouseAction::Nothing]
+    }
+
+    /// the name of the action in config.toml
+    pub fn id(&self) -> &'static str {
+        match *self {
+            MouseAction::Select => "select",
+            MouseAction::Cancel => "cancel",
+            MouseAction::Examine => "examine",
+            MouseAction::Nothing => "nothing",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<MouseAction> {
+        MouseAction::all().into_iter().find(|action| action.id() == id)
+    }
+}
+
+pub type Map = Vec<Vec<Tile>>;
+type Messages = Vec<(String, Color)>;
+
+/// A tile of the map and its properties
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Tile {
+    pub blocked: bool,
+    pub explored: bool,
+    pub block_sight: bool,
+    /// a secret door: looks and acts like a wall until someone finds it
+    #[serde(default)]
+    pub hidden: bool,
+    #[serde(default)]
+    pub kind: TileKind,
+}
+
+/// What the ground of a tile is like, or the door in it. It decides how
+/// long a step onto the tile takes, for the player and monsters alike, and
+/// so which way the pathfinding prefers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum TileKind {
+    Floor,
+    /// fallen stones, hard going
+    Rubble,
+    /// the paved streets of the town
+    Road,
+    /// knee-deep, it slows walkers down
+    ShallowWater,
+    /// over the head of anyone who can't swim
+    DeepWater,
+    /// burns whoever doesn't fly over it
+    Lava,
+    /// open when the tile isn't blocked, closed when it is
+    Door,
+    /// a closed door that only opens when someone forces the lock, it's
+    /// a `Door` from then on
+    LockedDoor,
+}
+
+impl Default for TileKind {
+    fn default() -> Self {
+        TileKind::Floor
+    }
+}
+
+impl TileKind {
+    /// the energy a walking step onto this kind of ground costs
+    pub fn move_cost(&self) -> i32 {
+        match *self {
+            TileKind::Floor => ACTION_COST,
+            TileKind::Rubble => ACTION_COST * 2,
+            TileKind::Road => ACTION_COST * 3 / 4,
+            TileKind::ShallowWater => ACTION_COST * 3 / 2,
+            TileKind::DeepWater => ACTION_COST * 2,
+            TileKind::Lava => ACTION_COST,
+            TileKind::Door | TileKind::LockedDoor => ACTION_COST,
+        }
+    }
+
+    pub fn is_water(&self) -> bool {
+        *self == TileKind::ShallowWater || *self == TileKind::DeepWater
+    }
+
+    /// the ground's own color, or None for the branch's usual floor
+    pub fn color(&self, lit: bool) -> Option<Color> {
+        let (r, g, b) = match (*self, lit) {
+            (TileKind::Floor, _) | (TileKind::Door, _) | (TileKind::LockedDoor, _) => return None,
+            (TileKind::Rubble, false) => (45, 40, 60),
+            (TileKind::Rubble, true) => (140, 120, 100),
+            (TileKind::Road, false) => (60, 60, 80),
+            (TileKind::Road, true) => (180, 165, 130),
+            (TileKind::ShallowWater, false) => (25, 35, 95),
+            (TileKind::ShallowWater, true) => (70, 110, 200),
+            (TileKind::DeepWater, false) => (10, 15, 70),
+            (TileKind::DeepWater, true) => (30, 55, 170),
+            (TileKind::Lava, false) => (100, 35, 10),
+            (TileKind::Lava, true) => (230, 90, 20),
+        };
+        Some(Color { r: r, g: g, b: b })
+    }
+
+    fn to_bits(&self) -> u8 {
+        match *self {
+            TileKind::Floor => 0,
+            TileKind::Rubble => 1,
+            TileKind::Road => 2,
+            TileKind::ShallowWater => 3,
+            TileKind::DeepWater => 4,
+            TileKind::Lava => 5,
+            TileKind::Door => 6,
+            TileKind::LockedDoor => 7,
+        }
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        match bits {
+            1 => TileKind::Rubble,
+            2 => TileKind::Road,
+            3 => TileKind::ShallowWater,
+            4 => TileKind::DeepWater,
+            5 => TileKind::Lava,
+            6 => TileKind::Door,
+            7 => TileKind::LockedDoor,
+            _ => TileKind::Floor,
+        }
+    }
+}
+
+impl Tile {
+    pub fn empty() -> Self {
+        Tile{blocked: false, explored: false, block_sight: false, hidden: false, kind: TileKind::Floor}
+    }
+
+    pub fn wall() -> Self {
+        Tile{blocked: true, explored: false, block_sight: true, hidden: false, kind: TileKind::Floor}
+    }
+
+    pub fn secret_door() -> Self {
+        Tile{blocked: true, explored: false, block_sight: true, hidden: true, kind: TileKind::Floor}
+    }
+
+    pub fn ground(kind: TileKind) -> Self {
+        Tile{kind: kind, ..Tile::empty()}
+    }
+
+    /// a closed door
+    pub fn door(locked: bool) -> Self {
+        let kind = if locked { TileKind::LockedDoor } else { TileKind::Door };
+        Tile{blocked: true, block_sight: true, ..Tile::ground(kind)}
+    }
+
+    pub fn is_door(&self) -> bool {
+        self.kind == TileKind::Door || self.kind == TileKind::LockedDoor
+    }
+
+    /// open ground full of smoke, which blocks the sight until it clears,
+    /// see `make_smoke`
+    pub fn is_smoky(&self) -> bool {
+        !self.blocked && self.block_sight
+    }
+
+    /// whether a creature that moves like `movement` can open the door
+    /// here, it can't be bothered with one that's open already
+    pub fn can_open(&self, movement: Movement) -> bool {
+        self.kind == TileKind::Door && self.blocked && movement.opens_doors
+    }
+
+    /// open or close the door here, opening a locked one breaks the lock
+    pub fn set_door_open(&mut self, open: bool) {
+        self.blocked = !open;
+        self.block_sight = !open;
+        if open {
+            self.kind = TileKind::Door;
+        }
+    }
+}
+
+impl Tile {
+    /// the tile's flags packed into the lowest four bits, its kind into
+    /// the upper four
+    fn to_bits(&self) -> u8 {
+        (self.blocked as u8) | (self.explored as u8) << 1 | (self.block_sight as u8) << 2 |
+            (self.hidden as u8) << 3 | self.kind.to_bits() << 4
+    }
+
+    fn from_bits(bits: u8) -> Self {
+        Tile{blocked: bits & 1 != 0, explored: bits & 2 != 0, block_sight: bits & 4 != 0, hidden: bits & 8 != 0,
+             kind: TileKind::from_bits(bits >> 4)}
+    }
+}
+
+/// The map as it is stored in a save: the tiles column by column, as runs
+/// of identical tiles. Maps are mostly long stretches of unexplored wall, so
+/// this is a small fraction of the size of a JSON struct for every tile.
+#[derive(Serialize, Deserialize)]
+struct PackedMap {
+    width: usize,
+    height: usize,
+    /// (tile bits, how many tiles in a row have them)
+    runs: Vec<(u8, u16)>,
+}
+
+impl PackedMap {
+    fn pack(map: &Map) -> Self {
+        let mut runs: Vec<(u8, u16)> = vec![];
+        for tile in map.iter().flat_map(|column| column.iter()) {
+            let bits = tile.to_bits();
+            match runs.last_mut() {
+                Some(&mut (last_bits, ref mut count)) if last_bits == bits && *count < u16::max_value() => {
+                    *count += 1;
+                    continue;
+                }
+                _ => {}
+            }
+            runs.push((bits, 1));
+        }
+        PackedMap {
+            width: map.len(),
+            height: map.first().map_or(0, |column| column.len()),
+            runs: runs,
+        }
+    }
+
+    /// the map in the size it was saved at, whatever the config says now,
+    /// see `load_game`
+    fn unpack(&self) -> Result<Map, String> {
+        let tiles: Vec<Tile> = self.runs.iter()
+            .flat_map(|&(bits, count)| std::iter::repeat(Tile::from_bits(bits)).take(count as usize))
+            .collect();
+        if tiles.len() != self.width * self.height {
+            return Err(format!("the map should have {} tiles, the save has {}",
+                               self.width * self.height, tiles.len()));
+        }
+        Ok(tiles.chunks(self.height).map(|column| column.to_vec()).collect())
+    }
+}
+
+fn serialize_map<S: serde::Serializer>(map: &Map, serializer: S) -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+    PackedMap::pack(map).serialize(serializer)
+}
+
+fn deserialize_map<D: serde::Deserializer>(deserializer: D) -> Result<Map, D::Error> {
+    use serde::Deserialize;
+    use serde::de::Error;
+    let packed = try! { PackedMap::deserialize(deserializer) };
+    packed.unpack().map_err(D::Error::custom)
+}
+
+/// A rectangle on the map, used to characterise a room.
+#[derive(Clone, Copy, Debug)]
+pub struct Rect {
+    x1: i32,
+    y1: i32,
+    x2: i32,
+    y2: i32,
+}
+
+impl Rect {
+    pub fn new(x: i32, y: i32, w: i32, h: i32) -> Self {
+        Rect { x1: x, y1: y, x2: x + w, y2: y + h }
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        let center_x = (self.x1 + self.x2) / 2;
+        let center_y = (self.y1 + self.y2) / 2;
+        (center_x, center_y)
+    }
+
+    pub fn intersects_with(&self, other: &Rect) -> bool {
+        // returns true if this rectangle intersects with another one
+        (self.x1 <= other.x2) && (self.x2 >= other.x1) &&
+            (self.y1 <= other.y2) && (self.y2 >= other.y1)
+    }
+}
+
+/// This is a generic object: the player, a monster, an item, the stairs...
+/// It's always represented by a character on screen.
+#[derive(Debug, Serialize, Deserialize)]
+struct Object {
+    x: i32,
+    y: i32,
+    char: char,
+    color: Color,
+    name: String,
+    blocks: bool,
+    alive: bool,
+    fighter: Option<Fighter>,
+    ai: Option<Ai>,
+    item: Option<Item>,
+    equipment: Option<Equipment>,
+    // id of the item's effect or the monster's ability, see `EffectRegistry`
+    effect: Option<String>,
+    inventory: Vec<Object>,
+    // uses left, for items that have more than one, and spells left for a
+    // monster that only has so many
+    charges: Option<i32>,
+    // the turn the object rots away at, if it does
+    rots_at: Option<u32>,
+    // who a monster sides with, and its place in the pack it came with
+    faction: Option<Faction>,
+    pack: Option<PackRole>,
+    // how it takes to the player, only hostile ones get attacked on a bump
+    disposition: Disposition,
+    trap: Option<Trap>,
+    // turns of poison left
+    poison: u32,
+    movement: Movement,
+    // what a townsperson offers, they can't be attacked
+    service: Option<Service>,
+    gold: u32,
+    // luck before equipment, only the player's ever changes
+    base_luck: i32,
+    // tiles a side, (x, y) is the top left one of the square it fills
+    size: i32,
+    // a monster that has spotted the player, it lets them know when it does
+    alerted: bool,
+    always_visible: bool,
+    level: i32,
+    // a free slot in the objects list, see `ObjectSlots`
+    removed: bool,
+    // how many times the slot was reused, see `ObjectId`
+    generation: u32,
+}
+
+impl Object {
+    pub fn new(x: i32, y: i32, char: char, name: &str, color: Color, blocks: bool) -> Self {
+        Object {
+            x: x,
+            y: y,
+            char: char,
+            color: color,
+            name: name.into(),
+            blocks: blocks,
+            alive: false,
+            fighter: None,
+            ai: None,
+            item: None,
+            equipment: None,
+            effect: None,
+            inventory: vec![],
+            charges: None,
+            rots_at: None,
+            faction: None,
+            pack: None,
+            disposition: Disposition::Hostile,
+            trap: None,
+            poison: 0,
+            movement: Movement::walking(),
+            service: None,
+            gold: 0,
+            base_luck: 0,
+            size: 1,
+            alerted: false,
+            always_visible: false,
+            level: 1,
+            removed: false,
+            generation: 0,
+        }
+    }
+
+    /// draw the character that represents this object on every tile it fills, in its color
+    pub fn draw(&self, con: &mut Screen, camera: &Camera) {
+        for (x, y) in self.tiles() {
+            if let Some((x, y)) = camera.to_screen(x, y) {
+                con.put_char(x, y, self.char, self.color);
+            }
+        }
+    }
+
+    /// Erase the character that represents this object
+    pub fn clear(&self, con: &mut Screen, camera: &Camera) {
+        for (x, y) in self.tiles() {
+            if let Some((x, y)) = camera.to_screen(x, y) {
+                con.put_char(x, y, ' ', colors::WHITE);
+            }
+        }
+    }
+
+    /// the statuses the object is under right now
+    pub fn statuses(&self) -> Vec<Status> {
+        let mut statuses = vec![];
+        match self.ai {
+            Some(Ai::Confused{..}) => statuses.push(Status::Confused),
+            Some(Ai::Investigating{..}) => statuses.push(Status::Investigating),
+            Some(Ai::Fleeing{..}) => statuses.push(Status::Fleeing),
+            Some(Ai::Preparing{..}) => statuses.push(Status::Preparing),
+            Some(Ai::Flanking{..}) => statuses.push(Status::Flanking),
+            Some(Ai::Asleep{..}) => statuses.push(Status::Asleep),
+            _ => {}
+        }
+        if self.poison > 0 {
+            statuses.push(Status::Poisoned);
+        }
+        statuses
+    }
+
+    /// out to fight the player, see `Disposition`
+    pub fn is_hostile(&self) -> bool {
+        self.disposition == Disposition::Hostile
+    }
+
+    /// a trap nobody has found yet
+    pub fn hidden(&self) -> bool {
+        self.trap.map_or(false, |trap| trap.hidden)
+    }
+
+    pub fn pos(&self) -> (i32, i32) {
+        (self.x, self.y)
+    }
+
+    pub fn set_pos(&mut self, x: i32, y: i32) {
+        self.x = x;
+        self.y = y;
+    }
+
+    /// the tiles the object fills, just its position unless it's a large monster
+    pub fn tiles(&self) -> Vec<(i32, i32)> {
+        let (x, y, size) = (self.x, self.y, self.size);
+        (x..x + size).flat_map(|x| (y..y + size).map(move |y| (x, y))).collect()
+    }
+
+    /// whether (x, y) is one of the tiles the object fills
+    pub fn occupies(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.x + self.size && y < self.y + self.size
+    }
+
+    /// the tile of the object closest to (x, y)
+    pub fn nearest_tile(&self, x: i32, y: i32) -> (i32, i32) {
+        (cmp::max(self.x, cmp::min(x, self.x + self.size - 1)),
+         cmp::max(self.y, cmp::min(y, self.y + self.size - 1)))
+    }
+
+    /// whether any of the object's tiles is in view
+    pub fn in_fov(&self, fov_map: &FovMap) -> bool {
+        self.tiles().into_iter().any(|(x, y)| fov_map.is_in_fov(x, y))
+    }
+
+    /// return the distance to another object, between their closest tiles
+    pub fn distance_to(&self, other: &Object) -> f32 {
+        let (x, y) = other.nearest_tile(self.x, self.y);
+        let (x, y) = self.nearest_tile(x, y);
+        other.distance(x, y)
+    }
+
+
+    /// return the distance to some coordinates, from the object's closest tile
+    pub fn distance(&self, x: i32, y: i32) -> f32 {
+        let (near_x, near_y) = self.nearest_tile(x, y);
+        (((x - near_x).pow(2) + (y - near_y).pow(2)) as f32).sqrt()
+    }
+
+    pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
+        // nobody sleeps through that
+        self.wake_up();
+        // nor lets it go
+        if self.disposition == Disposition::Neutral {
+            self.disposition = Disposition::Hostile;
+        }
+        // apply damage if possible
+        if let Some(fighter) = self.fighter.as_mut() {
+            if damage > 0 {
+                fighter.hp -= damage;
+            }
+        }
+        // check for death, call the death function
+        if let Some(fighter) = self.fighter {
+            if fighter.hp <= 0 {
+                self.alive = false;
+                fighter.on_death.callback(self, game);
+                return Some(fighter.xp);
+            }
+        }
+        None
+    }
+
+    /// Hit `target`. Returns the experience for killing it, if it died.
+    pub fn attack(&mut self, target: &mut Object, game: &mut Game) -> Option<i32> {
+        // a simple formula for attack damage
+        let damage = self.power() - target.defense();
+        debug!("{} attacks {}: {} power - {} defense = {} damage",
+               self.name, target.name, self.power(), target.defense(), damage);
+        let critical_chance = game.rules().critical_percent + self.luck() * LUCK_CRITICAL_PERCENT;
+        if damage > 0 && game_rng().gen_range(0, 100) < critical_chance {
+            game.log.add(format!("{} lands a critical hit on {} for {} hit points!",
+                                 self.name, target.name, damage * 2),
+                         colors::LIGHT_ORANGE);
+            target.take_damage(damage * 2, game)
+        } else if damage > 0 {
+            // make the target take some damage
+            game.log.add(format!("{} attacks {} for {} hit points.", self.name, target.name, damage),
+                         colors::WHITE);
+            target.take_damage(damage, game)
+        } else {
+            game.log.add(format!("{} attacks {} but it has no effect!", self.name, target.name),
+                         colors::WHITE);
+            None
+        }
+    }
+
+    /// put the monster to sleep, see `ai_sleep`
+    pub fn fall_asleep(&mut self) {
+        if let Some(ai) = self.ai.take() {
+            self.ai = Some(Ai::Asleep{previous_ai: Box::new(ai)});
+        }
+    }
+
+    /// wake the monster up, if it's asleep; returns whether it was
+    pub fn wake_up(&mut self) -> bool {
+        match self.ai.take() {
+            Some(Ai::Asleep{previous_ai}) => {
+                self.ai = Some(*previous_ai);
+                true
+            }
+            ai => {
+                self.ai = ai;
+                false
+            }
+        }
+    }
+
+    /// heal by the given amount, without going over the maximum
+    pub fn heal(&mut self, amount: i32) {
+        let max_hp = self.max_hp();
+        if let Some(ref mut fighter) = self.fighter {
+            fighter.hp += amount;
+            if fighter.hp > max_hp {
+                fighter.hp = max_hp;
+            }
+        }
+    }
+
+    /// Equip object and show a message about it
+    pub fn equip(&mut self, log: &mut Vec<(String, Color)>) {
+        if self.item.is_none() {
+            log.add(format!("Can't equip {:?} because it's not an Item.", self),
+                    colors::RED);
+            return
+        };
+        if let Some(ref mut equipment) = self.equipment {
+            if !equipment.equipped {
+                equipment.equipped = true;
+                log.add(format!("Equipped {} on {}.", self.name, equipment.slot),
+                        colors::LIGHT_GREEN);
+            }
+        } else {
+            log.add(format!("Can't equip {:?} because it's not an Equipment.", self),
+                    colors::RED);
+        }
+    }
+
+    /// Dequip object and show a message about it
+    pub fn dequip(&mut self, log: &mut Vec<(String, Color)>) {
+        if self.item.is_none() {
+            log.add(format!("Can't dequip {:?} because it's not an Item.", self),
+                    colors::RED);
+            return
+        };
+        if let Some(ref mut equipment) = self.equipment {
+            if equipment.equipped {
+                equipment.equipped = false;
+                log.add(format!("Dequipped {} from {}.", self.name, equipment.slot),
+                        colors::LIGHT_YELLOW);
+            }
+        } else {
+            log.add(format!("Can't dequip {:?} because it's not an Equipment.", self),
+                    colors::RED);
+        }
+    }
+
+    pub fn power(&self) -> i32 {
+        let base_power = self.fighter.map_or(0, |f| f.base_power);
+        let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.power_bonus);
+        base_power + bonus
+    }
+
+    pub fn defense(&self) -> i32 {
+        let base_defense = self.fighter.map_or(0, |f| f.base_defense);
+        let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.defense_bonus);
+        base_defense + bonus
+    }
+
+    /// how lucky it is with its equipment, see ALTAR_LUCK
+    pub fn luck(&self) -> i32 {
+        let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.luck_bonus);
+        self.base_luck + bonus
+    }
+
+    pub fn max_hp(&self) -> i32 {
+        let base_max_hp = self.fighter.map_or(0, |f| f.base_max_hp);
+        let bonus = self.get_all_equipped().iter().fold(0, |sum, e| sum + e.max_hp_bonus);
+        base_max_hp + bonus
+    }
+
+    /// returns a list of equipped items
+    pub fn get_all_equipped(&self) -> Vec<Equipment> {
+        self.inventory
+            .iter()
+            .filter(|item| {
+                item.equipment.map_or(false, |e| e.equipped)
+            })
+            .map(|item| item.equipment.unwrap())
+            .collect()
+    }
+
+    /// describe the object, including the equipment it visibly carries
+    pub fn describe(&self) -> String {
+        let equipped = self.inventory
+            .iter()
+            .filter(|item| item.equipment.map_or(false, |e| e.equipped))
+            .map(|item| format!("a {}", item.name))
+            .collect::<Vec<_>>();
+        if equipped.is_empty() {
+            format!("You see {}.", self.name)
+        } else {
+            format!("The {} wields {}.", self.name, equipped.join(" and "))
+        }
+    }
+
+    /// what a shop asks for the item, it pays SELL_PRICE_PERCENT of that
+    pub fn price(&self) -> u32 {
+        match self.item {
+            Some(Item::Heal) => 25,
+            Some(Item::Lightning) => 40,
+            Some(Item::Confuse) => 30,
+            Some(Item::Fireball) => 60,
+            Some(Item::Sword) => 80,
+            Some(Item::Shield) => 70,
+            Some(Item::Scripted) => 40,
+            // by the charges it has left
+            Some(Item::Wand) => 30 * cmp::max(self.charges.unwrap_or(0), 0) as u32,
+            Some(Item::Rock) => 1,
+            Some(Item::Bell) => 10,
+            Some(Item::SmokeBomb) => 20,
+            Some(Item::Crown) => 300,
+            Some(Item::Pickaxe) => 50,
+            Some(Item::Digging) => 45,
+            Some(Item::Return) => 60,
+            Some(Item::Charm) => 120,
+            Some(Item::Trophy) => TROPHIES.iter().find(|trophy| trophy.name == self.name).map_or(0, |t| t.price),
+            None => 0,
+        }
+    }
+}
+
+/// Objects are referred to by their index in the objects list, so removing
+/// one must never move the others around. A removed object leaves an inert
+/// free slot behind instead, kept in the game's free list, and the next
+/// object added reuses it.
+trait ObjectSlots {
+    fn insert_object(&mut self, object: Object, free_slots: &mut Vec<usize>) -> usize;
+    fn remove_object(&mut self, id: usize, free_slots: &mut Vec<usize>) -> Object;
+}
+
+impl ObjectSlots for Vec<Object> {
+    fn insert_object(&mut self, mut object: Object, free_slots: &mut Vec<usize>) -> usize {
+        // the list may have been replaced since a slot was freed, only a
+        // slot that is still free is taken
+        while let Some(id) = free_slots.pop() {
+            if id < self.len() && self[id].removed {
+                object.generation = self[id].generation.wrapping_add(1);
+                self[id] = object;
+                return id;
+            }
+        }
+        self.push(object);
+        self.len() - 1
+    }
+
+    fn remove_object(&mut self, id: usize, free_slots: &mut Vec<usize>) -> Object {
+        take_object(self, id, free_slots)
+    }
+}
+
+/// `remove_object` for when the objects list can only be changed in place,
+/// like while the monsters take their turns.
+fn take_object(objects: &mut [Object], id: usize, free_slots: &mut Vec<usize>) -> Object {
+    assert!(id != PLAYER, "the player can't be removed");
+    let mut free_slot = Object::new(0, 0, ' ', "", colors::BLACK, false);
+    free_slot.removed = true;
+    free_slot.generation = objects[id].generation;
+    free_slots.push(id);
+    std::mem::replace(&mut objects[id], free_slot)
+}
+
+/// The free slots of an objects list that was put together anew, like
+/// when coming back to a level or loading a game.
+fn free_slots(objects: &[Object]) -> Vec<usize> {
+    (0..objects.len()).filter(|&id| objects[id].removed).collect()
+}
+
+/// An object that is kept track of for later, when its slot may have been
+/// reused by another one: the generation tells them apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ObjectId {
+    index: usize,
+    generation: u32,
+}
+
+impl ObjectId {
+    pub fn of(id: usize, objects: &[Object]) -> Self {
+        ObjectId { index: id, generation: objects[id].generation }
+    }
+
+    /// the object's id, if it's still the one in its slot
+    pub fn find(&self, objects: &[Object]) -> Option<usize> {
+        match objects.get(self.index) {
+            Some(object) if !object.removed && object.generation == self.generation => Some(self.index),
+            _ => None,
+        }
+    }
+}
+
+/// move by the given amount, if the destination is not blocked
+fn move_by(id: usize, dx: i32, dy: i32, map: &Map, objects: &mut [Object]) {
+    let (x, y) = objects[id].pos();
+    if can_move_to(id, x + dx, y + dy, map, objects) {
+        objects[id].set_pos(x + dx, y + dy);
+        // the step itself is paid for already, the ground may make it
+        // cheaper or dearer
+        let cost = step_cost(&map[(x + dx) as usize][(y + dy) as usize], objects[id].movement);
+        if let Some(fighter) = objects[id].fighter.as_mut() {
+            fighter.energy -= cost - ACTION_COST;
+        }
+    }
+}
+
+/// How a creature gets around: walkers keep to the floor, flyers pass over
+/// whatever lies in their way, burrowers dig through rock, slowly, and
+/// swimmers are at home in the water. Some can open doors on top of that,
+/// though not locked ones.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Movement {
+    walks: bool,
+    flies: bool,
+    burrows: bool,
+    swims: bool,
+    opens_doors: bool,
+}
+
+impl Movement {
+    pub fn walking() -> Self {
+        Movement { walks: true, flies: false, burrows: false, swims: false, opens_doors: false }
+    }
+
+    pub fn flying() -> Self {
+        Movement { walks: false, flies: true, burrows: false, swims: false, opens_doors: false }
+    }
+}
+
+/// Whether a creature that moves like `movement` can be on `tile`. Every
+/// move goes through this, the creature's own steps as well as the ones it
+/// is forced to take.
+fn can_enter(tile: &Tile, movement: Movement) -> bool {
+    if tile.blocked {
+        movement.burrows
+    } else if tile.kind.is_water() {
+        movement.walks || movement.flies || movement.swims
+    } else {
+        movement.walks || movement.flies
+    }
+}
+
+/// Whether `tile` hurts a creature that moves like `movement`: lava burns
+/// everyone but flyers, deep water may drown everyone but flyers and
+/// swimmers.
+fn is_hazard(tile: &Tile, movement: Movement) -> bool {
+    match tile.kind {
+        TileKind::Lava => !movement.flies,
+        TileKind::DeepWater => !movement.flies && !movement.swims,
+        _ => false,
+    }
+}
+
+/// Whether the object `id` would step onto (x, y) of its own accord: it can
+/// be there and the ground won't hurt it.
+fn will_step_to(id: usize, x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    can_move_to(id, x, y, map, objects) && !is_hazard_at(id, x, y, map, objects)
+}
+
+/// Whether any tile the object `id` would fill at (x, y) hurts it.
+fn is_hazard_at(id: usize, x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    let (size, movement) = (objects[id].size, objects[id].movement);
+    (x..x + size).any(|x| (y..y + size).any(|y| is_hazard(&map[x as usize][y as usize], movement)))
+}
+
+/// The energy a step onto `tile` costs a creature that moves like
+/// `movement`. Flyers don't mind the ground below them, nor swimmers the
+/// water, and digging through rock takes a while.
+fn step_cost(tile: &Tile, movement: Movement) -> i32 {
+    if tile.blocked {
+        ACTION_COST + BURROW_EXTRA_COST
+    } else if movement.flies || (movement.swims && tile.kind.is_water()) {
+        ACTION_COST
+    } else {
+        tile.kind.move_cost()
+    }
+}
+
+/// Whether the object `id` can be at (x, y): every tile it would fill
+/// suits the way it moves and nothing else stands there.
+fn can_move_to(id: usize, x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    can_fill(id, x, y, map, objects, |x, y| {
+        objects.iter().enumerate().any(|(other, o)| other != id && o.blocks && o.occupies(x, y))
+    })
+}
+
+/// Whether every tile the object `id` would fill at (x, y) is on the map
+/// and suits the way it moves, and `taken` says nobody stands there.
+fn can_fill<F: Fn(i32, i32) -> bool>(id: usize, x: i32, y: i32, map: &Map, objects: &[Object], taken: F) -> bool {
+    let size = objects[id].size;
+    (x..x + size).all(|x| (y..y + size).all(|y| {
+        x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+            can_enter(&map[x as usize][y as usize], objects[id].movement) && !taken(x, y)
+    }))
+}
+
+fn move_towards(id: usize, target_x: i32, target_y: i32, map: &mut Map, objects: &mut [Object]) {
+    // vector from this object to the target, and distance
+    let dx = target_x - objects[id].x;
+    let dy = target_y - objects[id].y;
+    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
+
+    // normalize it to length 1 (preserving direction), then round it and
+    // convert to integer so the movement is restricted to the map grid
+    let dx = (dx as f32 / distance).round() as i32;
+    let dy = (dy as f32 / distance).round() as i32;
+    let (x, y) = objects[id].pos();
+    if open_door_in_way(id, x + dx, y + dy, map, objects) {
+        return;
+    }
+    if will_step_to(id, x + dx, y + dy, map, objects) {
+        move_by(id, dx, dy, map, objects);
+    }
+}
+
+/// Open the door at (x, y) for the object `id` if it's closed and the
+/// object can open it, that takes its step. Returns whether it did.
+fn open_door_in_way(id: usize, x: i32, y: i32, map: &mut Map, objects: &[Object]) -> bool {
+    let in_map = x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height;
+    if !in_map || objects[id].size > 1 || !map[x as usize][y as usize].can_open(objects[id].movement) {
+        return false;
+    }
+    debug!("{} #{} opens the door at {:?}", objects[id].name, id, (x, y));
+    map[x as usize][y as usize].set_door_open(true);
+    true
+}
+
+/// Take one step along the cheapest way for the object `id` to (target_x,
+/// target_y): around whatever stands in the way and over the easiest
+/// ground, or through doors it can open. When there's no such way, or it's
+/// a long detour, head straight for the target instead. The path goes by
+/// `game.blockers`, the step itself by where everyone stands now.
+fn move_astar(id: usize, target_x: i32, target_y: i32, game: &mut Game, objects: &mut [Object]) {
+    let map = &mut game.map;
+    let step = {
+        let others: &[Object] = objects;
+        let map: &Map = map;
+        let blockers = &game.blockers;
+        let movement = others[id].movement;
+        let mut path = AStar::new_from_callback(CONFIG.map_width, CONFIG.map_height, move |_from, (x, y)| {
+            if (x, y) == (target_x, target_y) {
+                // the target itself is usually someone standing there
+                1.0
+            } else if can_fill(id, x, y, map, others, |x, y| blockers.taken(x, y, id)) &&
+                !is_hazard_at(id, x, y, map, others) {
+                step_cost(&map[x as usize][y as usize], movement) as f32 / ACTION_COST as f32
+            } else if map[x as usize][y as usize].can_open(movement) && others[id].size == 1 {
+                // a step to open it and one to go through
+                2.0
+            } else {
+                0.0
+            }
+        }, 1.41);
+        if path.find(others[id].pos(), (target_x, target_y)) && path.len() <= MONSTER_PATH_LIMIT {
+            path.walk_one_step(false)
+        } else {
+            None
+        }
+    };
+    match step {
+        Some((x, y)) if open_door_in_way(id, x, y, map, objects) => {}
+        Some((x, y)) => {
+            let (from_x, from_y) = objects[id].pos();
+            move_by(id, x - from_x, y - from_y, map, objects);
+        }
+        None => move_towards(id, target_x, target_y, map, objects),
+    }
+}
+
+/// Mutably borrow two *separate* elements from the given slice.
+/// Panics when the indexes are equal or out of bounds.
+fn mut_two<T>(first_index: usize, second_index: usize, items: &mut [T]) -> (&mut T, &mut T) {
+    assert!(first_index != second_index);
+    let split_at_index = cmp::max(first_index, second_index);
+    let (first_slice, second_slice) = items.split_at_mut(split_at_index);
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+/// add to the player's inventory and remove from the map
+fn pick_item_up(object_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
+    if objects[PLAYER].inventory.len() >= INVENTORY_SIZE {
+        game.log.add(format!("Your inventory is full, cannot pick up {}.", objects[object_id].name),
+                     colors::RED);
+    } else {
+        let mut item = objects.remove_object(object_id, &mut game.free_slots);
+        item.rots_at = None;
+        game.log.add(format!("You picked up a {}!", item.name), colors::GREEN);
+        let inventory = &mut objects[PLAYER].inventory;
+        let index = inventory.len();
+        let slot = item.equipment.map(|e| e.slot);
+        inventory.push(item);
+
+        // automatically equip, if the corresponding equipment slot is unused
+        if let Some(slot) = slot {
+            if get_equipped_in_slot(slot, inventory).is_none() {
+                inventory[index].equip(&mut game.log);
+            }
+        }
+    }
+}
+
+/// Remove the remains that rotted away by now; some of the corpses raise
+/// flies as they go.
+fn remove_rotten(objects: &mut Vec<Object>, game: &mut Game, fov_map: &FovMap) {
+    let rotten: Vec<usize> = (0..objects.len())
+        .filter(|&id| objects[id].rots_at.map_or(false, |turn| turn <= game.turn))
+        .collect();
+    for id in rotten {
+        let object = objects.remove_object(id, &mut game.free_slots);
+        let (x, y) = object.pos();
+        let flies = object.item.is_none() && game_rng().gen::<f32>() < FLIES_CHANCE;
+        if flies && can_spawn("swarm of flies", objects) {
+            if fov_map.is_in_fov(x, y) {
+                game.log.add(format!("A swarm of flies rises from the {}.", object.name),
+                             colors::DARK_GREEN);
+            }
+            let swarm = create_monster("swarm of flies", x, y).expect("Flies are a known species");
+            objects.insert_object(swarm, &mut game.free_slots);
+        } else if fov_map.is_in_fov(x, y) {
+            game.log.add(format!("The {} rots away.", object.name), colors::LIGHT_GREY);
+        }
+    }
+}
+
+/// Let the oldest remains rot away right now if there are too many of them.
+fn cleanup_level(objects: &mut Vec<Object>, game: &mut Game, fov_map: &FovMap) {
+    let mut decaying: Vec<(usize, u32)> = objects.iter()
+        .enumerate()
+        .filter_map(|(id, o)| o.rots_at.map(|turn| (id, turn)))
+        .collect();
+    if decaying.len() > MAX_REMAINS {
+        decaying.sort_by_key(|&(_, turn)| turn);
+        let excess = decaying.len() - MAX_REMAINS;
+        for &(id, _) in &decaying[..excess] {
+            objects[id].rots_at = Some(game.turn);
+        }
+        remove_rotten(objects, game, fov_map);
+    }
+}
+
+/// Make `object` rot away `turns` from now.
+fn start_decay(object: &mut Object, turns: u32, game: &mut Game) {
+    let turn = game.turn + turns;
+    object.rots_at = Some(turn);
+    game.schedule.at(turn, TimedEvent::Rot);
+}
+
+/// Look for secret doors and traps around the player, each one turns up with
+/// `chance`. Returns how many were found.
+fn search(chance: f32, objects: &mut [Object], game: &mut Game) -> usize {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut found = 0;
+    for (x, y) in (-1..2).flat_map(|dx| (-1..2).map(move |dy| (player_x + dx, player_y + dy))) {
+        if x < 0 || y < 0 || x >= CONFIG.map_width || y >= CONFIG.map_height {
+            continue;
+        }
+        if game.map[x as usize][y as usize].hidden && game_rng().gen::<f32>() < chance {
+            // still closed, but the player knows it for a door now
+            game.map[x as usize][y as usize] = Tile { explored: true, ..Tile::door(false) };
+            found += 1;
+        }
+    }
+    if found > 0 {
+        game.log.add("You find a secret door!", colors::LIGHT_CYAN);
+    }
+    for object in objects.iter_mut() {
+        let near = (object.x - player_x).abs() <= 1 && (object.y - player_y).abs() <= 1;
+        if near && object.hidden() && game_rng().gen::<f32>() < chance {
+            if let Some(ref mut trap) = object.trap {
+                trap.hidden = false;
+            }
+            game.log.add(format!("You find a {}!", object.name), colors::LIGHT_CYAN);
+            found += 1;
+        }
+    }
+    found
+}
+
+fn create_trap(kind: TrapKind, x: i32, y: i32) -> Object {
+    let mut trap = Object::new(x, y, '^', kind.name(), kind.color(), false);
+    trap.trap = Some(Trap { kind: kind, hidden: true });
+    // once found, it's remembered like the stairs
+    trap.always_visible = true;
+    trap
+}
+
+/// Kneel at the altar the player stepped on, if there is one. Its blessing
+/// makes them luckier, once; after that it's just cold stone.
+fn kneel_at_altar(objects: &mut [Object], game: &mut Game) {
+    let position = objects[PLAYER].pos();
+    let altar_id = objects.iter().position(|o| o.pos() == position && !o.removed && o.name == ALTAR_NAME);
+    if let Some(altar_id) = altar_id {
+        pray_at(altar_id, objects, game);
+    }
+}
+
+/// The altar `altar_id` grants the player some luck, once.
+fn pray_at(altar_id: usize, objects: &mut [Object], game: &mut Game) {
+    objects[PLAYER].base_luck += ALTAR_LUCK;
+    game.stats.prayers += 1;
+    let altar = &mut objects[altar_id];
+    altar.name = "cold altar".into();
+    altar.color = colors::GREY;
+    game.log.add("You kneel at the altar. A warm glow fills you, and you feel lucky.", colors::LIGHT_AMBER);
+}
+
+/// Set off the trap under the creature `victim_id`, if there is one.
+fn spring_trap(victim_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let position = objects[victim_id].pos();
+    let trap_id = objects.iter().position(|o| o.pos() == position && o.trap.is_some() && !o.removed);
+    if let Some(trap_id) = trap_id {
+        trigger_trap(trap_id, victim_id, objects, game, fov_map);
+    }
+}
+
+/// What the ground does to the creature `victim_id` ending its turn there:
+/// lava burns it, deep water may drown it.
+fn terrain_effects(victim_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let (x, y) = objects[victim_id].pos();
+    let tile = game.map[x as usize][y as usize];
+    if !objects[victim_id].alive || objects[victim_id].fighter.is_none() ||
+        !is_hazard(&tile, objects[victim_id].movement) {
+        return;
+    }
+    let player = victim_id == PLAYER;
+    let seen = player || fov_map.is_in_fov(x, y);
+    let name = objects[victim_id].name.clone();
+    match tile.kind {
+        TileKind::Lava => {
+            if player {
+                game.log.add("The lava burns you!", colors::RED);
+            } else if seen {
+                game.log.add(format!("The {} burns in the lava!", name), colors::LIGHT_GREY);
+            }
+            inflict_damage(victim_id, LAVA_DAMAGE, DamageSource::Hazard(None), objects, game);
+        }
+        TileKind::DeepWater if game_rng().gen::<f32>() < DROWNING_CHANCE => {
+            if player {
+                game.log.add("You go under and swallow water!", colors::RED);
+            } else if seen {
+                game.log.add(format!("The {} flounders in the deep water!", name), colors::LIGHT_GREY);
+            }
+            inflict_damage(victim_id, DROWNING_DAMAGE, DamageSource::Hazard(None), objects, game);
+        }
+        _ => {}
+    }
+}
+
+/// The trap `trap_id` goes off on the creature `victim_id`.
+fn trigger_trap(trap_id: usize, victim_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let kind = match objects[trap_id].trap {
+        Some(trap) => trap.kind,
+        None => return,
+    };
+    let (x, y) = objects[victim_id].pos();
+    let player = victim_id == PLAYER;
+    let seen = player || fov_map.is_in_fov(x, y);
+    if seen {
+        if let Some(ref mut trap) = objects[trap_id].trap {
+            trap.hidden = false;
+        }
+    }
+    let name = objects[victim_id].name.clone();
+    debug!("{} #{} sets off the {} at {:?}", name, victim_id, kind.name(), (x, y));
+    match kind {
+        TrapKind::Spikes => {
+            if player {
+                game.log.add("You fall into a spike pit!", colors::RED);
+            } else if seen {
+                game.log.add(format!("The {} falls into a spike pit!", name), colors::LIGHT_GREY);
+            }
+            inflict_damage(victim_id, SPIKE_DAMAGE, DamageSource::Hazard(None), objects, game);
+        }
+        TrapKind::PoisonDart => {
+            if player {
+                game.log.add("A poisoned dart hits you!", colors::RED);
+            } else if seen {
+                game.log.add(format!("A poisoned dart hits the {}!", name), colors::LIGHT_GREY);
+            }
+            if !inflict_damage(victim_id, DART_DAMAGE, DamageSource::Hazard(None), objects, game) {
+                let poison = &mut objects[victim_id].poison;
+                *poison = cmp::max(*poison, POISON_TURNS);
+            }
+        }
+        TrapKind::Teleport => {
+            // somewhere random on the level, as long as there's room
+            let destination = (0..100)
+                .map(|_| (game_rng().gen_range(0, CONFIG.map_width), game_rng().gen_range(0, CONFIG.map_height)))
+                .find(|&(x, y)| can_move_to(victim_id, x, y, &game.map, objects));
+            if let Some((x, y)) = destination {
+                if player {
+                    game.log.add("The floor flashes, and you are somewhere else!", colors::LIGHT_MAGENTA);
+                } else if seen {
+                    game.log.add(format!("The {} vanishes in a flash!", name), colors::LIGHT_MAGENTA);
+                }
+                objects[victim_id].set_pos(x, y);
+            }
+        }
+        TrapKind::Alarm => {
+            if seen {
+                game.log.add("An alarm bell rings out!", colors::LIGHT_YELLOW);
+            } else {
+                game.log.add("You hear an alarm ringing somewhere.", colors::LIGHT_YELLOW);
+            }
+            make_noise(x, y, ALARM_LOUDNESS, objects, game, fov_map);
+        }
+    }
+}
+
+/// The poison in everyone who has some does its work for another turn.
+fn suffer_poison(objects: &mut [Object], game: &mut Game) {
+    for id in 0..objects.len() {
+        if objects[id].poison == 0 {
+            continue;
+        }
+        if objects[id].fighter.is_none() {
+            // dead already
+            objects[id].poison = 0;
+            continue;
+        }
+        objects[id].poison -= 1;
+        if id == PLAYER {
+            game.log.add("The poison burns in your veins.", colors::CHARTREUSE);
+        }
+        inflict_damage(id, POISON_DAMAGE, DamageSource::Hazard(None), objects, game);
+        if id == PLAYER && objects[id].poison == 0 && objects[id].alive {
+            game.log.add("The poison wears off.", colors::LIGHT_GREEN);
+        }
+    }
+}
+
+/// Put everything dead creatures were carrying on the floor where they died.
+fn drop_loot_of_the_dead(objects: &mut Vec<Object>, game: &mut Game) {
+    let mut dropped = vec![];
+    for object in objects.iter_mut().filter(|o| !o.alive && !o.inventory.is_empty()) {
+        let (x, y) = object.pos();
+        for mut item in object.inventory.drain(..) {
+            if let Some(ref mut equipment) = item.equipment {
+                equipment.equipped = false;
+            }
+            item.set_pos(x, y);
+            start_decay(&mut item, JUNK_DECAY_TURNS, game);
+            dropped.push(item);
+        }
+    }
+    for item in dropped {
+        objects.insert_object(item, &mut game.free_slots);
+    }
+}
+
+fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
+    for (inventory_id, item) in inventory.iter().enumerate() {
+        if item.equipment.as_ref().map_or(false, |e| e.equipped && e.slot == slot) {
+            return Some(inventory_id)
+        }
+    }
+    None
+}
+
+fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
+    // first test the map tile
+    if map[x as usize][y as usize].blocked {
+        return true;
+    }
+    // now check for any blocking objects
+    objects.iter().any(|object| {
+        object.blocks && object.occupies(x, y)
+    })
+}
+
+
+// combat-related properties and methods (monster, player, NPC).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Fighter {
+    hp: i32,
+    base_max_hp: i32,
+    base_defense: i32,
+    base_power: i32,
+    xp: i32,
+    speed: i32,
+    energy: i32,
+    // how much it takes to make it run, see `fear`: 100 never runs
+    morale: i32,
+    on_death: DeathCallback,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum DeathCallback {
+    Player,
+    Monster,
+}
+
+impl DeathCallback {
+    fn callback(self, object: &mut Object, game: &mut Game) {
+        use DeathCallback::*;
+        let callback: fn(&mut Object, &mut Game) = match self {
+            Player => player_death,
+            Monster => monster_death,
+        };
+        callback(object, game);
+    }
+}
+
+/// A passing condition of a creature, shown on the map as a tint of its
+/// character so it can be seen without reading the log.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Confused,
+    Investigating,
+    Fleeing,
+    Preparing,
+    Flanking,
+    Asleep,
+    Poisoned,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Status::Confused => "confused",
+            Status::Investigating => "investigating",
+            Status::Fleeing => "fleeing",
+            Status::Preparing => "winding up",
+            Status::Flanking => "flanking",
+            Status::Asleep => "asleep",
+            Status::Poisoned => "poisoned",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match *self {
+            Status::Confused => colors::LIGHT_MAGENTA,
+            Status::Investigating => colors::LIGHT_YELLOW,
+            Status::Fleeing => colors::LIGHT_AZURE,
+            Status::Preparing => colors::ORANGE,
+            Status::Flanking => colors::LIGHT_FLAME,
+            Status::Asleep => colors::LIGHT_BLUE,
+            Status::Poisoned => colors::CHARTREUSE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TrapKind {
+    Spikes,
+    PoisonDart,
+    Teleport,
+    Alarm,
+}
+
+impl TrapKind {
+    pub fn all() -> Vec<TrapKind> {
+        vec![TrapKind::Spikes, TrapKind::PoisonDart, TrapKind::Teleport, TrapKind::Alarm]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TrapKind::Spikes => "spike pit",
+            TrapKind::PoisonDart => "poison dart trap",
+            TrapKind::Teleport => "teleport trap",
+            TrapKind::Alarm => "alarm trap",
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        match *self {
+            TrapKind::Spikes => colors::LIGHT_GREY,
+            TrapKind::PoisonDart => colors::CHARTREUSE,
+            TrapKind::Teleport => colors::LIGHT_MAGENTA,
+            TrapKind::Alarm => colors::LIGHT_YELLOW,
+        }
+    }
+}
+
+/// A trap on the floor. It stays hidden until something sets it off or the
+/// player finds it by searching.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Trap {
+    kind: TrapKind,
+    hidden: bool,
+}
+
+/// The side a monster is on. Monsters of a faction lose heart when one of
+/// their leaders falls.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Faction {
+    Greenskins,
+    Vermin,
+    Undead,
+}
+
+/// How a creature takes to the player. Bumping into a hostile one attacks
+/// it, a friendly one swaps places with the player and a neutral one asks
+/// first, see `greet`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Disposition {
+    Hostile,
+    /// leaves the player be until they hurt it
+    Neutral,
+    /// on the player's side, like the folk in town
+    Friendly,
+}
+
+/// What one of the friendly folk in town (or the stash chest) does for the
+/// player when they bump into it.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Service {
+    /// sells what's in its inventory, and buys the player's loot
+    Shop,
+    /// closes the player's wounds and cures their poison, for gold
+    Healer,
+    /// keeps the items the player leaves in it
+    Stash,
+    /// raises the player's stats and lets them take back a level-up's
+    /// choice, both for gold
+    Trainer,
+    /// pays for trophies, a few of a kind at a time, see `Bounty`
+    Hunter,
+}
+
+/// What the player told their companion to do, see `ai_companion`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Order {
+    Follow,
+    Stay,
+    /// fight this creature, then follow again
+    Attack(ObjectId),
+}
+
+/// A monster's place in the pack it was spawned with, by the pack's id.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum PackRole {
+    Leader(u32),
+    Follower(u32),
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Ai {
+    Basic,
+    /// stumbles around until the turn `until`, then goes back to the previous AI
+    Confused{until: u32, previous_ai: Box<Ai>},
+    /// went to see where a noise came from, gives up at the turn `until`
+    Investigating{x: i32, y: i32, until: u32, previous_ai: Box<Ai>},
+    /// runs from the player until the turn `until`, then goes back to the previous AI
+    Fleeing{until: u32, previous_ai: Box<Ai>},
+    /// wound up for its ability, which comes on its next turn
+    Preparing{previous_ai: Box<Ai>},
+    /// keeps its distance and shoots, see `Missile`
+    Ranged,
+    /// goes round to the tile (dx, dy) off the player to come at them from
+    /// there, gives up at the turn `until`
+    Flanking{dx: i32, dy: i32, until: u32, previous_ai: Box<Ai>},
+    /// sleeps until something wakes it up, see `ai_sleep`
+    Asleep{previous_ai: Box<Ai>},
+    /// never moves and only brings forth more monsters, see `Summons`
+    Spawner,
+    /// on the player's side, does what it was told last
+    Companion{order: Order},
+}
+
+fn ai_take_turn(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    use Ai::*;
+    if let Some(ai) = objects[monster_id].ai.take() {
+        let new_ai = match ai {
+            Basic => ai_basic(monster_id, objects, game, fov_map),
+            Confused{until, previous_ai} => ai_confused(monster_id, objects, game, until, previous_ai),
+            Investigating{x, y, until, previous_ai} => ai_investigate(
+                monster_id, objects, game, fov_map, (x, y), until, previous_ai),
+            Fleeing{until, previous_ai} => ai_flee(monster_id, objects, game, until, previous_ai),
+            Preparing{previous_ai} => ai_unleash(monster_id, objects, game, fov_map, previous_ai),
+            Ranged => ai_ranged(monster_id, objects, game, fov_map),
+            Flanking{dx, dy, until, previous_ai} => ai_flank(
+                monster_id, objects, game, fov_map, (dx, dy), until, previous_ai),
+            Asleep{previous_ai} => ai_sleep(monster_id, objects, game, fov_map, previous_ai),
+            Spawner => ai_spawner(monster_id, objects, game, fov_map),
+            Companion{order} => ai_companion(monster_id, order, objects, game),
+        };
+        objects[monster_id].ai = Some(new_ai);
+    }
+}
+
+/// The monsters in view that get to act before the player's next turn, if
+/// the player's action costs `cost`, in the order they do: one that acts
+/// twice is in there twice. The ticks of `advance_time`, worked out ahead.
+fn turn_order(objects: &[Object], cost: i32, fov_map: &FovMap) -> Vec<usize> {
+    let mut energy: Vec<Option<i32>> = objects.iter().map(|o| o.fighter.map(|f| f.energy)).collect();
+    let mut order = vec![];
+    if let Some(player_energy) = energy[PLAYER].as_mut() {
+        *player_energy -= cost;
+    }
+    while objects[PLAYER].alive && energy[PLAYER].map_or(false, |energy| energy < ACTION_COST) {
+        for (id, object) in objects.iter().enumerate() {
+            let fighter = match object.fighter {
+                Some(fighter) => fighter,
+                None => continue,
+            };
+            let energy = energy[id].as_mut().unwrap();
+            *energy += cmp::max(fighter.speed, 1);
+            if id == PLAYER || object.ai.is_none() || *energy < ACTION_COST {
+                continue;
+            }
+            *energy -= ACTION_COST;
+            let asleep = match object.ai {
+                Some(Ai::Asleep{..}) => true,
+                _ => false,
+            };
+            if !asleep && object.in_fov(fov_map) {
+                order.push(id);
+            }
+        }
+    }
+    order
+}
+
+/// Advance time until the player has enough energy to act again. Every tick
+/// each fighter gains energy according to its speed and every monster that
+/// has enough of it takes a turn.
+fn advance_time(objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let can_act = |object: &Object| object.fighter.map_or(false, |f| f.energy >= ACTION_COST);
+    // one map for every monster that chases the player, rather than a path each
+    game.scent = ScentMap::from(objects[PLAYER].pos(), &game.map);
+    // and one look at who stands where for all the paths, see `move_astar`
+    game.blockers = Blockers::from(objects);
+    while objects[PLAYER].alive && !can_act(&objects[PLAYER]) {
+        for id in 0..objects.len() {
+            if let Some(fighter) = objects[id].fighter.as_mut() {
+                fighter.energy += cmp::max(fighter.speed, 1);
+            }
+            if id != PLAYER && objects[id].ai.is_some() && can_act(&objects[id]) {
+                objects[id].fighter.as_mut().unwrap().energy -= ACTION_COST;
+                let position = objects[id].pos();
+                ai_take_turn(id, objects, game, fov_map);
+                if objects[id].pos() != position {
+                    spring_trap(id, objects, game, fov_map);
+                }
+                terrain_effects(id, objects, game, fov_map);
+            }
+        }
+    }
+}
+
+fn ai_basic(monster_id: usize, objects: &mut [Object], game: &mut Game,
+            fov_map: &FovMap) -> Ai {
+    // a companion of the player's at its throat gets fought first, unless
+    // the player is right there too
+    if objects[monster_id].is_hostile() && objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+        let companion = (0..objects.len()).find(|&id| {
+            is_companion(&objects[id]) && objects[id].distance_to(&objects[monster_id]) < 2.0
+        });
+        if let Some(companion_id) = companion {
+            let xp = {
+                let (monster, companion) = mut_two(monster_id, companion_id, objects);
+                monster.attack(companion, game)
+            };
+            award_xp(monster_id, xp.unwrap_or(0), objects, game);
+            return Ai::Basic;
+        }
+    }
+    // a basic monster takes its turn. If you can see it, it may see you
+    if spots_player(monster_id, objects, game, fov_map) {
+        // monsters with an ability try to use it first
+        match use_ability(monster_id, objects, game, fov_map) {
+            Some(UseResult::WindingUp) => return Ai::Preparing{previous_ai: Box::new(Ai::Basic)},
+            Some(_) => return Ai::Basic,
+            None => {}
+        }
+        if call_up_minion(monster_id, objects, game) {
+            return Ai::Basic;
+        }
+        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+            // move towards player if far away, a pack from every side at once
+            if let Some(side) = pack_side(monster_id, objects, game) {
+                let until = game.turn + FLANK_TURNS;
+                return ai_flank(monster_id, objects, game, fov_map, side, until, Box::new(Ai::Basic));
+            }
+            chase_player(monster_id, objects, game);
+        } else if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+            // close enough, attack! (if the player is still alive.)
+            let xp = {
+                let (monster, player) = mut_two(monster_id, PLAYER, objects);
+                monster.attack(player, game)
+            };
+            award_xp(monster_id, xp.unwrap_or(0), objects, game);
+        }
+    } else if !go_for_gear(monster_id, objects, game, fov_map) {
+        keep_up_with_leader(monster_id, objects, game);
+    }
+    Ai::Basic
+}
+
+/// How much good a piece of equipment does whoever has it on, to tell
+/// whether a monster would rather have another.
+fn gear_value(equipment: &Equipment) -> i32 {
+    equipment.power_bonus + equipment.defense_bonus + equipment.max_hp_bonus / 10
+}
+
+/// Whether the species knows what to do with weapons and armour.
+fn uses_gear(species: &str) -> bool {
+    match species {
+        "orc" | "orc chieftain" | "orc warchief" | "goblin archer" | "goblin shaman" | "dark mage" |
+        "necromancer" | "skeleton" => true,
+        _ => false,
+    }
+}
+
+/// Go for the weapon or armour in sight that beats what the monster has on
+/// the most, and put it on once there. What it had on before it keeps, and
+/// drops with the rest when it dies. Returns whether that took its turn.
+fn go_for_gear(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> bool {
+    if !uses_gear(&objects[monster_id].name) {
+        return false;
+    }
+    let best = {
+        let monster = &objects[monster_id];
+        let better_by = |equipment: Equipment| {
+            let worn = get_equipped_in_slot(equipment.slot, &monster.inventory)
+                .and_then(|id| monster.inventory[id].equipment)
+                .map_or(0, |worn| gear_value(&worn));
+            gear_value(&equipment) - worn
+        };
+        objects.iter().enumerate()
+            .filter(|&(_, o)| !o.removed && o.item.is_some() && monster.distance_to(o) <= GEAR_SIGHT)
+            .filter_map(|(id, o)| o.equipment.map(|equipment| (id, better_by(equipment))))
+            .filter(|&(id, better_by)| better_by > 0 && line_of_sight(monster.pos(), objects[id].pos(), &game.map))
+            .max_by_key(|&(id, better_by)| (better_by, -(monster.distance_to(&objects[id]) * 100.0) as i32))
+            .map(|(id, _)| id)
+    };
+    let item_id = match best {
+        Some(item_id) => item_id,
+        None => return false,
+    };
+    let (x, y) = objects[item_id].pos();
+    if objects[monster_id].pos() != (x, y) {
+        debug!("{} #{} goes for the {} at {:?}",
+               objects[monster_id].name, monster_id, objects[item_id].name, (x, y));
+        move_astar(monster_id, x, y, game, objects);
+        return true;
+    }
+    let mut item = take_object(objects, item_id, &mut game.free_slots);
+    item.rots_at = None;
+    let slot = item.equipment.map(|e| e.slot).unwrap();
+    let monster = &mut objects[monster_id];
+    if let Some(worn) = get_equipped_in_slot(slot, &monster.inventory) {
+        monster.inventory[worn].equipment.as_mut().unwrap().equipped = false;
+    }
+    item.equipment.as_mut().unwrap().equipped = true;
+    if monster.in_fov(fov_map) {
+        game.log.add(format!("The {} picks up the {}.", monster.name, item.name), colors::LIGHT_GREY);
+    }
+    monster.inventory.push(item);
+    true
+}
+
+/// A nest: while the player is in sight it keeps bringing forth more of
+/// its kind.
+fn ai_spawner(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> Ai {
+    if objects[monster_id].in_fov(fov_map) {
+        call_up_minion(monster_id, objects, game);
+    }
+    Ai::Spawner
+}
+
+/// What a monster that calls up others brings forth, how many of them it
+/// keeps around at most and how likely it calls up another on its turn.
+struct Summons {
+    minion: &'static str,
+    verb: &'static str,
+    most: usize,
+    chance: f32,
+}
+
+impl Summons {
+    pub fn of(species: &str) -> Option<Summons> {
+        match species {
+            "necromancer" => Some(Summons { minion: "skeleton", verb: "raises", most: 3, chance: 0.3 }),
+            "gnat nest" => Some(Summons { minion: "cloud of gnats", verb: "releases", most: 4, chance: 0.25 }),
+            _ => None,
+        }
+    }
+}
+
+/// The minions of the summoner that are still around.
+fn minions(summoner_id: usize, objects: &[Object]) -> usize {
+    match objects[summoner_id].pack {
+        Some(PackRole::Leader(pack)) => {
+            objects.iter().filter(|o| o.alive && o.pack == Some(PackRole::Follower(pack))).count()
+        }
+        _ => 0,
+    }
+}
+
+/// Have a summoner call up another minion, if it has room for one and
+/// feels like it. The objects list can't grow while the monsters take
+/// their turns, so the minion turns up once they're done, see
+/// `raise_minion`. Returns whether the summoner spent its turn on it.
+fn call_up_minion(summoner_id: usize, objects: &mut [Object], game: &mut Game) -> bool {
+    let summons = match Summons::of(&objects[summoner_id].name) {
+        Some(summons) => summons,
+        None => return false,
+    };
+    if minions(summoner_id, objects) >= summons.most || !can_spawn(summons.minion, objects) ||
+        game_rng().gen::<f32>() >= summons.chance {
+        return false;
+    }
+    debug!("{} #{} calls up a {}", objects[summoner_id].name, summoner_id, summons.minion);
+    let turn = game.turn + 1;
+    game.schedule.at(turn, TimedEvent::Summon(ObjectId::of(summoner_id, objects)));
+    true
+}
+
+/// A free tile right next to (x, y) that's safe to walk on, picked at
+/// random.
+fn spot_beside(x: i32, y: i32, objects: &[Object], map: &Map) -> Option<(i32, i32)> {
+    let mut spots: Vec<(i32, i32)> = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy))).collect();
+    game_rng().shuffle(&mut spots);
+    spots.into_iter().find(|&(x, y)| {
+        x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+            !is_blocked(x, y, map, objects) &&
+            !is_hazard(&map[x as usize][y as usize], Movement::walking())
+    })
+}
+
+/// Put the minion the summoner called up next to it, see `call_up_minion`.
+/// It joins the summoner's pack, the summoner leading it.
+fn raise_minion(summoner: ObjectId, objects: &mut Vec<Object>, game: &mut Game, fov_map: &FovMap) {
+    // it may have died since, or someone else taken its place
+    let summoner_id = match summoner.find(objects) {
+        Some(summoner_id) if objects[summoner_id].alive => summoner_id,
+        _ => return,
+    };
+    let summons = match Summons::of(&objects[summoner_id].name) {
+        Some(summons) => summons,
+        None => return,
+    };
+    if minions(summoner_id, objects) >= summons.most {
+        return;
+    }
+    let (x, y) = objects[summoner_id].pos();
+    let spot = spot_beside(x, y, objects, &game.map);
+    let mut minion = match spot.and_then(|(x, y)| create_monster(summons.minion, x, y)) {
+        Some(minion) => minion,
+        None => return,
+    };
+    let pack = match objects[summoner_id].pack {
+        Some(PackRole::Leader(pack)) => pack,
+        _ => {
+            let pack = new_pack_id(objects);
+            objects[summoner_id].pack = Some(PackRole::Leader(pack));
+            pack
+        }
+    };
+    minion.pack = Some(PackRole::Follower(pack));
+    minion.alerted = true;
+    if objects[summoner_id].in_fov(fov_map) {
+        game.log.add(format!("The {} {} a {}!", objects[summoner_id].name, summons.verb, summons.minion),
+                     colors::LIGHT_VIOLET);
+    }
+    objects.insert_object(minion, &mut game.free_slots);
+}
+
+/// The side of the player a pack member goes round to, one none of the
+/// pack stands on or is heading for.
+fn pack_side(monster_id: usize, objects: &[Object], game: &Game) -> Option<(i32, i32)> {
+    let pack = match objects[monster_id].pack {
+        Some(PackRole::Leader(pack)) | Some(PackRole::Follower(pack)) => pack,
+        None => return None,
+    };
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let taken: Vec<(i32, i32)> = objects.iter().enumerate()
+        .filter(|&(id, o)| id != monster_id && o.alive && match o.pack {
+            Some(PackRole::Leader(other)) | Some(PackRole::Follower(other)) => other == pack,
+            None => false,
+        })
+        .filter_map(|(_, o)| match o.ai {
+            Some(Ai::Flanking{dx, dy, ..}) => Some((dx, dy)),
+            _ => None,
+        })
+        .collect();
+    free_side(monster_id, (player_x, player_y), &taken, &game.map, objects)
+}
+
+/// Whether the creature is the player's companion, see `ai_companion`.
+fn is_companion(object: &Object) -> bool {
+    match object.ai {
+        Some(Ai::Companion{..}) => object.alive && !object.removed,
+        _ => false,
+    }
+}
+
+/// Whether the object is a monster the player's companions fight.
+fn is_foe(object: &Object) -> bool {
+    object.alive && !object.removed && object.fighter.is_some() && object.ai.is_some() && object.is_hostile()
+}
+
+/// The player's companion fights the foe it was told to, or the closest one
+/// it sees, and otherwise keeps close to the player. Told to stay, it
+/// stays put and only fights what comes right up to it.
+fn ai_companion(companion_id: usize, order: Order, objects: &mut [Object], game: &mut Game) -> Ai {
+    // the foe may be gone, and another object in its slot
+    let order = match order {
+        Order::Attack(target) => match target.find(objects) {
+            Some(target_id) if is_foe(&objects[target_id]) => order,
+            _ => Order::Follow,
+        },
+        order => order,
+    };
+    let reach = if order == Order::Stay { 1.5 } else { COMPANION_SIGHT };
+    let target = match order {
+        Order::Attack(target) => target.find(objects),
+        _ => {
+            let companion = &objects[companion_id];
+            (0..objects.len())
+                .filter(|&id| is_foe(&objects[id]) && companion.distance_to(&objects[id]) <= reach)
+                .filter(|&id| line_of_sight(companion.pos(), objects[id].pos(), &game.map))
+                .min_by_key(|&id| (companion.distance_to(&objects[id]) * 100.0) as i32)
+        }
+    };
+    if let Some(target_id) = target {
+        if objects[companion_id].distance_to(&objects[target_id]) < 2.0 {
+            let xp = {
+                let (companion, foe) = mut_two(companion_id, target_id, objects);
+                companion.attack(foe, game)
+            };
+            share_xp(companion_id, xp.unwrap_or(0), objects, game);
+        } else if order != Order::Stay {
+            let (x, y) = objects[target_id].pos();
+            move_astar(companion_id, x, y, game, objects);
+        }
+    } else if order == Order::Follow && objects[companion_id].distance_to(&objects[PLAYER]) > COMPANION_LEASH {
+        let (x, y) = objects[PLAYER].pos();
+        move_astar(companion_id, x, y, game, objects);
+    }
+    Ai::Companion{order: order}
+}
+
+/// The experience for a companion's kill goes to the player and the
+/// companion both, which gets tougher with it, see COMPANION_LEVEL_XP.
+fn share_xp(companion_id: usize, xp: i32, objects: &mut [Object], game: &mut Game) {
+    if xp <= 0 {
+        return;
+    }
+    award_xp(PLAYER, xp, objects, game);
+    let grown = match objects[companion_id].fighter.as_mut() {
+        Some(fighter) => {
+            let levels = (fighter.xp + xp) / COMPANION_LEVEL_XP - fighter.xp / COMPANION_LEVEL_XP;
+            fighter.xp += xp;
+            fighter.base_max_hp += levels * COMPANION_LEVEL_HP;
+            fighter.hp += levels * COMPANION_LEVEL_HP;
+            fighter.base_power += levels;
+            levels > 0
+        }
+        None => false,
+    };
+    if grown {
+        game.log.add(format!("Your {} grows stronger.", objects[companion_id].name), colors::LIGHT_GREEN);
+    }
+}
+
+/// Follow the pack's leader around when it strays too far.
+fn keep_up_with_leader(monster_id: usize, objects: &mut [Object], game: &mut Game) {
+    let pack = match objects[monster_id].pack {
+        Some(PackRole::Follower(pack)) => pack,
+        _ => return,
+    };
+    let leader = objects.iter()
+        .find(|o| o.alive && o.pack == Some(PackRole::Leader(pack)))
+        .map(|leader| leader.pos());
+    if let Some((x, y)) = leader {
+        if objects[monster_id].distance(x, y) > PACK_LEASH {
+            move_astar(monster_id, x, y, game, objects);
+        }
+    }
+}
+
+/// Whether the monster in the player's view notices them, see
+/// `notices_player`. The first time it does, it lets the player know.
+fn spots_player(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> bool {
+    // the ones that aren't after the player let them be
+    if !objects[monster_id].is_hostile() || !objects[monster_id].in_fov(fov_map) ||
+        !notices_player(&objects[monster_id], &objects[PLAYER], game) {
+        return false;
+    }
+    if !objects[monster_id].alerted {
+        objects[monster_id].alerted = true;
+        game.log.add(battle_cry(&objects[monster_id]), colors::ORANGE);
+    }
+    true
+}
+
+/// Take a step towards the player: down the scent if the monster follows
+/// it, or along a path of its own.
+fn chase_player(monster_id: usize, objects: &mut [Object], game: &mut Game) {
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    let (player_x, player_y) = objects[PLAYER].pos();
+    debug!("{} #{} at {:?} moves towards the player at {:?}",
+           objects[monster_id].name, monster_id, (monster_x, monster_y), (player_x, player_y));
+    let step = game.scent.downhill(monster_id, &game.map, objects);
+    match step {
+        Some((x, y)) if open_door_in_way(monster_id, x, y, &mut game.map, objects) => {}
+        Some((x, y)) => move_by(monster_id, x - monster_x, y - monster_y, &game.map, objects),
+        // the way down is taken, or the scent is no use to it
+        None => move_astar(monster_id, player_x, player_y, game, objects),
+    }
+}
+
+/// A monster that shoots: it backs off from a player that comes too close,
+/// shoots when it has a clear line at them in range and closes in
+/// otherwise. Cornered, it shoots point-blank.
+fn ai_ranged(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> Ai {
+    if !spots_player(monster_id, objects, game, fov_map) {
+        return Ai::Ranged;
+    }
+    let missile = Missile::of(&objects[monster_id].name);
+    let (monster, player) = (objects[monster_id].pos(), objects[PLAYER].pos());
+    let distance = objects[monster_id].distance_to(&objects[PLAYER]);
+    if distance < RANGED_MIN_DISTANCE {
+        if let Some((x, y)) = step_away(monster_id, objects, game) {
+            debug!("{} #{} backs off to {:?}", objects[monster_id].name, monster_id, (x, y));
+            move_by(monster_id, x - monster.0, y - monster.1, &game.map, objects);
+            return Ai::Ranged;
+        }
+    }
+    if distance <= missile.range && line_of_sight(monster, player, &game.map) {
+        if objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+            shoot(monster_id, PLAYER, &missile, objects, game);
+        }
+    } else {
+        chase_player(monster_id, objects, game);
+    }
+    Ai::Ranged
+}
+
+/// What a monster with the `Ranged` AI shoots, how far and how likely it
+/// hits. An arrow points the way it flies, the others look like `glyph`.
+struct Missile {
+    name: &'static str,
+    glyph: Option<char>,
+    color: Color,
+    range: f32,
+    hit_chance: f32,
+}
+
+impl Missile {
+    pub fn of(species: &str) -> Missile {
+        match species {
+            "fire imp" => Missile { name: "fire bolt", glyph: Some('*'), color: colors::FLAME, range: 6.0,
+                                    hit_chance: 0.8 },
+            _ => Missile { name: "arrow", glyph: None, color: colors::LIGHTER_SEPIA, range: 8.0, hit_chance: 0.7 },
+        }
+    }
+
+    /// how it looks flying from `from` to `to`
+    pub fn glyph(&self, (x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> char {
+        let (dx, dy) = (x2 - x1, y2 - y1);
+        match self.glyph {
+            Some(glyph) => glyph,
+            None if dx.abs() > 2 * dy.abs() => '-',
+            None if dy.abs() > 2 * dx.abs() => '|',
+            None if (dx > 0) == (dy > 0) => '\\',
+            None => '/',
+        }
+    }
+}
+
+/// A shot on its way, for the screen to show once the monsters' turns are
+/// over: the tiles it flew over and how it looked.
+struct Projectile {
+    path: Vec<(i32, i32)>,
+    glyph: char,
+    color: Color,
+}
+
+/// Shoot a `missile` from `shooter_id` at `target_id`. It may miss, and
+/// whoever stands in the way may take it instead, see `fire`.
+fn shoot(shooter_id: usize, target_id: usize, missile: &Missile, objects: &mut [Object], game: &mut Game) {
+    let (from, to) = (objects[shooter_id].pos(), objects[target_id].pos());
+    let shot = if game_rng().gen::<f32>() < missile.hit_chance {
+        fire(shooter_id, to, objects, &game.map)
+    } else {
+        // wide of the mark, it flies on past the target until it hits a wall
+        let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+        let beyond = (to.0 + dx.signum() * missile.range as i32, to.1 + dy.signum() * missile.range as i32);
+        let open = |&(x, y): &(i32, i32)| {
+            x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+                !game.map[x as usize][y as usize].blocked
+        };
+        let landing = trajectory(from, beyond).into_iter().take_while(open).last().unwrap_or(from);
+        game.log.add(format!("{}'s {} misses {}.", objects[shooter_id].name, missile.name,
+                             objects[target_id].name), colors::WHITE);
+        Shot::Lands(landing)
+    };
+    let end = match shot {
+        Shot::Hits(id) => objects[id].pos(),
+        Shot::Lands(landing) => landing,
+    };
+    let glyph = missile.glyph(from, to);
+    game.shots.push(Projectile { path: trajectory(from, end), glyph: glyph, color: missile.color });
+    if let Shot::Hits(id) = shot {
+        let damage = objects[shooter_id].power() - objects[id].defense();
+        if damage > 0 {
+            game.log.add(format!("{}'s {} hits {} for {} hit points.", objects[shooter_id].name, missile.name,
+                                 objects[id].name, damage), colors::WHITE);
+            inflict_damage(id, damage, DamageSource::Creature(shooter_id), objects, game);
+        } else {
+            game.log.add(format!("{}'s {} glances off {}.", objects[shooter_id].name, missile.name,
+                                 objects[id].name), colors::WHITE);
+        }
+    }
+}
+
+/// Whether nothing blocks the way between `from` and `to`, for a look or a
+/// shot. What stands at either end doesn't count.
+fn line_of_sight(from: (i32, i32), to: (i32, i32), map: &Map) -> bool {
+    trajectory(from, to).into_iter()
+        .filter(|&tile| tile != to)
+        .all(|(x, y)| !map[x as usize][y as usize].blocked)
+}
+
+/// Have the monster use its ability, if it has one and something to use it
+/// on. Returns what became of it, or `None` if the monster didn't use it.
+fn use_ability(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> Option<UseResult> {
+    let effect = match objects[monster_id].effect.clone() {
+        Some(effect) => effect,
+        None => return None,
+    };
+    // a caster with only so many spells doesn't cast one every turn
+    match objects[monster_id].charges {
+        Some(charges) if charges <= 0 => return None,
+        Some(_) if game_rng().gen::<f32>() >= CAST_CHANCE => return None,
+        _ => {}
+    }
+    let targeting = EFFECTS.with(|effects| effects.targeting(&effect, game.rules()));
+    let target = match monster_target(targeting, monster_id, objects, &game.map) {
+        Some(target) => target,
+        None => return None,
+    };
+    match EFFECTS.with(|effects| effects.apply(&effect, monster_id, target, objects, game, fov_map)) {
+        Ok(UseResult::NoTurn) | Ok(UseResult::Cancelled) => None,
+        Ok(result) => {
+            debug!("{} #{} uses {} on {:?}: {:?}", objects[monster_id].name, monster_id, effect, target, result);
+            if let Some(charges) = objects[monster_id].charges.as_mut() {
+                *charges -= 1;
+            }
+            Some(result)
+        }
+        Err(e) => {
+            warn!("{} #{} can't use {}: {}", objects[monster_id].name, monster_id, effect, e);
+            None
+        }
+    }
+}
+
+/// Let loose the ability the monster wound up for on its last turn. When
+/// it no longer can, say the player stepped away, it gets on with what it
+/// was doing before.
+fn ai_unleash(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap,
+              previous_ai: Box<Ai>) -> Ai {
+    // the ability has to see the monster is wound up, see `wind_up`
+    objects[monster_id].ai = Some(Ai::Preparing{previous_ai: previous_ai.clone()});
+    let used = use_ability(monster_id, objects, game, fov_map);
+    objects[monster_id].ai = None;
+    match (used, *previous_ai) {
+        (None, Ai::Basic) => ai_basic(monster_id, objects, game, fov_map),
+        (_, previous_ai) => previous_ai,
+    }
+}
+
+/// What the player hears when a monster spots them.
+fn battle_cry(monster: &Object) -> String {
+    match monster.faction {
+        Some(Faction::Greenskins) => format!("The {} shouts and charges!", monster.name),
+        Some(Faction::Undead) => format!("The {} turns its dead eyes on you.", monster.name),
+        Some(Faction::Vermin) => format!("The {} notices you and closes in!", monster.name),
+        None => format!("The {} roars!", monster.name),
+    }
+}
+
+/// Whether a monster in the player's view sees them too. Right next to the
+/// player it always does, further away it's less likely the less light the
+/// player gives off.
+fn notices_player(monster: &Object, player: &Object, game: &Game) -> bool {
+    let chance = notice_chance(monster.distance_to(player), game);
+    chance >= 1.0 || game_rng().gen::<f32>() < chance
+}
+
+/// The chance an awake monster notices the player this far from it, on a
+/// turn it's in their view.
+fn notice_chance(distance: f32, game: &Game) -> f32 {
+    if distance < 1.5 {
+        1.0
+    } else {
+        game.light_radius() as f32 / CONFIG.torch_radius as f32
+    }
+}
+
+/// The chance a sleeping monster wakes up on a turn the player is within
+/// WAKE_DISTANCE of it.
+fn wake_chance(game: &Game) -> f32 {
+    if game.torch_lit { WAKE_CHANCE } else { WAKE_CHANCE / 2.0 }
+}
+
+/// The chance the monster finds out about the player standing at (x, y) on
+/// a turn, if it hasn't yet: sleeping ones wake up close by, awake ones spot
+/// the player anywhere they can see, see `ai_sleep` and `notices_player`.
+/// None for the ones already after the player or that aren't hostile.
+fn detection_chance(monster: &Object, (x, y): (i32, i32), game: &Game) -> Option<f32> {
+    if !monster.is_hostile() || monster.alerted {
+        return None;
+    }
+    let distance = monster.distance(x, y);
+    match monster.ai {
+        Some(Ai::Asleep{..}) if distance <= WAKE_DISTANCE => Some(wake_chance(game)),
+        Some(Ai::Basic) | Some(Ai::Ranged) | Some(Ai::Investigating{..})
+            if line_of_sight(monster.pos(), (x, y), &game.map) => Some(notice_chance(distance, game).min(1.0)),
+        _ => None,
+    }
+}
+
+/// Sleep on, unless the player comes close enough to wake the monster, see
+/// WAKE_DISTANCE. Noise and getting hurt wake it up too, see `make_noise`
+/// and `Object::take_damage`.
+fn ai_sleep(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap,
+            previous_ai: Box<Ai>) -> Ai {
+    let chance = wake_chance(game);
+    if objects[monster_id].distance_to(&objects[PLAYER]) > WAKE_DISTANCE || game_rng().gen::<f32>() >= chance {
+        return Ai::Asleep{previous_ai: previous_ai};
+    }
+    debug!("{} #{} wakes up", objects[monster_id].name, monster_id);
+    if objects[monster_id].in_fov(fov_map) {
+        game.log.add(format!("The {} wakes up!", objects[monster_id].name), colors::ORANGE);
+    }
+    *previous_ai
+}
+
+fn ai_confused(monster_id: usize, objects: &mut [Object], game: &mut Game,
+               until: u32, previous_ai: Box<Ai>) -> Ai {
+    if until <= game.turn {
+        // `end_confusion` missed it, something else was on its mind then
+        return come_to_senses(monster_id, objects, game, previous_ai);
+    }
+    // move in a random idrection
+    let (dx, dy) = (game_rng().gen_range(-1, 2), game_rng().gen_range(-1, 2));
+    debug!("{} #{} is confused, stumbles by {:?}", objects[monster_id].name, monster_id, (dx, dy));
+    move_by(monster_id, dx, dy, &game.map, objects);
+    Ai::Confused{until: until, previous_ai: previous_ai}
+}
+
+fn ai_investigate(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap,
+                  (x, y): (i32, i32), until: u32, previous_ai: Box<Ai>) -> Ai {
+    if objects[monster_id].in_fov(fov_map) && notices_player(&objects[monster_id], &objects[PLAYER], game) {
+        // found the player instead, that's much more interesting
+        debug!("{} #{} spots the player while investigating", objects[monster_id].name, monster_id);
+        return match *previous_ai {
+            Ai::Basic => ai_basic(monster_id, objects, game, fov_map),
+            Ai::Ranged => ai_ranged(monster_id, objects, game, fov_map),
+            previous_ai => previous_ai,
+        };
+    }
+    if objects[monster_id].distance(x, y) < 1.5 {
+        // nothing to see here
+        debug!("{} #{} finds nothing at {:?}", objects[monster_id].name, monster_id, (x, y));
+        if objects[monster_id].in_fov(fov_map) {
+            game.log.add(format!("The {} looks around, puzzled.", objects[monster_id].name), colors::LIGHT_GREY);
+        }
+        return *previous_ai;
+    }
+    move_astar(monster_id, x, y, game, objects);
+    Ai::Investigating{x: x, y: y, until: until, previous_ai: previous_ai}
+}
+
+/// Head for the tile (dx, dy) off the player rather than straight at them.
+/// Once there, next to the player anyway, stuck or out of time, the monster
+/// gets on with what it was doing.
+fn ai_flank(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap,
+            (dx, dy): (i32, i32), until: u32, previous_ai: Box<Ai>) -> Ai {
+    let (x, y) = (objects[PLAYER].x + dx, objects[PLAYER].y + dy);
+    let arrived = objects[monster_id].pos() == (x, y) ||
+        objects[monster_id].distance_to(&objects[PLAYER]) < 1.5;
+    if arrived || until <= game.turn || game.map[x as usize][y as usize].blocked {
+        debug!("{} #{} is done flanking", objects[monster_id].name, monster_id);
+        return match *previous_ai {
+            Ai::Basic => ai_basic(monster_id, objects, game, fov_map),
+            Ai::Ranged => ai_ranged(monster_id, objects, game, fov_map),
+            previous_ai => previous_ai,
+        };
+    }
+    let position = objects[monster_id].pos();
+    move_astar(monster_id, x, y, game, objects);
+    if objects[monster_id].pos() == position {
+        // no way round, it'll come straight at them after all
+        chase_player(monster_id, objects, game);
+        return *previous_ai;
+    }
+    Ai::Flanking{dx: dx, dy: dy, until: until, previous_ai: previous_ai}
+}
+
+/// Which object blocks each tile of the level, worked out once a turn so
+/// the monsters' pathfinding looks a tile up rather than going through
+/// every object for it. Those who moved since still show where they
+/// were, which only costs a path some sense; `can_move_to` has the last
+/// word on every step.
+#[derive(Default)]
+struct Blockers {
+    tiles: Vec<Vec<Option<usize>>>,
+}
+
+impl Blockers {
+    pub fn from(objects: &[Object]) -> Self {
+        let mut tiles = vec![vec![None; CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        for (id, object) in objects.iter().enumerate().filter(|&(_, o)| o.blocks) {
+            for (x, y) in object.tiles() {
+                if x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height {
+                    tiles[x as usize][y as usize] = Some(id);
+                }
+            }
+        }
+        Blockers { tiles: tiles }
+    }
+
+    /// whether something other than the object `id` stood on (x, y)
+    pub fn taken(&self, x: i32, y: i32, id: usize) -> bool {
+        self.tiles.get(x as usize).and_then(|column| column.get(y as usize))
+            .map_or(false, |&blocker| blocker.map_or(false, |blocker| blocker != id))
+    }
+}
+
+/// How far every tile of the level is from the player, in energy a walker
+/// spends to get there: a Dijkstra map. Monsters that chase the player roll
+/// down it a step at a time, and the fleeing roll up it, so a swarm costs a
+/// look at the neighbouring tiles per monster rather than a path each.
+/// Closed doors count as a step to open them and one to go through, locked
+/// ones and whatever would hurt a walker as walls.
+#[derive(Default)]
+struct ScentMap {
+    costs: Vec<Vec<Option<i32>>>,
+}
+
+impl ScentMap {
+    pub fn from(player: (i32, i32), map: &Map) -> Self {
+        let mut costs = vec![vec![None; CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        let passable = |x: i32, y: i32| x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height && {
+            let tile = &map[x as usize][y as usize];
+            (!tile.blocked || tile.kind == TileKind::Door) && !is_hazard(tile, Movement::walking())
+        };
+        if !passable(player.0, player.1) {
+            return ScentMap { costs: costs };
+        }
+        // the heap gives out the largest first, so the costs go in negated
+        let mut queue = BinaryHeap::new();
+        costs[player.0 as usize][player.1 as usize] = Some(0);
+        queue.push((0, player.0, player.1));
+        while let Some((cost, x, y)) = queue.pop() {
+            let cost = -cost;
+            if costs[x as usize][y as usize].map_or(false, |known| known < cost) {
+                continue;
+            }
+            for (dx, dy) in (-1..2).flat_map(|dx| (-1..2).map(move |dy| (dx, dy))) {
+                let (nx, ny) = (x + dx, y + dy);
+                if (dx, dy) == (0, 0) || !passable(nx, ny) {
+                    continue;
+                }
+                let tile = &map[nx as usize][ny as usize];
+                let step = if tile.blocked { ACTION_COST * 2 } else { tile.kind.move_cost() };
+                let next = cost + step;
+                if costs[nx as usize][ny as usize].map_or(true, |known| next < known) {
+                    costs[nx as usize][ny as usize] = Some(next);
+                    queue.push((-next, nx, ny));
+                }
+            }
+        }
+        ScentMap { costs: costs }
+    }
+
+    /// how far (x, y) is from the player, None when a walker can't get there
+    pub fn get(&self, x: i32, y: i32) -> Option<i32> {
+        self.costs.get(x as usize).and_then(|column| column.get(y as usize)).and_then(|&cost| cost)
+    }
+
+    /// whether the map is any use to `monster`: it's worked out for walkers
+    /// of one tile, the others find their own way
+    pub fn follows(monster: &Object) -> bool {
+        let movement = monster.movement;
+        monster.size == 1 && movement.walks && !movement.flies && !movement.burrows && !movement.swims
+    }
+
+    /// The tile next to the monster `id` that's closest to the player, if
+    /// it's closer than where it stands and the monster can step there or
+    /// open the door there.
+    pub fn downhill(&self, id: usize, map: &Map, objects: &[Object]) -> Option<(i32, i32)> {
+        let (x, y) = objects[id].pos();
+        let here = match self.get(x, y) {
+            Some(cost) if ScentMap::follows(&objects[id]) => cost,
+            _ => return None,
+        };
+        (-1..2).flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy)))
+            .filter(|&(x, y)| will_step_to(id, x, y, map, objects) ||
+                    (self.get(x, y).is_some() && map[x as usize][y as usize].can_open(objects[id].movement)))
+            .filter_map(|(x, y)| self.get(x, y).map(|cost| (cost, (x, y))))
+            .filter(|&(cost, _)| cost < here)
+            .min_by_key(|&(cost, _)| cost)
+            .map(|(_, step)| step)
+    }
+}
+
+/// Make a noise at (x, y). Sound goes `loudness` steps around walls, and
+/// every monster that hears it and isn't busy with the player goes to have
+/// a look.
+fn make_noise(x: i32, y: i32, loudness: i32, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let mut heard = vec![vec![false; CONFIG.map_height as usize]; CONFIG.map_width as usize];
+    heard[x as usize][y as usize] = true;
+    let mut queue = VecDeque::new();
+    queue.push_back((x, y, 0));
+    while let Some((x, y, steps)) = queue.pop_front() {
+        if steps >= loudness {
+            continue;
+        }
+        for dx in -1..2 {
+            for dy in -1..2 {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= CONFIG.map_width || ny >= CONFIG.map_height {
+                    continue;
+                }
+                if !heard[nx as usize][ny as usize] && !game.map[nx as usize][ny as usize].blocked {
+                    heard[nx as usize][ny as usize] = true;
+                    queue.push_back((nx, ny, steps + 1));
+                }
+            }
+        }
+    }
+
+    let until = game.turn + INVESTIGATE_TURNS;
+    for (id, monster) in objects.iter_mut().enumerate() {
+        if monster.removed || !heard[monster.x as usize][monster.y as usize] {
+            continue;
+        }
+        let in_fov = fov_map.is_in_fov(monster.x, monster.y);
+        if monster.wake_up() && in_fov {
+            game.log.add(format!("The {} wakes up!", monster.name), colors::ORANGE);
+        }
+        if in_fov {
+            continue;
+        }
+        let previous_ai = match monster.ai.take() {
+            Some(ai @ Ai::Basic) | Some(ai @ Ai::Ranged) => Box::new(ai),
+            // already looking for something else: look here instead
+            Some(Ai::Investigating{previous_ai, ..}) => previous_ai,
+            // confused or no AI at all, can't go anywhere on purpose
+            ai => {
+                monster.ai = ai;
+                continue;
+            }
+        };
+        debug!("{} #{} heard a noise at {:?}", monster.name, id, (x, y));
+        // off to look elsewhere, it'll have to spot the player again
+        monster.alerted = false;
+        monster.ai = Some(Ai::Investigating{x: x, y: y, until: until, previous_ai: previous_ai});
+        let monster_id = ObjectId { index: id, generation: monster.generation };
+        game.schedule.at(until, TimedEvent::EndInvestigation(monster_id));
+    }
+}
+
+/// Bring back the AI a monster had before it heard a noise, if it is still
+/// looking for it by now.
+fn end_investigation(monster_id: usize, objects: &mut [Object], game: &mut Game) {
+    match objects[monster_id].ai.take() {
+        Some(Ai::Investigating{until, previous_ai, ..}) if until <= game.turn => {
+            objects[monster_id].ai = Some(*previous_ai);
+        }
+        ai => objects[monster_id].ai = ai,
+    }
+}
+
+/// Run from the player, or fight back when there's nowhere to go.
+fn ai_flee(monster_id: usize, objects: &mut [Object], game: &mut Game, until: u32, previous_ai: Box<Ai>) -> Ai {
+    if until <= game.turn {
+        // `end_flight` missed it, something else was on its mind then
+        return regain_courage(monster_id, objects, game, previous_ai);
+    }
+    let (x, y) = objects[monster_id].pos();
+    match step_away(monster_id, objects, game) {
+        Some((to_x, to_y)) => {
+            debug!("{} #{} flees by {:?}", objects[monster_id].name, monster_id, (to_x - x, to_y - y));
+            move_by(monster_id, to_x - x, to_y - y, &game.map, objects);
+        }
+        _ => {
+            // cornered: fight back
+            if objects[monster_id].distance_to(&objects[PLAYER]) < 1.5 &&
+                objects[PLAYER].fighter.map_or(false, |f| f.hp > 0) {
+                let xp = {
+                    let (monster, player) = mut_two(monster_id, PLAYER, objects);
+                    monster.attack(player, game)
+                };
+                award_xp(monster_id, xp.unwrap_or(0), objects, game);
+            }
+        }
+    }
+    Ai::Fleeing{until: until, previous_ai: previous_ai}
+}
+
+/// The free tile next to the monster that takes it furthest from the
+/// player, if there's one further than where it stands. The ones that
+/// follow the scent run up it, around walls rather than into corners.
+fn step_away(monster_id: usize, objects: &[Object], game: &Game) -> Option<(i32, i32)> {
+    let (x, y) = objects[monster_id].pos();
+    let scent = &game.scent;
+    let follows_scent = ScentMap::follows(&objects[monster_id]) && scent.get(x, y).is_some();
+    let distance_from_player = |(x, y): (i32, i32)| if follows_scent {
+        scent.get(x, y).map_or(std::f32::MAX, |cost| cost as f32)
+    } else {
+        objects[PLAYER].distance(x, y)
+    };
+    let step = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy)))
+        .filter(|&(x, y)| will_step_to(monster_id, x, y, &game.map, objects))
+        .map(|step| (step, distance_from_player(step)))
+        .fold(None, |best: Option<((i32, i32), f32)>, (step, distance)| match best {
+            Some((_, best_distance)) if best_distance >= distance => best,
+            _ => Some((step, distance)),
+        });
+    match step {
+        Some((step, distance)) if distance > distance_from_player((x, y)) => Some(step),
+        _ => None,
+    }
+}
+
+/// Make a monster run from the player for a while.
+fn start_fleeing(monster_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let previous_ai = match objects[monster_id].ai.take() {
+        Some(ai) => ai,
+        None => return,
+    };
+    let (x, y) = objects[monster_id].pos();
+    if fov_map.is_in_fov(x, y) {
+        game.log.add(format!("The {} turns to flee!", objects[monster_id].name), colors::LIGHT_AZURE);
+    }
+    let turn = game.turn + FLEE_TURNS;
+    objects[monster_id].ai = Some(Ai::Fleeing{until: turn, previous_ai: Box::new(previous_ai)});
+    let monster_id = ObjectId::of(monster_id, objects);
+    game.schedule.at(turn, TimedEvent::EndFlight(monster_id));
+}
+
+fn end_flight(monster_id: usize, objects: &mut [Object], game: &mut Game) {
+    match objects[monster_id].ai.take() {
+        Some(Ai::Fleeing{until, previous_ai}) if until <= game.turn => {
+            let ai = regain_courage(monster_id, objects, game, previous_ai);
+            objects[monster_id].ai = Some(ai);
+        }
+        // died in the meantime or ran off again since; if it's confused or
+        // the like, `ai_flee` stops the flight once that's over
+        ai => objects[monster_id].ai = ai,
+    }
+}
+
+/// the AI a monster that stops fleeing goes back to, a little braver
+fn regain_courage(monster_id: usize, objects: &mut [Object], game: &mut Game, previous_ai: Box<Ai>) -> Ai {
+    game.log.add(format!("The {} gathers its courage.", objects[monster_id].name), colors::RED);
+    if let Some(fighter) = objects[monster_id].fighter.as_mut() {
+        fighter.morale += MORALE_RALLY;
+    }
+    *previous_ai
+}
+
+/// Pack leaders `monster_death` left on the floor shake the monsters of
+/// their faction around them: each may flee, the leader's own followers
+/// more likely than the rest. Every leader is mourned once.
+fn check_morale(objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    let fallen: Vec<(usize, u32)> = objects.iter().enumerate()
+        .filter(|&(_, o)| !o.alive && !o.removed)
+        .filter_map(|(id, o)| match o.pack {
+            Some(PackRole::Leader(pack)) => Some((id, pack)),
+            _ => None,
+        })
+        .collect();
+    for (leader_id, pack) in fallen {
+        objects[leader_id].pack = None;
+        let faction = match objects[leader_id].faction {
+            Some(faction) => faction,
+            None => continue,
+        };
+        for id in 0..objects.len() {
+            let steady = {
+                let monster = &objects[id];
+                match monster.ai {
+                    // the confused and the already fleeing have other worries
+                    Some(Ai::Basic) | Some(Ai::Ranged) | Some(Ai::Investigating{..}) | Some(Ai::Flanking{..}) => {}
+                    _ => continue,
+                }
+                if id == PLAYER || !monster.alive || monster.faction != Some(faction) ||
+                    monster.distance_to(&objects[leader_id]) > MORALE_RADIUS {
+                    continue;
+                }
+                let chance = if monster.pack == Some(PackRole::Follower(pack)) {
+                    FOLLOWER_FLEE_CHANCE
+                } else {
+                    FACTION_FLEE_CHANCE
+                };
+                game_rng().gen::<f32>() >= chance
+            };
+            if !steady {
+                start_fleeing(id, objects, game, fov_map);
+            }
+        }
+    }
+}
+
+/// What a monster does about a blast that went off among others nearby.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlastReaction {
+    /// runs from the caster for a while
+    Scatter,
+    /// keeps away from the caster and shoots at them
+    Harass,
+    /// comes at the caster from a side nobody else is taking
+    Converge,
+}
+
+impl BlastReaction {
+    pub fn of(monster: &Object) -> BlastReaction {
+        if monster.ai == Some(Ai::Ranged) {
+            BlastReaction::Harass
+        } else if monster.fighter.map_or(false, |f| f.morale < BLAST_SCATTER_MORALE) {
+            BlastReaction::Scatter
+        } else {
+            BlastReaction::Converge
+        }
+    }
+}
+
+/// Let the monsters that saw a blast of `radius` at (x, y) go off, and
+/// weren't caught in it, react to it rather than carry on as they were,
+/// see `BlastReaction`. The ones closest to the caster pick their side first.
+fn react_to_blast(x: i32, y: i32, radius: i32, caster_id: usize, objects: &mut [Object], game: &mut Game,
+                  fov_map: &FovMap) {
+    let mut witnesses: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            let monster = &objects[id];
+            let distance = monster.distance(x, y);
+            id != caster_id && id != PLAYER && monster.alive && monster.is_hostile() &&
+                distance > radius as f32 && distance <= radius as f32 + BLAST_WITNESS_RADIUS &&
+                line_of_sight(monster.pos(), (x, y), &game.map)
+        })
+        .filter(|&id| match objects[id].ai {
+            Some(Ai::Basic) | Some(Ai::Ranged) | Some(Ai::Investigating{..}) => true,
+            // the confused, the fleeing and the busy have other worries
+            _ => false,
+        })
+        .collect();
+    witnesses.sort_by_key(|&id| (objects[id].distance_to(&objects[caster_id]) * 10.0) as i32);
+    let (caster_x, caster_y) = objects[caster_id].pos();
+    let mut sides_taken = vec![];
+    for id in witnesses {
+        // whatever it went to look at can wait
+        let ai = match objects[id].ai.take() {
+            Some(Ai::Investigating{previous_ai, ..}) => *previous_ai,
+            ai => ai.unwrap_or(Ai::Basic),
+        };
+        objects[id].ai = Some(ai);
+        objects[id].alerted = true;
+        let reaction = BlastReaction::of(&objects[id]);
+        debug!("{} #{} reacts to the blast: {:?}", objects[id].name, id, reaction);
+        match reaction {
+            BlastReaction::Scatter => start_fleeing(id, objects, game, fov_map),
+            BlastReaction::Harass => {
+                if !objects[id].in_fov(fov_map) {
+                    // it has to see the caster to shoot them
+                    let until = game.turn + INVESTIGATE_TURNS;
+                    objects[id].ai = Some(Ai::Investigating{x: caster_x, y: caster_y, until: until,
+                                                            previous_ai: Box::new(Ai::Ranged)});
+                    let id = ObjectId::of(id, objects);
+                    game.schedule.at(until, TimedEvent::EndInvestigation(id));
+                }
+            }
+            BlastReaction::Converge => {
+                if let Some((dx, dy)) = free_side(id, (caster_x, caster_y), &sides_taken, &game.map, objects) {
+                    sides_taken.push((dx, dy));
+                    let previous_ai = objects[id].ai.take().unwrap_or(Ai::Basic);
+                    objects[id].ai = Some(Ai::Flanking{dx: dx, dy: dy, until: game.turn + FLANK_TURNS,
+                                                       previous_ai: Box::new(previous_ai)});
+                    if objects[id].in_fov(fov_map) {
+                        game.log.add(format!("The {} moves to cut you off!", objects[id].name), colors::ORANGE);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The free tile next to (x, y) closest to the monster, as the side (dx, dy)
+/// of (x, y) it's on, leaving out the sides already `taken`.
+fn free_side(monster_id: usize, (x, y): (i32, i32), taken: &[(i32, i32)], map: &Map,
+             objects: &[Object]) -> Option<(i32, i32)> {
+    let monster = &objects[monster_id];
+    (-1..2).flat_map(|dx| (-1..2).map(move |dy| (dx, dy)))
+        .filter(|&side| side != (0, 0) && !taken.contains(&side))
+        .filter(|&(dx, dy)| !is_blocked(x + dx, y + dy, map, objects) || monster.occupies(x + dx, y + dy))
+        .fold(None, |best: Option<(i32, i32)>, (dx, dy)| match best {
+            Some((best_dx, best_dy)) if monster.distance(x + best_dx, y + best_dy) <=
+                monster.distance(x + dx, y + dy) => best,
+            _ => Some((dx, dy)),
+        })
+}
+
+/// How scared a monster is of the player: the percentage of its hit points
+/// it lost, and OUTMATCHED_FEAR more when one blow of the player's would
+/// finish it.
+fn fear(monster: &Object, player: &Object) -> i32 {
+    let hp = monster.fighter.map_or(0, |f| f.hp);
+    let max_hp = monster.max_hp();
+    if max_hp <= 0 {
+        return 0;
+    }
+    let wounds = 100 - hp * 100 / max_hp;
+    let outmatched = player.power() - monster.defense() >= hp;
+    wounds + if outmatched { OUTMATCHED_FEAR } else { 0 }
+}
+
+/// The monsters that spotted the player and are more scared of them than
+/// their morale allows run for a while.
+fn check_nerve(objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    for id in 0..objects.len() {
+        let steady = {
+            let monster = &objects[id];
+            match monster.ai {
+                Some(Ai::Basic) | Some(Ai::Ranged) | Some(Ai::Flanking{..}) => {}
+                _ => continue,
+            }
+            if id == PLAYER || !monster.alive || !monster.alerted {
+                continue;
+            }
+            let morale = monster.fighter.map_or(100, |f| f.morale);
+            morale >= 100 || fear(monster, &objects[PLAYER]) <= morale
+        };
+        if !steady {
+            debug!("{} #{} loses its nerve", objects[id].name, id);
+            start_fleeing(id, objects, game, fov_map);
+        }
+    }
+}
+
+fn end_confusion(monster_id: usize, objects: &mut [Object], game: &mut Game) {
+    match objects[monster_id].ai.take() {
+        Some(Ai::Confused{until, previous_ai}) if until <= game.turn => {
+            let ai = come_to_senses(monster_id, objects, game, previous_ai);
+            objects[monster_id].ai = Some(ai);
+        }
+        // died in the meantime or confused again since; if it's fleeing or
+        // the like, `ai_confused` ends the confusion once that's over
+        ai => objects[monster_id].ai = ai,
+    }
+}
+
+/// the AI a monster that is no longer confused goes back to
+fn come_to_senses(monster_id: usize, objects: &[Object], game: &mut Game, previous_ai: Box<Ai>) -> Ai {
+    game.log.add(format!("The {} is no longer confused!", objects[monster_id].name), colors::RED);
+    *previous_ai
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Item {
+    Heal,
+    Lightning,
+    Confuse,
+    Fireball,
+    Sword,
+    Shield,
+    Scripted,
+    Wand,
+    Rock,
+    Bell,
+    SmokeBomb,
+    Crown,
+    Pickaxe,
+    Digging,
+    Return,
+    Charm,
+    /// cut from the remains of a monster, see `Trophy`
+    Trophy,
+}
+
+impl Item {
+    pub fn all() -> Vec<Item> {
+        vec![Item::Heal, Item::Lightning, Item::Confuse, Item::Fireball, Item::Sword, Item::Shield,
+             Item::Scripted, Item::Wand, Item::Rock, Item::Bell, Item::SmokeBomb, Item::Crown, Item::Pickaxe,
+             Item::Digging, Item::Return, Item::Charm]
+    }
+
+    /// how much energy using (or putting on) the item takes
+    pub fn use_cost(&self) -> i32 {
+        match *self {
+            // a quick zap
+            Item::Wand => QUICK_ACTION_COST,
+            // strapping a shield on takes a while
+            Item::Shield => ACTION_COST * 3,
+            // and so does hacking through rock
+            Item::Pickaxe => ACTION_COST * 3,
+            Item::Heal | Item::Lightning | Item::Confuse | Item::Fireball | Item::Sword |
+            Item::Scripted | Item::Rock | Item::Bell | Item::SmokeBomb | Item::Crown | Item::Digging |
+            Item::Return | Item::Charm | Item::Trophy => ACTION_COST,
+        }
+    }
+}
+
+/// What using an item or an effect did
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UseResult {
+    /// used, and the item is gone
+    Consumed,
+    /// used one of the item's charges, this many are left
+    KeepWithCharges(i32),
+    /// used, and the item stays
+    UsedAndKept,
+    /// used without spending a turn
+    NoTurn,
+    /// the caster got ready, the effect comes on its next turn
+    WindingUp,
+    Cancelled,
+    /// thrown, the item lands at this tile
+    Thrown(i32, i32),
+}
+
+/// What an effect is aimed at. Each effect declares it, and whoever uses the
+/// effect picks the target accordingly before the effect runs: the player
+/// through `choose_target`, monsters through `monster_target`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Targeting {
+    /// nothing in particular
+    Nothing,
+    /// whoever uses it
+    Caster,
+    /// the closest enemy within range
+    NearestEnemy(f32),
+    /// a monster picked within an optional range
+    Monster(Option<f32>),
+    /// a tile picked within an optional range, hitting everything within
+    /// `radius` of it
+    Tile { range: Option<f32>, radius: i32 },
+    /// one of the eight directions, given as the neighbouring tile in it
+    Direction,
+}
+
+/// A built-in effect: who uses it, the tile they picked (if the effect asked
+/// for one) and the game.
+type EffectFn = fn(usize, Option<(i32, i32)>, &mut [Object], &mut Game, &FovMap) -> UseResult;
+/// the targeting of a built-in effect, its range depends on the rules
+type TargetingFn = fn(&rules::Rules) -> Targeting;
+
+enum Effect {
+    BuiltIn { targeting: TargetingFn, on_use: EffectFn },
+    /// written in `scripts/<name>.rhai`, compiled when the game starts
+    Script { ast: rhai::AST, targeting: Targeting },
+}
+
+thread_local! {
+    /// every effect in the game, it's per thread as rhai's engine and
+    /// scripts can't be shared between them
+    static EFFECTS: EffectRegistry = EffectRegistry::new();
+}
+
+lazy_static! {
+    /// the hand-made rooms levels are built with, see `mapgen::Prefab`
+    static ref PREFABS: Vec<mapgen::Prefab> = {
+        let (prefabs, problems) = mapgen::load_prefabs(PREFAB_DIR);
+        for problem in problems {
+            warn!("leaving out a prefab, {}", problem);
+        }
+        prefabs
+    };
+
+    /// the trophies of every species that has one, see `Trophy`
+    static ref TROPHIES: Vec<Trophy> = load_trophies(TROPHIES_PATH).unwrap_or_else(|e| {
+        warn!("there are no trophies, {}", e);
+        vec![]
+    });
+}
+
+/// Every effect in the game by id. Items, wands, traps and monster abilities
+/// only store the id of their effect and use it through here, so a new
+/// effect is either a function registered in `new` or a script dropped into
+/// the `scripts` directory.
+struct EffectRegistry {
+    effects: HashMap<String, Effect>,
+    /// the one engine every script is compiled with and runs on
+    engine: rhai::Engine,
+    /// what the script that's running sees and asks for
+    script_state: Rc<RefCell<ScriptState>>,
+}
+
+impl EffectRegistry {
+    pub fn new() -> Self {
+        let script_state = Rc::new(RefCell::new(ScriptState::default()));
+        let mut registry = EffectRegistry {
+            effects: HashMap::new(),
+            engine: script_engine(script_state.clone()),
+            script_state: script_state,
+        };
+        registry.register_built_in("heal", |_| Targeting::Caster, cast_heal);
+        registry.register_built_in("lightning", |rules| Targeting::NearestEnemy(rules.lightning_range as f32),
+                                   cast_lightning);
+        registry.register_built_in("confuse", |rules| Targeting::Monster(Some(rules.confuse_range as f32)),
+                                   cast_confuse);
+        registry.register_built_in("fireball",
+                                   |rules| Targeting::Tile { range: None, radius: rules.fireball_radius },
+                                   cast_fireball);
+        let throw = |_: &rules::Rules| Targeting::Tile { range: Some(THROW_RANGE), radius: 0 };
+        registry.register_built_in("rock", throw, throw_rock);
+        registry.register_built_in("bell", throw, throw_bell);
+        registry.register_built_in("smoke",
+                                   |_| Targeting::Tile { range: Some(THROW_RANGE), radius: SMOKE_RADIUS },
+                                   throw_smoke_bomb);
+        registry.register_built_in("dig", |_| Targeting::Direction, dig_with_pickaxe);
+        registry.register_built_in("digging", |_| Targeting::Direction, cast_digging);
+        registry.register_built_in("return", |_| Targeting::Nothing, cast_return);
+        // a script with the same name as a built-in effect replaces it
+        if let Ok(entries) = std::fs::read_dir("scripts") {
+            for path in entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+                let name = path.file_stem().and_then(|name| name.to_str()).map(|name| name.to_string());
+                match (name, path.extension().and_then(|ext| ext.to_str())) {
+                    (Some(name), Some("rhai")) => {
+                        let script = registry.load_script(&name)
+                            .and_then(|ast| registry.script_targeting(&ast).map(|targeting| (ast, targeting)));
+                        match script {
+                            Ok((ast, targeting)) => {
+                                registry.effects.insert(name, Effect::Script { ast: ast, targeting: targeting });
+                            }
+                            Err(e) => warn!("leaving out {}, {}", path.display(), e),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        registry
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.effects.contains_key(id)
+    }
+
+    fn register_built_in(&mut self, id: &str, targeting: TargetingFn, on_use: EffectFn) {
+        self.effects.insert(id.into(), Effect::BuiltIn { targeting: targeting, on_use: on_use });
+    }
+
+    /// What the user has to pick before the effect can be used
+    pub fn targeting(&self, id: &str, rules: &rules::Rules) -> Targeting {
+        match self.effects.get(id) {
+            Some(&Effect::BuiltIn { targeting, .. }) => targeting(rules),
+            Some(&Effect::Script { targeting, .. }) => targeting,
+            None => Targeting::Nothing,
+        }
+    }
+
+    /// Use the effect on behalf of `caster_id`. Fails if there's no such
+    /// effect or its script is broken.
+    pub fn apply(&self, id: &str, caster_id: usize, target: Option<(i32, i32)>,
+                 objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> Result<UseResult, String> {
+        match self.effects.get(id) {
+            Some(&Effect::BuiltIn { on_use, .. }) => Ok(on_use(caster_id, target, objects, game, fov_map)),
+            Some(&Effect::Script { ref ast, .. }) => self.run_script(ast, caster_id, target, objects, game, fov_map),
+            None => Err(format!("there is no effect called '{}'", id)),
+        }
+    }
+}
+
+fn item_targeting(item: &Object, rules: &rules::Rules) -> Targeting {
+    item.effect.as_ref().map_or(Targeting::Nothing, |effect| {
+        EFFECTS.with(|effects| effects.targeting(effect, rules))
+    })
+}
+
+fn use_item(inventory_id: usize, target: Option<(i32, i32)>, objects: &mut Vec<Object>, game: &mut Game,
+            fov_map: &FovMap) -> UseResult {
+    let (is_equipment, effect) = {
+        let item = &objects[PLAYER].inventory[inventory_id];
+        (item.equipment.is_some(), item.effect.clone())
+    };
+    let result = if is_equipment {
+        toggle_equipment(inventory_id, objects, game)
+    } else if let Some(effect) = effect {
+        match EFFECTS.with(|effects| effects.apply(&effect, PLAYER, target, objects, game, fov_map)) {
+            Ok(result) => result,
+            Err(e) => {
+                game.log.add(format!("The {} fizzles: {}", objects[PLAYER].inventory[inventory_id].name, e),
+                             colors::RED);
+                UseResult::Cancelled
+            }
+        }
+    } else {
+        game.log.add(format!("The {} cannot be used.", objects[PLAYER].inventory[inventory_id].name),
+                     colors::WHITE);
+        return UseResult::Cancelled;
+    };
+    // items with charges are only gone once the last one is used
+    let result = match (result, objects[PLAYER].inventory[inventory_id].charges) {
+        (UseResult::Consumed, Some(charges)) if charges > 1 => UseResult::KeepWithCharges(charges - 1),
+        (result, _) => result,
+    };
+    match result {
+        UseResult::Consumed => {
+            // destroy after use, unless it was cancelled for some reason
+            objects[PLAYER].inventory.remove(inventory_id);
+        }
+        UseResult::KeepWithCharges(charges) => {
+            let item = &mut objects[PLAYER].inventory[inventory_id];
+            item.charges = Some(charges);
+            game.log.add(format!("The {} has {} charges left.", item.name, charges), colors::WHITE);
+        }
+        UseResult::UsedAndKept | UseResult::NoTurn | UseResult::WindingUp => {}, // do nothing
+        UseResult::Cancelled => {
+            game.log.add("Cancelled", colors::WHITE);
+        }
+        UseResult::Thrown(x, y) => {
+            let mut item = objects[PLAYER].inventory.remove(inventory_id);
+            item.set_pos(x, y);
+            objects.insert_object(item, &mut game.free_slots);
+        }
+    }
+    result
+}
+
+fn drop_item(inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game) {
+    let mut item = objects[PLAYER].inventory.remove(inventory_id);
+    if item.equipment.is_some() {
+        item.dequip(&mut game.log);
+    }
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+    game.log.add(format!("You dropped a {}.", item.name), colors::YELLOW);
+    objects.insert_object(item, &mut game.free_slots);
+}
+
+/// Let the player point at a tile in their FOV (optionally in a range) and
+/// return it, or None if they cancelled. The cursor starts on the closest
+/// monster, moves with the movement keys or the mouse and Tab jumps to the
+/// next monster in view. With a `radius` the area around the cursor is
+/// highlighted.
+fn target_tile(tcod: &mut Tcod,
+               objects: &[Object],
+               game: &mut Game,
+               max_range: Option<f32>,
+               radius: i32)
+               -> Option<(i32, i32)> {
+    use tcod::input::KeyCode::{Enter, Escape, Tab};
+    let mut cursor = closest_monster(max_range.unwrap_or(std::f32::INFINITY), objects, &tcod.fov)
+        .map_or(objects[PLAYER].pos(), |id| objects[id].pos());
+    loop {
+        if tcod.backend.window_closed() {
+            tcod.cursor = None;
+            return None;
+        }
+        // render the screen. this erases the inventory and shows the names of
+        // objects under the cursor.
+        tcod.cursor = Some(cursor);
+        tcod.flush();
+        let event = tcod.backend.check_for_event();
+        let mut key = None;
+        let mut clicked = MouseAction::Nothing;
+        match event {
+            Some(Event::Mouse(m)) => {
+                tcod.mouse = m;
+                if let Some(pos) = tcod.camera.to_world(m.cx as i32, m.cy as i32) {
+                    cursor = pos;
+                }
+                clicked = CONFIG.mouse_action(&m);
+            }
+            Some(Event::Key(k)) => key = Some(k),
+            None => {}
+        }
+        let code = key.map(|k| k.code);
+        match key.and_then(|k| tcod.keymap.action(k)) {
+            Some(InputAction::Move(dx, dy)) if code != Some(Tab) && code != Some(Escape) => {
+                cursor = (cmp::max(0, cmp::min(cursor.0 + dx, CONFIG.map_width - 1)),
+                          cmp::max(0, cmp::min(cursor.1 + dy, CONFIG.map_height - 1)));
+            }
+            _ => {}
+        }
+        if code == Some(Tab) {
+            let mut monsters: Vec<&Object> = objects.iter().enumerate()
+                .filter(|&(id, o)| id != PLAYER && o.fighter.is_some() && o.alive && !o.hidden())
+                .filter(|&(_, o)| o.in_fov(&tcod.fov))
+                .map(|(_, o)| o)
+                .collect();
+            monsters.sort_by(|a, b| {
+                let player = &objects[PLAYER];
+                player.distance_to(a).partial_cmp(&player.distance_to(b)).unwrap_or(cmp::Ordering::Equal)
+            });
+            let next = monsters.iter().position(|m| m.occupies(cursor.0, cursor.1))
+                .map_or(0, |i| (i + 1) % monsters.len());
+            if let Some(monster) = monsters.get(next) {
+                cursor = monster.pos();
+            }
+        }
+        if clicked == MouseAction::Examine {
+            examine(cursor.0, cursor.1, objects, game, &tcod.fov);
+        }
+
+        render_all(tcod, objects, game, false);
+        if radius > 0 {
+            highlight_area(tcod, cursor.0, cursor.1, radius);
+        }
+        if let Some((screen_x, screen_y)) = tcod.camera.to_screen(cursor.0, cursor.1) {
+            let bg = tcod.root.get(screen_x, screen_y).bg;
+            tcod.root.set_background(screen_x, screen_y, colors::lerp(bg, colors::WHITE, 0.5));
+        }
+
+        // accept the target if it's in FOV, and in case a range is
+        // specified, if it's in that range
+        let in_fov = tcod.fov.is_in_fov(cursor.0, cursor.1);
+        let in_range = max_range.map_or(
+            true, |range| objects[PLAYER].distance(cursor.0, cursor.1) <= range);
+        if (clicked == MouseAction::Select || code == Some(Enter)) && in_fov && in_range {
+            tcod.cursor = None;
+            return Some(cursor)
+        }
+
+        if clicked == MouseAction::Cancel || code == Some(Escape) {
+            tcod.cursor = None;
+            return None
+        }
+    }
+}
+
+/// how to pick a target, for the message asking for `what`
+fn targeting_hint(what: &str) -> String {
+    let mut hint = format!("Move the cursor to {} and press Enter, or Escape to cancel", what);
+    if let Some(button) = CONFIG.mouse_button(MouseAction::Select) {
+        hint.push_str(&format!(" ({}-clicking works too)", button));
+    }
+    hint + "."
+}
+
+/// describe everything the player can see on a tile
+fn examine(x: i32, y: i32, objects: &[Object], game: &mut Game, fov_map: &FovMap) {
+    if !fov_map.is_in_fov(x, y) {
+        return;
+    }
+    for object in objects.iter().filter(|o| o.occupies(x, y) && !o.removed && !o.hidden()) {
+        game.log.add(object.describe(), colors::LIGHT_GREY);
+    }
+}
+
+/// Tint the tiles in view by how likely the monsters in view that haven't
+/// noticed the player yet are to, should the player stand there. It makes
+/// sneaking past them something to learn rather than guess at.
+fn show_detection_hints(tcod: &mut Tcod, objects: &[Object], game: &Game) {
+    let unaware: Vec<&Object> = objects.iter()
+        .filter(|o| !o.removed && o.in_fov(&tcod.fov) && detection_chance(o, o.pos(), game).is_some())
+        .collect();
+    if unaware.is_empty() {
+        return;
+    }
+    for screen_y in 0..CONFIG.view_height() {
+        for screen_x in 0..CONFIG.view_width() {
+            let (x, y) = (tcod.camera.x + screen_x, tcod.camera.y + screen_y);
+            if !tcod.fov.is_in_fov(x, y) {
+                continue;
+            }
+            let chance = unaware.iter()
+                .filter_map(|monster| detection_chance(monster, (x, y), game))
+                .fold(0.0, f32::max);
+            if chance > 0.0 {
+                let bg = tcod.root.get(screen_x, screen_y).bg;
+                let tint = colors::lerp(bg, colors::RED, DETECTION_HINT_TINT * (1.0 + chance) / 2.0);
+                tcod.root.set_background(screen_x, screen_y, tint);
+            }
+        }
+    }
+}
+
+/// tint the tiles in view within `radius` of (x, y)
+fn highlight_area(tcod: &mut Tcod, x: i32, y: i32, radius: i32) {
+    for tile_y in y - radius..y + radius + 1 {
+        for tile_x in x - radius..x + radius + 1 {
+            let in_map = tile_x >= 0 && tile_y >= 0 && tile_x < CONFIG.map_width && tile_y < CONFIG.map_height;
+            let dx = (tile_x - x) as f32;
+            let dy = (tile_y - y) as f32;
+            let on_screen = tcod.camera.to_screen(tile_x, tile_y);
+            if in_map && tcod.fov.is_in_fov(tile_x, tile_y) && (dx * dx + dy * dy).sqrt() <= radius as f32 {
+                if let Some((screen_x, screen_y)) = on_screen {
+                    let bg = tcod.root.get(screen_x, screen_y).bg;
+                    tcod.root.set_background(screen_x, screen_y, colors::lerp(bg, colors::ORANGE, 0.5));
+                }
+            }
+        }
+    }
+}
+
+/// returns a monster picked inside FOV up to a range, or None if cancelled
+fn target_monster(tcod: &mut Tcod,
+                  objects: &[Object],
+                  game: &mut Game,
+                  max_range: Option<f32>)
+                  -> Option<usize> {
+    loop {
+        match target_tile(tcod, objects, game, max_range, 0) {
+            Some((x, y)) => {
+                // return the first monster picked, otherwise continue looping
+                for (id, obj) in objects.iter().enumerate() {
+                    if obj.occupies(x, y) && obj.fighter.is_some() && id != PLAYER {
+                        return Some(id)
+                    }
+                }
+            }
+            None => return None,
+        }
+    }
+}
+
+/// Let the player pick what the targeting asks for. Returns `None` if they
+/// cancelled and `Some(None)` if there was nothing to pick.
+fn choose_target(targeting: Targeting, tcod: &mut Tcod, objects: &[Object], game: &mut Game)
+                 -> Option<Option<(i32, i32)>> {
+    match targeting {
+        Targeting::Nothing => Some(None),
+        Targeting::Caster => Some(Some(objects[PLAYER].pos())),
+        Targeting::NearestEnemy(range) => {
+            match closest_monster(range, objects, &tcod.fov) {
+                Some(id) => Some(Some(objects[id].pos())),
+                None => {
+                    game.log.add("No enemy is close enough to strike.", colors::RED);
+                    None
+                }
+            }
+        }
+        Targeting::Tile { range, radius } => {
+            game.log.add(targeting_hint("a target tile"), colors::LIGHT_CYAN);
+            target_tile(tcod, objects, game, range, radius).map(Some)
+        }
+        Targeting::Monster(range) => {
+            game.log.add(targeting_hint("an enemy to target"), colors::LIGHT_CYAN);
+            target_monster(tcod, objects, game, range).map(|id| Some(objects[id].pos()))
+        }
+        Targeting::Direction => {
+            game.log.add("Which direction? Press a movement key, or Escape to cancel.",
+                         colors::LIGHT_CYAN);
+            render_all(tcod, objects, game, false);
+            tcod.flush();
+            let key = tcod.backend.wait_for_keypress();
+            match tcod.keymap.action(key) {
+                Some(InputAction::Move(dx, dy)) => {
+                    let (x, y) = objects[PLAYER].pos();
+                    Some(Some((x + dx, y + dy)))
+                }
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Aim an effect a monster uses at the player, or one on itself at the
+/// worst hurt of the monsters around it, see `most_wounded_ally`. Returns
+/// `None` if the player is out of the effect's range.
+fn monster_target(targeting: Targeting, monster_id: usize, objects: &[Object], map: &Map)
+                  -> Option<Option<(i32, i32)>> {
+    let monster = &objects[monster_id];
+    let player = &objects[PLAYER];
+    let in_range = |range: Option<f32>| range.map_or(true, |range| monster.distance_to(player) <= range);
+    match targeting {
+        Targeting::Nothing => Some(None),
+        Targeting::Caster => Some(Some(objects[most_wounded_ally(monster_id, objects, map)].pos())),
+        Targeting::NearestEnemy(range) if in_range(Some(range)) => Some(Some(player.pos())),
+        Targeting::Monster(range) | Targeting::Tile { range, .. } if in_range(range) => Some(Some(player.pos())),
+        Targeting::Direction => {
+            let dx = (player.x - monster.x).signum();
+            let dy = (player.y - monster.y).signum();
+            Some(Some((monster.x + dx, monster.y + dy)))
+        }
+        _ => None,
+    }
+}
+
+/// The monster the caster sees within ALLY_HEAL_RANGE that has lost the
+/// most of its hit points, the caster itself included.
+fn most_wounded_ally(caster_id: usize, objects: &[Object], map: &Map) -> usize {
+    let caster = &objects[caster_id];
+    let lost = |id: usize| objects[id].fighter.map_or(0, |f| objects[id].max_hp() - f.hp);
+    (0..objects.len())
+        .filter(|&id| id == caster_id || {
+            let ally = &objects[id];
+            id != PLAYER && ally.alive && ally.ai.is_some() && ally.fighter.is_some() && !ally.removed &&
+                caster.distance_to(ally) <= ALLY_HEAL_RANGE &&
+                line_of_sight(caster.pos(), ally.pos(), map)
+        })
+        .max_by_key(|&id| lost(id))
+        .unwrap_or(caster_id)
+}
+
+/// find closest enemy, up to a maximum range, and in the player's FOV
+fn closest_monster(max_range: f32, objects: &[Object], fov_map: &FovMap) -> Option<usize> {
+    let mut closest_enemy = None;
+    let mut closest_dist = max_range + 1.0;  // start with (slightly more than) maximum range
+
+    for (id, object) in objects.iter().enumerate() {
+        if (id != PLAYER) && object.fighter.is_some() && object.ai.is_some() && object.is_hostile() &&
+            object.in_fov(fov_map)
+        {
+            // calculate distance between this object and the player
+            let dist = objects[PLAYER].distance_to(object);
+            if dist < closest_dist {  // it's closer, so remember it
+                closest_enemy = Some(id);
+                closest_dist = dist;
+            }
+        }
+    }
+    closest_enemy
+}
+
+fn cast_heal(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+             game: &mut Game, _fov_map: &FovMap) -> UseResult
+{
+    // heal whoever it was cast on, or else whoever used it
+    let patient_id = target
+        .and_then(|(x, y)| objects.iter().position(|o| o.occupies(x, y) && o.fighter.is_some() && !o.removed))
+        .unwrap_or(caster_id);
+    let caster_name = objects[caster_id].name.clone();
+    let patient = &mut objects[patient_id];
+    if let Some(fighter) = patient.fighter {
+        if fighter.hp == patient.max_hp() {
+            if patient_id == PLAYER {
+                game.log.add("You are already at full health.", colors::RED);
+            }
+            return UseResult::Cancelled;
+        }
+        if patient_id == PLAYER {
+            game.log.add("Your wounds start to feel better!", colors::LIGHT_VIOLET);
+        } else if patient_id == caster_id {
+            game.log.add(format!("The {} looks healthier.", patient.name), colors::LIGHT_VIOLET);
+        } else {
+            game.log.add(format!("The {} heals the {}'s wounds.", caster_name, patient.name),
+                         colors::LIGHT_VIOLET);
+        }
+        patient.heal(game.rules().heal_amount);
+        return UseResult::Consumed;
+    }
+    UseResult::Cancelled
+}
+
+/// give the xp for a kill to whoever made it
+fn award_xp(killer_id: usize, xp: i32, objects: &mut [Object], game: &mut Game) {
+    if xp <= 0 {
+        return;
+    }
+    if let Some(fighter) = objects[killer_id].fighter.as_mut() {
+        fighter.xp += xp;
+        if killer_id == PLAYER {
+            game.log.add(format!("You gain {} experience points.", xp), colors::ORANGE);
+        }
+    }
+}
+
+/// Count a kill towards the score, if it was the player's.
+fn count_kill(killer_id: usize, xp: i32, game: &mut Game) {
+    if killer_id == PLAYER {
+        game.stats.kills += 1;
+        game.stats.kill_xp += cmp::max(xp, 0) as u32;
+    }
+}
+
+/// What hurt a creature, to know who killed it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DamageSource {
+    /// a blow, spell or script of the creature with this id
+    Creature(usize),
+    /// the terrain or a trap, with whoever drove the victim into it
+    Hazard(Option<usize>),
+}
+
+/// Hurt a creature with anything but a blow. If that kills it, the killer
+/// gets its experience: all of it for a spell, `HAZARD_XP_PERCENT` of it for
+/// driving the victim into a hazard, and nothing for killing themselves.
+/// Returns whether the victim died.
+fn inflict_damage(victim_id: usize, damage: i32, source: DamageSource, objects: &mut [Object],
+                  game: &mut Game) -> bool {
+    let xp = match objects[victim_id].take_damage(damage, game) {
+        Some(xp) => xp,
+        None => return false,
+    };
+    let (killer_id, xp) = match source {
+        DamageSource::Creature(id) => (Some(id), xp),
+        DamageSource::Hazard(credit) => (credit, xp * HAZARD_XP_PERCENT / 100),
+    };
+    match killer_id {
+        Some(killer_id) if killer_id != victim_id => {
+            count_kill(killer_id, xp, game);
+            award_xp(killer_id, xp, objects, game);
+        }
+        _ => {}
+    }
+    true
+}
+
+/// The tiles a projectile flies over from one point to another, in order,
+/// the target included and the start left out.
+fn trajectory((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> Vec<(i32, i32)> {
+    let (dx, dy) = ((x2 - x1).abs(), -(y2 - y1).abs());
+    let (step_x, step_y) = ((x2 - x1).signum(), (y2 - y1).signum());
+    let mut error = dx + dy;
+    let (mut x, mut y) = (x1, y1);
+    let mut tiles = vec![];
+    while (x, y) != (x2, y2) {
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+        tiles.push((x, y));
+    }
+    tiles
+}
+
+/// Where a shot ends up.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Shot {
+    /// it hit the creature with this id, the target or someone in the way
+    Hits(usize),
+    /// it hit nobody and came down on this tile
+    Lands((i32, i32)),
+}
+
+/// Follow a shot from `shooter_id` to the tile (x, y). A wall stops it, a
+/// creature in the way may take it instead and squeezing past a corner may
+/// send it wide.
+fn fire(shooter_id: usize, (x, y): (i32, i32), objects: &[Object], map: &Map) -> Shot {
+    let blocked = |(x, y): (i32, i32)| map[x as usize][y as usize].blocked;
+    let mut last = objects[shooter_id].pos();
+    for tile in trajectory(last, (x, y)) {
+        if blocked(tile) {
+            return Shot::Lands(last);
+        }
+        let diagonal = tile.0 != last.0 && tile.1 != last.1;
+        if diagonal && (blocked((last.0, tile.1)) || blocked((tile.0, last.1))) &&
+            game_rng().gen::<f32>() < CORNER_MISS_CHANCE {
+            debug!("the shot from {:?} to {:?} clips a corner at {:?}", objects[shooter_id].pos(), (x, y), tile);
+            return Shot::Lands(last);
+        }
+        let creature = objects.iter().enumerate().position(|(id, o)| {
+            id != shooter_id && o.occupies(tile.0, tile.1) && o.fighter.is_some() && !o.removed
+        });
+        match creature {
+            Some(id) if objects[id].occupies(x, y) || game_rng().gen::<f32>() < COVER_HIT_CHANCE => {
+                return Shot::Hits(id)
+            }
+            _ => {}
+        }
+        last = tile;
+    }
+    Shot::Lands(last)
+}
+
+fn cast_lightning(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                  game: &mut Game, _fov_map: &FovMap) -> UseResult
+{
+    // strike the enemy it was aimed at, if it's inside the maximum range
+    let rules = game.rules();
+    let monster_id = target.and_then(|(x, y)| {
+        objects.iter().enumerate().position(|(id, o)| {
+            id != caster_id && o.occupies(x, y) && o.fighter.is_some() &&
+                objects[caster_id].distance(x, y) <= rules.lightning_range as f32
+        })
+    });
+    if let Some(monster_id) = monster_id {
+        // zap it! unless something else is in the way
+        let target = objects[monster_id].pos();
+        match fire(caster_id, target, objects, &game.map) {
+            Shot::Hits(id) => {
+                let in_the_way = if id == monster_id { "" } else { " in the way" };
+                game.log.add(format!("A lightning bolt strikes the {}{} with a loud thunder! \
+                                      The damage is {} hit points.",
+                                     objects[id].name, in_the_way, rules.lightning_damage),
+                             colors::LIGHT_BLUE);
+                inflict_damage(id, rules.lightning_damage, DamageSource::Creature(caster_id), objects, game);
+            }
+            Shot::Lands(_) => {
+                game.log.add(format!("The lightning bolt misses the {}.", objects[monster_id].name),
+                             colors::LIGHT_BLUE);
+            }
+        }
+        UseResult::Consumed
+    } else {  // no enemy found within maximum range
+        if caster_id == PLAYER {
+            game.log.add("No enemy is close enough to strike.", colors::RED);
+        }
+        UseResult::Cancelled
+    }
+}
+
+fn cast_confuse(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    // confuse the monster the caster picked, if it's in view and in range
+    let rules = game.rules();
+    let monster_id = target.and_then(|(x, y)| {
+        objects.iter().enumerate().position(|(id, o)| {
+            id != caster_id && o.occupies(x, y) && o.fighter.is_some() && o.ai.is_some() &&
+                fov_map.is_in_fov(x, y) && objects[caster_id].distance(x, y) <= rules.confuse_range as f32
+        })
+    });
+    if let Some(monster_id) = monster_id {
+        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
+        // replace the monster's AI with a "confused" one; after
+        // some turns it will restore the old AI
+        let turn = game.turn + rules.confuse_num_turns as u32;
+        objects[monster_id].ai = Some(Ai::Confused {
+            until: turn,
+            previous_ai: Box::new(old_ai),
+        });
+        game.schedule.at(turn, TimedEvent::EndConfusion(ObjectId::of(monster_id, objects)));
+        game.log.add(format!("The eyes of {} look vacant, as he starts to stumble around!",
+                             objects[monster_id].name),
+                     colors::LIGHT_GREEN);
+        UseResult::Consumed
+    } else {  // no enemy fonud within maximum range
+        if caster_id == PLAYER {
+            game.log.add("No enemy is close enough to strike.", colors::RED);
+        }
+        UseResult::Cancelled
+    }
+}
+
+fn cast_fireball(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                 game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    // throw a fireball at the tile the player picked
+    let rules = game.rules();
+    let (x, y) = match target {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    game.log.add(format!("The fireball explodes, burning everything within {} tiles!", rules.fireball_radius),
+                 colors::ORANGE);
+
+    let mut burned = false;
+    for id in 0..objects.len() {
+        if objects[id].distance(x, y) <= rules.fireball_radius as f32 && objects[id].fighter.is_some() {
+            game.log.add(format!("The {} gets burned for {} hit points.", objects[id].name, rules.fireball_damage),
+                         colors::ORANGE);
+            inflict_damage(id, rules.fireball_damage, DamageSource::Creature(caster_id), objects, game);
+            burned = true;
+        }
+    }
+    // the monsters only come after the player for it
+    if burned && caster_id == PLAYER {
+        react_to_blast(x, y, rules.fireball_radius, caster_id, objects, game, fov_map);
+    }
+    // what burned smoulders on for a bit
+    make_smoke(x, y, 1, FIRE_SMOKE_TURNS, game);
+
+    UseResult::Consumed
+}
+
+/// Throw the item at the tile the caster picked. Where it lands it makes a
+/// noise that draws the monsters who hear it.
+fn throw_noisemaker(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object], game: &mut Game,
+                    fov_map: &FovMap, loudness: i32, sound: &str) -> UseResult {
+    let (x, y) = match target {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    if game.map[x as usize][y as usize].blocked {
+        if caster_id == PLAYER {
+            game.log.add("There's no room to throw it there.", colors::RED);
+        }
+        return UseResult::Cancelled;
+    }
+    // whoever is in the way stops it
+    let (x, y) = match fire(caster_id, (x, y), objects, &game.map) {
+        Shot::Hits(id) => {
+            if fov_map.is_in_fov(objects[id].x, objects[id].y) {
+                game.log.add(format!("It bounces off the {}.", objects[id].name), colors::LIGHT_GREY);
+            }
+            objects[id].pos()
+        }
+        Shot::Lands(tile) => tile,
+    };
+    if fov_map.is_in_fov(x, y) {
+        game.log.add(sound, colors::LIGHT_GREY);
+    }
+    make_noise(x, y, loudness, objects, game, fov_map);
+    UseResult::Thrown(x, y)
+}
+
+fn throw_rock(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+              game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    throw_noisemaker(caster_id, target, objects, game, fov_map, ROCK_LOUDNESS,
+                     "The rock clatters across the floor.")
+}
+
+fn throw_bell(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+              game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    throw_noisemaker(caster_id, target, objects, game, fov_map, BELL_LOUDNESS,
+                     "The bell rings out through the dungeon!")
+}
+
+/// Throw the bomb at the tile the caster picked, or whoever is in the way,
+/// where it bursts into a cloud of smoke.
+fn throw_smoke_bomb(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                    game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    let (x, y) = match target {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    if game.map[x as usize][y as usize].blocked {
+        if caster_id == PLAYER {
+            game.log.add("There's no room to throw it there.", colors::RED);
+        }
+        return UseResult::Cancelled;
+    }
+    let (x, y) = match fire(caster_id, (x, y), objects, &game.map) {
+        Shot::Hits(id) => objects[id].pos(),
+        Shot::Lands(tile) => tile,
+    };
+    if fov_map.is_in_fov(x, y) {
+        game.log.add("The smoke bomb bursts into a thick cloud!", colors::LIGHT_GREY);
+    }
+    make_smoke(x, y, SMOKE_RADIUS, SMOKE_TURNS, game);
+    UseResult::Consumed
+}
+
+/// Fill the open ground within `radius` of (x, y) that the smoke can get to
+/// with it, for `turns` turns. It doesn't go through walls or closed doors,
+/// nor fill the doorways. Where another cloud is, the smoke lasts until the
+/// later of the two clears.
+fn make_smoke(x: i32, y: i32, radius: i32, turns: u32, game: &mut Game) {
+    let turn = game.turn + turns;
+    for tile_x in cmp::max(x - radius, 0)..cmp::min(x + radius + 1, CONFIG.map_width) {
+        for tile_y in cmp::max(y - radius, 0)..cmp::min(y + radius + 1, CONFIG.map_height) {
+            let (dx, dy) = ((tile_x - x) as f32, (tile_y - y) as f32);
+            let tile = game.map[tile_x as usize][tile_y as usize];
+            if (dx * dx + dy * dy).sqrt() > radius as f32 || tile.blocked || tile.is_door() ||
+                !line_of_sight((x, y), (tile_x, tile_y), &game.map) {
+                continue;
+            }
+            game.map[tile_x as usize][tile_y as usize].block_sight = true;
+            let earlier = game.smoke.iter_mut().find(|smoke| (smoke.0, smoke.1) == (tile_x, tile_y));
+            match earlier {
+                Some(&mut (_, _, ref mut until)) => *until = cmp::max(*until, turn),
+                None => game.smoke.push((tile_x, tile_y, turn)),
+            }
+        }
+    }
+    game.schedule.at(turn, TimedEvent::ClearSmoke{x: x, y: y, radius: radius});
+}
+
+/// Let the smoke `make_smoke` left within `radius` of (x, y) clear away,
+/// except where a later cloud still hangs.
+fn clear_smoke(x: i32, y: i32, radius: i32, game: &mut Game) {
+    let turn = game.turn;
+    for tile_x in cmp::max(x - radius, 0)..cmp::min(x + radius + 1, CONFIG.map_width) {
+        for tile_y in cmp::max(y - radius, 0)..cmp::min(y + radius + 1, CONFIG.map_height) {
+            let lingers = game.smoke.iter()
+                .any(|&(smoke_x, smoke_y, until)| (smoke_x, smoke_y) == (tile_x, tile_y) && until > turn);
+            let tile = &mut game.map[tile_x as usize][tile_y as usize];
+            if tile.is_smoky() && !lingers {
+                tile.block_sight = false;
+            }
+        }
+    }
+    game.smoke.retain(|&(_, _, until)| until > turn);
+}
+
+/// Dig the rock at (x, y) away, unless it's the edge of the map. Returns
+/// whether there was rock to dig. What's left is rubble, and the FOV map
+/// has to be set up again afterwards.
+fn dig(x: i32, y: i32, map: &mut Map) -> bool {
+    if x <= 0 || y <= 0 || x >= CONFIG.map_width - 1 || y >= CONFIG.map_height - 1 ||
+        !map[x as usize][y as usize].blocked || map[x as usize][y as usize].is_door() {
+        return false;
+    }
+    let explored = map[x as usize][y as usize].explored;
+    map[x as usize][y as usize] = Tile { explored: explored, ..Tile::ground(TileKind::Rubble) };
+    true
+}
+
+fn dig_with_pickaxe(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                    game: &mut Game, fov_map: &FovMap) -> UseResult
+{
+    let (x, y) = match target {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    if !dig(x, y, &mut game.map) {
+        if caster_id == PLAYER {
+            game.log.add("There's no rock there you could dig through.", colors::WHITE);
+        }
+        return UseResult::Cancelled;
+    }
+    if caster_id == PLAYER {
+        game.log.add("You hack your way through the rock.", colors::LIGHT_GREY);
+    }
+    make_noise(x, y, DIG_LOUDNESS, objects, game, fov_map);
+    UseResult::UsedAndKept
+}
+
+fn cast_digging(caster_id: usize, target: Option<(i32, i32)>, objects: &mut [Object],
+                game: &mut Game, _fov_map: &FovMap) -> UseResult
+{
+    // bore a tunnel straight on in the direction picked, through whatever
+    // rock is in the way
+    let (x, y) = match target {
+        Some(tile_pos) => tile_pos,
+        None => return UseResult::Cancelled,
+    };
+    let (caster_x, caster_y) = objects[caster_id].pos();
+    let (dx, dy) = (x - caster_x, y - caster_y);
+    let mut dug = 0;
+    for step in 1..(DIG_TUNNEL_LENGTH + 1) {
+        if dig(caster_x + dx * step, caster_y + dy * step, &mut game.map) {
+            dug += 1;
+        }
+    }
+    if caster_id == PLAYER {
+        if dug > 0 {
+            game.log.add("The rock before you crumbles away!", colors::LIGHT_GREY);
+        } else {
+            game.log.add("The scroll crumbles to dust, but there is no rock for it to work on.",
+                         colors::WHITE);
+        }
+    }
+    UseResult::Consumed
+}
+
+fn cast_return(caster_id: usize, _target: Option<(i32, i32)>, objects: &mut [Object],
+               game: &mut Game, _fov_map: &FovMap) -> UseResult
+{
+    // open a portal to town under the player's feet, `sync_portals` puts
+    // both of its ends on the map
+    if caster_id != PLAYER {
+        return UseResult::Cancelled;
+    }
+    if game.depth() == Depth::town() {
+        game.log.add("You are in town already.", colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    let pos = objects[PLAYER].pos();
+    if objects.iter().any(|o| o.pos() == pos && !o.removed && o.name.ends_with("stairs")) {
+        game.log.add("There's no room for a portal on the stairs.", colors::WHITE);
+        return UseResult::Cancelled;
+    }
+    if game.portal.is_some() {
+        game.log.add("Your old portal to town flickers out.", colors::LIGHT_GREY);
+    }
+    game.portal = Some(Portal { depth: game.depth(), pos: pos });
+    game.log.add("A shimmering portal to town opens at your feet.", colors::LIGHT_MAGENTA);
+    UseResult::Consumed
+}
+
+fn toggle_equipment(inventory_id: usize, objects: &mut [Object], game: &mut Game) -> UseResult
+{
+    let inventory = &mut objects[PLAYER].inventory;
+    let equipment = match inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    if equipment.equipped {
+        inventory[inventory_id].dequip(&mut game.log);
+    } else {
+        // if the slot is already being used, dequip whatever is there first
+        if let Some(current) = get_equipped_in_slot(equipment.slot, inventory) {
+            inventory[current].dequip(&mut game.log);
+        }
+        inventory[inventory_id].equip(&mut game.log);
+    }
+    UseResult::UsedAndKept
+}
+
+/// Gameplay changes requested by a script. They are applied only after the
+/// script finishes, so scripts never touch the game state directly.
+#[derive(Clone, Debug)]
+enum ScriptCommand {
+    Message(String, Color),
+    Damage(usize, i32),
+    Heal(usize, i32),
+}
+
+/// What a script can see about an object.
+#[derive(Clone, Debug)]
+struct ScriptObject {
+    name: String,
+    pos: (i32, i32),
+    hp: i32,
+    max_hp: i32,
+    is_monster: bool,
+    in_fov: bool,
+    wound_up: bool,
+}
+
+/// What the script that's running sees, the objects as they were when it
+/// started, and what it asked for. The functions of `script_engine` go
+/// through it, `EffectRegistry::run_script` fills it in for each run.
+#[derive(Default)]
+struct ScriptState {
+    caster_id: usize,
+    target: Option<(i32, i32)>,
+    objects: Vec<ScriptObject>,
+    commands: Vec<ScriptCommand>,
+    /// what the script said became of the item, `Consumed` if it didn't say
+    result: Option<UseResult>,
+}
+
+impl EffectRegistry {
+    /// Compile `scripts/<name>.rhai`.
+    fn load_script(&self, name: &str) -> Result<rhai::AST, String> {
+        let path = std::path::PathBuf::from(format!("scripts/{}.rhai", name));
+        self.engine.compile_file(path).map_err(|e| e.to_string())
+    }
+
+    /// The targeting the script declares through its optional `targeting()`
+    /// function: a mode ("self", "nearest", "monster", "tile" or "direction"),
+    /// an optional range and, for tiles, a radius.
+    fn script_targeting(&self, ast: &rhai::AST) -> Result<Targeting, String> {
+        if !ast.iter_functions().any(|f| f.name == "targeting") {
+            return Ok(Targeting::Nothing);
+        }
+        // only the function: the script's body uses what `run_script` provides
+        let options = rhai::CallFnOptions::new().eval_ast(false);
+        let targeting = try! {
+            self.engine.call_fn_with_options::<rhai::Map>(options, &mut rhai::Scope::new(), ast, "targeting", ())
+                .map_err(|e| e.to_string())
+        };
+        let mode = targeting.get("mode")
+            .and_then(|mode| mode.clone().into_string().ok())
+            .unwrap_or_else(|| "tile".into());
+        let range = targeting.get("range")
+            .and_then(|range| range.as_int().ok())
+            .map(|range| range as f32);
+        let radius = targeting.get("radius")
+            .and_then(|radius| radius.as_int().ok())
+            .map_or(0, |radius| radius as i32);
+        match &mode[..] {
+            "self" => Ok(Targeting::Caster),
+            // without a range, anything the player can see
+            "nearest" => Ok(Targeting::NearestEnemy(range.unwrap_or(CONFIG.torch_radius as f32))),
+            "monster" => Ok(Targeting::Monster(range)),
+            "tile" => Ok(Targeting::Tile { range: range, radius: radius }),
+            "direction" => Ok(Targeting::Direction),
+            _ => Err(format!("unknown targeting mode '{}'", mode)),
+        }
+    }
+
+    /// Run a compiled script on behalf of `caster_id` and apply what it asked
+    /// for. The item is consumed unless the script called `cancel`, `keep_item`
+    /// or `no_turn`.
+    fn run_script(&self, ast: &rhai::AST, caster_id: usize, target: Option<(i32, i32)>,
+                  objects: &mut [Object], game: &mut Game, fov_map: &FovMap) -> Result<UseResult, String> {
+        let seen = objects.iter().map(|o| ScriptObject {
+            name: o.name.clone(),
+            pos: o.pos(),
+            hp: o.fighter.map_or(0, |f| f.hp),
+            max_hp: o.max_hp(),
+            is_monster: o.fighter.is_some() && o.ai.is_some(),
+            in_fov: o.in_fov(fov_map),
+            wound_up: match o.ai {
+                Some(Ai::Preparing{..}) => true,
+                _ => false,
+            },
+        }).collect();
+        *self.script_state.borrow_mut() = ScriptState {
+            caster_id: caster_id,
+            target: target,
+            objects: seen,
+            commands: vec![],
+            result: None,
+        };
+        let ran = self.engine.run_ast(ast).map_err(|e| e.to_string());
+        // take it all out, so nothing is left over for the next script
+        let state = std::mem::replace(&mut *self.script_state.borrow_mut(), ScriptState::default());
+        try! { ran };
+
+        for command in state.commands {
+            match command {
+                ScriptCommand::Message(text, color) => game.log.add(text, color),
+                ScriptCommand::Damage(id, amount) => {
+                    inflict_damage(id, amount, DamageSource::Creature(caster_id), objects, game);
+                }
+                ScriptCommand::Heal(id, amount) => objects[id].heal(amount),
+            }
+        }
+        Ok(state.result.unwrap_or(UseResult::Consumed))
+    }
+}
+
+fn color_by_name(name: &str) -> Color {
+    match name {
+        "red" => colors::RED,
+        "orange" => colors::ORANGE,
+        "yellow" => colors::YELLOW,
+        "green" => colors::LIGHT_GREEN,
+        "cyan" => colors::LIGHT_CYAN,
+        "blue" => colors::LIGHT_BLUE,
+        "violet" => colors::LIGHT_VIOLET,
+        _ => colors::WHITE,
+    }
+}
+
+/// The engine scripts run on, with what they can ask about the game and do
+/// to it, going through `state`. It stops scripts that go on for too long,
+/// see SCRIPT_MAX_OPERATIONS.
+fn script_engine(state: Rc<RefCell<ScriptState>>) -> rhai::Engine {
+    let mut engine = rhai::Engine::new();
+    engine.set_max_operations(SCRIPT_MAX_OPERATIONS);
+    engine.set_max_call_levels(SCRIPT_MAX_CALL_LEVELS);
+    engine.set_max_string_size(SCRIPT_MAX_SIZE);
+    engine.set_max_array_size(SCRIPT_MAX_SIZE);
+    engine.set_max_map_size(SCRIPT_MAX_SIZE);
+
+    // read-only queries; invalid ids read as nothing
+    {
+        let state = state.clone();
+        engine.register_fn("caster", move || state.borrow().caster_id as i64);
+    }
+    engine.register_fn("player", || PLAYER as i64);
+    {
+        let state = state.clone();
+        engine.register_fn("target_tile", move || -> rhai::Dynamic {
+            match state.borrow().target {
+                Some((x, y)) => vec![rhai::Dynamic::from(x as i64), rhai::Dynamic::from(y as i64)].into(),
+                None => rhai::Dynamic::UNIT,
+            }
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("target_monster", move || -> i64 {
+            let state = state.borrow();
+            state.target.and_then(|pos| {
+                // any fighter, so monsters can aim at the player too
+                state.objects.iter().position(|o| o.pos == pos && o.max_hp > 0)
+            }).map_or(-1, |id| id as i64)
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("name", move |id: i64| {
+            state.borrow().objects.get(id as usize).map_or(String::new(), |o| o.name.clone())
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("hp", move |id: i64| state.borrow().objects.get(id as usize).map_or(0, |o| o.hp as i64));
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("max_hp", move |id: i64| {
+            state.borrow().objects.get(id as usize).map_or(0, |o| o.max_hp as i64)
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("x", move |id: i64| {
+            state.borrow().objects.get(id as usize).map_or(-1, |o| o.pos.0 as i64)
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("y", move |id: i64| {
+            state.borrow().objects.get(id as usize).map_or(-1, |o| o.pos.1 as i64)
+        });
+    }
+    {
+        // the player's closest visible monster, or the player for monster casters
+        let state = state.clone();
+        engine.register_fn("closest_enemy", move |range: i64| -> i64 {
+            let state = state.borrow();
+            let caster_id = state.caster_id;
+            let (cx, cy) = state.objects[caster_id].pos;
+            let distance = |o: &ScriptObject| {
+                (((o.pos.0 - cx).pow(2) + (o.pos.1 - cy).pow(2)) as f32).sqrt()
+            };
+            state.objects.iter()
+                .enumerate()
+                .filter(|&(id, o)| if caster_id == PLAYER {
+                    id != PLAYER && o.is_monster && o.in_fov
+                } else {
+                    id == PLAYER && o.hp > 0
+                })
+                .filter(|&(_, o)| distance(o) <= range as f32)
+                .min_by_key(|&(_, o)| (distance(o) * 100.0) as i32)
+                .map_or(-1, |(id, _)| id as i64)
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("fighters_in_radius", move |x: i64, y: i64, radius: i64| -> rhai::Array {
+            state.borrow().objects.iter()
+                .enumerate()
+                .filter(|&(_, o)| o.hp > 0)
+                .filter(|&(_, o)| {
+                    ((((o.pos.0 as i64 - x).pow(2) + (o.pos.1 as i64 - y).pow(2)) as f32).sqrt())
+                        <= radius as f32
+                })
+                .map(|(id, _)| rhai::Dynamic::from(id as i64))
+                .collect()
+        });
+    }
+
+    // gameplay actions
+    {
+        let state = state.clone();
+        engine.register_fn("message", move |text: &str| {
+            state.borrow_mut().commands.push(ScriptCommand::Message(text.into(), colors::WHITE));
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("message", move |text: &str, color: &str| {
+            state.borrow_mut().commands.push(ScriptCommand::Message(text.into(), color_by_name(color)));
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("damage", move |id: i64, amount: i64| {
+            let mut state = state.borrow_mut();
+            if (id as usize) < state.objects.len() {
+                state.commands.push(ScriptCommand::Damage(id as usize, amount as i32));
+            }
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("heal", move |id: i64, amount: i64| {
+            let mut state = state.borrow_mut();
+            if (id as usize) < state.objects.len() {
+                state.commands.push(ScriptCommand::Heal(id as usize, amount as i32));
+            }
+        });
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("cancel", move || state.borrow_mut().result = Some(UseResult::Cancelled));
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("keep_item", move || state.borrow_mut().result = Some(UseResult::UsedAndKept));
+    }
+    {
+        let state = state.clone();
+        engine.register_fn("no_turn", move || state.borrow_mut().result = Some(UseResult::NoTurn));
+    }
+    {
+        // true once the caster spent its last turn winding up, until then
+        // it does so now and warns the player if they can see it
+        let state = state.clone();
+        engine.register_fn("wind_up", move |text: &str| -> bool {
+            let mut state = state.borrow_mut();
+            let (wound_up, in_fov) = {
+                let caster = &state.objects[state.caster_id];
+                (caster.wound_up, caster.in_fov)
+            };
+            if wound_up {
+                return true;
+            }
+            if in_fov {
+                state.commands.push(ScriptCommand::Message(text.into(), colors::ORANGE));
+            }
+            state.result = Some(UseResult::WindingUp);
+            false
+        });
+    }
+    engine.register_fn("cancel", move |text: &str| {
+        let mut state = state.borrow_mut();
+        state.commands.push(ScriptCommand::Message(text.into(), colors::RED));
+        state.result = Some(UseResult::Cancelled);
+    });
+    engine
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+/// An object that can be equipped, yielding bonuses.
+struct Equipment {
+    slot: Slot,
+    equipped: bool,
+    max_hp_bonus: i32,
+    defense_bonus: i32,
+    power_bonus: i32,
+    luck_bonus: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Slot {
+    LeftHand,
+    RightHand,
+    Head,
+    Neck,
+}
+
+impl std::fmt::Display for Slot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Slot::LeftHand => write!(f, "left hand"),
+            Slot::RightHand => write!(f, "right hand"),
+            Slot::Head => write!(f, "head"),
+            Slot::Neck => write!(f, "neck"),
+        }
+    }
+}
+
+/// Laying out the dungeon's levels, apart from what lives in them: rooms
+/// joined by tunnels, where the player starts and where the stairs go.
+///
+/// It needs nothing of the game but the [map] settings, so levels can be
+/// made and checked on their own. After `seed_rng` the same seed makes the
+/// same level:
+///
+/// ```
+/// use roguelike_tutorial::{mapgen, seed_rng};
+///
+/// let (prefabs, _) = mapgen::load_prefabs("prefabs");
+/// for seed in 0..10 {
+///     seed_rng(seed);
+///     let level = mapgen::generate(3, &prefabs, None, 1);
+///     assert_eq!(mapgen::check_connected(&level), Ok(()));
+/// }
+/// ```
+pub mod mapgen {
+    use std::cmp;
+    use std::collections::VecDeque;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use rand::Rng;
+    use super::{Map, Movement, Rect, Tile, TileKind, CONFIG, MAX_STAIRS, STAIRS_MIN_DISTANCE, STAIRS_MIN_SEPARATION};
+    use super::is_hazard;
+    use super::{PREFAB_CHANCE, RUBBLE_CHANCE, RUBBLE_RADIUS, SECRET_PASSAGE_CHANCE, game_rng};
+    use super::{DOOR_CHANCE, LOCKED_DOOR_CHANCE};
+    use super::{LAVA_CHANCE, LAVA_MIN_LEVEL, POOL_CHANCE, POOL_RADIUS, RIVER_CHANCE};
+    use super::{CAVE_CELL_MIN_FLOOR, CAVE_CELL_SIZE, CAVE_WALK_LIMIT};
+    use super::{CORNERS_CHANCE, DECORATED_ROOM_SIZE, MAX_STREWN, STREWN_CHANCE};
+    use super::{ARENA_HEIGHT, ARENA_PILLAR_SPACING, ARENA_WIDTH, TOWN_HOUSE_HEIGHT, TOWN_HOUSE_WIDTH};
+    use super::Service;
+
+    pub struct GeneratedMap {
+        pub tiles: Map,
+        /// the plain rooms, prefabs aren't counted
+        pub rooms: Vec<Rect>,
+        /// where the player starts
+        pub spawn: (i32, i32),
+        /// the down staircases, one or two, or as many as were asked for
+        pub stairs: Vec<(i32, i32)>,
+        /// what the prefabs want on their tiles
+        pub placements: Vec<((i32, i32), Placement)>,
+    }
+
+    /// What a prefab's legend puts on one of its tiles
+    #[derive(Clone, Debug, PartialEq)]
+    pub enum Placement {
+        Monster(String),
+        /// an item, by (a part of) its name
+        Item(String),
+        /// one of the town's folk
+        Npc(Service),
+        Decoration(Decoration),
+    }
+
+    /// Scenery that only changes how a floor looks: it's in nobody's way
+    /// and can't be picked up.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum Decoration {
+        Rubble,
+        Bloodstain,
+        Grass,
+        Pillar,
+        Brazier,
+        Altar,
+    }
+
+    impl Decoration {
+        /// the decoration a prefab's legend names
+        pub fn from_name(name: &str) -> Option<Decoration> {
+            match name {
+                "rubble" => Some(Decoration::Rubble),
+                "bloodstain" => Some(Decoration::Bloodstain),
+                "grass" => Some(Decoration::Grass),
+                "pillar" => Some(Decoration::Pillar),
+                "brazier" => Some(Decoration::Brazier),
+                "altar" => Some(Decoration::Altar),
+                _ => None,
+            }
+        }
+    }
+
+    /// A hand-made room, read from `prefabs/*.txt`. The file starts with
+    /// `key = value` lines: `name`, `min_level`, `weight` and one line for
+    /// each legend character, like `o = monster orc`, `! = item healing` or
+    /// `* = decoration brazier`.
+    /// After a `---` line comes the layout: `#` is wall, `.` is floor, a
+    /// space leaves the rock as it is and a legend character is floor with
+    /// something on it.
+    pub struct Prefab {
+        pub name: String,
+        /// the first dungeon level it can show up on
+        pub min_level: u32,
+        /// how often it shows up compared to the other prefabs
+        pub weight: u32,
+        pub layout: Vec<Vec<char>>,
+        pub legend: Vec<(char, Placement)>,
+    }
+
+    impl Prefab {
+        pub fn parse(text: &str) -> Result<Prefab, String> {
+            let mut prefab = Prefab {
+                name: String::new(),
+                min_level: 1,
+                weight: 10,
+                layout: vec![],
+                legend: vec![],
+            };
+            let mut lines = text.lines();
+            let mut found_layout = false;
+            for line in &mut lines {
+                let line = line.trim();
+                if line == "---" {
+                    found_layout = true;
+                    break;
+                }
+                if line.is_empty() || line.starts_with("# ") {
+                    continue;
+                }
+                let (key, value) = match line.find('=') {
+                    Some(index) => (line[..index].trim(), line[index + 1..].trim()),
+                    None => return Err(format!("expected 'key = value', found '{}'", line)),
+                };
+                match key {
+                    "name" => prefab.name = value.into(),
+                    "min_level" => prefab.min_level = try! {
+                        value.parse().map_err(|_| format!("'{}' is not a dungeon level", value))
+                    },
+                    "weight" => prefab.weight = try! {
+                        value.parse().map_err(|_| format!("'{}' is not a weight", value))
+                    },
+                    _ if key.chars().count() == 1 && key != "." => {
+                        let mut words = value.splitn(2, ' ');
+                        let placement = match (words.next(), words.next()) {
+                            (Some("monster"), Some(species)) => Placement::Monster(species.trim().into()),
+                            (Some("item"), Some(name)) => Placement::Item(name.trim().into()),
+                            (Some("decoration"), Some(name)) => match Decoration::from_name(name.trim()) {
+                                Some(decoration) => Placement::Decoration(decoration),
+                                None => return Err(format!("there is no decoration '{}'", name.trim())),
+                            },
+                            _ => return Err(format!("'{}' should be 'monster <species>', 'item <name>' \
+                                                     or 'decoration <kind>'", value)),
+                        };
+                        prefab.legend.push((key.chars().next().unwrap(), placement));
+                    }
+                    _ => return Err(format!("unknown setting '{}'", key)),
+                }
+            }
+            if !found_layout {
+                return Err("there is no '---' line before the layout".into());
+            }
+
+            prefab.layout = lines.map(|line| line.chars().collect()).collect();
+            while prefab.layout.last().map_or(false, |row: &Vec<char>| row.is_empty()) {
+                prefab.layout.pop();
+            }
+            let width = prefab.layout.iter().map(|row| row.len()).max().unwrap_or(0);
+            for row in prefab.layout.iter_mut() {
+                row.resize(width, ' ');
+            }
+            let mut floor = 0;
+            for &c in prefab.layout.iter().flat_map(|row| row.iter()) {
+                match c {
+                    ' ' | '#' => {}
+                    '.' => floor += 1,
+                    _ if prefab.legend.iter().any(|&(symbol, _)| symbol == c) => floor += 1,
+                    _ => return Err(format!("'{}' in the layout isn't in the legend", c)),
+                }
+            }
+            if floor == 0 {
+                return Err("the layout has no floor".into());
+            }
+            Ok(prefab)
+        }
+
+        /// the layout, mirrored left to right if `mirror` and then turned
+        /// clockwise `quarter_turns` times
+        pub fn layout(&self, quarter_turns: u32, mirror: bool) -> Vec<Vec<char>> {
+            let mut layout = self.layout.clone();
+            if mirror {
+                for row in layout.iter_mut() {
+                    row.reverse();
+                }
+            }
+            for _ in 0..quarter_turns % 4 {
+                // the new rows are the old columns, read from the bottom up
+                let turned = (0..layout[0].len())
+                    .map(|x| (0..layout.len()).rev().map(|y| layout[y][x]).collect())
+                    .collect();
+                layout = turned;
+            }
+            layout
+        }
+    }
+
+    /// Read every prefab in `dir`. Broken files are left out, what's wrong
+    /// with them is returned along with the prefabs.
+    pub fn load_prefabs(dir: &str) -> (Vec<Prefab>, Vec<String>) {
+        let mut paths: Vec<_> = fs::read_dir(dir).into_iter()
+            .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("txt"))
+            .collect();
+        paths.sort();
+
+        let mut prefabs = vec![];
+        let mut problems = vec![];
+        for path in paths {
+            let mut text = String::new();
+            let prefab = File::open(&path)
+                .and_then(|mut file| file.read_to_string(&mut text))
+                .map_err(|e| e.to_string())
+                .and_then(|_| Prefab::parse(&text));
+            match prefab {
+                Ok(mut prefab) => {
+                    if prefab.name.is_empty() {
+                        prefab.name = path.file_stem().map_or(String::new(), |name| name.to_string_lossy().into());
+                    }
+                    prefabs.push(prefab);
+                }
+                Err(e) => problems.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+        (prefabs, problems)
+    }
+
+    /// Put the layout on the map with its top left corner at (x, y), and
+    /// return the floor tile closest to its middle.
+    fn stamp(prefab: &Prefab, layout: &[Vec<char>], (x, y): (i32, i32), map: &mut Map,
+             placements: &mut Vec<((i32, i32), Placement)>) -> (i32, i32) {
+        let middle = (x + layout[0].len() as i32 / 2, y + layout.len() as i32 / 2);
+        let mut closest = None;
+        for (dy, row) in layout.iter().enumerate() {
+            for (dx, &c) in row.iter().enumerate() {
+                let (tile_x, tile_y) = (x + dx as i32, y + dy as i32);
+                let tile = &mut map[tile_x as usize][tile_y as usize];
+                match c {
+                    ' ' => continue,
+                    // walls are already there, unless a tunnel passes
+                    // through: that one stays open
+                    '#' => continue,
+                    _ => *tile = Tile::empty(),
+                }
+                if let Some(&(_, ref placement)) = prefab.legend.iter().find(|&&(symbol, _)| symbol == c) {
+                    placements.push(((tile_x, tile_y), placement.clone()));
+                }
+                let distance = (tile_x - middle.0).pow(2) + (tile_y - middle.1).pow(2);
+                if closest.map_or(true, |(closest_distance, _)| distance < closest_distance) {
+                    closest = Some((distance, (tile_x, tile_y)));
+                }
+            }
+        }
+        closest.map_or(middle, |(_, tile)| tile)
+    }
+
+    pub fn create_room(room: Rect, map: &mut Map) {
+        // go through the tiles in the rectangle and make them passable
+        for x in (room.x1 + 1)..room.x2 {
+            for y in (room.y1 + 1)..room.y2 {
+                map[x as usize][y as usize] = Tile::empty();
+            }
+        }
+    }
+
+    pub fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+        // horizontal tunnel. `min()` and `max()` are used in case `x1 > x2`
+        for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+
+    pub fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+        // vertical tunnel
+        for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+            map[x as usize][y as usize] = Tile::empty();
+        }
+    }
+
+    /// Dig a tunnel between two points that opens onto the rest of the map
+    /// only through secret doors.
+    fn dig_secret_passage((x1, y1): (i32, i32), (x2, y2): (i32, i32), map: &mut Map) {
+        let before = map.clone();
+        create_h_tunnel(x1, x2, y1, map);
+        create_v_tunnel(y1, y2, x2, map);
+        let open_before = |(x, y): (i32, i32)| {
+            x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+                !before[x as usize][y as usize].blocked
+        };
+        for x in 0..CONFIG.map_width {
+            for y in 0..CONFIG.map_height {
+                if map[x as usize][y as usize].blocked || open_before((x, y)) {
+                    continue;
+                }
+                // newly dug, and right next to somewhere that was open
+                let opening = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (x + dx, y + dy))).any(&open_before);
+                if opening {
+                    map[x as usize][y as usize] = Tile::secret_door();
+                }
+            }
+        }
+    }
+
+    /// Hang doors in the gaps the tunnels made in the walls of the rooms,
+    /// now and then a locked one.
+    fn add_doors(rooms: &[Rect], map: &mut Map) {
+        for room in rooms {
+            let walls: Vec<(i32, i32)> = (room.x1..room.x2 + 1)
+                .flat_map(|x| vec![(x, room.y1), (x, room.y2)])
+                .chain((room.y1 + 1..room.y2).flat_map(|y| vec![(room.x1, y), (room.x2, y)]))
+                .collect();
+            for (x, y) in walls {
+                if is_doorway(x, y, map) && game_rng().gen::<f32>() < DOOR_CHANCE {
+                    map[x as usize][y as usize] = Tile::door(game_rng().gen::<f32>() < LOCKED_DOOR_CHANCE);
+                }
+            }
+        }
+    }
+
+    /// Whether (x, y) is a plain floor tile between walls on two opposite
+    /// sides and open on the other two, where a door would fit.
+    fn is_doorway(x: i32, y: i32, map: &Map) -> bool {
+        let in_map = |x: i32, y: i32| x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height;
+        let open = |x: i32, y: i32| in_map(x, y) && !map[x as usize][y as usize].blocked;
+        let wall = |x: i32, y: i32| {
+            in_map(x, y) && map[x as usize][y as usize].blocked && !map[x as usize][y as usize].is_door()
+        };
+        open(x, y) && map[x as usize][y as usize].kind == TileKind::Floor &&
+            ((wall(x - 1, y) && wall(x + 1, y) && open(x, y - 1) && open(x, y + 1)) ||
+             (wall(x, y - 1) && wall(x, y + 1) && open(x - 1, y) && open(x + 1, y)))
+    }
+
+    /// Dig a tunnel between two points, around one corner or the other.
+    fn dig_tunnel((x1, y1): (i32, i32), (x2, y2): (i32, i32), map: &mut Map) {
+        // toss a coin (random bool value -- either true or false)
+        if game_rng().gen() {
+            // first move horizontally, then vertically
+            create_h_tunnel(x1, x2, y1, map);
+            create_v_tunnel(y1, y2, x2, map);
+        } else {
+            // first move vertically, then horizontally
+            create_v_tunnel(y1, y2, x1, map);
+            create_h_tunnel(x1, x2, y2, map);
+        }
+    }
+
+    fn squared_distance((x1, y1): (i32, i32), (x2, y2): (i32, i32)) -> i32 {
+        (x1 - x2).pow(2) + (y1 - y2).pow(2)
+    }
+
+    /// The shortest set of edges (pairs of indices into `points`) that joins
+    /// all the points, grown one nearest point at a time.
+    fn spanning_tree(points: &[(i32, i32)]) -> Vec<(usize, usize)> {
+        let mut joined = vec![false; points.len()];
+        let mut edges = vec![];
+        if points.is_empty() {
+            return edges;
+        }
+        joined[0] = true;
+        for _ in 1..points.len() {
+            let nearest = (0..points.len())
+                .filter(|&from| joined[from])
+                .flat_map(|from| (0..points.len()).filter(|&to| !joined[to]).map(move |to| (from, to)))
+                .min_by_key(|&(from, to)| squared_distance(points[from], points[to]));
+            if let Some((from, to)) = nearest {
+                joined[to] = true;
+                edges.push((from, to));
+            }
+        }
+        edges
+    }
+
+    /// Join the rooms with tunnels: a spanning tree so every room can be
+    /// reached, then `loop_corridors` percent more between rooms close by
+    /// so there's more than one way around. Returns the pairs of rooms
+    /// that were joined.
+    fn join_rooms(rooms: &[Rect], map: &mut Map) -> Vec<(usize, usize)> {
+        let centers: Vec<_> = rooms.iter().map(|room| room.center()).collect();
+        let mut edges = spanning_tree(&centers);
+        let mut candidates: Vec<(usize, usize)> = (0..centers.len())
+            .flat_map(|from| (from + 1..centers.len()).map(move |to| (from, to)))
+            .filter(|&(from, to)| !edges.contains(&(from, to)) && !edges.contains(&(to, from)))
+            .collect();
+        candidates.sort_by_key(|&(from, to)| squared_distance(centers[from], centers[to]));
+        // the loops come from the shorter ones, a tunnel across the whole
+        // map is no alternative route
+        let loops = edges.len() * CONFIG.loop_corridors as usize / 100;
+        candidates.truncate(loops * 3);
+        game_rng().shuffle(&mut candidates);
+        edges.extend(candidates.into_iter().take(loops));
+        for &(from, to) in &edges {
+            dig_tunnel(centers[from], centers[to], map);
+        }
+        edges
+    }
+
+    /// Carve a new level out of solid rock: rooms joined by tunnels, some of
+    /// them `prefabs` that can show up on this dungeon level, turned and
+    /// mirrored at random. The `required` prefab is put in first thing after
+    /// the player's room. There are at least `min_stairs` staircases down.
+    pub fn generate(level: u32, prefabs: &[Prefab], required: Option<&Prefab>, min_stairs: usize) -> GeneratedMap {
+        // fill map with "blocked" tiles
+        let mut map = vec![vec![Tile::wall(); CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        let mut rooms: Vec<Rect> = vec![];
+        let mut vaults: Vec<Rect> = vec![];
+        let mut placements = vec![];
+        // where the tunnels come into the prefabs
+        let mut entries = vec![];
+
+        let usable: Vec<&Prefab> = prefabs.iter()
+            .filter(|prefab| prefab.min_level <= level && prefab.weight > 0)
+            .collect();
+        let total_weight: u32 = usable.iter().map(|prefab| prefab.weight).sum();
+
+        let mut required = required;
+        for _ in 0..CONFIG.max_rooms {
+            // now and then a prefab instead of an empty room, but never
+            // where the player starts
+            let prefab = if !rooms.is_empty() && required.is_some() {
+                required
+            } else if !rooms.is_empty() && total_weight > 0 && game_rng().gen::<f32>() < PREFAB_CHANCE {
+                let mut roll = game_rng().gen_range(0, total_weight);
+                usable.iter().find(|prefab| {
+                    if roll < prefab.weight {
+                        true
+                    } else {
+                        roll -= prefab.weight;
+                        false
+                    }
+                }).cloned()
+            } else {
+                None
+            };
+            let layout = prefab.map(|prefab| prefab.layout(game_rng().gen_range(0, 4), game_rng().gen()));
+
+            let (w, h) = match layout {
+                // the rectangle's edges are the layout's outermost tiles
+                Some(ref layout) => (layout[0].len() as i32 - 1, layout.len() as i32 - 1),
+                // random width and height
+                None => (game_rng().gen_range(CONFIG.room_min_size, CONFIG.room_max_size + 1),
+                         game_rng().gen_range(CONFIG.room_min_size, CONFIG.room_max_size + 1)),
+            };
+            if w >= CONFIG.map_width || h >= CONFIG.map_height {
+                continue;  // a prefab too big for this map
+            }
+            // random position without going out of the boundaries of the map
+            let x = game_rng().gen_range(0, CONFIG.map_width - w);
+            let y = game_rng().gen_range(0, CONFIG.map_height - h);
+
+            let new_room = Rect::new(x, y, w, h);
+
+            // run through the other rooms and see if they intersect with this one
+            let failed = rooms.iter().chain(&vaults).any(|other_room| new_room.intersects_with(other_room));
+
+            if !failed {
+                // this means there are no intersections, so this room is valid
+
+                // "paint" it to the map's tiles, a prefab also says where
+                // the tunnel will come in
+                match (prefab, layout) {
+                    (Some(prefab), Some(layout)) => {
+                        info!("placing the prefab '{}' at {:?}", prefab.name, (x, y));
+                        if required.map_or(false, |required| required.name == prefab.name) {
+                            required = None;
+                        }
+                        vaults.push(new_room);
+                        entries.push(stamp(prefab, &layout, (x, y), &mut map, &mut placements));
+                    }
+                    _ => {
+                        create_room(new_room, &mut map);
+                        rooms.push(new_room);
+                    }
+                }
+            }
+        }
+
+        let joined = join_rooms(&rooms, &mut map);
+        // a prefab has just the one way in, from the room closest to it
+        for &entry in &entries {
+            let closest = rooms.iter()
+                .map(|room| room.center())
+                .min_by_key(|&center| squared_distance(center, entry));
+            if let Some(center) = closest {
+                dig_tunnel(center, entry, &mut map);
+            }
+        }
+
+        // now and then a shortcut between two rooms that have no tunnel
+        // between them, hidden behind secret doors
+        let unjoined: Vec<(usize, usize)> = (0..rooms.len())
+            .flat_map(|from| (from + 1..rooms.len()).map(move |to| (from, to)))
+            .filter(|&(from, to)| !joined.contains(&(from, to)) && !joined.contains(&(to, from)))
+            .collect();
+        if !unjoined.is_empty() && game_rng().gen::<f32>() < SECRET_PASSAGE_CHANCE {
+            let (from, to) = unjoined[game_rng().gen_range(0, unjoined.len())];
+            dig_secret_passage(rooms[from].center(), rooms[to].center(), &mut map);
+        }
+
+        // the player starts in the first room, the stairs are somewhere away from it
+        let spawn = rooms[0].center();
+        add_terrain(level, &rooms, spawn, &mut map);
+        add_doors(&rooms, &mut map);
+        decorate(&rooms, &map, &mut placements);
+        let centers: Vec<_> = rooms.iter()
+            .map(|room| room.center())
+            .filter(|&(x, y)| !is_hazard(&map[x as usize][y as usize], Movement::walking()))
+            .collect();
+        let stairs = choose_stairs(&centers, spawn, min_stairs);
+        GeneratedMap { tiles: map, rooms: rooms, spawn: spawn, stairs: stairs, placements: placements }
+    }
+
+    /// Winding caves dug by drunkards: each walker staggers about from
+    /// somewhere already dug out until together they have opened up
+    /// `cave_coverage` percent of the map. The rooms are the cells of a grid
+    /// over the map that ended up with enough floor in them. There are at
+    /// least `min_stairs` staircases down.
+    pub fn generate_caves(level: u32, min_stairs: usize) -> GeneratedMap {
+        let (width, height) = (CONFIG.map_width, CONFIG.map_height);
+        let mut map = vec![vec![Tile::wall(); height as usize]; width as usize];
+        let spawn = (width / 2, height / 2);
+        map[spawn.0 as usize][spawn.1 as usize] = Tile::empty();
+        let mut dug = vec![spawn];
+
+        let target = ((width - 2) * (height - 2) * CONFIG.cave_coverage / 100) as usize;
+        let walkers = CONFIG.cave_walkers as usize;
+        for walker in 0..walkers {
+            // each walker starts where an earlier one has been, that keeps
+            // the caves in one piece
+            let (mut x, mut y) = if walker == 0 { spawn } else { dug[game_rng().gen_range(0, dug.len())] };
+            let goal = target * (walker + 1) / walkers;
+            let mut steps = 0;
+            while dug.len() < goal && steps < (goal - dug.len()) * CAVE_WALK_LIMIT {
+                let (dx, dy) = match game_rng().gen_range(0, 4) {
+                    0 => (1, 0),
+                    1 => (-1, 0),
+                    2 => (0, 1),
+                    _ => (0, -1),
+                };
+                // the walkers stay off the edge of the map
+                x = cmp::max(1, cmp::min(width - 2, x + dx));
+                y = cmp::max(1, cmp::min(height - 2, y + dy));
+                if map[x as usize][y as usize].blocked {
+                    map[x as usize][y as usize] = Tile::empty();
+                    dug.push((x, y));
+                }
+                steps += 1;
+            }
+        }
+
+        // the cell the player starts in comes first, whatever is in it
+        let mut rooms = vec![];
+        for cell_x in (1..width - 1).filter(|x| (x - 1) % CAVE_CELL_SIZE == 0) {
+            for cell_y in (1..height - 1).filter(|y| (y - 1) % CAVE_CELL_SIZE == 0) {
+                let w = cmp::min(CAVE_CELL_SIZE, width - 1 - cell_x);
+                let h = cmp::min(CAVE_CELL_SIZE, height - 1 - cell_y);
+                // the cell's tiles are inside the rectangle's edges
+                let cell = Rect::new(cell_x - 1, cell_y - 1, w + 1, h + 1);
+                let inside = |&&(x, y): &&(i32, i32)| x > cell.x1 && x < cell.x2 && y > cell.y1 && y < cell.y2;
+                if inside(&&spawn) {
+                    rooms.insert(0, cell);
+                } else if dug.iter().filter(inside).count() >= CAVE_CELL_MIN_FLOOR {
+                    rooms.push(cell);
+                }
+            }
+        }
+
+        add_terrain(level, &rooms, spawn, &mut map);
+        let mut placements = vec![];
+        decorate(&rooms, &map, &mut placements);
+        let floor: Vec<_> = dug.into_iter()
+            .filter(|&(x, y)| !is_hazard(&map[x as usize][y as usize], Movement::walking()))
+            .collect();
+        let stairs = choose_stairs(&floor, spawn, min_stairs);
+        GeneratedMap { tiles: map, rooms: rooms, spawn: spawn, stairs: stairs, placements: placements }
+    }
+
+    /// Heaps of rubble, a stream and pools of water or lava in some of the
+    /// rooms, but no pools where the player starts.
+    fn add_terrain(level: u32, rooms: &[Rect], spawn: (i32, i32), map: &mut Map) {
+        for room in rooms {
+            if game_rng().gen::<f32>() < RUBBLE_CHANCE {
+                scatter_rubble(*room, map);
+            }
+        }
+        if game_rng().gen::<f32>() < RIVER_CHANCE {
+            carve_river(map);
+        }
+        for room in rooms.iter().skip(1) {
+            if level >= LAVA_MIN_LEVEL && game_rng().gen::<f32>() < LAVA_CHANCE {
+                fill_pool(*room, TileKind::Lava, TileKind::Lava, spawn, map);
+            } else if game_rng().gen::<f32>() < POOL_CHANCE {
+                fill_pool(*room, TileKind::DeepWater, TileKind::ShallowWater, spawn, map);
+            }
+        }
+    }
+
+    /// A boss's arena: one big hall with rows of pillars to hide behind. The
+    /// player comes in at one end and the stairs are at the other.
+    pub fn generate_arena() -> GeneratedMap {
+        let mut map = vec![vec![Tile::wall(); CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        let w = cmp::min(ARENA_WIDTH, CONFIG.map_width - 1);
+        let h = cmp::min(ARENA_HEIGHT, CONFIG.map_height - 1);
+        let hall = Rect::new((CONFIG.map_width - w) / 2, (CONFIG.map_height - h) / 2, w, h);
+        create_room(hall, &mut map);
+
+        // the pillars leave the middle row free, that's where the boss waits
+        let (center_x, center_y) = hall.center();
+        for x in (hall.x1 + 1)..hall.x2 {
+            for y in (hall.y1 + 1)..hall.y2 {
+                let pillar = (x - center_x) % ARENA_PILLAR_SPACING == 0 &&
+                    (y - center_y) % (ARENA_PILLAR_SPACING / 2) == 0 && y != center_y;
+                let inside = x > hall.x1 + 2 && x < hall.x2 - 2 && y > hall.y1 + 1 && y < hall.y2 - 1;
+                if pillar && inside {
+                    map[x as usize][y as usize] = Tile::wall();
+                }
+            }
+        }
+
+        GeneratedMap {
+            tiles: map,
+            rooms: vec![hall],
+            spawn: (hall.x1 + 1, center_y),
+            stairs: vec![(hall.x2 - 1, center_y)],
+            placements: vec![],
+        }
+    }
+
+    /// The town on the surface: a walled square with a house for each of
+    /// the services along its north side, and the way down into the dungeon
+    /// in the south.
+    pub fn generate_town() -> GeneratedMap {
+        let mut map = vec![vec![Tile::wall(); CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        let square = Rect::new(3, 2, CONFIG.map_width - 7, CONFIG.map_height - 5);
+        create_room(square, &mut map);
+
+        let (center_x, _) = square.center();
+        let spawn = (center_x, square.y2 - 5);
+        let stairs = (center_x, square.y2 - 2);
+        let mut placements = vec![];
+        let mut doors = vec![];
+        let services = [Service::Shop, Service::Healer, Service::Stash, Service::Trainer];
+        let spacing = TOWN_HOUSE_WIDTH + TOWN_HOUSE_WIDTH / 2;
+        for (i, &service) in services.iter().enumerate() {
+            let offset = i as i32 * spacing - (services.len() as i32 - 1) * spacing / 2;
+            let house = Rect::new(center_x - TOWN_HOUSE_WIDTH / 2 + offset, square.y1 + 2,
+                                  TOWN_HOUSE_WIDTH, TOWN_HOUSE_HEIGHT);
+            build_house(house, &mut map);
+            placements.push((house.center(), Placement::Npc(service)));
+            doors.push((house.center().0, house.y2));
+        }
+
+        // a street past the doors, and a road from it down to the stairs
+        let street_y = spawn.1;
+        for &(door_x, door_y) in &doors {
+            lay_road((door_x, door_y + 1), (door_x, street_y), &mut map);
+        }
+        if let (Some(&(west_x, _)), Some(&(east_x, _))) = (doors.first(), doors.last()) {
+            lay_road((west_x, street_y), (east_x, street_y), &mut map);
+        }
+        lay_road(spawn, stairs, &mut map);
+        // the hunter has no house, and waits by the way down for whoever comes back up
+        placements.push(((center_x + 3, stairs.1 - 1), Placement::Npc(Service::Hunter)));
+
+        GeneratedMap {
+            tiles: map,
+            rooms: vec![square],
+            spawn: spawn,
+            stairs: vec![stairs],
+            placements: placements,
+        }
+    }
+
+    /// pave a straight road between two points, both ends included
+    fn lay_road((x1, y1): (i32, i32), (x2, y2): (i32, i32), map: &mut Map) {
+        for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+            for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+                map[x as usize][y as usize] = Tile::ground(TileKind::Road);
+            }
+        }
+    }
+
+    /// the walls of a house, with the door in the middle of its south wall
+    fn build_house(house: Rect, map: &mut Map) {
+        for x in house.x1..(house.x2 + 1) {
+            for y in house.y1..(house.y2 + 1) {
+                if x == house.x1 || x == house.x2 || y == house.y1 || y == house.y2 {
+                    map[x as usize][y as usize] = Tile::wall();
+                }
+            }
+        }
+        let (door_x, _) = house.center();
+        map[door_x as usize][house.y2 as usize] = Tile::door(false);
+    }
+
+    /// Dress the rooms up with scenery, on plain floor only: pillars or
+    /// braziers in the corners of the larger ones and rubble, bloodstains or
+    /// grass strewn about.
+    fn decorate(rooms: &[Rect], map: &Map, placements: &mut Vec<((i32, i32), Placement)>) {
+        let plain_floor = |x: i32, y: i32| {
+            let tile = &map[x as usize][y as usize];
+            !tile.blocked && tile.kind == TileKind::Floor
+        };
+        for room in rooms {
+            let large = room.x2 - room.x1 >= DECORATED_ROOM_SIZE && room.y2 - room.y1 >= DECORATED_ROOM_SIZE;
+            if large && game_rng().gen::<f32>() < CORNERS_CHANCE {
+                let decoration = if game_rng().gen() { Decoration::Pillar } else { Decoration::Brazier };
+                let corners = [(room.x1 + 2, room.y1 + 2), (room.x2 - 2, room.y1 + 2),
+                               (room.x1 + 2, room.y2 - 2), (room.x2 - 2, room.y2 - 2)];
+                for &(x, y) in corners.iter().filter(|&&(x, y)| plain_floor(x, y)) {
+                    placements.push(((x, y), Placement::Decoration(decoration)));
+                }
+            }
+            if game_rng().gen::<f32>() < STREWN_CHANCE {
+                let decoration = *game_rng()
+                    .choose(&[Decoration::Rubble, Decoration::Bloodstain, Decoration::Grass])
+                    .unwrap();
+                for _ in 0..game_rng().gen_range(1, MAX_STREWN + 1) {
+                    let x = game_rng().gen_range(room.x1 + 1, room.x2);
+                    let y = game_rng().gen_range(room.y1 + 1, room.y2);
+                    if plain_floor(x, y) && !placements.iter().any(|&(pos, _)| pos == (x, y)) {
+                        placements.push(((x, y), Placement::Decoration(decoration)));
+                    }
+                }
+            }
+        }
+    }
+
+    /// A heap of rubble somewhere in the room, thinning out towards its edges
+    fn scatter_rubble(room: Rect, map: &mut Map) {
+        let center_x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let center_y = game_rng().gen_range(room.y1 + 1, room.y2);
+        for x in (room.x1 + 1)..room.x2 {
+            for y in (room.y1 + 1)..room.y2 {
+                let distance = cmp::max((x - center_x).abs(), (y - center_y).abs());
+                let tile = &mut map[x as usize][y as usize];
+                if distance <= RUBBLE_RADIUS && game_rng().gen_range(0, distance + 1) == 0 && !tile.blocked {
+                    tile.kind = TileKind::Rubble;
+                }
+            }
+        }
+    }
+
+    /// A shallow stream winding from the top of the map to the bottom. It
+    /// only shows where it crosses rooms and tunnels, the rest of its bed is
+    /// inside the rock.
+    fn carve_river(map: &mut Map) {
+        let mut x = game_rng().gen_range(1, CONFIG.map_width - 2);
+        for y in 0..CONFIG.map_height {
+            for bed_x in x..(x + 2) {
+                let tile = &mut map[bed_x as usize][y as usize];
+                if !tile.blocked {
+                    tile.kind = TileKind::ShallowWater;
+                }
+            }
+            x = cmp::max(1, cmp::min(CONFIG.map_width - 3, x + game_rng().gen_range(-1, 2)));
+        }
+    }
+
+    /// A round pool somewhere in the room: `middle` in its middle and
+    /// `shore` around it. A pool that would cut the player off from ground
+    /// they could walk to from `spawn` before is drained again.
+    fn fill_pool(room: Rect, middle: TileKind, shore: TileKind, spawn: (i32, i32), map: &mut Map) {
+        let before = map.clone();
+        let reachable_before = reachable(map, spawn);
+        let center_x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let center_y = game_rng().gen_range(room.y1 + 1, room.y2);
+        for x in (room.x1 + 1)..room.x2 {
+            for y in (room.y1 + 1)..room.y2 {
+                let distance = ((((x - center_x).pow(2) + (y - center_y).pow(2)) as f32).sqrt()).round() as i32;
+                let tile = &mut map[x as usize][y as usize];
+                if tile.blocked {
+                    continue;
+                } else if distance < POOL_RADIUS {
+                    tile.kind = middle;
+                } else if distance == POOL_RADIUS {
+                    tile.kind = shore;
+                }
+            }
+        }
+
+        let reachable_after = reachable(map, spawn);
+        let cut_off = map.iter().enumerate().any(|(x, column)| {
+            column.iter().enumerate().any(|(y, tile)| {
+                reachable_before[x][y] && !reachable_after[x][y] && !is_hazard(tile, Movement::walking())
+            })
+        });
+        if cut_off {
+            *map = before;
+        }
+    }
+
+    /// Pick one or two down staircases, but no fewer than `min_stairs`,
+    /// among the `spots` (the centers of rooms, say) that are far enough from
+    /// the entry point (and from each other).
+    fn choose_stairs(spots: &[(i32, i32)], entry: (i32, i32), min_stairs: usize) -> Vec<(i32, i32)> {
+        let distance = |(x1, y1): (i32, i32), (x2, y2): (i32, i32)| {
+            (((x2 - x1).pow(2) + (y2 - y1).pow(2)) as f32).sqrt()
+        };
+
+        let mut candidates: Vec<_> = spots.iter()
+            .cloned()
+            .filter(|&spot| distance(entry, spot) >= STAIRS_MIN_DISTANCE)
+            .collect();
+        if candidates.is_empty() {
+            // all spots are close to the entry, use the one furthest away
+            candidates.extend(spots.iter().cloned().max_by_key(|&spot| distance(entry, spot) as i32));
+        }
+        game_rng().shuffle(&mut candidates);
+
+        let num_stairs = cmp::max(min_stairs, game_rng().gen_range(1, MAX_STAIRS + 1));
+        let mut placed: Vec<(i32, i32)> = vec![];
+        for pos in candidates {
+            if placed.len() >= num_stairs {
+                break;
+            }
+            if placed.iter().all(|&other| distance(other, pos) >= STAIRS_MIN_SEPARATION) {
+                placed.push(pos);
+            }
+        }
+        // too few of them far enough apart, the furthest of the others do
+        if placed.len() < min_stairs {
+            let mut others: Vec<_> = spots.iter().cloned().filter(|spot| !placed.contains(spot)).collect();
+            others.sort_by_key(|&spot| -distance(entry, spot) as i32);
+            let missing = min_stairs - placed.len();
+            placed.extend(others.into_iter().take(missing));
+        }
+        placed
+    }
+
+    /// Every tile that can be walked to from `from`, moving in the eight
+    /// directions the player can, through doors but without going through
+    /// lava or deep water.
+    pub fn reachable(map: &Map, from: (i32, i32)) -> Vec<Vec<bool>> {
+        let (width, height) = (map.len() as i32, map.get(0).map_or(0, |column| column.len()) as i32);
+        let mut seen = vec![vec![false; height as usize]; width as usize];
+        let walkable = |x: i32, y: i32| x >= 0 && y >= 0 && x < width && y < height &&
+            (!map[x as usize][y as usize].blocked || map[x as usize][y as usize].is_door()) &&
+            !is_hazard(&map[x as usize][y as usize], Movement::walking());
+        if !walkable(from.0, from.1) {
+            return seen;
+        }
+        seen[from.0 as usize][from.1 as usize] = true;
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        while let Some((x, y)) = queue.pop_front() {
+            for dx in -1..2 {
+                for dy in -1..2 {
+                    let (nx, ny) = (x + dx, y + dy);
+                    if walkable(nx, ny) && !seen[nx as usize][ny as usize] {
+                        seen[nx as usize][ny as usize] = true;
+                        queue.push_back((nx, ny));
+                    }
+                }
+            }
+        }
+        seen
+    }
+
+    /// Make sure the level can be finished: the player starts on a floor
+    /// tile and can walk to every staircase.
+    pub fn check_connected(level: &GeneratedMap) -> Result<(), String> {
+        if level.stairs.is_empty() {
+            return Err("there are no stairs".into());
+        }
+        let reachable = reachable(&level.tiles, level.spawn);
+        let (x, y) = level.spawn;
+        if !reachable[x as usize][y as usize] {
+            return Err(format!("the player starts inside a wall or a pool at {:?}", level.spawn));
+        }
+        match level.stairs.iter().find(|&&(x, y)| !reachable[x as usize][y as usize]) {
+            Some(stairs) => Err(format!("the stairs at {:?} can't be reached from {:?}", stairs, level.spawn)),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::{seed_rng, MAPGEN_CHECK_SEEDS, PREFABS};
+
+        /// every level made with the seeds can be finished, at every depth
+        fn check_seeds<F: Fn(u32) -> GeneratedMap>(what: &str, generate: F) {
+            for seed in 0..MAPGEN_CHECK_SEEDS {
+                seed_rng(seed);
+                let level = seed as u32 % 10 + 1;
+                if let Err(e) = check_connected(&generate(level)) {
+                    panic!("the {} made with seed {} for level {}: {}", what, seed, level, e);
+                }
+            }
+        }
+
+        #[test]
+        fn rooms_are_connected() {
+            check_seeds("map", |level| generate(level, &PREFABS, None, 1));
+        }
+
+        #[test]
+        fn prefab_rooms_are_connected() {
+            for prefab in PREFABS.iter() {
+                check_seeds(&prefab.name, |level| generate(level, &PREFABS, Some(prefab), 1));
+            }
+        }
+
+        #[test]
+        fn caves_are_connected() {
+            check_seeds("caves", |level| generate_caves(level, 1));
+        }
+
+        #[test]
+        fn levels_get_the_stairs_they_need() {
+            for seed in 0..MAPGEN_CHECK_SEEDS {
+                seed_rng(seed);
+                let level = seed as u32 % 10 + 1;
+                let rooms = generate(level, &PREFABS, None, 2).stairs.len();
+                let caves = generate_caves(level, 2).stairs.len();
+                assert!(rooms >= 2 && caves >= 2, "seed {} for level {} made {} and {} stairs",
+                        seed, level, rooms, caves);
+            }
+        }
+
+        #[test]
+        fn arena_and_town_are_connected() {
+            check_seeds("boss arena", |_| generate_arena());
+            check_seeds("town", |_| generate_town());
+        }
+    }
+}
+
+/// Make a new level with its monsters, items and stairs. Levels the player
+/// couldn't finish are thrown away and made again.
+fn make_map(objects: &mut Vec<Object>, depth: Depth, rules: &rules::Rules, mutators: &[Mutator])
+            -> (Map, LevelFeeling) {
+    // Player is the first element, remove everything else (`change_level`
+    // already put the previous level's objects away in the level cache).
+    // NOTE: works only when the player is the first object!
+    assert_eq!(&objects[PLAYER] as *const _, &objects[0] as *const _);
+    objects.truncate(1);
+
+    let level = rules.difficulty(depth);
+    // the side branches entered from this level, each takes one of the stairs
+    let branches: Vec<Branch> = rules.branches.iter()
+        .filter(|branch| depth.branch == Branch::Main && branch.entry_level == depth.level)
+        .map(|branch| branch.branch)
+        .collect();
+    let bottom = rules.branch(depth.branch).map_or(false, |branch| depth.level >= branch.levels);
+    let boss = boss_of(depth, rules);
+    let town = depth == Depth::town();
+    // the Caves are all caves, elsewhere deeper levels are caves now and then
+    // a mini-boss's room is a prefab, and caves have none
+    let mini_boss = mini_boss_of(depth);
+    let guarded_room = mini_boss.and_then(|boss| PREFABS.iter().find(|prefab| prefab.name == boss.prefab));
+    let caves = !town && boss.is_none() && guarded_room.is_none() && (depth.branch == Branch::Caves ||
+        (level as i32 >= CONFIG.cave_min_level && game_rng().gen_range(0, 100) < CONFIG.cave_chance));
+
+    info!("generating {:?} level {}: {}x{} map, up to {} rooms of {} to {} tiles",
+          depth.branch, depth.level, CONFIG.map_width, CONFIG.map_height, CONFIG.max_rooms,
+          CONFIG.room_min_size, CONFIG.room_max_size);
+    let mut tries = 0;
+    let generated = loop {
+        let generated = if town {
+            mapgen::generate_town()
+        } else if boss.is_some() {
+            mapgen::generate_arena()
+        } else if caves {
+            mapgen::generate_caves(level, branches.len() + 1)
+        } else {
+            mapgen::generate(level, &PREFABS, guarded_room, branches.len() + 1)
+        };
+        let checked = mapgen::check_connected(&generated).and_then(|()| {
+            if generated.stairs.len() > branches.len() {
+                Ok(())
+            } else {
+                Err(format!("{} stairs for {} branches", generated.stairs.len(), branches.len()))
+            }
+        }).and_then(|()| match (mini_boss, guarded_room) {
+            (Some(boss), Some(_)) if !generated.placements.iter().any(|&(_, ref placement)| {
+                *placement == mapgen::Placement::Monster(boss.species.into())
+            }) => Err(format!("there was no room for the {}'s {}", boss.species, boss.prefab)),
+            _ => Ok(()),
+        });
+        match checked {
+            Ok(()) => break generated,
+            Err(e) => warn!("throwing away a broken level {}: {}", depth.level, e),
+        }
+        tries += 1;
+        if tries >= MAX_MAP_TRIES {
+            panic!("no good {:?} level {} after {} tries, the [map] settings in {} may not leave room for one",
+                   depth.branch, depth.level, tries, options().config_path);
+        }
+    };
+
+    // the player is put at the start first, so everything else keeps clear of it
+    objects[PLAYER].set_pos(generated.spawn.0, generated.spawn.1);
+    // the scenery comes first, so that everything else is drawn over it
+    for &((x, y), ref placement) in &generated.placements {
+        if let mapgen::Placement::Decoration(decoration) = *placement {
+            if !near_start(x, y, objects) {
+                objects.push(create_decoration(decoration, x, y));
+            }
+        }
+    }
+    // nothing lurks in town
+    let mut feeling = LevelFeeling::default();
+    for &room in generated.rooms.iter().filter(|_| !town) {
+        // add some content to this room, such as monsters
+        place_objects(room, &generated.tiles, objects, depth, rules, mutators, &mut feeling);
+    }
+    for &((x, y), ref placement) in &generated.placements {
+        let object = match *placement {
+            mapgen::Placement::Monster(ref species) if can_spawn(species, objects) => {
+                create_monster(species, x, y)
+            }
+            mapgen::Placement::Monster(_) => None,
+            mapgen::Placement::Item(ref name) => item_kind(name).map(|kind| create_item(kind, x, y)),
+            mapgen::Placement::Npc(service) => Some(create_npc(service, x, y)),
+            mapgen::Placement::Decoration(_) => None,
+        };
+        if let Some(object) = object {
+            if !is_blocked(x, y, &generated.tiles, objects) && !near_start(x, y, objects) {
+                objects.push(object);
+            }
+        }
+    }
+    for (i, &(x, y)) in generated.stairs.iter().enumerate() {
+        let mut stairs = match branches.get(i) {
+            Some(branch) => {
+                let color = Theme::of(Depth::new(*branch, 1)).tile_color(true, true);
+                Object::new(x, y, '>', &branch.stairs_name(), color, false)
+            }
+            // the bottom of a branch is as far as it goes
+            None if bottom => continue,
+            None => Object::new(x, y, '>', "stairs", colors::WHITE, false),
+        };
+        stairs.always_visible = true;
+        objects.push(stairs);
+    }
+    // the way back up, where the player arrives
+    if !town {
+        let (x, y) = generated.spawn;
+        let mut up_stairs = Object::new(x, y, '<', "up stairs", colors::WHITE, false);
+        up_stairs.always_visible = true;
+        objects.push(up_stairs);
+    }
+    info!("{:?} level {} has {} rooms and {} objects, the player starts at {:?}",
+          depth.branch, depth.level, generated.rooms.len(), objects.len(), objects[PLAYER].pos());
+
+    let mut map = generated.tiles;
+    if town {
+        // the player grew up here
+        for tile in map.iter_mut().flat_map(|column| column.iter_mut()) {
+            tile.explored = true;
+        }
+    }
+    (map, feeling)
+}
+
+/// A part of the dungeon with levels of its own. The side branches are
+/// reached by stairs on some of the main dungeon's levels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum Branch {
+    Main,
+    Crypt,
+    Caves,
+    Sewers,
+}
+
+impl Branch {
+    pub fn all() -> Vec<Branch> {
+        vec![Branch::Main, Branch::Crypt, Branch::Caves, Branch::Sewers]
+    }
+
+    pub fn title(&self) -> &'static str {
+        match *self {
+            Branch::Main => "Dungeon",
+            Branch::Crypt => "Crypt",
+            Branch::Caves => "Caves",
+            Branch::Sewers => "Sewers",
+        }
+    }
+
+    /// the branch's key in the spawn tables
+    pub fn id(&self) -> String {
+        self.title().to_lowercase()
+    }
+
+    /// the name of the staircase leading into the branch
+    pub fn stairs_name(&self) -> String {
+        format!("stairs to the {}", self.title().to_lowercase())
+    }
+}
+
+/// How a stretch of the dungeon looks: the colors of its walls and floors,
+/// in view and remembered, and the glyphs drawn over them (' ' for none).
+#[derive(Clone, Copy, Debug)]
+struct Theme {
+    name: &'static str,
+    branch: Branch,
+    /// the first level of the branch it's used on, until the next theme's
+    from_level: u32,
+    dark_wall: (u8, u8, u8),
+    light_wall: (u8, u8, u8),
+    dark_ground: (u8, u8, u8),
+    light_ground: (u8, u8, u8),
+    wall_char: char,
+    ground_char: char,
+}
+
+/// every branch's themes, by level. The first one's colors are the ones
+/// config.toml can change.
+const THEMES: &[Theme] = &[
+    Theme {
+        name: "dungeon", branch: Branch::Main, from_level: 0,
+        dark_wall: (0, 0, 100), light_wall: (130, 110, 50),
+        dark_ground: (50, 50, 150), light_ground: (200, 180, 50),
+        wall_char: ' ', ground_char: ' ',
+    },
+    Theme {
+        name: "catacombs", branch: Branch::Main, from_level: 4,
+        dark_wall: (45, 40, 50), light_wall: (150, 140, 120),
+        dark_ground: (35, 30, 45), light_ground: (120, 110, 95),
+        wall_char: '#', ground_char: ' ',
+    },
+    Theme {
+        name: "caverns", branch: Branch::Main, from_level: 7,
+        dark_wall: (55, 35, 25), light_wall: (140, 95, 55),
+        dark_ground: (45, 35, 30), light_ground: (160, 130, 85),
+        wall_char: ' ', ground_char: ',',
+    },
+    Theme {
+        name: "hellish depths", branch: Branch::Main, from_level: 10,
+        dark_wall: (60, 10, 10), light_wall: (150, 40, 20),
+        dark_ground: (40, 20, 20), light_ground: (120, 60, 40),
+        wall_char: '%', ground_char: ' ',
+    },
+    Theme {
+        name: "crypt", branch: Branch::Crypt, from_level: 1,
+        dark_wall: (40, 40, 55), light_wall: (140, 140, 150),
+        dark_ground: (30, 30, 45), light_ground: (110, 100, 90),
+        wall_char: ' ', ground_char: ' ',
+    },
+    Theme {
+        name: "caves", branch: Branch::Caves, from_level: 1,
+        dark_wall: (60, 40, 20), light_wall: (150, 100, 60),
+        dark_ground: (50, 35, 20), light_ground: (170, 140, 90),
+        wall_char: ' ', ground_char: ' ',
+    },
+    Theme {
+        name: "sewers", branch: Branch::Sewers, from_level: 1,
+        dark_wall: (20, 50, 30), light_wall: (80, 120, 70),
+        dark_ground: (20, 40, 30), light_ground: (100, 140, 90),
+        wall_char: ' ', ground_char: ' ',
+    },
+];
+
+impl Theme {
+    /// the theme of a level: the last of its branch's that starts above it
+    pub fn of(depth: Depth) -> Theme {
+        let index = THEMES.iter()
+            .rposition(|theme| theme.branch == depth.branch && theme.from_level <= depth.level)
+            .expect("every branch has a theme from its top");
+        let mut theme = THEMES[index];
+        if index == 0 {
+            let rgb = |color: Color| (color.r, color.g, color.b);
+            theme.dark_wall = rgb(CONFIG.color_dark_wall);
+            theme.light_wall = rgb(CONFIG.color_light_wall);
+            theme.dark_ground = rgb(CONFIG.color_dark_ground);
+            theme.light_ground = rgb(CONFIG.color_light_ground);
+        }
+        theme
+    }
+
+    /// the color of a wall or floor tile
+    pub fn tile_color(&self, wall: bool, lit: bool) -> Color {
+        let (r, g, b) = match (lit, wall) {
+            (false, true) => self.dark_wall,
+            (false, false) => self.dark_ground,
+            (true, true) => self.light_wall,
+            (true, false) => self.light_ground,
+        };
+        Color { r: r, g: g, b: b }
+    }
+
+    /// the background of a tile, the glyph over it and the glyph's color;
+    /// ground of its own kind (water, roads...) has no glyph, doors show
+    /// whether they're open
+    pub fn look(&self, tile: &Tile, lit: bool) -> (Color, char, Color) {
+        if tile.is_smoky() {
+            let (r, g, b) = if lit { (150, 150, 150) } else { (70, 70, 75) };
+            let background = Color { r: r, g: g, b: b };
+            return (background, ' ', background);
+        }
+        let (background, char) = match tile.kind.color(lit) {
+            Some(color) => (color, ' '),
+            None if tile.is_door() => {
+                let (r, g, b) = if lit { (170, 120, 60) } else { (85, 60, 30) };
+                let char = if tile.blocked { '+' } else { '\'' };
+                return (self.tile_color(false, lit), char, Color { r: r, g: g, b: b });
+            }
+            None if tile.block_sight => (self.tile_color(true, lit), self.wall_char),
+            None => (self.tile_color(false, lit), self.ground_char),
+        };
+        (background, char, colors::lerp(background, colors::BLACK, 0.4))
+    }
+}
+
+/// Where a level is: its branch and how far down the branch it is,
+/// counting from 1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Depth {
+    branch: Branch,
+    level: u32,
+}
+
+impl Depth {
+    pub fn new(branch: Branch, level: u32) -> Self {
+        Depth { branch: branch, level: level }
+    }
+
+    /// the town on the surface, above the main dungeon's first level
+    pub fn town() -> Self {
+        Depth::new(Branch::Main, 0)
+    }
+
+    /// the level's name, for the player
+    pub fn title(&self) -> String {
+        if *self == Depth::town() {
+            "Town".into()
+        } else {
+            format!("{} level: {}", self.branch.title(), self.level)
+        }
+    }
+
+    /// roughly how many levels apart two levels are; levels in different
+    /// branches are reached through the top of their branches
+    pub fn distance(&self, other: Depth) -> u32 {
+        if self.branch == other.branch {
+            cmp::max(self.level, other.level) - cmp::min(self.level, other.level)
+        } else {
+            self.level + other.level
+        }
+    }
+}
+
+struct Transition {
+    level: u32,
+    value: u32,
+}
+
+/// Returns a value that depends on level. the table specifies what
+/// value occurs after each level, default is 0.
+fn from_dungeon_level(table: &[Transition], level: u32) -> u32 {
+    table.iter()
+        .rev()
+        .find(|transition| level >= transition.level)
+        .map_or(0, |transition| transition.value)
+}
+
+/// The game's balance: how strong the spells are, how much experience a
+/// level takes and what spawns where. A rule set is never changed once it
+/// is released, new balance goes into a new one, and every game keeps the
+/// rule set it was started with so old saves play like they used to.
+mod rules {
+    use super::{Branch, Depth, Item, TrapKind, Transition, CONFIG, SPAWN_TABLES_PATH};
+    use std::fs::File;
+    use std::io::Read;
+    use toml::{self, value::Table};
+
+    #[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+    pub enum RuleSet {
+        Classic,
+        Rebalanced,
+    }
+
+    impl RuleSet {
+        pub fn all() -> Vec<RuleSet> {
+            vec![RuleSet::Classic, RuleSet::Rebalanced]
+        }
+
+        /// what new games use unless told otherwise
+        pub fn latest() -> RuleSet {
+            RuleSet::Rebalanced
+        }
+
+        /// the rule set's [section] in the spawn tables
+        pub fn id(&self) -> &'static str {
+            match *self {
+                RuleSet::Classic => "classic",
+                RuleSet::Rebalanced => "rebalanced",
+            }
+        }
+
+        pub fn name(&self) -> &'static str {
+            match *self {
+                RuleSet::Classic => "v1 classic",
+                RuleSet::Rebalanced => "v2 rebalanced",
+            }
+        }
+
+        pub fn describe(&self) -> &'static str {
+            match *self {
+                RuleSet::Classic => "the balance the game shipped with",
+                RuleSet::Rebalanced => "weaker spells, slower levels, monsters in growing numbers",
+            }
+        }
+    }
+
+    pub struct Rules {
+        pub heal_amount: i32,
+        pub lightning_damage: i32,
+        pub lightning_range: i32,
+        pub confuse_range: i32,
+        pub confuse_num_turns: i32,
+        pub fireball_radius: i32,
+        pub fireball_damage: i32,
+
+        // experience needed for the next level: base + level * factor
+        pub level_up_base: i32,
+        pub level_up_factor: i32,
+        // what raising each stat gives
+        pub level_up_hp: i32,
+        pub level_up_power: i32,
+        pub level_up_defense: i32,
+        // how likely (in percent) a blow is a critical hit, for double damage
+        pub critical_percent: i32,
+
+        // maximum number of monsters per room
+        pub max_monsters: Vec<Transition>,
+        // monster random table
+        pub monster_chances: Vec<(String, Vec<Transition>)>,
+        // maximum number of items per room
+        pub max_items: Vec<Transition>,
+        // item random table; the first item should be able to show up on
+        // every level, even if all other items have 0 chance
+        pub item_chances: Vec<(Item, Vec<Transition>)>,
+        // packs that come instead of a single monster
+        pub packs: Vec<Pack>,
+        // maximum number of traps per room
+        pub max_traps: Vec<Transition>,
+        // trap random table
+        pub trap_chances: Vec<(TrapKind, Vec<Transition>)>,
+        // the side branches of the dungeon
+        pub branches: Vec<BranchRules>,
+        // whether every BOSS_LEVEL_INTERVAL'th level is a boss's arena
+        pub boss_floors: bool,
+    }
+
+    impl Rules {
+        pub fn branch(&self, branch: Branch) -> Option<&BranchRules> {
+            self.branches.iter().find(|rules| rules.branch == branch)
+        }
+
+        /// the level the spawn tables are read at: a branch's levels are
+        /// as hard as the level of its entrance plus how far down they are
+        pub fn difficulty(&self, depth: Depth) -> u32 {
+            match self.branch(depth.branch) {
+                Some(branch) => branch.entry_level + depth.level,
+                None => depth.level,
+            }
+        }
+
+        /// the monster random table of a branch
+        pub fn monster_chances(&self, branch: Branch) -> &[(String, Vec<Transition>)] {
+            match self.branch(branch) {
+                Some(branch) => &branch.monster_chances,
+                None => &self.monster_chances,
+            }
+        }
+    }
+
+    /// A side branch, entered by a staircase on a level of the main dungeon
+    /// and with monsters of its own. Its tables are read at the branch's
+    /// difficulty, like the main dungeon's.
+    pub struct BranchRules {
+        pub branch: Branch,
+        // the main dungeon level with the stairs into the branch
+        pub entry_level: u32,
+        // how many levels it has, the last one has no stairs down
+        pub levels: u32,
+        pub monster_chances: Vec<(String, Vec<Transition>)>,
+    }
+
+    /// A leader with some followers around it. `chance` is the percentage of
+    /// monsters that come as this pack instead.
+    pub struct Pack {
+        pub leader: String,
+        pub follower: String,
+        pub max_followers: u32,
+        pub chance: Vec<Transition>,
+    }
+
+    fn classic() -> Rules {
+        Rules {
+            heal_amount: 40,
+            lightning_damage: 40,
+            lightning_range: 5,
+            confuse_range: 8,
+            confuse_num_turns: 10,
+            fireball_radius: 3,
+            fireball_damage: 25,
+
+            level_up_base: 200,
+            level_up_factor: 150,
+            level_up_hp: 20,
+            level_up_power: 1,
+            level_up_defense: 1,
+            critical_percent: 0,
+
+            // the spawn tables are read from SPAWN_TABLES_PATH
+            max_monsters: vec![],
+            monster_chances: vec![],
+            max_items: vec![],
+            item_chances: vec![],
+            packs: vec![],
+            max_traps: vec![],
+            trap_chances: vec![],
+            branches: vec![],
+            boss_floors: false,
+        }
+    }
+
+    fn rebalanced() -> Rules {
+        Rules {
+            heal_amount: 35,
+            lightning_damage: 35,
+            lightning_range: 6,
+            confuse_range: 8,
+            confuse_num_turns: 8,
+            fireball_radius: 3,
+            fireball_damage: 30,
+
+            level_up_base: 250,
+            level_up_factor: 175,
+            level_up_hp: 15,
+            level_up_power: 1,
+            level_up_defense: 1,
+            critical_percent: 5,
+
+            // the spawn tables are read from SPAWN_TABLES_PATH
+            max_monsters: vec![],
+            monster_chances: vec![],
+            max_items: vec![],
+            item_chances: vec![],
+            packs: vec![],
+            max_traps: vec![],
+            trap_chances: vec![],
+            branches: vec![
+                BranchRules {
+                    branch: Branch::Crypt,
+                    entry_level: 3,
+                    levels: 3,
+                    monster_chances: vec![],
+                },
+                BranchRules {
+                    branch: Branch::Caves,
+                    entry_level: 4,
+                    levels: 4,
+                    monster_chances: vec![],
+                },
+                BranchRules {
+                    branch: Branch::Sewers,
+                    entry_level: 6,
+                    levels: 3,
+                    monster_chances: vec![],
+                },
+            ],
+            boss_floors: true,
+        }
+    }
+
+    lazy_static! {
+        /// the rule sets in the order of `RuleSet`, with their spawn tables
+        /// and the [spells] of config.toml applied
+        static ref RULES: Result<Vec<Rules>, String> = load(SPAWN_TABLES_PATH);
+    }
+
+    fn load(path: &str) -> Result<Vec<Rules>, String> {
+        let mut text = String::new();
+        try! {
+            File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+                .map_err(|e| format!("Can't read the spawn tables in {}: {}", path, e))
+        };
+        let doc = try! { text.parse::<toml::Value>().map_err(|e| format!("{} is not valid TOML: {}", path, e)) };
+        let mut sets = vec![];
+        for set in RuleSet::all() {
+            let mut rules = match set {
+                RuleSet::Classic => classic(),
+                RuleSet::Rebalanced => rebalanced(),
+            };
+            let tables = try! {
+                doc.as_table().and_then(|doc| doc.get(set.id())).and_then(|tables| tables.as_table())
+                    .ok_or(format!("{} has no [{}] tables", path, set.id()))
+            };
+            try! { read_spawn_tables(tables, set.id(), &mut rules).map_err(|e| format!("{}: {}", path, e)) };
+            fn override_with(setting: Option<i32>, value: &mut i32) {
+                if let Some(setting) = setting {
+                    *value = setting;
+                }
+            }
+            override_with(CONFIG.heal_amount, &mut rules.heal_amount);
+            override_with(CONFIG.lightning_damage, &mut rules.lightning_damage);
+            override_with(CONFIG.lightning_range, &mut rules.lightning_range);
+            override_with(CONFIG.confuse_range, &mut rules.confuse_range);
+            override_with(CONFIG.confuse_num_turns, &mut rules.confuse_num_turns);
+            override_with(CONFIG.fireball_radius, &mut rules.fireball_radius);
+            override_with(CONFIG.fireball_damage, &mut rules.fireball_damage);
+            sets.push(rules);
+        }
+        Ok(sets)
+    }
+
+    /// Fill in the spawn tables of `rules` from its [section] of the spawn
+    /// tables file.
+    fn read_spawn_tables(tables: &Table, section: &str, rules: &mut Rules) -> Result<(), String> {
+        /// a list of [level, value] steps
+        fn table(value: Option<&toml::Value>, what: &str) -> Result<Vec<Transition>, String> {
+            let steps = match value {
+                Some(steps) => try! { steps.as_array().ok_or(format!("{} is not a list of steps", what)) },
+                None => return Ok(vec![]),
+            };
+            let mut table = vec![];
+            for step in steps {
+                let numbers: Vec<u32> = step.as_array().into_iter().flat_map(|step| step.iter())
+                    .filter_map(|number| number.as_integer())
+                    .filter(|&number| number >= 0)
+                    .map(|number| number as u32)
+                    .collect();
+                match numbers.len() {
+                    2 => table.push(Transition {level: numbers[0], value: numbers[1]}),
+                    _ => return Err(format!("{} has a step that isn't [level, value]", what)),
+                }
+            }
+            Ok(table)
+        }
+        /// a list of { tables }
+        fn entries<'a>(value: Option<&'a toml::Value>, what: &str) -> Result<Vec<&'a Table>, String> {
+            let entries = match value {
+                Some(entries) => try! { entries.as_array().ok_or(format!("{} is not a list", what)) },
+                None => return Ok(vec![]),
+            };
+            entries.iter()
+                .map(|entry| entry.as_table().ok_or(format!("{} has an entry that isn't a {{ table }}", what)))
+                .collect()
+        }
+        /// a list of weights, `key` says what each one is the weight of
+        fn chances<T, F>(value: Option<&toml::Value>, what: &str, key: &str, kind: F)
+                         -> Result<Vec<(T, Vec<Transition>)>, String>
+            where F: Fn(&str) -> Option<T>
+        {
+            let mut chances = vec![];
+            for entry in try! { entries(value, what) } {
+                let id = try! {
+                    entry.get(key).and_then(|id| id.as_str())
+                        .ok_or(format!("{} has an entry without a {}", what, key))
+                };
+                let thing = try! { kind(id).ok_or(format!("{}: there is no {} called '{}'", what, key, id)) };
+                let table = try! { table(entry.get("chances"), &format!("{} of {}", what, id)) };
+                chances.push((thing, table));
+            }
+            Ok(chances)
+        }
+        let species = |species: &str| Some(species.to_string());
+        let item = |id: &str| Item::all().into_iter().find(|item| format!("{:?}", item) == id);
+        let trap = |id: &str| TrapKind::all().into_iter().find(|kind| format!("{:?}", kind) == id);
+        let what = |key: &str| format!("{}.{}", section, key);
+
+        rules.max_monsters = try! { table(tables.get("max_monsters"), &what("max_monsters")) };
+        rules.monster_chances = try! { chances(tables.get("monsters"), &what("monsters"), "species", species) };
+        rules.max_items = try! { table(tables.get("max_items"), &what("max_items")) };
+        rules.item_chances = try! { chances(tables.get("items"), &what("items"), "item", item) };
+        rules.max_traps = try! { table(tables.get("max_traps"), &what("max_traps")) };
+        rules.trap_chances = try! { chances(tables.get("traps"), &what("traps"), "trap", trap) };
+
+        let mut packs = vec![];
+        for entry in try! { entries(tables.get("packs"), &what("packs")) } {
+            let field = |key: &str| entry.get(key).and_then(|value| value.as_str()).map(String::from)
+                .ok_or(format!("{} has a pack without a {}", what("packs"), key));
+            let leader = try! { field("leader") };
+            packs.push(Pack {
+                follower: try! { field("follower") },
+                max_followers: entry.get("max_followers").and_then(|value| value.as_integer()).unwrap_or(0) as u32,
+                chance: try! { table(entry.get("chances"), &format!("{} of {}", what("packs"), leader)) },
+                leader: leader,
+            });
+        }
+        rules.packs = packs;
+
+        // every side branch of the rule set has its own monsters
+        let branches = match tables.get("branches") {
+            Some(branches) => try! { branches.as_table().ok_or(format!("{} is not a table", what("branches"))) },
+            None => return Ok(()),
+        };
+        if let Some(key) = branches.keys().find(|key| !rules.branches.iter().any(|b| b.branch.id() == **key)) {
+            return Err(format!("{}: there is no branch called '{}'", what("branches"), key));
+        }
+        let mut branch_rules = vec![];
+        for branch in &rules.branches {
+            let key = what(&format!("branches.{}", branch.branch.id()));
+            branch_rules.push(BranchRules {
+                monster_chances: try! { chances(branches.get(&branch.branch.id()), &key, "species", species) },
+                ..*branch
+            });
+        }
+        rules.branches = branch_rules;
+        Ok(())
+    }
+
+    /// why the rule sets can't be put together, if they can't
+    pub fn problem() -> Option<&'static str> {
+        RULES.as_ref().err().map(|e| e.as_str())
+    }
+
+    pub fn get(set: RuleSet) -> &'static Rules {
+        &RULES.as_ref().expect("the rule sets are checked at startup")[set as usize]
+    }
+}
+
+/// What the player senses of a level on arriving, added up while it's filled.
+#[derive(Default)]
+struct LevelFeeling {
+    monsters: usize,
+    expected_monsters: f32,
+    valuables: u32,
+    out_of_depth: bool,
+}
+
+impl LevelFeeling {
+    fn messages(&self) -> Vec<(&'static str, Color)> {
+        let mut messages = vec![];
+        if self.out_of_depth {
+            messages.push(("You feel a sense of dread...", colors::RED));
+        } else if self.expected_monsters > 0.0 {
+            let crowd = self.monsters as f32 / self.expected_monsters;
+            if crowd > 1.5 {
+                messages.push(("You hear a lot of shuffling about. This level feels crowded.", colors::ORANGE));
+            } else if crowd < 0.5 {
+                messages.push(("It's eerily quiet here.", colors::LIGHT_GREY));
+            }
+        }
+        match self.valuables {
+            0 => {}
+            1 => messages.push(("You have a feeling there's something worth finding here.", colors::GOLD)),
+            _ => messages.push(("You sense this level holds great treasure!", colors::GOLD)),
+        }
+        messages
+    }
+}
+
+/// Whether (x, y) is where the player starts a new level or right by it.
+/// Nothing is put there, so the player doesn't arrive on top of something
+/// or with a monster at their throat.
+fn near_start(x: i32, y: i32, objects: &[Object]) -> bool {
+    let (start_x, start_y) = objects[PLAYER].pos();
+    (x - start_x).abs() <= START_CLEARANCE && (y - start_y).abs() <= START_CLEARANCE
+}
+
+fn place_objects(room: Rect, map: &Map, objects: &mut Vec<Object>, depth: Depth, rules: &rules::Rules,
+                 mutators: &[Mutator], feeling: &mut LevelFeeling) {
+    use rand::distributions::{Weighted, WeightedChoice, IndependentSample};
+
+    let level = rules.difficulty(depth);
+    let mut max_monsters = from_dungeon_level(&rules.max_monsters, level);
+    if mutators.contains(&Mutator::DoubleMonsters) {
+        max_monsters *= 2;
+    }
+
+    // choose random number of monsters, a boss's arena has only the boss
+    let num_monsters = match boss_of(depth, rules) {
+        Some(boss) => {
+            let (x, y) = room.center();
+            objects.extend(create_monster(boss, x, y));
+            0
+        }
+        None => game_rng().gen_range(0, max_monsters + 1),
+    };
+
+    let mut monster_chances: Vec<_> = rules.monster_chances(depth.branch).iter()
+        .map(|&(ref species, ref table)| Weighted {weight: from_dungeon_level(table, level), item: &species[..]})
+        .collect();
+    let monster_choice = WeightedChoice::new(&mut monster_chances);
+    // what lives a few levels further down
+    let mut deeper_chances: Vec<_> = rules.monster_chances(depth.branch).iter()
+        .map(|&(ref species, ref table)| {
+            Weighted {weight: from_dungeon_level(table, level + OUT_OF_DEPTH_LEVELS), item: &species[..]}
+        })
+        .collect();
+    let deeper_choice = WeightedChoice::new(&mut deeper_chances);
+    let is_native = |species: &str| rules.monster_chances(depth.branch).iter()
+        .any(|&(ref native, ref table)| native == species && from_dungeon_level(table, level) > 0);
+
+    let max_items = from_dungeon_level(&rules.max_items, level);
+
+    let mut item_chances: Vec<_> = rules.item_chances.iter()
+        .map(|&(item, ref table)| Weighted {weight: from_dungeon_level(table, level), item: item})
+        .collect();
+    let item_choice = WeightedChoice::new(&mut item_chances);
+
+    let monsters_before = objects.len();
+    for _ in 0..num_monsters {
+        // choose random spot for this monster
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+
+        // only place it if the tile is not blocked
+        if !is_blocked(x, y, map, objects) && !near_start(x, y, objects) {
+            // now and then a whole pack instead, they only roam the main dungeon
+            let pack = rules.packs.iter()
+                .filter(|_| depth.branch == Branch::Main)
+                .find(|pack| game_rng().gen_range(0, 100) < from_dungeon_level(&pack.chance, level));
+            if let Some(pack) = pack {
+                if !is_hazard(&map[x as usize][y as usize], Movement::walking()) {
+                    place_pack(pack, (x, y), room, map, objects);
+                }
+                continue;
+            }
+            let out_of_depth = game_rng().gen::<f32>() < OUT_OF_DEPTH_CHANCE;
+            let species = if out_of_depth {
+                deeper_choice.ind_sample(&mut game_rng())
+            } else {
+                monster_choice.ind_sample(&mut game_rng())
+            };
+            if !can_spawn(species, objects) {
+                continue;
+            }
+            if let Some(mut monster) = create_monster(species, x, y) {
+                // somewhere it can live, an eel wants water and nobody lava,
+                // with room in the room for all of a large monster
+                let size = monster.size;
+                let suits = |x: i32, y: i32| {
+                    x + size <= room.x2 && y + size <= room.y2 &&
+                        (x..x + size).all(|x| (y..y + size).all(|y| {
+                            let tile = &map[x as usize][y as usize];
+                            can_enter(tile, monster.movement) && !is_hazard(tile, monster.movement) &&
+                                !is_blocked(x, y, map, objects) && !near_start(x, y, objects)
+                        }))
+                };
+                let spot = if suits(x, y) {
+                    Some((x, y))
+                } else {
+                    let mut spots: Vec<(i32, i32)> = (room.x1 + 1..room.x2)
+                        .flat_map(|x| (room.y1 + 1..room.y2).map(move |y| (x, y)))
+                        .filter(|&(x, y)| suits(x, y) && !is_blocked(x, y, map, objects))
+                        .filter(|&(x, y)| !near_start(x, y, objects))
+                        .collect();
+                    game_rng().shuffle(&mut spots);
+                    spots.pop()
+                };
+                if let Some((x, y)) = spot {
+                    if out_of_depth && !is_native(species) {
+                        info!("an out of depth {} at {:?} on {:?} level {}",
+                              species, (x, y), depth.branch, depth.level);
+                        feeling.out_of_depth = true;
+                    }
+                    monster.set_pos(x, y);
+                    if game_rng().gen::<f32>() < SLEEP_CHANCE {
+                        monster.fall_asleep();
+                    }
+                    objects.push(monster);
+                }
+            }
+        }
+    }
+    if boss_of(depth, rules).is_none() {
+        feeling.monsters += objects.len() - monsters_before;
+        feeling.expected_monsters += max_monsters as f32 / 2.0;
+    }
+
+    // choose random number of items
+    let num_items = game_rng().gen_range(0, max_items + 1);
+
+    for _ in 0..num_items {
+        // choose random spot for this item
+        let x = game_rng().gen_range(room.x1 + 1, room.x2);
+        let y = game_rng().gen_range(room.y1 + 1, room.y2);
+
+        // only place it if the tile is not blocked, nor lava
+        if !is_blocked(x, y, map, objects) && map[x as usize][y as usize].kind != TileKind::Lava &&
+            !near_start(x, y, objects) {
+            let mut item = create_item(item_choice.ind_sample(&mut game_rng()), x, y);
+            // a lucky player finds the better of two, an unlucky one the worse
+            let luck = objects[PLAYER].luck();
+            if game_rng().gen_range(0, 100) < luck.abs() * LUCK_LOOT_PERCENT {
+                let other = create_item(item_choice.ind_sample(&mut game_rng()), x, y);
+                if (other.price() > item.price()) == (luck > 0) {
+                    item = other;
+                }
+            }
+            if item.price() >= VALUABLE_PRICE {
+                feeling.valuables += 1;
+            }
+            objects.push(item);
+        }
+    }
+
+    // and traps, on tiles with nothing else on them
+    let max_traps = from_dungeon_level(&rules.max_traps, level);
+    if max_traps > 0 {
+        let mut trap_chances: Vec<_> = rules.trap_chances.iter()
+            .map(|&(kind, ref table)| Weighted {weight: from_dungeon_level(table, level), item: kind})
+            .collect();
+        let trap_choice = WeightedChoice::new(&mut trap_chances);
+
+        for _ in 0..game_rng().gen_range(0, max_traps + 1) {
+            let x = game_rng().gen_range(room.x1 + 1, room.x2);
+            let y = game_rng().gen_range(room.y1 + 1, room.y2);
+            let tile = &map[x as usize][y as usize];
+            if !tile.blocked && tile.kind != TileKind::Lava && !tile.kind.is_water() &&
+                !objects.iter().any(|o| o.pos() == (x, y)) && !near_start(x, y, objects) {
+                objects.push(create_trap(trap_choice.ind_sample(&mut game_rng()), x, y));
+            }
+        }
+    }
+}
+
+/// An id no pack on the level has yet.
+fn new_pack_id(objects: &[Object]) -> u32 {
+    objects.iter()
+        .filter_map(|o| match o.pack {
+            Some(PackRole::Leader(id)) => Some(id + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Put a pack's leader at (x, y) and its followers close by in the room.
+fn place_pack(pack: &rules::Pack, (x, y): (i32, i32), room: Rect, map: &Map, objects: &mut Vec<Object>) {
+    let id = new_pack_id(objects);
+    let mut leader = match create_monster(&pack.leader, x, y) {
+        Some(leader) if can_spawn(&pack.leader, objects) => leader,
+        _ => return,
+    };
+    leader.pack = Some(PackRole::Leader(id));
+    // the pack sleeps or keeps watch all together
+    let asleep = game_rng().gen::<f32>() < SLEEP_CHANCE;
+    if asleep {
+        leader.fall_asleep();
+    }
+    objects.push(leader);
+
+    let mut spots: Vec<(i32, i32)> = (room.x1 + 1..room.x2)
+        .flat_map(|x| (room.y1 + 1..room.y2).map(move |y| (x, y)))
+        .filter(|&(spot_x, spot_y)| (spot_x - x).abs() <= 2 && (spot_y - y).abs() <= 2)
+        .collect();
+    game_rng().shuffle(&mut spots);
+    let followers = game_rng().gen_range(1, pack.max_followers + 1) as usize;
+    let free_spots: Vec<_> = spots.into_iter()
+        .filter(|&(x, y)| !is_blocked(x, y, map, objects) && !near_start(x, y, objects))
+        .filter(|&(x, y)| !is_hazard(&map[x as usize][y as usize], Movement::walking()))
+        .collect();
+    for (x, y) in free_spots.into_iter().take(followers) {
+        if !can_spawn(&pack.follower, objects) {
+            break;
+        }
+        if let Some(mut follower) = create_monster(&pack.follower, x, y) {
+            follower.pack = Some(PackRole::Follower(id));
+            if asleep {
+                follower.fall_asleep();
+            }
+            objects.push(follower);
+        }
+    }
+}
+
+/// The faction monsters of a species belong to.
+fn faction(species: &str) -> Option<Faction> {
+    match species {
+        "orc" | "orc chieftain" | "orc warchief" | "troll" | "troll matriarch" | "troll queen" |
+        "goblin archer" | "goblin shaman" => {
+            Some(Faction::Greenskins)
+        }
+        "bat" | "swarm of flies" | "rock worm" | "eel" | "jackal" | "gnat nest" | "cloud of gnats" => {
+            Some(Faction::Vermin)
+        }
+        "zombie" | "bone king" | "necromancer" | "skeleton" => Some(Faction::Undead),
+        _ => None,
+    }
+}
+
+/// Whether the species knows how to work a door handle.
+fn opens_doors(species: &str) -> bool {
+    match species {
+        "orc" | "orc chieftain" | "orc warchief" | "troll" | "troll matriarch" | "troll queen" |
+        "bone king" | "giant" | "goblin archer" | "goblin shaman" | "dark mage" | "necromancer" => true,
+        _ => false,
+    }
+}
+
+/// The boss waiting on a level, if it's a boss's arena.
+fn boss_of(depth: Depth, rules: &rules::Rules) -> Option<&'static str> {
+    if rules.boss_floors && depth != Depth::town() && depth.branch == Branch::Main &&
+        depth.level % BOSS_LEVEL_INTERVAL == 0 {
+        let arena = (depth.level / BOSS_LEVEL_INTERVAL - 1) as usize;
+        Some(BOSSES[arena % BOSSES.len()])
+    } else {
+        None
+    }
+}
+
+/// A monster guarding a room on the way down, less of a fight than a boss.
+/// Its room is a prefab that never shows up anywhere else.
+struct MiniBoss {
+    species: &'static str,
+    prefab: &'static str,
+    /// what the player senses on arriving at its level
+    feeling: &'static str,
+}
+
+/// The mini-boss guarding a room on this level, if there is one.
+fn mini_boss_of(depth: Depth) -> Option<&'static MiniBoss> {
+    if depth.branch == Branch::Main && depth.level % BOSS_LEVEL_INTERVAL == MINI_BOSS_LEVEL {
+        Some(&MINI_BOSSES[(depth.level / BOSS_LEVEL_INTERVAL) as usize % MINI_BOSSES.len()])
+    } else {
+        None
+    }
+}
+
+/// Whether the monster keeps the stairs of its level sealed while it lives
+fn is_guardian(name: &str) -> bool {
+    BOSSES.contains(&name) || MINI_BOSSES.iter().any(|boss| boss.species == name)
+}
+
+/// Create one of the town's folk offering `service` at (x, y)
+fn create_npc(service: Service, x: i32, y: i32) -> Object {
+    let mut npc = match service {
+        Service::Shop => {
+            let mut shopkeeper = Object::new(x, y, '@', "shopkeeper", colors::LIGHT_AZURE, true);
+            let stock = [Item::Heal, Item::Heal, Item::Heal, Item::Lightning, Item::Lightning, Item::Confuse,
+                         Item::Confuse, Item::Fireball, Item::Sword, Item::Shield, Item::Pickaxe, Item::Digging,
+                         Item::Return, Item::Return, Item::SmokeBomb];
+            for &item in &stock {
+                shopkeeper.inventory.push(create_item(item, x, y));
+            }
+            shopkeeper
+        }
+        Service::Healer => Object::new(x, y, '@', "healer", colors::LIGHT_VIOLET, true),
+        Service::Stash => Object::new(x, y, '&', "stash chest", colors::DARKER_ORANGE, true),
+        Service::Trainer => Object::new(x, y, '@', "trainer", colors::LIGHT_CRIMSON, true),
+        Service::Hunter => Object::new(x, y, '@', "hunter", colors::DARK_AMBER, true),
+    };
+    npc.service = Some(service);
+    npc.disposition = Disposition::Friendly;
+    npc
+}
+
+/// A piece of scenery at (x, y). Like the stairs it stays on the map once
+/// seen.
+fn create_decoration(decoration: mapgen::Decoration, x: i32, y: i32) -> Object {
+    use mapgen::Decoration::*;
+    let mut object = match decoration {
+        Rubble => Object::new(x, y, ',', "rubble", colors::GREY, false),
+        Bloodstain => Object::new(x, y, '.', "bloodstain", colors::DARKER_RED, false),
+        Grass => Object::new(x, y, '"', "grass", colors::DARK_GREEN, false),
+        Pillar => Object::new(x, y, 'I', "pillar", colors::LIGHT_GREY, false),
+        Brazier => Object::new(x, y, '0', "brazier", colors::FLAME, false),
+        Altar => Object::new(x, y, '_', ALTAR_NAME, colors::LIGHTEST_AMBER, false),
+    };
+    object.always_visible = true;
+    object
+}
+
+/// Create a monster of the given species at (x, y), if there is such a species
+fn create_monster(species: &str, x: i32, y: i32) -> Option<Object> {
+    let mut monster = match species {
+        "orc" => {
+            // create an orc
+            let mut orc = Object::new(x, y, 'o', "orc", colors::DESATURATED_GREEN, true);
+            orc.fighter = Some(Fighter{base_max_hp: 20, hp: 20, base_defense: 0, base_power: 4, xp: 35,
+                                       speed: NORMAL_SPEED, energy: 0, morale: 50,
+                                       on_death: DeathCallback::Monster});
+            orc.ai = Some(Ai::Basic);
+            if game_rng().gen::<f32>() < 0.3 {
+                // some orcs wield a rusty sword
+                let mut sword = create_item(Item::Sword, x, y);
+                sword.name = "rusty sword".into();
+                if let Some(ref mut equipment) = sword.equipment {
+                    equipment.power_bonus = 1;
+                    equipment.equipped = true;
+                }
+                orc.inventory.push(sword);
+            }
+            if game_rng().gen::<f32>() < 0.15 {
+                orc.inventory.push(create_item(Item::Heal, x, y));
+            }
+            orc
+        }
+        "orc chieftain" => {
+            // leads packs of orcs, never placed on its own
+            let mut chieftain = Object::new(x, y, 'O', "orc chieftain", colors::DARK_GREEN, true);
+            chieftain.fighter = Some(Fighter{base_max_hp: 30, hp: 30, base_defense: 1, base_power: 6, xp: 80,
+                                             speed: NORMAL_SPEED, energy: 0, morale: 80,
+                                             on_death: DeathCallback::Monster});
+            chieftain.ai = Some(Ai::Basic);
+            if game_rng().gen::<f32>() < 0.5 {
+                chieftain.inventory.push(create_item(Item::Heal, x, y));
+            }
+            chieftain
+        }
+        "troll" => {
+            // create a troll
+            let mut troll = Object::new(x, y, 'T', "troll", colors::DARKER_GREEN, true);
+            troll.fighter = Some(Fighter{base_max_hp: 30, hp: 30, base_defense: 2, base_power: 8, xp: 100,
+                                         speed: NORMAL_SPEED, energy: 0, morale: 70,
+                                         on_death: DeathCallback::Monster});
+            troll.ai = Some(Ai::Basic);
+            if game_rng().gen::<f32>() < 0.25 {
+                troll.inventory.push(create_item(Item::Heal, x, y));
+            }
+            troll
+        }
+        "bat" => {
+            // create a bat: weak, but it moves twice as fast as you
+            let mut bat = Object::new(x, y, 'b', "bat", colors::LIGHT_SEPIA, true);
+            bat.fighter = Some(Fighter{base_max_hp: 6, hp: 6, base_defense: 0, base_power: 3, xp: 20,
+                                       speed: NORMAL_SPEED * 2, energy: 0, morale: 30,
+                                       on_death: DeathCallback::Monster});
+            bat.ai = Some(Ai::Basic);
+            bat.movement = Movement::flying();
+            bat
+        }
+        "zombie" => {
+            // create a zombie: tough, but it only acts every other turn
+            let mut zombie = Object::new(x, y, 'z', "zombie", colors::DESATURATED_LIME, true);
+            zombie.fighter = Some(Fighter{base_max_hp: 40, hp: 40, base_defense: 1, base_power: 7, xp: 60,
+                                          speed: NORMAL_SPEED / 2, energy: 0, morale: 100,
+                                          on_death: DeathCallback::Monster});
+            zombie.ai = Some(Ai::Basic);
+            zombie
+        }
+        "bone king" => {
+            // the boss of the first arena: when hurt it shakes the ground
+            // around it, see scripts/grave_shockwave.rhai
+            let mut king = Object::new(x, y, 'K', "bone king", colors::LIGHTEST_GREY, true);
+            king.fighter = Some(Fighter{base_max_hp: 90, hp: 90, base_defense: 2, base_power: 9, xp: 500,
+                                        speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                        on_death: DeathCallback::Monster});
+            king.ai = Some(Ai::Basic);
+            king.effect = Some("grave_shockwave".into());
+            let mut crown = create_item(Item::Crown, x, y);
+            crown.equipment.as_mut().unwrap().equipped = true;
+            king.inventory.push(crown);
+            king
+        }
+        "orc warchief" => {
+            // guards its hall (see MINI_BOSSES) and gives up a good sword
+            let mut warchief = Object::new(x, y, 'W', "orc warchief", colors::DARKER_GREEN, true);
+            warchief.fighter = Some(Fighter{base_max_hp: 50, hp: 50, base_defense: 2, base_power: 5, xp: 250,
+                                            speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                            on_death: DeathCallback::Monster});
+            warchief.ai = Some(Ai::Basic);
+            let mut sword = create_item(Item::Sword, x, y);
+            sword.equipment.as_mut().unwrap().equipped = true;
+            warchief.inventory.push(sword);
+            warchief.inventory.push(create_item(Item::Heal, x, y));
+            warchief
+        }
+        "troll matriarch" => {
+            // guards her den (see MINI_BOSSES), with a shield and a wand
+            let mut matriarch = Object::new(x, y, 'M', "troll matriarch", colors::DARKER_GREEN, true);
+            matriarch.fighter = Some(Fighter{base_max_hp: 70, hp: 70, base_defense: 2, base_power: 8, xp: 400,
+                                             speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                             on_death: DeathCallback::Monster});
+            matriarch.ai = Some(Ai::Basic);
+            let mut shield = create_item(Item::Shield, x, y);
+            shield.equipment.as_mut().unwrap().equipped = true;
+            matriarch.inventory.push(shield);
+            let mut wand = create_item(Item::Wand, x, y);
+            wand.charges = Some(5);
+            matriarch.inventory.push(wand);
+            matriarch
+        }
+        "troll queen" => {
+            // the boss of the second arena: mends her wounds once they get
+            // bad, see scripts/regenerate.rhai
+            let mut queen = Object::new(x, y, 'Q', "troll queen", colors::DARKEST_GREEN, true);
+            queen.fighter = Some(Fighter{base_max_hp: 120, hp: 120, base_defense: 4, base_power: 12, xp: 900,
+                                         speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                         on_death: DeathCallback::Monster});
+            queen.ai = Some(Ai::Basic);
+            queen.effect = Some("regenerate".into());
+            let mut wand = create_item(Item::Wand, x, y);
+            wand.charges = Some(8);
+            queen.inventory.push(wand);
+            queen.inventory.push(create_item(Item::Heal, x, y));
+            queen.inventory.push(create_item(Item::Heal, x, y));
+            queen
+        }
+        "rock worm" => {
+            // crawls through the rock as well as the floor, but digging is slow
+            let mut worm = Object::new(x, y, 'w', "rock worm", colors::DARKER_SEPIA, true);
+            worm.fighter = Some(Fighter{base_max_hp: 15, hp: 15, base_defense: 0, base_power: 5, xp: 40,
+                                        speed: NORMAL_SPEED, energy: 0, morale: 60,
+                                        on_death: DeathCallback::Monster});
+            worm.ai = Some(Ai::Basic);
+            worm.movement = Movement { walks: true, flies: false, burrows: true, swims: false, opens_doors: false };
+            worm
+        }
+        "eel" => {
+            // lurks in the water and never leaves it
+            let mut eel = Object::new(x, y, 'e', "eel", colors::DARK_AZURE, true);
+            eel.fighter = Some(Fighter{base_max_hp: 12, hp: 12, base_defense: 0, base_power: 5, xp: 35,
+                                       speed: NORMAL_SPEED, energy: 0, morale: 50,
+                                       on_death: DeathCallback::Monster});
+            eel.ai = Some(Ai::Basic);
+            eel.movement = Movement { walks: false, flies: false, burrows: false, swims: true, opens_doors: false };
+            eel
+        }
+        "giant" => {
+            // too big for the corridors, it fills a square of 2x2 tiles
+            let mut giant = Object::new(x, y, 'G', "giant", colors::LIGHT_SEPIA, true);
+            giant.fighter = Some(Fighter{base_max_hp: 80, hp: 80, base_defense: 3, base_power: 12, xp: 500,
+                                         speed: NORMAL_SPEED, energy: 0, morale: 80,
+                                         on_death: DeathCallback::Monster});
+            giant.ai = Some(Ai::Basic);
+            giant.size = 2;
+            giant
+        }
+        "dragon" => {
+            // as big as a giant, and it flies over whatever is below it
+            let mut dragon = Object::new(x, y, 'D', "dragon", colors::DARK_RED, true);
+            dragon.fighter = Some(Fighter{base_max_hp: 140, hp: 140, base_defense: 5, base_power: 16, xp: 1200,
+                                          speed: NORMAL_SPEED, energy: 0, morale: 90,
+                                          on_death: DeathCallback::Monster});
+            dragon.ai = Some(Ai::Basic);
+            dragon.movement = Movement::flying();
+            dragon.size = 2;
+            dragon
+        }
+        "goblin archer" => {
+            // keeps its distance and shoots arrows
+            let mut archer = Object::new(x, y, 'g', "goblin archer", colors::DARK_CHARTREUSE, true);
+            archer.fighter = Some(Fighter{base_max_hp: 12, hp: 12, base_defense: 0, base_power: 4, xp: 45,
+                                          speed: NORMAL_SPEED, energy: 0, morale: 40,
+                                          on_death: DeathCallback::Monster});
+            archer.ai = Some(Ai::Ranged);
+            archer
+        }
+        "fire imp" => {
+            // flits about and hurls fire bolts
+            let mut imp = Object::new(x, y, 'i', "fire imp", colors::FLAME, true);
+            imp.fighter = Some(Fighter{base_max_hp: 10, hp: 10, base_defense: 1, base_power: 6, xp: 70,
+                                       speed: NORMAL_SPEED, energy: 0, morale: 60,
+                                       on_death: DeathCallback::Monster});
+            imp.ai = Some(Ai::Ranged);
+            imp.movement = Movement::flying();
+            imp
+        }
+        "swarm of flies" => {
+            // rises from rotting corpses, never placed with the rest
+            let mut swarm = Object::new(x, y, 'f', "swarm of flies", colors::DARKER_GREY, true);
+            swarm.fighter = Some(Fighter{base_max_hp: 3, hp: 3, base_defense: 0, base_power: 1, xp: 5,
+                                         speed: NORMAL_SPEED * 2, energy: 0, morale: 100,
+                                         on_death: DeathCallback::Monster});
+            swarm.ai = Some(Ai::Basic);
+            swarm.movement = Movement::flying();
+            swarm
+        }
+        "jackal" => {
+            // weak and quick, comes in packs that close in from every side
+            let mut jackal = Object::new(x, y, 'j', "jackal", colors::DARK_AMBER, true);
+            jackal.fighter = Some(Fighter{base_max_hp: 8, hp: 8, base_defense: 0, base_power: 3, xp: 15,
+                                          speed: NORMAL_SPEED * 3 / 2, energy: 0, morale: 40,
+                                          on_death: DeathCallback::Monster});
+            jackal.ai = Some(Ai::Basic);
+            jackal
+        }
+        "goblin shaman" => {
+            // hangs back with the others and mends their wounds, a few times
+            let mut shaman = Object::new(x, y, 's', "goblin shaman", colors::LIGHT_PURPLE, true);
+            shaman.fighter = Some(Fighter{base_max_hp: 14, hp: 14, base_defense: 0, base_power: 3, xp: 60,
+                                          speed: NORMAL_SPEED, energy: 0, morale: 40,
+                                          on_death: DeathCallback::Monster});
+            shaman.ai = Some(Ai::Basic);
+            shaman.effect = Some("heal".into());
+            shaman.charges = Some(3);
+            shaman
+        }
+        "dark mage" => {
+            // calls down lightning on the player, until it runs out of spells
+            let mut mage = Object::new(x, y, 'm', "dark mage", colors::DARK_VIOLET, true);
+            mage.fighter = Some(Fighter{base_max_hp: 20, hp: 20, base_defense: 1, base_power: 4, xp: 120,
+                                        speed: NORMAL_SPEED, energy: 0, morale: 50,
+                                        on_death: DeathCallback::Monster});
+            mage.ai = Some(Ai::Basic);
+            mage.effect = Some("lightning".into());
+            mage.charges = Some(2);
+            mage
+        }
+        "necromancer" => {
+            // raises skeletons to fight for it, see `Summons`
+            let mut necromancer = Object::new(x, y, 'n', "necromancer", colors::DARK_PURPLE, true);
+            necromancer.fighter = Some(Fighter{base_max_hp: 25, hp: 25, base_defense: 1, base_power: 4, xp: 150,
+                                               speed: NORMAL_SPEED, energy: 0, morale: 60,
+                                               on_death: DeathCallback::Monster});
+            necromancer.ai = Some(Ai::Basic);
+            necromancer
+        }
+        "skeleton" => {
+            // rattles up from the ground at a necromancer's call
+            let mut skeleton = Object::new(x, y, 'S', "skeleton", colors::LIGHTER_GREY, true);
+            skeleton.fighter = Some(Fighter{base_max_hp: 10, hp: 10, base_defense: 1, base_power: 4, xp: 10,
+                                            speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                            on_death: DeathCallback::Monster});
+            skeleton.ai = Some(Ai::Basic);
+            skeleton
+        }
+        "gnat nest" => {
+            // stuck to the wall, it keeps releasing gnats until it's destroyed
+            let mut nest = Object::new(x, y, 'N', "gnat nest", colors::DARK_SEPIA, true);
+            nest.fighter = Some(Fighter{base_max_hp: 20, hp: 20, base_defense: 2, base_power: 0, xp: 60,
+                                        speed: NORMAL_SPEED, energy: 0, morale: 100,
+                                        on_death: DeathCallback::Monster});
+            nest.ai = Some(Ai::Spawner);
+            nest.movement = Movement {
+                walks: false, flies: false, burrows: false, swims: false, opens_doors: false,
+            };
+            nest
+        }
+        "giant beetle" => {
+            // grazes on the moss, it only bites back
+            let mut beetle = Object::new(x, y, 'B', "giant beetle", colors::DARK_CYAN, true);
+            beetle.fighter = Some(Fighter{base_max_hp: 16, hp: 16, base_defense: 3, base_power: 4, xp: 40,
+                                          speed: NORMAL_SPEED * 3 / 4, energy: 0, morale: 60,
+                                          on_death: DeathCallback::Monster});
+            beetle.ai = Some(Ai::Basic);
+            beetle.disposition = Disposition::Neutral;
+            beetle
+        }
+        "dog" => {
+            // the player's companion from the start, see `ai_companion`
+            let mut dog = Object::new(x, y, 'd', "dog", colors::LIGHT_SEPIA, true);
+            dog.fighter = Some(Fighter{base_max_hp: 25, hp: 25, base_defense: 1, base_power: 3, xp: 0,
+                                       speed: NORMAL_SPEED * 5 / 4, energy: 0, morale: 100,
+                                       on_death: DeathCallback::Monster});
+            dog.ai = Some(Ai::Companion{order: Order::Follow});
+            dog.disposition = Disposition::Friendly;
+            dog
+        }
+        "cloud of gnats" => {
+            // weak, but there's always more of them while their nest stands
+            let mut gnats = Object::new(x, y, 'c', "cloud of gnats", colors::DARKER_GREY, true);
+            gnats.fighter = Some(Fighter{base_max_hp: 4, hp: 4, base_defense: 0, base_power: 2, xp: 5,
+                                         speed: NORMAL_SPEED * 3 / 2, energy: 0, morale: 100,
+                                         on_death: DeathCallback::Monster});
+            gnats.ai = Some(Ai::Basic);
+            gnats.movement = Movement::flying();
+            gnats
+        }
+        _ => return None,
+    };
+    monster.alive = true;
+    monster.faction = faction(species);
+    monster.movement.opens_doors = opens_doors(species);
+    Some(monster)
+}
+
+/// Create an object for the given kind of item, lying on the floor at (x, y)
+fn create_item(item: Item, x: i32, y: i32) -> Object {
+    let mut object = match item {
+        Item::Heal => {
+            // create a healing potion
+            let mut object = Object::new(x, y, '!', "healing potion", colors::VIOLET, false);
+            object.item = Some(Item::Heal);
+            object.effect = Some("heal".into());
+            object
+        }
+        Item::Lightning => {
+            // create a lightning bolt scroll
+            let mut object = Object::new(x, y, '#', "scroll of lightning bolt",
+                                         colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Lightning);
+            object.effect = Some("lightning".into());
+            object
+        }
+        Item::Fireball => {
+            // create a fireball scroll
+            let mut object = Object::new(x, y, '#', "scroll of fireball", colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Fireball);
+            object.effect = Some("fireball".into());
+            object
+        }
+        Item::Confuse => {
+            // create a confuse scroll
+            let mut object = Object::new(x, y, '#', "scroll of confusion",
+                                         colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Confuse);
+            object.effect = Some("confuse".into());
+            object
+        }
+        Item::Sword => {
+            // create a sword
+            let mut object = Object::new(x, y, '/', "sword", colors::SKY, false);
+            object.item = Some(Item::Sword);
+            object.equipment = Some(Equipment{equipped: false, slot: Slot::RightHand, max_hp_bonus: 0, defense_bonus: 0, power_bonus: 3, luck_bonus: 0});
+            object
+        }
+        Item::Shield => {
+            // create a shield
+            let mut object = Object::new(x, y, '[', "shield", colors::DARKER_ORANGE, false);
+            object.item = Some(Item::Shield);
+            object.equipment = Some(Equipment{equipped: false, slot: Slot::LeftHand, max_hp_bonus: 0, defense_bonus: 1, power_bonus: 0, luck_bonus: 0});
+            object
+        }
+        Item::Scripted => {
+            // create a drain life scroll, its effect lives in scripts/drain_life.rhai
+            let mut object = Object::new(x, y, '#', "scroll of drain life", colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Scripted);
+            object.effect = Some("drain_life".into());
+            object
+        }
+        Item::Wand => {
+            // create a wand of lightning, good for a few bolts
+            let mut object = Object::new(x, y, '-', "wand of lightning", colors::LIGHT_BLUE, false);
+            object.item = Some(Item::Wand);
+            object.effect = Some("lightning".into());
+            object.charges = Some(3);
+            object
+        }
+        Item::Rock => {
+            // create a rock, to throw where the monsters should go
+            let mut object = Object::new(x, y, '*', "rock", colors::GREY, false);
+            object.item = Some(Item::Rock);
+            object.effect = Some("rock".into());
+            object
+        }
+        Item::Bell => {
+            // create a bell, like a rock but heard much further
+            let mut object = Object::new(x, y, '(', "bell", colors::GOLD, false);
+            object.item = Some(Item::Bell);
+            object.effect = Some("bell".into());
+            object
+        }
+        Item::SmokeBomb => {
+            // create a smoke bomb, to hide behind its cloud
+            let mut object = Object::new(x, y, '*', "smoke bomb", colors::LIGHTEST_GREY, false);
+            object.item = Some(Item::SmokeBomb);
+            object.effect = Some("smoke".into());
+            object
+        }
+        Item::Crown => {
+            // the bone king's crown, only ever found on its remains
+            let mut object = Object::new(x, y, '=', "crown of the bone king", colors::GOLD, false);
+            object.item = Some(Item::Crown);
+            object.equipment = Some(Equipment{equipped: false, slot: Slot::Head, max_hp_bonus: 20, defense_bonus: 1, power_bonus: 1, luck_bonus: 0});
+            object
+        }
+        Item::Pickaxe => {
+            // create a pickaxe, to dig through the rock one tile at a time
+            let mut object = Object::new(x, y, '(', "pickaxe", colors::LIGHTER_SEPIA, false);
+            object.item = Some(Item::Pickaxe);
+            object.effect = Some("dig".into());
+            object
+        }
+        Item::Digging => {
+            // create a digging scroll, it bores a whole tunnel at once
+            let mut object = Object::new(x, y, '#', "scroll of digging", colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Digging);
+            object.effect = Some("digging".into());
+            object
+        }
+        Item::Return => {
+            // create a return scroll, it opens a portal to town and back
+            let mut object = Object::new(x, y, '#', "scroll of return", colors::LIGHT_YELLOW, false);
+            object.item = Some(Item::Return);
+            object.effect = Some("return".into());
+            object
+        }
+        Item::Charm => {
+            // create a lucky charm, worn around the neck
+            let mut object = Object::new(x, y, '\'', "lucky charm", colors::LIGHT_GREEN, false);
+            object.item = Some(Item::Charm);
+            object.equipment = Some(Equipment {
+                equipped: false,
+                slot: Slot::Neck,
+                max_hp_bonus: 0,
+                defense_bonus: 0,
+                power_bonus: 0,
+                luck_bonus: 2,
+            });
+            object
+        }
+        Item::Trophy => {
+            // only ever cut from remains, see `create_trophy`
+            let mut object = Object::new(x, y, '~', "trophy", colors::LIGHTER_SEPIA, false);
+            object.item = Some(Item::Trophy);
+            object
+        }
+    };
+    object.always_visible = true;
+    object
+}
+
+/// Something the player can cut from the remains of a monster of a species
+/// and bring to the hunter in town, or sell. They're read from
+/// TROPHIES_PATH, at most one for each species.
+struct Trophy {
+    species: String,
+    name: String,
+    price: u32,
+    /// the percentage of remains that still have one worth taking
+    chance: u32,
+}
+
+fn load_trophies(path: &str) -> Result<Vec<Trophy>, String> {
+    let mut text = String::new();
+    try! {
+        File::open(path).and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("can't read {}: {}", path, e))
+    };
+    let doc = try! { text.parse::<toml::Value>().map_err(|e| format!("{} is not valid TOML: {}", path, e)) };
+    let entries = doc.get("trophies").and_then(|entries| entries.as_array()).map_or(&[][..], |e| &e[..]);
+    let mut trophies = vec![];
+    for entry in entries {
+        let text = |key: &str| entry.get(key).and_then(|value| value.as_str()).map(|value| value.to_string())
+            .ok_or(format!("{}: a trophy has no {}", path, key));
+        let number = |key: &str| match entry.get(key).and_then(|value| value.as_integer()) {
+            Some(number) if number >= 0 => Ok(number as u32),
+            _ => Err(format!("{}: a trophy has no {}", path, key)),
+        };
+        trophies.push(Trophy {
+            species: try! { text("species") },
+            name: try! { text("name") },
+            price: try! { number("price") },
+            chance: try! { number("chance") },
+        });
+    }
+    Ok(trophies)
+}
+
+/// the trophy of the species the remains `corpse` were, if it has one and
+/// nobody cut it off yet
+fn trophy_of(corpse: &Object) -> Option<&'static Trophy> {
+    if corpse.removed || corpse.item.is_some() || corpse.fighter.is_some() ||
+        !corpse.name.starts_with(REMAINS_PREFIX) {
+        return None;
+    }
+    let species = &corpse.name[REMAINS_PREFIX.len()..];
+    TROPHIES.iter().find(|trophy| trophy.species == species)
+}
+
+fn create_trophy(trophy: &Trophy, x: i32, y: i32) -> Object {
+    let mut object = create_item(Item::Trophy, x, y);
+    object.name = trophy.name.clone();
+    object
+}
+
+/// The kind of item whose name contains `name`, like `Heal` for "healing".
+fn item_kind(name: &str) -> Option<Item> {
+    Item::all().into_iter().find(|&kind| create_item(kind, 0, 0).name.contains(name))
+}
+
+/// Advance to the next level
+fn next_level(objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    let depth = Depth::new(game.branch, game.dungeon_level + 1);
+    descend_to(depth, objects, game, fov_map);
+}
+
+/// Take the stairs into a side branch of the dungeon
+fn enter_branch(branch: Branch, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    descend_to(Depth::new(branch, 1), objects, game, fov_map);
+}
+
+fn descend_to(depth: Depth, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    report_level(objects, game);
+    if game.levels.contains(depth) {
+        game.log.add("You go back down the stairs.", colors::LIGHT_GREY);
+    } else {
+        // only a new level is worth a rest, or going up and down would heal
+        game.log.add("You take a moment to rest, and recover your strength.", colors::VIOLET);
+        let heal_hp = objects[PLAYER].max_hp() / 2;
+        objects[PLAYER].heal(heal_hp);
+
+        if depth.branch != game.branch {
+            game.log.add(format!("You step down into the {}...", depth.branch.title().to_lowercase()),
+                         colors::RED);
+        } else {
+            game.log.add("After a rare moment of peace, you descend deeper into \
+                          the heart of the dungeon...", colors::RED);
+        }
+        if let Some(boss) = mini_boss_of(depth) {
+            game.log.add(boss.feeling, colors::LIGHT_RED);
+        }
+    }
+    let from = game.depth();
+    let companions = change_level(depth, objects, game, fov_map);
+    arrive_by_stairs(from, &companions, objects, game);
+}
+
+/// Go back up to the level above, from the top of a branch that's the
+/// level with its entrance
+fn previous_level(objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    let depth = match game.rules().branch(game.branch) {
+        Some(branch) if game.dungeon_level == 1 => Depth::new(Branch::Main, branch.entry_level),
+        _ => Depth::new(game.branch, game.dungeon_level - 1),
+    };
+    report_level(objects, game);
+    game.log.add("You climb back up the stairs.", colors::LIGHT_GREY);
+    let from = game.depth();
+    let companions = change_level(depth, objects, game, fov_map);
+    arrive_by_stairs(from, &companions, objects, game);
+}
+
+/// Put the player on the stairs back to the level they came `from`, or next
+/// to them if something stands there, and their companions beside them. A
+/// level left through a portal wasn't left by its stairs.
+fn arrive_by_stairs(from: Depth, companions: &[usize], objects: &mut [Object], game: &Game) {
+    let name = stairs_to(game.depth(), from);
+    let (x, y) = match objects.iter().find(|o| !o.removed && o.name == name) {
+        Some(stairs) => stairs.pos(),
+        None => return,
+    };
+    let taken = objects[PLAYER + 1..].iter().any(|o| o.blocks && o.occupies(x, y));
+    let (x, y) = if taken { spot_beside(x, y, objects, &game.map).unwrap_or((x, y)) } else { (x, y) };
+    objects[PLAYER].set_pos(x, y);
+    for &id in companions {
+        place_beside_player(id, objects, &game.map);
+    }
+}
+
+/// Leave the current level for another one. The player comes back where
+/// they left a level, or to the start of a new one. Their companions close
+/// by come along, unless told to stay, returns where they are now.
+fn change_level(depth: Depth, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) -> Vec<usize> {
+    game.stats.deepest = cmp::max(game.stats.deepest, game.rules().difficulty(depth));
+    let followers: Vec<usize> = (0..objects.len())
+        .filter(|&id| {
+            let companion = &objects[id];
+            is_companion(companion) && companion.ai != Some(Ai::Companion{order: Order::Stay}) &&
+                companion.distance_to(&objects[PLAYER]) <= COMPANION_LEASH
+        })
+        .collect();
+    let followers: Vec<Object> = followers.into_iter()
+        .map(|id| objects.remove_object(id, &mut game.free_slots))
+        .collect();
+    let level = Level {
+        map: std::mem::replace(&mut game.map, vec![]),
+        objects: objects.drain(1..).collect(),
+        player_pos: objects[PLAYER].pos(),
+        schedule: std::mem::replace(&mut game.schedule, Schedule::new()),
+        smoke: std::mem::replace(&mut game.smoke, vec![]),
+    };
+    game.levels.store(game.depth(), level);
+    game.branch = depth.branch;
+    game.dungeon_level = depth.level;
+    let cached = match game.levels.take(depth) {
+        Ok(cached) => cached,
+        Err(e) => {
+            warn!("could not load {:?} level {}: {}", depth.branch, depth.level, e);
+            game.log.add(format!("Could not load the level, it was made anew: {}", e), colors::RED);
+            None
+        }
+    };
+    game.map = match cached {
+        Some(level) => {
+            // been here before: come back where we left, whatever was due
+            // in the meantime happens right away
+            objects.extend(level.objects);
+            objects[PLAYER].set_pos(level.player_pos.0, level.player_pos.1);
+            game.schedule = level.schedule;
+            game.smoke = level.smoke;
+            level.map
+        }
+        None => {
+            game.schedule = Schedule::starting_at(game.turn);
+            let (map, feeling) = make_map(objects, depth, game.rules(), &game.mutators);
+            for (message, color) in feeling.messages() {
+                game.log.add(message, color);
+            }
+            map
+        }
+    };
+    game.levels.evict_distant(depth);
+    game.free_slots = free_slots(objects);
+    // whoever they were told to fight stayed behind
+    let arrived = followers.into_iter()
+        .map(|mut follower| {
+            follower.ai = Some(Ai::Companion{order: Order::Follow});
+            let id = objects.insert_object(follower, &mut game.free_slots);
+            place_beside_player(id, objects, &game.map);
+            id
+        })
+        .collect();
+    initialise_fov(&game.map, fov_map);
+    sync_portals(objects, game);
+    if depth == Depth::town() {
+        start_town_event(objects, game);
+    }
+    arrived
+}
+
+/// Put the companion down next to the player, or right on top of them if
+/// there's no room: it steps off on its turn.
+fn place_beside_player(companion_id: usize, objects: &mut [Object], map: &Map) {
+    let (x, y) = objects[PLAYER].pos();
+    let (x, y) = spot_beside(x, y, objects, map).unwrap_or((x, y));
+    objects[companion_id].set_pos(x, y);
+}
+
+/// Tell the player how much of the level they're leaving they saw, and
+/// what they left on it. Clearing it out the first time earns a bonus.
+/// There's nothing to tell about town.
+fn report_level(objects: &mut [Object], game: &mut Game) {
+    if game.depth() == Depth::town() {
+        return;
+    }
+    let floor: Vec<&Tile> = game.map.iter()
+        .flat_map(|column| column.iter())
+        .filter(|tile| !tile.blocked)
+        .collect();
+    let explored = floor.iter().filter(|tile| tile.explored).count() * 100 / cmp::max(floor.len(), 1);
+    let monsters = objects.iter().filter(|o| is_foe(o)).count();
+    let items = objects.iter().filter(|o| !o.removed && o.item.is_some()).count();
+    game.log.add(format!("You explored {}% of this level, leaving {} monsters and {} items behind.",
+                         explored, monsters, items),
+                 colors::LIGHT_GREY);
+
+    let depth = game.depth();
+    if monsters == 0 && explored >= CLEARED_EXPLORED_PERCENT && !game.cleared_levels.contains(&depth) {
+        game.cleared_levels.push(depth);
+        game.log.add("You leave this level cleared out.", colors::LIGHT_GREEN);
+        let xp = CLEAR_BONUS_XP * game.rules().difficulty(depth) as i32;
+        award_xp(PLAYER, xp, objects, game);
+    }
+}
+
+/// Now and then something is going on in town when the player gets there:
+/// a festival, or orcs raiding the streets.
+fn start_town_event(objects: &mut Vec<Object>, game: &mut Game) {
+    if game.town_event.is_some() || game.turn < game.next_town_event ||
+        game_rng().gen::<f32>() >= TOWN_EVENT_CHANCE {
+        return;
+    }
+    if game_rng().gen() {
+        game.town_event = Some(TownEvent::Festival);
+        let end = game.turn + FESTIVAL_TURNS;
+        game.schedule.at(end, TimedEvent::EndFestival);
+        game.log.add("The town is holding a festival! The shop sells everything for less.", colors::LIGHT_YELLOW);
+        return;
+    }
+
+    // they come down the streets
+    let mut spots: Vec<(i32, i32)> = (0..CONFIG.map_width)
+        .flat_map(|x| (0..CONFIG.map_height).map(move |y| (x, y)))
+        .filter(|&(x, y)| {
+            game.map[x as usize][y as usize].kind == TileKind::Road && !is_blocked(x, y, &game.map, objects) &&
+                !objects.iter().any(|o| !o.removed && o.pos() == (x, y)) &&
+                objects[PLAYER].distance(x, y) >= RAID_MIN_DISTANCE
+        })
+        .collect();
+    game_rng().shuffle(&mut spots);
+    let mut raiders = 0;
+    for &(x, y) in spots.iter().take(RAIDERS) {
+        if let Some(mut raider) = create_monster("orc", x, y) {
+            raider.name = RAIDER_NAME.into();
+            objects.insert_object(raider, &mut game.free_slots);
+            raiders += 1;
+        }
+    }
+    if raiders > 0 {
+        debug!("{} orcs raid the town", raiders);
+        game.town_event = Some(TownEvent::Raid(raiders));
+        let check = game.turn + RAID_CHECK_INTERVAL;
+        game.schedule.at(check, TimedEvent::CheckRaid);
+        game.log.add("Orcs are raiding the town! The townsfolk promise a reward for driving them off.",
+                     colors::RED);
+    }
+}
+
+fn end_town_event(game: &mut Game) {
+    if game.town_event == Some(TownEvent::Festival) {
+        game.log.add("The festival is over.", colors::LIGHT_GREY);
+    }
+    game.town_event = None;
+    game.next_town_event = game.turn + TOWN_EVENT_COOLDOWN;
+}
+
+/// Once all the raiders are dead the town pays the player for each of them.
+fn check_raid(objects: &mut [Object], game: &mut Game) {
+    let raiders = match game.town_event {
+        Some(TownEvent::Raid(raiders)) => raiders,
+        _ => return,
+    };
+    if objects.iter().any(|o| !o.removed && o.fighter.is_some() && o.name == RAIDER_NAME) {
+        let check = game.turn + RAID_CHECK_INTERVAL;
+        game.schedule.at(check, TimedEvent::CheckRaid);
+        return;
+    }
+    let reward = raiders * RAID_BOUNTY;
+    earn_gold(reward, objects, game);
+    game.log.add(format!("The raid is over! The grateful townsfolk give you {} gold.", reward), colors::GOLD);
+    end_town_event(game);
+}
+
+/// Whether the player stands in a portal
+fn on_portal(objects: &[Object]) -> bool {
+    objects.iter().any(|o| o.pos() == objects[PLAYER].pos() && !o.removed && o.name == PORTAL_NAME)
+}
+
+/// Step through the portal the player stands in: from the dungeon to
+/// town, or from town back to where it was opened. Coming back closes it.
+fn take_portal(objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    let portal = match game.portal {
+        Some(portal) => portal,
+        None => return,
+    };
+    if game.depth() == Depth::town() {
+        game.log.add("You step through the portal, back into the dungeon.", colors::LIGHT_MAGENTA);
+        game.portal = None;
+        let companions = change_level(portal.depth, objects, game, fov_map);
+        objects[PLAYER].set_pos(portal.pos.0, portal.pos.1);
+        for id in companions {
+            place_beside_player(id, objects, &game.map);
+        }
+        game.log.add("The portal closes behind you.", colors::LIGHT_GREY);
+    } else {
+        report_level(objects, game);
+        game.log.add("You step through the portal and find yourself in town.", colors::LIGHT_MAGENTA);
+        let companions = change_level(Depth::town(), objects, game, fov_map);
+        let arrival = objects.iter().find(|o| !o.removed && o.name == PORTAL_NAME).map(|o| o.pos());
+        if let Some((x, y)) = arrival {
+            objects[PLAYER].set_pos(x, y);
+        }
+        for id in companions {
+            place_beside_player(id, objects, &game.map);
+        }
+    }
+}
+
+/// Put the current level's end of the open portal on the map and take
+/// away any portal that isn't open anymore. The portal's end in the dungeon
+/// is where it was opened, the one in town next to the stairs.
+fn sync_portals(objects: &mut Vec<Object>, game: &mut Game) {
+    let existing = objects.iter().find(|o| !o.removed && o.name == PORTAL_NAME).map(|o| o.pos());
+    let wanted = match game.portal {
+        Some(portal) if portal.depth == game.depth() => Some(portal.pos),
+        Some(_) if game.depth() == Depth::town() => existing.or_else(|| town_portal_spot(objects, game)),
+        _ => None,
+    };
+    let stale: Vec<usize> = (0..objects.len())
+        .filter(|&id| !objects[id].removed && objects[id].name == PORTAL_NAME && Some(objects[id].pos()) != wanted)
+        .collect();
+    for id in stale {
+        objects.remove_object(id, &mut game.free_slots);
+    }
+    if let Some((x, y)) = wanted {
+        if existing != wanted {
+            let mut portal = Object::new(x, y, 'O', PORTAL_NAME, colors::LIGHT_MAGENTA, false);
+            portal.always_visible = true;
+            objects.insert_object(portal, &mut game.free_slots);
+        }
+    }
+}
+
+/// A free tile close to the town's stairs, for the town's end of a portal
+fn town_portal_spot(objects: &[Object], game: &Game) -> Option<(i32, i32)> {
+    let (stairs_x, stairs_y) = match objects.iter().find(|o| o.name == "stairs") {
+        Some(stairs) => stairs.pos(),
+        None => return None,
+    };
+    let mut spots: Vec<(i32, i32)> = (-3..4)
+        .flat_map(|dx| (-3..4).map(move |dy| (stairs_x + dx, stairs_y + dy)))
+        .filter(|&(x, y)| x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height)
+        .filter(|&(x, y)| !is_blocked(x, y, &game.map, objects) && !objects.iter().any(|o| o.pos() == (x, y)))
+        .collect();
+    spots.sort_by_key(|&(x, y)| (x - stairs_x).pow(2) + (y - stairs_y).pow(2));
+    spots.first().cloned()
+}
+
+fn render_bar(panel: &mut Screen,
+              x: i32,
+              y: i32,
+              total_width: i32,
+              name: &str,
+              value: i32,
+              maximum: i32,
+              bar_color: Color,
+              back_color: Color)
+{
+    // render a bar (HP, experience, etc). First calculate the width of the bar
+    let bar_width = (value as f32 / maximum as f32 * total_width as f32) as i32;
+
+    // render the background first
+    panel.fill_background(x, y, total_width, 1, back_color);
+
+    // now render the bar on top
+    if bar_width > 0 {
+        panel.fill_background(x, y, bar_width, 1, bar_color);
+    }
+
+    // finally, some centered text with the values
+    panel.print_centered(x + total_width / 2, y, &format!("{}: {}/{}", name, value, maximum),
+                         colors::WHITE);
+}
+
+/// return a string with the names of all objects on the tile pointed at
+fn get_names_at(pos: Option<(i32, i32)>, objects: &[Object], fov_map: &FovMap) -> String {
+    let (x, y) = match pos {
+        Some(pos) => pos,
+        None => return String::new(),
+    };
+
+    // create a list with the names of all objects at the coordinates and in FOV
+    let names = objects
+        .iter()
+        .filter(|obj| {obj.occupies(x, y) && !obj.removed && !obj.hidden() && fov_map.is_in_fov(x, y)})
+        .map(|obj| {
+            let statuses: Vec<_> = obj.statuses().iter().map(|status| status.name()).collect();
+            if statuses.is_empty() {
+                obj.name.clone()
+            } else {
+                format!("{} ({})", obj.name, statuses.join(", "))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    names.join(", ")  // join the names, separated by commas
+}
+
+/// Recompute the player's FOV and explore every tile that became visible.
+fn compute_fov(fov_map: &mut FovMap, objects: &[Object], game: &mut Game) {
+    sync_sight(&game.map, fov_map);
+    let player = &objects[PLAYER];
+    fov_map.compute_fov(player.x, player.y, game.light_radius(), FOV_LIGHT_WALLS, FOV_ALGO);
+    for y in 0..CONFIG.map_height {
+        for x in 0..CONFIG.map_width {
+            if fov_map.is_in_fov(x, y) {
+                game.map[x as usize][y as usize].explored = true;
+            }
+        }
+    }
+}
+
+/// Bring the FOV map up to date with the tiles that change without the rest
+/// of the map changing: doors that open and close, smoke that comes and goes.
+fn sync_sight(map: &Map, fov_map: &mut FovMap) {
+    for y in 0..CONFIG.map_height {
+        for x in 0..CONFIG.map_width {
+            let tile = &map[x as usize][y as usize];
+            if fov_map.is_transparent(x, y) == tile.block_sight {
+                fov_map.set(x, y, !tile.block_sight, !tile.blocked);
+            }
+        }
+    }
+}
+
+/// Show all of the level the player has explored on the whole screen,
+/// shrunk down if it doesn't fit, until they press a key. Each cell stands
+/// for a square of tiles and shows the most important thing in it: the
+/// player, then stairs and portals, then items and the traps they found.
+fn show_overview(tcod: &mut Tcod, objects: &[Object], game: &Game) {
+    let (width, height) = (CONFIG.screen_width, CONFIG.screen_height - 1);
+    let ceil_div = |a: i32, b: i32| (a + b - 1) / b;
+    let scale = cmp::max(1, cmp::max(ceil_div(CONFIG.map_width, width), ceil_div(CONFIG.map_height, height)));
+    // center the map on the screen
+    let left = (width - ceil_div(CONFIG.map_width, scale)) / 2;
+    let top = (height - ceil_div(CONFIG.map_height, scale)) / 2;
+    let explored = |x: i32, y: i32| game.map[x as usize][y as usize].explored;
+    let importance = |o: &Object| if o.name.contains("stairs") || o.name == PORTAL_NAME {
+        2
+    } else if o.item.is_some() || o.trap.is_some() {
+        1
+    } else {
+        0
+    };
+
+    let theme = game.theme();
+    let mut window = Screen::new(CONFIG.screen_width, CONFIG.screen_height);
+    for cell_y in 0..ceil_div(CONFIG.map_height, scale) {
+        for cell_x in 0..ceil_div(CONFIG.map_width, scale) {
+            let columns = cell_x * scale..cmp::min((cell_x + 1) * scale, CONFIG.map_width);
+            let rows = cell_y * scale..cmp::min((cell_y + 1) * scale, CONFIG.map_height);
+            let tiles: Vec<(i32, i32)> = columns
+                .flat_map(|x| rows.clone().map(move |y| (x, y)))
+                .filter(|&(x, y)| explored(x, y))
+                .collect();
+            // a floor if there's any, so the shrunk down corridors don't vanish
+            let shown = tiles.iter().find(|&&(x, y)| !game.map[x as usize][y as usize].block_sight)
+                .or(tiles.first());
+            if let Some(&(x, y)) = shown {
+                let (color, _, _) = theme.look(&game.map[x as usize][y as usize], false);
+                window.set_background(left + cell_x, top + cell_y, color);
+            }
+        }
+    }
+    let mut marked: Vec<&Object> = objects.iter()
+        .filter(|o| !o.removed && !o.hidden() && explored(o.x, o.y) && importance(o) > 0)
+        .collect();
+    marked.sort_by_key(|o| importance(o));
+    marked.push(&objects[PLAYER]);
+    for object in marked {
+        window.put_char(left + object.x / scale, top + object.y / scale, object.char, object.color);
+    }
+    let legend = if scale > 1 {
+        format!("{} (1:{}) - press any key to go back", game.level_title(), scale)
+    } else {
+        format!("{} - press any key to go back", game.level_title())
+    };
+    window.print(1, height, &legend, colors::LIGHT_GREY);
+
+    window.blit(&mut tcod.root, 0, 0, 1.0);
+    tcod.flush();
+    tcod.backend.wait_for_keypress();
+}
+
+fn render_all(tcod: &mut Tcod, objects: &[Object], game: &mut Game, fov_recompute: bool) {
+    tcod.frame_times.fov = Duration::from_millis(0);
+    if fov_recompute {
+        // recompute FOV if needed (the player moved or something)
+        let fov_start = Instant::now();
+        compute_fov(&mut tcod.fov, objects, game);
+        tcod.frame_times.fov = fov_start.elapsed();
+    }
+
+    // the view scrolls along with the player, all of it has to be drawn again then
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let camera_moved = tcod.camera.follow(player_x, player_y);
+    if camera_moved {
+        tcod.con.clear();
+    }
+
+    let theme = game.theme();
+    if fov_recompute || camera_moved {
+        // go through all tiles in view, and set their background color
+        for screen_y in 0..CONFIG.view_height() {
+            for screen_x in 0..CONFIG.view_width() {
+                let (x, y) = (tcod.camera.x + screen_x, tcod.camera.y + screen_y);
+                let visible = tcod.fov.is_in_fov(x, y);
+                let (color, _, _) = theme.look(&game.map[x as usize][y as usize], visible);
+
+                if game.map[x as usize][y as usize].explored {
+                    // show explored tiles only (any visible tile is explored already)
+                    tcod.con.set_background(screen_x, screen_y, color);
+                }
+            }
+        }
+    }
+    // the glyphs of the theme and the doors go on every frame, objects
+    // moving away clear their tiles
+    for screen_y in 0..CONFIG.view_height() {
+        for screen_x in 0..CONFIG.view_width() {
+            let (x, y) = (tcod.camera.x + screen_x, tcod.camera.y + screen_y);
+            let tile = &game.map[x as usize][y as usize];
+            let (_, char, color) = theme.look(tile, tcod.fov.is_in_fov(x, y));
+            if tile.explored && char != ' ' {
+                tcod.con.put_char(screen_x, screen_y, char, color);
+            }
+        }
+    }
+
+    let mut to_draw: Vec<_> = objects
+        .iter()
+        .filter(|o| !o.removed && !o.hidden())
+        .filter(|o| {
+            o.in_fov(&tcod.fov) ||
+                (o.always_visible && game.map[o.x as usize][o.y as usize].explored)
+        })
+        .collect();
+    // sort so that non-blocknig objects come first
+    to_draw.sort_by(|o1, o2| { o1.blocks.cmp(&o2.blocks) });
+    // draw the objects in the list
+    for object in &to_draw {
+        object.draw(&mut tcod.con, &tcod.camera);
+    }
+    // then, over them, the status of whoever is under one
+    for object in &to_draw {
+        if let Some(status) = object.statuses().first() {
+            let tint = colors::lerp(object.color, status.color(), STATUS_TINT);
+            if let Some((x, y)) = tcod.camera.to_screen(object.x, object.y) {
+                tcod.con.put_char(x, y, object.char, tint);
+            }
+        }
+    }
+
+    // blit the contents of "con" to the root screen
+    tcod.con.blit(&mut tcod.root, 0, 0, 1.0);
+    if CONFIG.detection_hints && !game.torch_lit {
+        show_detection_hints(tcod, objects, game);
+    }
+
+    // prepare to render the GUI panel
+    tcod.panel.clear();
+
+    // print the game messages, one line at a time
+    let mut y = MSG_HEIGHT as i32;
+    for &(ref msg, color) in game.log.iter().rev() {
+        let msg_height = wrap_text(msg, CONFIG.msg_width()).len() as i32;
+        y -= msg_height;
+        if y < 0 {
+            break;
+        }
+        tcod.panel.print_wrapped(MSG_X, y, CONFIG.msg_width(), msg, color);
+    }
+
+
+    // show the player's stats
+    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    let max_hp = objects[PLAYER].max_hp();
+    render_bar(&mut tcod.panel, 1, 1, BAR_WIDTH, "HP", hp, max_hp, colors::LIGHT_RED, colors::DARKER_RED);
+
+    tcod.panel.print(1, 2, &format!("Gold: {}", objects[PLAYER].gold), colors::GOLD);
+    tcod.panel.print(1, 3, &game.level_title(), colors::WHITE);
+    if let Some(status) = objects[PLAYER].statuses().first() {
+        tcod.panel.print(1, 4, &format!("You are {}", status.name()), status.color());
+    }
+    if !game.torch_lit {
+        tcod.panel.print(1, 5, "Your torch is out", colors::GREY);
+    }
+    // who gets to act before the player does again, after a plain move
+    let order = turn_order(objects, ACTION_COST, &tcod.fov);
+    if !order.is_empty() {
+        tcod.panel.print(1, 6, "Next:", colors::LIGHT_GREY);
+        for (i, &id) in order.iter().take(BAR_WIDTH as usize - 6).enumerate() {
+            tcod.panel.put_char(7 + i as i32, 6, objects[id].char, objects[id].color);
+        }
+    }
+
+    // display names of objects under the mouse or the cursor
+    tcod.panel.print(1, 0, &get_names_at(tcod.pointed_at(), objects, &tcod.fov),
+                     colors::LIGHT_GREY);
+
+    // blit the contents of `panel` to the root screen
+    tcod.panel.blit(&mut tcod.root, 0, CONFIG.panel_y(), 1.0);
+
+    if tcod.show_profiler {
+        render_profiler(tcod, objects);
+    }
+}
+
+/// Show how long the phases of the last frame took and how many monsters of
+/// each species live on the level, in the top right corner. The AI time is
+/// for the last turn the monsters took.
+fn render_profiler(tcod: &mut Tcod, objects: &[Object]) {
+    fn millis(time: Duration) -> f64 {
+        time.as_secs() as f64 * 1000.0 + time.subsec_nanos() as f64 / 1_000_000.0
+    }
+
+    let times = tcod.frame_times;
+    let mut lines: Vec<String> = [("input", times.input), ("fov", times.fov),
+                                  ("render", times.render), ("ai", times.ai)]
+        .iter()
+        .map(|&(name, time)| format!("{:<12}{:>6.2} ms", name, millis(time)))
+        .collect();
+    for (species, count) in population(objects) {
+        lines.push(format!("{:<14}{:>3}/{}", species, count, population_cap(species)));
+    }
+
+    let width = 22;
+    let x = CONFIG.screen_width - width;
+    tcod.root.fill_background(x, 0, width, lines.len() as i32, colors::BLACK);
+    for (y, line) in lines.iter().enumerate() {
+        tcod.root.print(x + 1, y as i32, line, colors::LIGHT_GREEN);
+    }
+}
+
+/// How many monsters of a species may live on one level at a time. Nothing
+/// spawns more of a species once its cap is reached.
+fn population_cap(species: &str) -> usize {
+    CONFIG.population_caps.iter()
+        .find(|&&(ref name, _)| name == species)
+        .map_or(CONFIG.default_population_cap, |&(_, cap)| cap)
+}
+
+/// The number of living monsters of each species on the level.
+fn population(objects: &[Object]) -> std::collections::BTreeMap<&str, usize> {
+    let mut counts = std::collections::BTreeMap::new();
+    for object in objects.iter().filter(|o| !o.removed && o.ai.is_some() && o.fighter.is_some()) {
+        *counts.entry(&object.name[..]).or_insert(0) += 1;
+    }
+    counts
+}
+
+fn can_spawn(species: &str, objects: &[Object]) -> bool {
+    let count = population(objects).get(species).cloned().unwrap_or(0);
+    count < population_cap(species)
+}
+
+/// A* path between two points going only through explored, walkable tiles
+/// and doors that aren't locked, preferring easy ground and keeping out of
+/// lava and deep water. Returns `None` when there is no such path.
+fn travel_path<'a>(from: (i32, i32), to: (i32, i32), map: &'a Map) -> Option<AStar<'a>> {
+    let mut path = AStar::new_from_callback(CONFIG.map_width, CONFIG.map_height, move |_from, (x, y)| {
+        let tile = &map[x as usize][y as usize];
+        if tile.explored && !tile.blocked && !is_hazard(tile, Movement::walking()) {
+            tile.kind.move_cost() as f32 / ACTION_COST as f32
+        } else if tile.explored && tile.kind == TileKind::Door {
+            // opening it takes a step of its own
+            2.0
+        } else {
+            0.0
+        }
+    }, 1.41);
+    if path.find(from, to) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Pick the explored staircase closest to the player (by walking distance)
+/// and start travelling towards it.
+fn travel_to_stairs(tcod: &mut Tcod, objects: &[Object], game: &mut Game) {
+    let player_pos = objects[PLAYER].pos();
+    let closest = objects.iter()
+        .filter(|o| o.name.starts_with("stairs") && game.map[o.x as usize][o.y as usize].explored)
+        .filter_map(|o| travel_path(player_pos, o.pos(), &game.map).map(|path| (o.pos(), path.len())))
+        .min_by_key(|&(_, len)| len);
+    match closest {
+        Some((pos, _)) => tcod.travel_target = Some(pos),
+        None => game.log.add("You don't know the way to any stairs.", colors::WHITE),
+    }
+}
+
+/// Take one step of auto-travel. The travel stops when the destination is
+/// reached, the way is blocked or a monster comes into view.
+fn auto_travel_step(tcod: &mut Tcod, objects: &mut [Object], game: &mut Game) -> PlayerAction {
+    let target = match tcod.travel_target {
+        Some(target) => target,
+        None => return PlayerAction::DidntTakeTurn,
+    };
+    let monster_in_view = objects.iter().any(|o| {
+        o.ai.is_some() && o.is_hostile() && o.in_fov(&tcod.fov)
+    });
+    if monster_in_view {
+        game.log.add("You stop travelling, there's a monster nearby.", colors::LIGHT_RED);
+        tcod.travel_target = None;
+        tcod.travel_route.clear();
+        return PlayerAction::DidntTakeTurn;
+    }
+
+    let player_pos = objects[PLAYER].pos();
+    let next_step = travel_path(player_pos, target, &game.map).and_then(|mut path| path.walk_one_step(false));
+    let warning = next_step.and_then(|(x, y)| {
+        action_warning(Action::MoveOrAttack(x - player_pos.0, y - player_pos.1), objects, game)
+    });
+    if let Some(warning) = warning {
+        game.log.add(format!("You stop travelling. {}", warning), colors::LIGHT_RED);
+        tcod.travel_target = None;
+        tcod.travel_route.clear();
+        return PlayerAction::DidntTakeTurn;
+    }
+    match next_step {
+        Some((x, y)) if game.map[x as usize][y as usize].kind == TileKind::Door &&
+            game.map[x as usize][y as usize].blocked => {
+            open_door(x, y, game);
+            PlayerAction::TookTime(ACTION_COST)
+        }
+        Some((x, y)) if !is_blocked(x, y, &game.map, objects) => {
+            objects[PLAYER].set_pos(x, y);
+            if (x, y) == target {
+                tcod.travel_target = None;
+            }
+            PlayerAction::TookTime(step_cost(&game.map[x as usize][y as usize], objects[PLAYER].movement))
+        }
+        _ => {
+            tcod.travel_target = None;
+            tcod.travel_route.clear();
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+/// Take one step of fast travel: walk to the stairs to the next level on
+/// the route and take them once there. It stops like `auto_travel_step`
+/// does, or when the stairs won't take the player where the route goes.
+fn travel_step(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) -> PlayerAction {
+    let next = match tcod.travel_route.first() {
+        Some(&next) if tcod.travel_target.is_none() => next,
+        _ => return auto_travel_step(tcod, objects, game),
+    };
+    let name = stairs_to(game.depth(), next);
+    let stairs = objects.iter().find(|o| !o.removed && o.name == name).map(|o| o.pos());
+    match stairs {
+        Some(pos) if pos == objects[PLAYER].pos() => {
+            tcod.travel_route.remove(0);
+            let action = if name == "up stairs" { Action::Ascend } else { Action::Descend };
+            let taken = perform_action(action, objects, game, &mut tcod.fov);
+            if game.depth() != next {
+                game.log.add("You stop travelling, the stairs don't lead on.", colors::LIGHT_RED);
+                tcod.travel_route.clear();
+            } else if tcod.travel_route.is_empty() {
+                game.log.add(format!("You arrive on {}.", next.title()), colors::LIGHT_GREY);
+            }
+            taken
+        }
+        Some(pos) => {
+            tcod.travel_target = Some(pos);
+            auto_travel_step(tcod, objects, game)
+        }
+        None => {
+            game.log.add("You stop travelling, you can't find the way on.", colors::LIGHT_RED);
+            tcod.travel_route.clear();
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+/// The name of the stairs that lead from the level `from` to the level `to`
+/// next to it.
+fn stairs_to(from: Depth, to: Depth) -> String {
+    if to.branch == from.branch && to.level == from.level + 1 {
+        "stairs".into()
+    } else if to.branch != from.branch && to.level == 1 {
+        to.branch.stairs_name()
+    } else {
+        "up stairs".into()
+    }
+}
+
+/// The levels the stairs lead through from `from` to `to`, `to` last: up
+/// to the level both are below, then down.
+fn stairs_route(from: Depth, to: Depth, rules: &rules::Rules) -> Vec<Depth> {
+    let from_town = |depth: Depth| -> Vec<Depth> {
+        let mut levels: Vec<Depth> = match rules.branch(depth.branch) {
+            Some(branch) => (0..branch.entry_level + 1).map(|level| Depth::new(Branch::Main, level)).collect(),
+            None => vec![],
+        };
+        let first = if depth.branch == Branch::Main { 0 } else { 1 };
+        levels.extend((first..depth.level + 1).map(|level| Depth::new(depth.branch, level)));
+        levels
+    };
+    let (up, down) = (from_town(from), from_town(to));
+    let shared = up.iter().zip(down.iter()).take_while(|&(a, b)| a == b).count();
+    up[shared - 1..up.len() - 1].iter().rev().chain(down[shared..].iter()).cloned().collect()
+}
+
+/// The levels the player can fast travel to from here: the ones they've
+/// been to, with only cleared levels (or the town) on the way.
+fn travel_destinations(game: &Game) -> Vec<Depth> {
+    let here = game.depth();
+    let passable = |depth: &Depth| *depth == Depth::town() || game.cleared_levels.contains(depth);
+    let mut destinations: Vec<Depth> = game.levels.depths().into_iter()
+        .filter(|&depth| depth != here)
+        .filter(|&depth| {
+            let route = stairs_route(here, depth, game.rules());
+            route[..route.len() - 1].iter().all(|depth| passable(depth))
+        })
+        .collect();
+    destinations.sort_by_key(|depth| (Branch::all().iter().position(|&branch| branch == depth.branch), depth.level));
+    destinations
+}
+
+/// Let the player pick a level to fast travel to, and set off.
+fn fast_travel_menu(tcod: &mut Tcod, game: &mut Game) {
+    let destinations = travel_destinations(game);
+    if destinations.is_empty() {
+        game.log.add("There's nowhere you know the way to through cleared levels.", colors::WHITE);
+        return;
+    }
+    let options: Vec<String> = destinations.iter().map(|depth| depth.title()).collect();
+    if let Some(index) = menu("Travel where?\n", &options, INVENTORY_WIDTH, tcod) {
+        let destination = destinations[index];
+        game.log.add(format!("You set off for {}.", destination.title()), colors::LIGHT_GREY);
+        tcod.travel_route = stairs_route(game.depth(), destination, game.rules());
+    }
+}
+
+/// Which way to attack: towards an enemy next to the player, or else one
+/// step towards the closest enemy in view. Ties go to the enemy that comes
+/// first in reading order, so the same situation always picks the same one.
+fn auto_attack_direction(objects: &[Object], game: &Game, fov_map: &FovMap) -> Option<(i32, i32)> {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let mut enemies: Vec<&Object> = objects.iter()
+        .filter(|o| o.fighter.is_some() && o.ai.is_some() && o.is_hostile() && !o.removed && o.in_fov(fov_map))
+        .collect();
+    enemies.sort_by_key(|o| (o.y, o.x));
+
+    let adjacent = enemies.iter()
+        .map(|o| o.nearest_tile(player_x, player_y))
+        .find(|&(x, y)| (x - player_x).abs() <= 1 && (y - player_y).abs() <= 1);
+    if let Some((x, y)) = adjacent {
+        return Some((x - player_x, y - player_y));
+    }
+    enemies.iter()
+        .filter_map(|enemy| {
+            let mut path = match travel_path((player_x, player_y), enemy.pos(), &game.map) {
+                Some(path) => path,
+                None => return None,
+            };
+            let length = path.len();
+            path.walk_one_step(false).map(|step| (length, step))
+        })
+        .min_by_key(|&(length, _)| length)
+        .map(|(_, (x, y))| (x - player_x, y - player_y))
+}
+
+/// Whether the object `npc_id` is there and offers `service`.
+fn offers(npc_id: usize, service: Service, objects: &[Object]) -> bool {
+    objects.get(npc_id).map_or(false, |npc| !npc.removed && npc.service == Some(service))
+}
+
+fn is_equipped(item: &Object) -> bool {
+    item.equipment.map_or(false, |e| e.equipped)
+}
+
+/// how much of an item's price the shop asks for, less during a festival
+fn buy_price_percent(game: &Game) -> u32 {
+    if game.town_event == Some(TownEvent::Festival) {
+        100 - FESTIVAL_DISCOUNT_PERCENT
+    } else {
+        100
+    }
+}
+
+/// Buy the shop's item at `index`, if the player can pay for it.
+fn buy(npc_id: usize, index: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Shop, objects) || index >= objects[npc_id].inventory.len() {
+        return false;
+    }
+    let price = objects[npc_id].inventory[index].price() * buy_price_percent(game) / 100;
+    if objects[PLAYER].gold < price {
+        game.log.add(format!("You can't afford the {}.", objects[npc_id].inventory[index].name), colors::RED);
+        return false;
+    }
+    if objects[PLAYER].inventory.len() >= INVENTORY_SIZE {
+        game.log.add("Your inventory is full.", colors::RED);
+        return false;
+    }
+    let item = objects[npc_id].inventory.remove(index);
+    game.log.add(format!("You buy a {} for {} gold.", item.name, price), colors::GOLD);
+    objects[PLAYER].gold -= price;
+    game.stats.purchases += 1;
+    objects[PLAYER].inventory.push(item);
+    true
+}
+
+/// Give the player gold, and count it towards the score.
+fn earn_gold(amount: u32, objects: &mut [Object], game: &mut Game) {
+    objects[PLAYER].gold += amount;
+    game.stats.gold_earned += amount;
+}
+
+/// Sell the player's item at `inventory_id` to a shop, which puts it on
+/// sale at the full price.
+fn sell(npc_id: usize, inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Shop, objects) || inventory_id >= objects[PLAYER].inventory.len() {
+        return false;
+    }
+    if is_equipped(&objects[PLAYER].inventory[inventory_id]) {
+        game.log.add(format!("You have to take off the {} first.", objects[PLAYER].inventory[inventory_id].name),
+                     colors::WHITE);
+        return false;
+    }
+    let item = objects[PLAYER].inventory.remove(inventory_id);
+    let price = item.price() * SELL_PRICE_PERCENT / 100;
+    game.log.add(format!("You sell the {} for {} gold.", item.name, price), colors::GOLD);
+    earn_gold(price, objects, game);
+    objects[npc_id].inventory.push(item);
+    true
+}
+
+/// what the healer asks for closing all of the player's wounds
+fn healing_price(player: &Object) -> u32 {
+    let wounds = player.max_hp() - player.fighter.map_or(0, |f| f.hp);
+    cmp::max(wounds, 0) as u32 * HEALING_PRICE_PER_HP
+}
+
+fn pay_healer(npc_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Healer, objects) {
+        return false;
+    }
+    let price = healing_price(&objects[PLAYER]);
+    if price == 0 {
+        game.log.add("You are not hurt.", colors::WHITE);
+        return false;
+    }
+    if objects[PLAYER].gold < price {
+        game.log.add(format!("You can't afford the {} gold for healing.", price), colors::RED);
+        return false;
+    }
+    objects[PLAYER].gold -= price;
+    let max_hp = objects[PLAYER].max_hp();
+    objects[PLAYER].heal(max_hp);
+    game.log.add(format!("The healer closes your wounds for {} gold.", price), colors::LIGHT_VIOLET);
+    true
+}
+
+/// what the healer asks for drawing out the poison, the more the worse it is
+fn cure_price(player: &Object) -> u32 {
+    player.poison * CURE_PRICE_PER_TURN
+}
+
+fn pay_for_cure(npc_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Healer, objects) {
+        return false;
+    }
+    let price = cure_price(&objects[PLAYER]);
+    if price == 0 {
+        game.log.add("You are not poisoned.", colors::WHITE);
+        return false;
+    }
+    if objects[PLAYER].gold < price {
+        game.log.add(format!("You can't afford the {} gold for the cure.", price), colors::RED);
+        return false;
+    }
+    objects[PLAYER].gold -= price;
+    objects[PLAYER].poison = 0;
+    game.log.add(format!("The healer draws the poison out for {} gold.", price), colors::LIGHT_VIOLET);
+    true
+}
+
+/// what the trainer asks for the next lesson, every lesson costs more
+fn training_price(game: &Game) -> u32 {
+    TRAINING_PRICE * (game.trainings + 1)
+}
+
+/// what the trainer asks for taking back a level-up
+fn relearning_price(game: &Game) -> u32 {
+    training_price(game) / 2
+}
+
+/// Have the trainer raise `stat` as much as a level-up would.
+fn train(npc_id: usize, stat: Stat, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Trainer, objects) {
+        return false;
+    }
+    let price = training_price(game);
+    if objects[PLAYER].gold < price {
+        game.log.add(format!("You can't afford the {} gold for a lesson.", price), colors::RED);
+        return false;
+    }
+    objects[PLAYER].gold -= price;
+    game.trainings += 1;
+    add_to_stat(stat, 1, objects[PLAYER].fighter.as_mut().unwrap(), game.rules());
+    game.log.add(format!("The trainer drills you for {} gold, your {} improves.", price, stat), colors::YELLOW);
+    true
+}
+
+/// Take back what the level-up at `index` raised and raise `stat` instead.
+fn relearn(npc_id: usize, index: usize, stat: Stat, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Trainer, objects) || index >= game.level_ups.len() {
+        return false;
+    }
+    let old_stat = game.level_ups[index];
+    if old_stat == stat {
+        game.log.add(format!("You raised your {} that time already.", stat), colors::WHITE);
+        return false;
+    }
+    let price = relearning_price(game);
+    if objects[PLAYER].gold < price {
+        game.log.add(format!("You can't afford the {} gold for relearning.", price), colors::RED);
+        return false;
+    }
+    objects[PLAYER].gold -= price;
+    game.trainings += 1;
+    game.level_ups[index] = stat;
+    {
+        let fighter = objects[PLAYER].fighter.as_mut().unwrap();
+        add_to_stat(old_stat, -1, fighter, game.rules());
+        add_to_stat(stat, 1, fighter, game.rules());
+    }
+    game.log.add(format!("For {} gold the trainer turns your {} into {}.", price, old_stat, stat), colors::YELLOW);
+    true
+}
+
+/// Have the hunter put a bounty on a few trophies of a kind.
+fn take_bounty(npc_id: usize, objects: &[Object], game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Hunter, objects) || game.bounty.is_some() || TROPHIES.is_empty() {
+        return false;
+    }
+    let trophy = &TROPHIES[game_rng().gen_range(0, TROPHIES.len())];
+    let count = game_rng().gen_range(BOUNTY_MIN_TROPHIES, BOUNTY_MAX_TROPHIES + 1);
+    let reward = trophy.price * count * BOUNTY_PRICE_PERCENT / 100;
+    game.log.add(format!("\"Bring me {} {}s, and {} gold is yours.\"", count, trophy.name, reward),
+                 colors::WHITE);
+    game.bounty = Some(Bounty { trophy: trophy.name.clone(), count: count, reward: reward });
+    true
+}
+
+/// how many of the trophies the bounty asks for the player carries
+fn bounty_trophies(bounty: &Bounty, player: &Object) -> u32 {
+    player.inventory.iter()
+        .filter(|item| item.item == Some(Item::Trophy) && item.name == bounty.trophy)
+        .count() as u32
+}
+
+/// Hand the hunter the trophies of the bounty, for the reward.
+fn claim_bounty(npc_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    let bounty = match game.bounty.clone() {
+        Some(bounty) if offers(npc_id, Service::Hunter, objects) => bounty,
+        _ => return false,
+    };
+    let carried = bounty_trophies(&bounty, &objects[PLAYER]);
+    if carried < bounty.count {
+        game.log.add(format!("You only have {} of the {} {}s.", carried, bounty.count, bounty.trophy), colors::RED);
+        return false;
+    }
+    let mut left = bounty.count;
+    objects[PLAYER].inventory.retain(|item| {
+        let handed_in = left > 0 && item.item == Some(Item::Trophy) && item.name == bounty.trophy;
+        if handed_in {
+            left -= 1;
+        }
+        !handed_in
+    });
+    earn_gold(bounty.reward, objects, game);
+    game.bounty = None;
+    game.log.add(format!("The hunter takes the {} {}s and pays you {} gold.", bounty.count, bounty.trophy,
+                         bounty.reward), colors::GOLD);
+    true
+}
+
+/// Cut the trophy from the remains `corpse_id`, if there's one left worth
+/// taking. Either way the remains have nothing more to give.
+fn butcher(corpse_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    let trophy = match objects.get(corpse_id).and_then(trophy_of) {
+        Some(trophy) if objects[corpse_id].distance_to(&objects[PLAYER]) < 1.5 => trophy,
+        _ => {
+            game.log.add("There is nothing here to butcher.", colors::RED);
+            return false;
+        }
+    };
+    let name = objects[corpse_id].name.clone();
+    objects[corpse_id].name = format!("butchered {}", name);
+    if game_rng().gen_range(0, 100) >= trophy.chance {
+        game.log.add(format!("You butcher the {}, but there's nothing worth taking.", name), colors::LIGHT_GREY);
+        return true;
+    }
+    let (x, y) = objects[PLAYER].pos();
+    let item = create_trophy(trophy, x, y);
+    if objects[PLAYER].inventory.len() >= INVENTORY_SIZE {
+        game.log.add(format!("You cut off a {}, but have no room for it.", item.name), colors::YELLOW);
+        objects.insert_object(item, &mut game.free_slots);
+    } else {
+        game.log.add(format!("You cut off a {}.", item.name), colors::GREEN);
+        objects[PLAYER].inventory.push(item);
+    }
+    true
+}
+
+/// Leave the player's item at `inventory_id` in the stash.
+fn deposit(npc_id: usize, inventory_id: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Stash, objects) || inventory_id >= objects[PLAYER].inventory.len() {
+        return false;
+    }
+    if is_equipped(&objects[PLAYER].inventory[inventory_id]) {
+        game.log.add(format!("You have to take off the {} first.", objects[PLAYER].inventory[inventory_id].name),
+                     colors::WHITE);
+        return false;
+    }
+    if game.stash.len() >= STASH_CAPACITY {
+        game.log.add("The stash is full.", colors::RED);
+        return false;
+    }
+    let item = objects[PLAYER].inventory.remove(inventory_id);
+    game.log.add(format!("You put the {} in the stash.", item.name), colors::WHITE);
+    game.stash.push(item);
+    true
+}
+
+/// Take the stash's item at `index` back.
+fn withdraw(npc_id: usize, index: usize, objects: &mut Vec<Object>, game: &mut Game) -> bool {
+    if !offers(npc_id, Service::Stash, objects) || index >= game.stash.len() {
+        return false;
+    }
+    if objects[PLAYER].inventory.len() >= INVENTORY_SIZE {
+        game.log.add("Your inventory is full.", colors::RED);
+        return false;
+    }
+    let item = game.stash.remove(index);
+    game.log.add(format!("You take the {} out of the stash.", item.name), colors::WHITE);
+    objects[PLAYER].inventory.push(item);
+    true
+}
+
+fn player_move_or_attack(dx: i32, dy: i32, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    // the coordinates the player is moving to/attacking
+    let x = objects[PLAYER].x + dx;
+    let y = objects[PLAYER].y + dy;
+
+    // try to find an attackable object there (not the player, who stays
+    // put when moving by (0, 0))
+    let target_id = objects.iter().enumerate().position(|(id, object)| {
+        id != PLAYER && object.fighter.is_some() && object.occupies(x, y)
+    });
+
+    // attack if target found, move otherwise
+    match target_id {
+        Some(target_id) if objects[target_id].is_hostile() => {
+            player_attack(target_id, objects, game, fov_map);
+        }
+        Some(target_id) if objects[target_id].disposition == Disposition::Friendly => {
+            swap_places(target_id, objects, game);
+        }
+        Some(target_id) => {
+            game.log.add(format!("The {} pays you no mind.", objects[target_id].name), colors::LIGHT_GREY);
+        }
+        None if x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+            game.map[x as usize][y as usize].is_door() && game.map[x as usize][y as usize].blocked => {
+            open_door(x, y, game);
+        }
+        None => {
+            move_by(PLAYER, dx, dy, &game.map, objects);
+        }
+    }
+}
+
+/// The player hits the creature, which turns on them if it wasn't hostile
+/// already.
+fn player_attack(target_id: usize, objects: &mut [Object], game: &mut Game, fov_map: &FovMap) {
+    if !objects[target_id].is_hostile() {
+        objects[target_id].disposition = Disposition::Hostile;
+        game.log.add(format!("The {} turns on you!", objects[target_id].name), colors::ORANGE);
+    }
+    let xp = {
+        let (player, target) = mut_two(PLAYER, target_id, objects);
+        player.attack(target, game)
+    };
+    if let Some(xp) = xp {
+        count_kill(PLAYER, xp, game);
+    }
+    award_xp(PLAYER, xp.unwrap_or(0), objects, game);
+    // the fight can be heard around
+    let (x, y) = objects[target_id].pos();
+    make_noise(x, y, COMBAT_LOUDNESS, objects, game, fov_map);
+}
+
+/// The player and the friendly creature in their way trade places, if it
+/// can stand where the player does.
+fn swap_places(friend_id: usize, objects: &mut [Object], game: &mut Game) {
+    let (player_x, player_y) = objects[PLAYER].pos();
+    let (x, y) = objects[friend_id].pos();
+    let fits = objects[friend_id].size == 1 &&
+        can_enter(&game.map[player_x as usize][player_y as usize], objects[friend_id].movement) &&
+        can_enter(&game.map[x as usize][y as usize], objects[PLAYER].movement);
+    if !fits {
+        game.log.add(format!("The {} is in your way.", objects[friend_id].name), colors::LIGHT_GREY);
+        return;
+    }
+    objects[friend_id].set_pos(player_x, player_y);
+    objects[PLAYER].set_pos(x, y);
+    game.log.add(format!("You swap places with the {}.", objects[friend_id].name), colors::LIGHT_GREY);
+}
+
+/// The player opens the closed door at (x, y), or tries to force its lock.
+fn open_door(x: i32, y: i32, game: &mut Game) {
+    let door = &mut game.map[x as usize][y as usize];
+    if door.kind == TileKind::LockedDoor {
+        if game_rng().gen::<f32>() < FORCE_LOCK_CHANCE {
+            door.set_door_open(true);
+            game.log.add("You force the lock and the door swings open.", colors::LIGHT_GREEN);
+        } else {
+            game.log.add("The door is locked. You throw your weight against it.", colors::LIGHT_GREY);
+        }
+    } else {
+        door.set_door_open(true);
+        game.log.add("You open the door.", colors::LIGHT_GREY);
+    }
+}
+
+/// Whether the player can close the door at (x, y): it's open and there's
+/// nothing in the doorway.
+fn can_close_door(x: i32, y: i32, objects: &[Object], map: &Map) -> bool {
+    let tile = &map[x as usize][y as usize];
+    tile.is_door() && !tile.blocked && !objects.iter().any(|o| !o.removed && o.occupies(x, y))
+}
+
+/// The key that picks an option: a-z, then A-Z. The options after those can
+/// still be picked by their number or with the arrow keys.
+fn menu_hotkey(index: usize) -> Option<char> {
+    if index < 26 {
+        Some((b'a' + index as u8) as char)
+    } else if index < 52 {
+        Some((b'A' + (index - 26) as u8) as char)
+    } else {
+        None
+    }
+}
+
+/// the option picked by a hotkey, if any
+fn menu_index(key: char, num_options: usize) -> Option<usize> {
+    if !(key.is_ascii() && key.is_alphabetic()) {
+        return None;
+    }
+    let index = if num_options <= 26 {
+        // short menus take either case
+        key.to_ascii_lowercase() as usize - 'a' as usize
+    } else if key.is_lowercase() {
+        key as usize - 'a' as usize
+    } else {
+        key as usize - 'A' as usize + 26
+    };
+    if index < num_options {
+        Some(index)
+    } else {
+        None
+    }
+}
+
+/// Show a menu and return the index of the chosen option. An option can be
+/// picked with its letter, by typing its number, or by moving the highlight
+/// with the arrow keys and pressing Enter. Menus too long for the screen
+/// scroll. Escape or any other key cancels.
+fn menu<T: AsRef<str>>(header: &str, options: &[T], width: i32,
+                       tcod: &mut Tcod) -> Option<usize> {
+    menu_within(header, options, width, None, tcod).unwrap_or(None)
+}
+
+/// Wait for a key press, but no longer than `timeout`.
+fn wait_for_keypress_within(timeout: Duration, tcod: &mut Tcod) -> Option<Key> {
+    let start = Instant::now();
+    while start.elapsed() < timeout && !tcod.backend.window_closed() {
+        if let Some(Event::Key(key)) = tcod.backend.check_for_event() {
+            return Some(key);
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+/// `menu`, giving up with `None` when no key is pressed for `idle`.
+fn menu_within<T: AsRef<str>>(header: &str, options: &[T], width: i32, idle: Option<Duration>,
+                              tcod: &mut Tcod) -> Option<Option<usize>> {
+    use tcod::input::KeyCode::{Up, Down, PageUp, PageDown, Enter, NumPadEnter, Escape, Backspace};
+
+    // calculate total height for the header (after auto-wrap) and one line per option
+    let header_height = if header.is_empty() {
+        0
+    } else {
+        wrap_text(header, width).len() as i32
+    };
+    // long menus show a page of options and a line saying where we are
+    let page = cmp::max(1, cmp::min(options.len() as i32, CONFIG.screen_height - header_height - 1));
+    let scrolls = options.len() as i32 > page;
+    let height = header_height + cmp::min(options.len() as i32, page) + if scrolls { 1 } else { 0 };
+    let x = CONFIG.screen_width / 2 - width / 2;
+    let y = CONFIG.screen_height / 2 - height / 2;
+
+    let background = tcod.root.clone();
+    let mut selected = 0;
+    let mut number = String::new();
+    loop {
+        // create an off-screen buffer that represents the menu's window
+        let mut window = Screen::new(width, height);
+
+        // print the header, with auto-wrap
+        window.print_wrapped(0, 0, width, header, colors::WHITE);
+
+        // print the options on the highlighted option's page
+        let first = selected - selected % page as usize;
+        for (row, index) in (first..cmp::min(first + page as usize, options.len())).enumerate() {
+            let label = match menu_hotkey(index) {
+                Some(hotkey) => format!("({}) {}", hotkey, options[index].as_ref()),
+                None => format!("({}) {}", index + 1, options[index].as_ref()),
+            };
+            let row = header_height + row as i32;
+            if index == selected {
+                window.fill_background(0, row, width, 1, colors::DARKER_SKY);
+            }
+            window.print(0, row, &label, colors::WHITE);
+        }
+        if scrolls {
+            let position = format!("{}-{} of {} {}", first + 1, cmp::min(first + page as usize, options.len()),
+                                   options.len(), number);
+            window.print(0, height - 1, &position, colors::LIGHT_GREY);
+        }
+
+        // blit the contents of "window" to the root screen
+        tcod.root = background.clone();
+        window.blit(&mut tcod.root, x, y, 0.7);
+
+        // present the root screen to the player and wait for a key-press
+        tcod.flush();
+        let key = match idle {
+            Some(idle) => match wait_for_keypress_within(idle, tcod) {
+                Some(key) => key,
+                None if tcod.backend.window_closed() => return Some(None),
+                None => return None,
+            },
+            None => tcod.backend.wait_for_keypress(),
+        };
+        if options.is_empty() || tcod.backend.window_closed() {
+            // a message box: any key closes it
+            return Some(None);
+        }
+
+        let count = options.len();
+        match key.code {
+            Up => selected = (selected + count - 1) % count,
+            Down => selected = (selected + 1) % count,
+            PageUp => selected = selected.saturating_sub(page as usize),
+            PageDown => selected = cmp::min(selected + page as usize, count - 1),
+            Enter | NumPadEnter => {
+                match number.parse::<usize>() {
+                    Ok(n) if n >= 1 && n <= count => return Some(Some(n - 1)),
+                    _ if number.is_empty() => return Some(Some(selected)),
+                    _ => number.clear(),
+                }
+            }
+            Escape => return Some(None),
+            Backspace if !number.is_empty() => {
+                number.pop();
+            }
+            _ if key.printable.is_digit(10) => {
+                number.push(key.printable);
+                match number.parse::<usize>() {
+                    // no more digits could make a valid choice: take it right away
+                    Ok(n) if n >= 1 && n <= count && n * 10 > count => return Some(Some(n - 1)),
+                    Ok(n) if n >= 1 && n <= count => selected = n - 1,
+                    _ => number.clear(),
+                }
+            }
+            // convert the key to an index; if it corresponds to an option, return it.
+            // anything else cancels the menu
+            _ => return Some(menu_index(key.printable, count)),
+        }
+    }
+}
+
+fn inventory_menu(inventory: &[Object], header: &str, tcod: &mut Tcod) -> Option<usize> {
+    // how a menu with each item of the inventory as an option
+    let options = if inventory.len() == 0 {
+        vec!["Inventory is empty.".into()]
+    } else {
+        inventory.iter().map(|item| {
+            // show additional information, in case it's equipped
+            match item.equipment {
+                Some(equipment) if equipment.equipped => {
+                    format!("{} (on {})", item.name, equipment.slot)
+                }
+                _ => match item.charges {
+                    Some(charges) => format!("{} ({} charges)", item.name, charges),
+                    None => item.name.clone(),
+                }
+            }
+        }).collect()
+    };
+
+    let inventory_index = menu(header, &options, INVENTORY_WIDTH, tcod);
+
+    // if an item was chosen, return it
+    if inventory.len() > 0 {
+        inventory_index
+    } else {
+        None
+    }
+}
+
+fn msgbox(text: &str, width: i32, tcod: &mut Tcod) {
+    let options: &[&str] = &[];
+    menu(text, options, width, tcod);
+}
+
+/// What the player asked for by pressing a key. The keymap turns the
+/// backend's key presses into these, so nothing below it needs to know which
+/// key does what.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InputAction {
+    ToggleFullscreen,
+    Exit,
+    ToggleProfiler,
+    Move(i32, i32),
+    Wait,
+    PickUp,
+    UseItem,
+    DropItem,
+    Descend,
+    Ascend,
+    Examine,
+    TravelToStairs,
+    ToggleTorch,
+    Search,
+    Disarm,
+    Act,
+    AttackNearest,
+    OrderCompanion,
+    CharacterScreen,
+    MapOverview,
+    DebugConsole,
+}
+
+impl InputAction {
+    /// every action a key can be bound to, in the order the key binding
+    /// screen lists them
+    pub fn all() -> Vec<InputAction> {
+        use InputAction::*;
+        vec![Move(0, -1), Move(1, -1), Move(1, 0), Move(1, 1),
+             Move(0, 1), Move(-1, 1), Move(-1, 0), Move(-1, -1), Wait, AttackNearest,
+             PickUp, UseItem, DropItem, Descend, Ascend, Examine, TravelToStairs, ToggleTorch, Search,
+             Disarm, Act, OrderCompanion, CharacterScreen, MapOverview,
+             ToggleFullscreen, ToggleProfiler, DebugConsole, Exit]
+    }
+
+    /// the name of the action in keys.toml
+    pub fn id(&self) -> &'static str {
+        use InputAction::*;
+        match *self {
+            ToggleFullscreen => "toggle_fullscreen",
+            Exit => "exit",
+            ToggleProfiler => "toggle_profiler",
+            Move(0, -1) => "move_north",
+            Move(1, -1) => "move_northeast",
+            Move(1, 0) => "move_east",
+            Move(1, 1) => "move_southeast",
+            Move(0, 1) => "move_south",
+            Move(-1, 1) => "move_southwest",
+            Move(-1, 0) => "move_west",
+            Move(-1, -1) => "move_northwest",
+            Move(..) => "move",
+            Wait => "wait",
+            PickUp => "pick_up",
+            UseItem => "use_item",
+            DropItem => "drop_item",
+            Descend => "descend",
+            Ascend => "ascend",
+            Examine => "examine",
+            TravelToStairs => "travel_to_stairs",
+            ToggleTorch => "toggle_torch",
+            Search => "search",
+            Disarm => "disarm",
+            Act => "act",
+            AttackNearest => "attack_nearest",
+            OrderCompanion => "order_companion",
+            CharacterScreen => "character_screen",
+            MapOverview => "map_overview",
+            DebugConsole => "debug_console",
+        }
+    }
+
+    /// what the key binding screen calls the action
+    pub fn describe(&self) -> String {
+        let id = self.id().replace('_', " ");
+        let mut chars = id.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect(),
+            None => id,
+        }
+    }
+}
+
+/// The names of the special keys in keys.toml
+const KEY_NAMES: &'static [(&'static str, input::KeyCode)] = &[
+    ("Escape", input::KeyCode::Escape), ("Backspace", input::KeyCode::Backspace),
+    ("Tab", input::KeyCode::Tab), ("Enter", input::KeyCode::Enter),
+    ("Space", input::KeyCode::Spacebar), ("Pause", input::KeyCode::Pause),
+    ("Insert", input::KeyCode::Insert), ("Delete", input::KeyCode::Delete),
+    ("Home", input::KeyCode::Home), ("End", input::KeyCode::End),
+    ("PageUp", input::KeyCode::PageUp), ("PageDown", input::KeyCode::PageDown),
+    ("Up", input::KeyCode::Up), ("Down", input::KeyCode::Down),
+    ("Left", input::KeyCode::Left), ("Right", input::KeyCode::Right),
+    ("0", input::KeyCode::Number0), ("1", input::KeyCode::Number1),
+    ("2", input::KeyCode::Number2), ("3", input::KeyCode::Number3),
+    ("4", input::KeyCode::Number4), ("5", input::KeyCode::Number5),
+    ("6", input::KeyCode::Number6), ("7", input::KeyCode::Number7),
+    ("8", input::KeyCode::Number8), ("9", input::KeyCode::Number9),
+    ("NumPad0", input::KeyCode::NumPad0), ("NumPad1", input::KeyCode::NumPad1),
+    ("NumPad2", input::KeyCode::NumPad2), ("NumPad3", input::KeyCode::NumPad3),
+    ("NumPad4", input::KeyCode::NumPad4), ("NumPad5", input::KeyCode::NumPad5),
+    ("NumPad6", input::KeyCode::NumPad6), ("NumPad7", input::KeyCode::NumPad7),
+    ("NumPad8", input::KeyCode::NumPad8), ("NumPad9", input::KeyCode::NumPad9),
+    ("NumPadAdd", input::KeyCode::NumPadAdd), ("NumPadSubtract", input::KeyCode::NumPadSubtract),
+    ("NumPadMultiply", input::KeyCode::NumPadMultiply), ("NumPadDivide", input::KeyCode::NumPadDivide),
+    ("NumPadDecimal", input::KeyCode::NumPadDecimal), ("NumPadEnter", input::KeyCode::NumPadEnter),
+    ("F1", input::KeyCode::F1), ("F2", input::KeyCode::F2), ("F3", input::KeyCode::F3),
+    ("F4", input::KeyCode::F4), ("F5", input::KeyCode::F5), ("F6", input::KeyCode::F6),
+    ("F7", input::KeyCode::F7), ("F8", input::KeyCode::F8), ("F9", input::KeyCode::F9),
+    ("F10", input::KeyCode::F10), ("F11", input::KeyCode::F11), ("F12", input::KeyCode::F12),
+];
+
+/// A key (or key combination) that can be bound to an `InputAction`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyPattern {
+    /// a special key, like the arrows or Escape
+    Code(input::KeyCode),
+    /// a special key pressed together with Alt
+    AltCode(input::KeyCode),
+    /// a printable character
+    Char(char),
+}
+
+impl KeyPattern {
+    pub fn matches(&self, key: Key) -> bool {
+        match *self {
+            KeyPattern::Code(code) => key.code == code,
+            KeyPattern::AltCode(code) => key.alt && key.code == code,
+            KeyPattern::Char(printable) => key.printable == printable,
+        }
+    }
+
+    /// The pattern for a key the player just pressed, `None` for a lone
+    /// modifier key.
+    pub fn from_key(key: Key) -> Option<KeyPattern> {
+        use tcod::input::KeyCode::*;
+        match key.code {
+            NoKey | Shift | Control | Alt | LeftWin | RightWin => None,
+            Char | Text => Some(KeyPattern::Char(key.printable)),
+            code if key.alt => Some(KeyPattern::AltCode(code)),
+            code => Some(KeyPattern::Code(code)),
+        }
+    }
+
+    /// read a key written by `name`
+    pub fn parse(name: &str) -> Option<KeyPattern> {
+        let code_named = |name: &str| KEY_NAMES.iter().find(|&&(n, _)| n == name).map(|&(_, code)| code);
+        if name.starts_with("Alt+") {
+            if let Some(code) = code_named(&name[4..]) {
+                return Some(KeyPattern::AltCode(code));
+            }
+        }
+        if let Some(code) = code_named(name) {
+            return Some(KeyPattern::Code(code));
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(printable), None) => Some(KeyPattern::Char(printable)),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        let code_name = |code| KEY_NAMES.iter().find(|&&(_, c)| c == code).map_or("?", |&(n, _)| n);
+        match *self {
+            KeyPattern::Code(code) => code_name(code).into(),
+            KeyPattern::AltCode(code) => format!("Alt+{}", code_name(code)),
+            KeyPattern::Char(printable) => printable.to_string(),
+        }
+    }
+}
+
+/// Which key does what. A key does one thing: binding it again takes it
+/// from whatever it did before, so the last action given a key keeps it.
+struct Keymap {
+    bindings: Vec<(KeyPattern, InputAction)>,
+}
+
+impl Keymap {
+    pub fn new() -> Self {
+        use tcod::input::KeyCode::*;
+        use KeyPattern::{Code, AltCode, Char};
+        use InputAction::*;
+
+        Keymap {
+            bindings: vec![
+                (AltCode(Enter), ToggleFullscreen),
+                (Code(Escape), Exit),
+                (Code(F3), ToggleProfiler),
+
+                // movement keys
+                (Code(Up), Move(0, -1)), (Code(NumPad8), Move(0, -1)),
+                (Code(Down), Move(0, 1)), (Code(NumPad2), Move(0, 1)),
+                (Code(Left), Move(-1, 0)), (Code(NumPad4), Move(-1, 0)),
+                (Code(Right), Move(1, 0)), (Code(NumPad6), Move(1, 0)),
+                (Code(Home), Move(-1, -1)), (Code(NumPad7), Move(-1, -1)),
+                (Code(PageUp), Move(1, -1)), (Code(NumPad9), Move(1, -1)),
+                (Code(End), Move(-1, 1)), (Code(NumPad1), Move(-1, 1)),
+                (Code(PageDown), Move(1, 1)), (Code(NumPad3), Move(1, 1)),
+                (Code(NumPad5), Wait),
+                (Code(Tab), AttackNearest),
+
+                (Char('g'), PickUp),
+                (Char('i'), UseItem),
+                (Char('d'), DropItem),
+                (Char('>'), Descend),
+                (Char('<'), Ascend),
+                (Char('x'), Examine),
+                (Char('T'), TravelToStairs),
+                (Char('t'), ToggleTorch),
+                (Char('s'), Search),
+                (Char('D'), Disarm),
+                (Char('a'), Act),
+                (Char('o'), OrderCompanion),
+                (Char('c'), CharacterScreen),
+                (Char('m'), MapOverview),
+                (Char('`'), DebugConsole),
+            ],
+        }
+    }
+
+    /// The default bindings with whatever keys.toml changes. An action
+    /// listed there gets exactly the keys it lists.
+    pub fn load(path: &str) -> Self {
+        let mut keymap = Keymap::new();
+        let mut text = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut text)).is_err() {
+            return keymap;
+        }
+        let doc = match text.parse::<toml::Value>() {
+            Ok(doc) => doc,
+            Err(e) => {
+                eprintln!("Ignoring {}, it's not valid TOML: {}", path, e);
+                return keymap;
+            }
+        };
+        let keys = doc.as_table().and_then(|doc| doc.get("keys")).and_then(|keys| keys.as_table());
+        for (id, names) in keys.into_iter().flat_map(|keys| keys.iter()) {
+            let action = match InputAction::all().into_iter().find(|action| action.id() == id) {
+                Some(action) => action,
+                None => {
+                    eprintln!("{}: there is no action called '{}'", path, id);
+                    continue;
+                }
+            };
+            keymap.clear(action);
+            for name in names.as_array().into_iter().flat_map(|names| names.iter()) {
+                match name.as_str().and_then(KeyPattern::parse) {
+                    Some(pattern) => keymap.bind(pattern, action),
+                    None => eprintln!("{}: don't know the key {} for '{}'", path, name, id),
+                }
+            }
+        }
+        keymap
+    }
+
+    /// Everything wrong with the key bindings file at `path`: unknown
+    /// actions and keys, and keys given to more than one action (only the
+    /// last one would get them). A missing file is fine.
+    pub fn check(path: &str) -> Vec<String> {
+        let mut text = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut text)).is_err() {
+            return vec![];
+        }
+        let doc = match text.parse::<toml::Value>() {
+            Ok(doc) => doc,
+            Err(e) => return vec![format!("{}: not valid TOML: {}", path, e)],
+        };
+        let mut problems = vec![];
+        let mut bound: Vec<(KeyPattern, &str)> = vec![];
+        let keys = doc.as_table().and_then(|doc| doc.get("keys")).and_then(|keys| keys.as_table());
+        for (id, names) in keys.into_iter().flat_map(|keys| keys.iter()) {
+            if !InputAction::all().into_iter().any(|action| action.id() == id) {
+                problems.push(format!("{}: there is no action called '{}'", path, id));
+            }
+            let names = match names.as_array() {
+                Some(names) => names,
+                None => {
+                    problems.push(format!("{}: the keys for '{}' should be a list", path, id));
+                    continue;
+                }
+            };
+            for name in names {
+                match name.as_str().and_then(KeyPattern::parse) {
+                    Some(pattern) => {
+                        if let Some(&(_, other)) = bound.iter().find(|&&(bound, _)| bound == pattern) {
+                            problems.push(format!("{}: {} is bound to both '{}' and '{}'",
+                                                  path, pattern.name(), other, id));
+                        }
+                        bound.push((pattern, id));
+                    }
+                    None => problems.push(format!("{}: don't know the key {} for '{}'", path, name, id)),
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), Box<Error>> {
+        let mut keys = std::collections::BTreeMap::new();
+        for action in InputAction::all() {
+            let names = self.keys_for(action).iter()
+                .map(|pattern| toml::Value::String(pattern.name()))
+                .collect();
+            keys.insert(action.id().to_string(), toml::Value::Array(names));
+        }
+        let mut doc = std::collections::BTreeMap::new();
+        doc.insert("keys".to_string(), toml::Value::Table(keys));
+        let mut file = try! { File::create(path) };
+        try! { file.write_all(toml::Value::Table(doc).to_string().as_bytes()) };
+        Ok(())
+    }
+
+    /// the action bound to a key press, if any
+    pub fn action(&self, key: Key) -> Option<InputAction> {
+        self.bindings.iter()
+            .find(|&&(pattern, _)| pattern.matches(key))
+            .map(|&(_, action)| action)
+    }
+
+    pub fn keys_for(&self, action: InputAction) -> Vec<KeyPattern> {
+        self.bindings.iter()
+            .filter(|&&(_, bound)| bound == action)
+            .map(|&(pattern, _)| pattern)
+            .collect()
+    }
+
+    /// bind a key to an action, taking it away from whatever it did before
+    pub fn bind(&mut self, pattern: KeyPattern, action: InputAction) {
+        self.bindings.retain(|&(bound, _)| bound != pattern);
+        self.bindings.push((pattern, action));
+    }
+
+    pub fn clear(&mut self, action: InputAction) {
+        self.bindings.retain(|&(_, bound)| bound != action);
+    }
+}
+
+/// Let the player change which keys do what, saving the result to keys.toml.
+fn key_bindings_screen(tcod: &mut Tcod) {
+    loop {
+        let actions = InputAction::all();
+        let mut options: Vec<String> = actions.iter().map(|&action| {
+            let keys: Vec<String> = tcod.keymap.keys_for(action).iter().map(|key| key.name()).collect();
+            format!("{:<18} {}", action.describe(), keys.join(", "))
+        }).collect();
+        options.push("Reset to defaults".into());
+        let choice = match menu("Pick an action to change its keys:\n", &options, 50, tcod) {
+            Some(choice) => choice,
+            None => break,
+        };
+        if choice == actions.len() {
+            tcod.keymap = Keymap::new();
+        } else {
+            let action = actions[choice];
+            let prompt = format!("\nPress a new key for {}.\nBackspace removes its keys, Escape cancels.\n",
+                                 action.describe());
+            let mut window = Screen::new(50, 4);
+            window.print_wrapped(0, 0, 50, &prompt, colors::WHITE);
+            window.blit(&mut tcod.root, CONFIG.screen_width / 2 - 25, CONFIG.screen_height / 2 - 2, 0.7);
+            tcod.flush();
+            let key = loop {
+                let key = tcod.backend.wait_for_keypress();
+                if KeyPattern::from_key(key).is_some() || tcod.backend.window_closed() {
+                    break key;
+                }
+            };
+            match key.code {
+                input::KeyCode::Escape => continue,
+                input::KeyCode::Backspace => tcod.keymap.clear(action),
+                _ => {
+                    if let Some(pattern) = KeyPattern::from_key(key) {
+                        tcod.keymap.bind(pattern, action);
+                    }
+                }
+            }
+        }
+        if let Err(e) = tcod.keymap.save(KEYMAP_PATH) {
+            msgbox(&format!("\nCould not save the key bindings: {}\n", e), 30, tcod);
+        }
+    }
+}
+
+fn handle_keys(input: InputAction, tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game)
+               -> PlayerAction {
+    use InputAction::*;
+    use PlayerAction::DidntTakeTurn;
+
+    let player_alive = objects[PLAYER].alive;
+    let action = match (input, player_alive) {
+        (ToggleFullscreen, _) => {
+            // Alt+Enter: toggle fullscreen
+            tcod.backend.toggle_fullscreen();
+            return DidntTakeTurn;
+        }
+        (Exit, _) => return PlayerAction::Exit,  // exit game
+        (ToggleProfiler, _) => {
+            // show or hide the timings of the game loop
+            tcod.show_profiler = !tcod.show_profiler;
+            return DidntTakeTurn;
+        }
+
+        (Move(dx, dy), true) => {
+            // bumping into one of the town's folk asks what they offer, into
+            // a neutral creature whether to pick a fight with it
+            let (x, y) = (objects[PLAYER].x + dx, objects[PLAYER].y + dy);
+            let npc_id = objects.iter().position(|o| o.pos() == (x, y) && o.service.is_some() && !o.removed);
+            let neutral_id = objects.iter().position(|o| {
+                o.occupies(x, y) && o.fighter.is_some() && o.disposition == Disposition::Neutral && !o.removed
+            });
+            let action = match (npc_id, neutral_id) {
+                (Some(npc_id), _) => visit(npc_id, tcod, objects, game),
+                (None, Some(neutral_id)) => greet(neutral_id, dx, dy, tcod, objects),
+                (None, None) => Some(Action::MoveOrAttack(dx, dy)),
+            };
+            match action {
+                Some(action) => action,
+                None => return DidntTakeTurn,
+            }
+        }
+        (Wait, true) => {
+            Action::Wait  // do nothing, i.e. wait for the monster to come to you
+        }
+
+        (AttackNearest, true) => {
+            // fight whoever is closest, without picking a direction
+            match auto_attack_direction(objects, game, &tcod.fov) {
+                Some((dx, dy)) => Action::MoveOrAttack(dx, dy),
+                None => {
+                    game.log.add("There is no enemy in sight.", colors::WHITE);
+                    return DidntTakeTurn;
+                }
+            }
+        }
+
+        (OrderCompanion, true) => {
+            match companion_order(tcod, objects, game) {
+                Some(order) => Action::Order(order),
+                None => return DidntTakeTurn,
+            }
+        }
+
+        (PickUp, true) => Action::PickUp,  // pick up an item
+
+        (UseItem, true) => {
+            // show the inventory: if an item is selected, use it
+            let inventory_index = inventory_menu(
+                &objects[PLAYER].inventory,
+                "Press the key next to an item to use it, or any other to cancel.\n",
+                tcod);
+            let inventory_index = match inventory_index {
+                Some(inventory_index) => inventory_index,
+                None => return DidntTakeTurn,
+            };
+            // ask for a target first, if the item needs one
+            let targeting = item_targeting(&objects[PLAYER].inventory[inventory_index], game.rules());
+            match choose_target(targeting, tcod, objects, game) {
+                Some(target) => Action::UseItem(inventory_index, target),
+                None => {
+                    game.log.add("Cancelled", colors::WHITE);
+                    return DidntTakeTurn;
+                }
+            }
+        }
+
+        (DropItem, true) => {
+            // show the inventory; if an item is selected, drop it
+            let inventory_index = inventory_menu(
+                &objects[PLAYER].inventory,
+                "Press the key next to an item to drop it, or any other to cancel.\n'",
+                tcod);
+            match inventory_index {
+                Some(inventory_index) => Action::DropItem(inventory_index),
+                None => return DidntTakeTurn,
+            }
+        }
+
+        (Descend, true) => Action::Descend,  // go down stairs
+        (Ascend, true) => Action::Ascend,  // go back up
+
+        (ToggleTorch, true) => Action::ToggleTorch,
+
+        (Search, true) => Action::Search,  // look for secret doors and traps
+
+        (Disarm, true) => Action::Disarm,
+
+        (Act, true) => {
+            // everything there is to do right around the player, in one menu
+            let interactions = interactions(objects, &game.map);
+            if interactions.is_empty() {
+                game.log.add("There is nothing around to do anything with.", colors::WHITE);
+                return DidntTakeTurn;
+            }
+            let options: Vec<&str> = interactions.iter().map(|&(ref name, _)| name.as_str()).collect();
+            let choice = menu("What do you want to do?\n", &options, INVENTORY_WIDTH, tcod);
+            match choice.map(|choice| interactions[choice].1) {
+                Some(Interaction::Visit(npc_id)) => match visit(npc_id, tcod, objects, game) {
+                    Some(action) => action,
+                    None => return DidntTakeTurn,
+                },
+                Some(Interaction::Do(action)) => action,
+                Some(Interaction::Travel) => {
+                    fast_travel_menu(tcod, game);
+                    return DidntTakeTurn;
+                }
+                None => return DidntTakeTurn,
+            }
+        }
+
+        (Examine, true) => {
+            // examine whatever is on a tile in view
+            game.log.add(targeting_hint("something to examine"), colors::LIGHT_CYAN);
+            if let Some((x, y)) = target_tile(tcod, objects, game, None, 0) {
+                examine(x, y, objects, game, &tcod.fov);
+            }
+            return DidntTakeTurn;
+        }
+
+        (TravelToStairs, true) => {
+            // travel to the nearest known stairs
+            travel_to_stairs(tcod, objects, game);
+            return DidntTakeTurn;
+        }
+
+        (CharacterScreen, true) => {
+            // show character information
+            let player = &objects[PLAYER];
+            let level = player.level;
+            let level_up_xp = level_up_xp(player, game.rules());
+            if let Some(fighter) = player.fighter.as_ref() {
+                let msg = format!("Character information
+
+Level: {}
+Experience: {}
+Experience to level up: {}
+
+Maximum HP: {}
+Attack: {}
+Defense: {}
+Luck: {}
+
+Score so far: {}
+Rules: {}
+Mutators: {}", level, fighter.xp, level_up_xp, player.max_hp(), player.power(), player.defense(),
+                                  player.luck(), total_score(game),
+                                  game.rule_set.name(), mutator_names(&game.mutators));
+                msgbox(&msg, CHARACTER_SCREEN_WIDTH, tcod);
+            }
+
+            return DidntTakeTurn;
+        }
+
+        (MapOverview, _) => {
+            show_overview(tcod, objects, game);
+            return DidntTakeTurn;
+        }
+
+        (DebugConsole, _) if options().debug => {
+            debug_console(tcod, objects, game);
+            return DidntTakeTurn;
+        }
+
+        _ => return DidntTakeTurn,
+    };
+
+    // ask before doing something the player would most likely regret
+    if let Some(warning) = action_warning(action, objects, game) {
+        let question = format!("\n{} Do it anyway?\n", warning);
+        if menu(&question, &["Yes", "No"], 40, tcod) != Some(0) {
+            return DidntTakeTurn;
+        }
+    }
+
+    let depth = game.depth();
+    let player_action = perform_action(action, objects, game, &mut tcod.fov);
+    if game.depth() != depth {
+        // a new level: unexplored areas start black again
+        tcod.con.clear();
+    }
+    player_action
+}
+
+/// Something the player can do with what's right around them, see `interactions`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Interaction {
+    /// see what the townsperson (or the stash) offers
+    Visit(usize),
+    Do(Action),
+    /// pick a level to go to by the stairs, see `fast_travel_menu`
+    Travel,
+}
+
+/// What the player can do with the things on their tile and the ones next
+/// to it, by what the act menu lists them as. Whatever new can be used in
+/// place goes in here rather than getting a key of its own.
+fn interactions(objects: &[Object], map: &Map) -> Vec<(String, Interaction)> {
+    let player = &objects[PLAYER];
+    let here = |o: &&Object| !o.removed && o.pos() == player.pos();
+    let near = |o: &&Object| !o.removed && o.distance_to(player) < 1.5;
+    let mut interactions = vec![];
+    for (id, npc) in objects.iter().enumerate().filter(|&(_, ref o)| near(o)) {
+        match npc.service {
+            Some(Service::Stash) => interactions.push((format!("Open the {}", npc.name), Interaction::Visit(id))),
+            Some(_) => interactions.push((format!("Talk to the {}", npc.name), Interaction::Visit(id))),
+            None if npc.fighter.is_some() && !npc.is_hostile() => {
+                let attack = Action::Attack(npc.x - player.x, npc.y - player.y);
+                interactions.push((format!("Attack the {}", npc.name), Interaction::Do(attack)));
+            }
+            None => {}
+        }
+    }
+    if let Some(item) = objects.iter().filter(here).find(|o| o.item.is_some()) {
+        interactions.push((format!("Pick up the {}", item.name), Interaction::Do(Action::PickUp)));
+    }
+    if let Some(altar_id) = objects.iter().position(|o| near(&o) && o.name == ALTAR_NAME) {
+        interactions.push(("Pray at the altar".into(), Interaction::Do(Action::Pray(altar_id))));
+    }
+    // the same trap `Action::Disarm` goes for
+    if let Some(trap) = objects.iter().filter(near).find(|o| o.trap.map_or(false, |trap| !trap.hidden)) {
+        interactions.push((format!("Disarm the {}", trap.name), Interaction::Do(Action::Disarm)));
+    }
+    if let Some(corpse_id) = objects.iter().position(|o| near(&o) && trophy_of(o).is_some()) {
+        interactions.push((format!("Butcher the {}", objects[corpse_id].name),
+                           Interaction::Do(Action::Butcher(corpse_id))));
+    }
+    let around = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (dx, dy))).filter(|&step| step != (0, 0));
+    for (dx, dy) in around {
+        let (x, y) = (player.x + dx, player.y + dy);
+        let in_map = x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height;
+        if !in_map || !map[x as usize][y as usize].is_door() {
+            continue;
+        }
+        if map[x as usize][y as usize].blocked {
+            interactions.push(("Open the door".into(), Interaction::Do(Action::MoveOrAttack(dx, dy))));
+        } else if can_close_door(x, y, objects, map) {
+            interactions.push(("Close the door".into(), Interaction::Do(Action::CloseDoor(x, y))));
+        }
+    }
+    if on_portal(objects) {
+        interactions.push(("Step through the portal".into(), Interaction::Do(Action::Descend)));
+    } else if let Some(stairs) = objects.iter().filter(here).find(|o| o.name.contains("stairs")) {
+        let action = if stairs.name == "up stairs" { Action::Ascend } else { Action::Descend };
+        interactions.push((format!("Take the {}", stairs.name), Interaction::Do(action)));
+        interactions.push(("Travel to a level you've been to".into(), Interaction::Travel));
+    }
+    interactions
+}
+
+/// Ask the player what to tell their companions on the level to do.
+fn companion_order(tcod: &mut Tcod, objects: &[Object], game: &mut Game) -> Option<Order> {
+    if !objects.iter().any(is_companion) {
+        game.log.add("You have no companion here.", colors::WHITE);
+        return None;
+    }
+    let options = &["Follow me", "Stay here", "Attack..."];
+    match menu("\nWhat do you tell your companion?\n", options, 30, tcod) {
+        Some(0) => Some(Order::Follow),
+        Some(1) => Some(Order::Stay),
+        Some(2) => {
+            game.log.add(targeting_hint("an enemy to attack"), colors::LIGHT_CYAN);
+            target_monster(tcod, objects, game, None).map(|id| Order::Attack(ObjectId::of(id, objects)))
+        }
+        _ => None,
+    }
+}
+
+/// Ask the player whether to attack the neutral creature they bumped into,
+/// at (dx, dy) from them.
+fn greet(creature_id: usize, dx: i32, dy: i32, tcod: &mut Tcod, objects: &[Object]) -> Option<Action> {
+    let question = format!("\nThe {} means you no harm.\n", objects[creature_id].name);
+    match menu(&question, &["Attack it", "Leave it be"], 40, tcod) {
+        Some(0) => Some(Action::Attack(dx, dy)),
+        _ => None,
+    }
+}
+
+/// Ask the player what they want from the one in town they bumped into.
+fn visit(npc_id: usize, tcod: &mut Tcod, objects: &[Object], game: &mut Game) -> Option<Action> {
+    let npc = &objects[npc_id];
+    let player = &objects[PLAYER];
+    let inventory_names = |items: &[Object], percent: u32| -> Vec<String> {
+        items.iter().map(|item| format!("{} ({} gold)", item.name, item.price() * percent / 100)).collect()
+    };
+    match npc.service {
+        Some(Service::Shop) if game.has_mutator(Mutator::NoShops) => {
+            game.log.add("\"Sorry, stranger, I'm not trading with you.\"", colors::WHITE);
+            None
+        }
+        Some(Service::Shop) => {
+            let header = format!("\"Welcome, stranger!\" You have {} gold.\n", player.gold);
+            match menu(&header, &["Buy", "Sell"], INVENTORY_WIDTH, tcod) {
+                Some(0) if npc.inventory.is_empty() => {
+                    game.log.add("\"Sorry, I'm all sold out.\"", colors::WHITE);
+                    None
+                }
+                Some(0) => {
+                    let options = inventory_names(&npc.inventory, buy_price_percent(game));
+                    menu("Buy which item?\n", &options, INVENTORY_WIDTH, tcod)
+                        .map(|index| Action::Buy(npc_id, index))
+                }
+                Some(1) if player.inventory.is_empty() => {
+                    game.log.add("You have nothing to sell.", colors::WHITE);
+                    None
+                }
+                Some(1) => {
+                    let options = inventory_names(&player.inventory, SELL_PRICE_PERCENT);
+                    menu("Sell which item?\n", &options, INVENTORY_WIDTH, tcod)
+                        .map(|inventory_id| Action::Sell(npc_id, inventory_id))
+                }
+                _ => None,
+            }
+        }
+        Some(Service::Healer) => {
+            let (healing, cure) = (healing_price(player), cure_price(player));
+            if healing == 0 && cure == 0 {
+                game.log.add("\"You look healthy enough to me.\"", colors::WHITE);
+                return None;
+            }
+            let mut options = vec![];
+            let mut actions = vec![];
+            if healing > 0 {
+                options.push(format!("Close the wounds ({} gold)", healing));
+                actions.push(Action::PayHealer(npc_id));
+            }
+            if cure > 0 {
+                options.push(format!("Cure the poison ({} gold)", cure));
+                actions.push(Action::PayForCure(npc_id));
+            }
+            options.push("Leave".into());
+            let question = format!("\n\"What can I do for you?\" You have {} gold.\n", player.gold);
+            menu(&question, &options, INVENTORY_WIDTH, tcod).and_then(|index| actions.get(index).cloned())
+        }
+        Some(Service::Stash) => {
+            let action = stash_screen(npc_id, tcod, objects, game);
+            tcod.open_stash = action.map(|_| npc_id);
+            action
+        }
+        Some(Service::Trainer) => {
+            let header = format!("\"A lesson is {} gold, unlearning a level-up {}.\" You have {} gold.\n",
+                                 training_price(game), relearning_price(game), player.gold);
+            match menu(&header, &["Train", "Relearn a level-up"], INVENTORY_WIDTH, tcod) {
+                Some(0) => {
+                    stat_menu("Train which stat?\n", player, game.rules(), tcod)
+                        .map(|stat| Action::Train(npc_id, stat))
+                }
+                Some(1) if game.level_ups.is_empty() => {
+                    game.log.add("\"You haven't learned anything I could make you forget.\"", colors::WHITE);
+                    None
+                }
+                Some(1) => {
+                    // games saved before the choices were kept only know the later ones
+                    let first_level = player.level + 1 - game.level_ups.len() as i32;
+                    let options: Vec<_> = game.level_ups.iter().enumerate()
+                        .map(|(i, stat)| format!("Level {}: {}", first_level + i as i32, stat))
+                        .collect();
+                    menu("Take back which level-up?\n", &options, INVENTORY_WIDTH, tcod).and_then(|index| {
+                        stat_menu("Raise which stat instead?\n", player, game.rules(), tcod)
+                            .map(|stat| Action::Relearn(npc_id, index, stat))
+                    })
+                }
+                _ => None,
+            }
+        }
+        Some(Service::Hunter) => match game.bounty.clone() {
+            None if TROPHIES.is_empty() => {
+                game.log.add("\"Nothing's worth hunting these days.\"", colors::WHITE);
+                None
+            }
+            None => {
+                let question = "\n\"Looking for work? I pay well for the right trophies.\"\n";
+                match menu(question, &["Ask for a bounty", "Leave"], INVENTORY_WIDTH, tcod) {
+                    Some(0) => Some(Action::TakeBounty(npc_id)),
+                    _ => None,
+                }
+            }
+            Some(ref bounty) if bounty_trophies(bounty, player) < bounty.count => {
+                game.log.add(format!("\"Bring me {} {}s, and {} gold is yours.\" You have {}.", bounty.count,
+                                     bounty.trophy, bounty.reward, bounty_trophies(bounty, player)),
+                             colors::WHITE);
+                None
+            }
+            Some(bounty) => {
+                let question = format!("\n\"Got the {} {}s?\"\n", bounty.count, bounty.trophy);
+                let hand_in = format!("Hand them in ({} gold)", bounty.reward);
+                match menu(&question, &[hand_in.as_str(), "Leave"], INVENTORY_WIDTH, tcod) {
+                    Some(0) => Some(Action::ClaimBounty(npc_id)),
+                    _ => None,
+                }
+            }
+        },
+        None => None,
+    }
+}
+
+/// The stash chest's own screen: what's in the stash on the left and what
+/// the player carries on the right. Up and down pick an item, left, right
+/// and Tab switch sides, Enter moves the item to the other side and long
+/// lists scroll. Escape closes the chest.
+fn stash_screen(npc_id: usize, tcod: &mut Tcod, objects: &[Object], game: &Game) -> Option<Action> {
+    use tcod::input::KeyCode::{Up, Down, Left, Right, Tab, PageUp, PageDown, Enter, NumPadEnter, Escape};
+
+    let sides: [&[Object]; 2] = [&game.stash, &objects[PLAYER].inventory];
+    let column_width = STASH_SCREEN_WIDTH / 2;
+    // a title and the columns' headers above the items, the keys below
+    let page = cmp::max(1, CONFIG.screen_height - 8) as usize;
+    let height = page as i32 + 4;
+    let x = CONFIG.screen_width / 2 - STASH_SCREEN_WIDTH / 2;
+    let y = CONFIG.screen_height / 2 - height / 2;
+
+    let background = tcod.root.clone();
+    let mut side = 0;
+    let mut selected = [0, 0];
+    loop {
+        let mut window = Screen::new(STASH_SCREEN_WIDTH, height);
+        window.print(0, 0, &format!("Your stash chest, {} of {} places used", game.stash.len(), STASH_CAPACITY),
+                     colors::WHITE);
+        for (column, items) in sides.iter().enumerate() {
+            let left = column as i32 * column_width;
+            let header = if column == 0 { "In the stash" } else { "In your pack" };
+            window.print(left, 1, &format!("{} ({})", header, items.len()), colors::LIGHT_GREY);
+            let first = selected[column] - selected[column] % page;
+            for (row, index) in (first..cmp::min(first + page, items.len())).enumerate() {
+                let item = &items[index];
+                let row = row as i32 + 2;
+                if column == side && index == selected[column] {
+                    window.fill_background(left, row, column_width - 1, 1, colors::DARKER_SKY);
+                }
+                let (label, color) = match item.equipment {
+                    Some(equipment) if equipment.equipped => {
+                        (format!("{} (on {})", item.name, equipment.slot), colors::GREY)
+                    }
+                    _ => (item.name.clone(), colors::WHITE),
+                };
+                let label: String = label.chars().take(column_width as usize - 1).collect();
+                window.print(left, row, &label, color);
+            }
+            if items.len() > page {
+                window.print(left, height - 2, &format!("{}-{} of {}", first + 1,
+                                                        cmp::min(first + page, items.len()), items.len()),
+                             colors::LIGHT_GREY);
+            }
+        }
+        window.print(0, height - 1, "Enter: move the item   Tab: other side   Escape: close", colors::LIGHT_GREY);
+
+        tcod.root = background.clone();
+        window.blit(&mut tcod.root, x, y, 0.7);
+        tcod.flush();
+        let key = tcod.backend.wait_for_keypress();
+        if tcod.backend.window_closed() {
+            return None;
+        }
+
+        let count = sides[side].len();
+        match key.code {
+            Up if count > 0 => selected[side] = (selected[side] + count - 1) % count,
+            Down if count > 0 => selected[side] = (selected[side] + 1) % count,
+            PageUp => selected[side] = selected[side].saturating_sub(page),
+            PageDown if count > 0 => selected[side] = cmp::min(selected[side] + page, count - 1),
+            Left | Right | Tab => side = 1 - side,
+            Enter | NumPadEnter if count > 0 && side == 0 => return Some(Action::Withdraw(npc_id, selected[0])),
+            Enter | NumPadEnter if count > 0 => return Some(Action::Deposit(npc_id, selected[1])),
+            Escape => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Open the stash chest again after an item was moved in or out, until the
+/// player closes it or walks away.
+fn reopen_stash(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) -> PlayerAction {
+    let npc_id = match tcod.open_stash {
+        Some(npc_id) if offers(npc_id, Service::Stash, objects) &&
+            objects[npc_id].distance_to(&objects[PLAYER]) < 1.5 => npc_id,
+        _ => {
+            tcod.open_stash = None;
+            return PlayerAction::DidntTakeTurn;
+        }
+    };
+    match stash_screen(npc_id, tcod, objects, game) {
+        Some(action) => perform_action(action, objects, game, &mut tcod.fov),
+        None => {
+            tcod.open_stash = None;
+            PlayerAction::DidntTakeTurn
+        }
+    }
+}
+
+/// Why an action is an obvious mistake, if it is one: stepping onto a trap
+/// the player knows about or into lava or deep water, or catching
+/// themselves in their own blast. Only what the player has seen counts,
+/// hidden traps don't give themselves away.
+fn action_warning(action: Action, objects: &[Object], game: &Game) -> Option<String> {
+    let player = &objects[PLAYER];
+    match action {
+        Action::MoveOrAttack(dx, dy) => {
+            let (x, y) = (player.x + dx, player.y + dy);
+            if x < 0 || y < 0 || x >= CONFIG.map_width || y >= CONFIG.map_height ||
+                !game.map[x as usize][y as usize].explored {
+                return None;
+            }
+            // a friend swaps places with the player, who ends up there all the same
+            let attack = objects.iter().any(|o| {
+                o.occupies(x, y) && o.fighter.is_some() && o.alive && o.disposition != Disposition::Friendly
+            });
+            // once in, the player isn't asked again on every step out
+            let tile = &game.map[x as usize][y as usize];
+            let here = &game.map[player.x as usize][player.y as usize];
+            if !attack && is_hazard(tile, player.movement) && !is_hazard(here, player.movement) {
+                return Some(match tile.kind {
+                    TileKind::Lava => "That's lava!".into(),
+                    _ => "The water there is too deep for you.".into(),
+                });
+            }
+            objects.iter()
+                .filter(|o| !attack && o.pos() == (x, y) && o.trap.is_some() && !o.hidden() && !o.removed)
+                .map(|trap| format!("There is a {} there.", trap.name))
+                .next()
+        }
+        Action::UseItem(inventory_id, Some((x, y))) => {
+            let item = &player.inventory[inventory_id];
+            match item_targeting(item, game.rules()) {
+                Targeting::Tile { radius, .. } if radius > 0 && player.distance(x, y) <= radius as f32 => {
+                    Some(format!("You are inside the blast of the {}.", item.name))
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Everything the player can do, no matter whether it was asked for by a
+/// key press, a bot or a test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    MoveOrAttack(i32, i32),
+    /// whoever is there, even if they mean the player no harm
+    Attack(i32, i32),
+    /// for all the player's companions on the level
+    Order(Order),
+    Wait,
+    PickUp,
+    UseItem(usize, Option<(i32, i32)>),
+    DropItem(usize),
+    Descend,
+    Ascend,
+    ToggleTorch,
+    Search,
+    Disarm,
+    /// the open door at this position, next to the player
+    CloseDoor(i32, i32),
+    /// kneel at the altar with this index, next to the player
+    Pray(usize),
+    /// cut the trophy from the remains with this index, next to the player
+    Butcher(usize),
+    LevelUp(Stat),
+    /// the shop's item with this index
+    Buy(usize, usize),
+    /// the player's item with this index, to a shop
+    Sell(usize, usize),
+    PayHealer(usize),
+    PayForCure(usize),
+    /// the player's item with this index, into the stash
+    Deposit(usize, usize),
+    /// the stash's item with this index
+    Withdraw(usize, usize),
+    Train(usize, Stat),
+    /// the level-up with this index, to raise the stat instead
+    Relearn(usize, usize, Stat),
+    TakeBounty(usize),
+    ClaimBounty(usize),
+}
+
+/// The stats a level-up can raise
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Stat {
+    Constitution,
+    Strength,
+    Agility,
+}
+
+impl std::fmt::Display for Stat {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Stat::Constitution => write!(f, "constitution"),
+            Stat::Strength => write!(f, "strength"),
+            Stat::Agility => write!(f, "agility"),
+        }
+    }
+}
+
+fn perform_action(action: Action, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap)
+                  -> PlayerAction {
+    use PlayerAction::*;
+
+    if !objects[PLAYER].alive {
+        return DidntTakeTurn;
+    }
+    match action {
+        Action::MoveOrAttack(dx, dy) => {
+            let position = objects[PLAYER].pos();
+            player_move_or_attack(dx, dy, objects, game, fov_map);
+            let landed = objects[PLAYER].pos();
+            if landed == position {
+                // bumping into a door opens it
+                compute_fov(fov_map, objects, game);
+            } else {
+                let (x, y) = position;
+                if CONFIG.close_doors_behind && can_close_door(x, y, objects, &game.map) {
+                    game.map[x as usize][y as usize].set_door_open(false);
+                    game.log.add("The door swings shut behind you.", colors::LIGHT_GREY);
+                }
+                kneel_at_altar(objects, game);
+                spring_trap(PLAYER, objects, game, fov_map);
+                if objects[PLAYER].pos() != landed {
+                    // teleported away
+                    compute_fov(fov_map, objects, game);
+                }
+            }
+            TookTime(ACTION_COST)
+        }
+        Action::Attack(dx, dy) => {
+            let (x, y) = (objects[PLAYER].x + dx, objects[PLAYER].y + dy);
+            let target_id = objects.iter().enumerate().position(|(id, object)| {
+                id != PLAYER && object.fighter.is_some() && object.occupies(x, y)
+            });
+            match target_id {
+                Some(target_id) => {
+                    player_attack(target_id, objects, game, fov_map);
+                    TookTime(ACTION_COST)
+                }
+                None => DidntTakeTurn,
+            }
+        }
+        Action::Order(order) => {
+            for companion in objects.iter_mut().filter(|o| is_companion(o)) {
+                companion.ai = Some(Ai::Companion{order: order});
+            }
+            let call = match order {
+                Order::Follow => "\"Come along!\"",
+                Order::Stay => "\"Stay!\"",
+                Order::Attack(_) => "\"Get them!\"",
+            };
+            game.log.add(call, colors::WHITE);
+            TookTime(ACTION_COST)
+        }
+        Action::Wait => TookTime(ACTION_COST),
+        Action::PickUp => {
+            let item_id = objects.iter().position(|object| {
+                object.pos() == objects[PLAYER].pos() && object.item.is_some()
+            });
+            match item_id {
+                Some(item_id) => {
+                    pick_item_up(item_id, objects, game);
+                    TookTime(QUICK_ACTION_COST)
+                }
+                None => DidntTakeTurn,
+            }
+        }
+        Action::UseItem(inventory_id, target) => {
+            if inventory_id >= objects[PLAYER].inventory.len() {
+                return DidntTakeTurn;
+            }
+            let cost = objects[PLAYER].inventory[inventory_id].item.map_or(ACTION_COST, |item| item.use_cost());
+            match use_item(inventory_id, target, objects, game, fov_map) {
+                UseResult::NoTurn | UseResult::Cancelled => DidntTakeTurn,
+                _ => {
+                    game.stats.items_used += 1;
+                    // digging may have opened up the map, a scroll of
+                    // return a portal
+                    initialise_fov(&game.map, fov_map);
+                    compute_fov(fov_map, objects, game);
+                    sync_portals(objects, game);
+                    TookTime(cost)
+                }
+            }
+        }
+        Action::DropItem(inventory_id) => {
+            if inventory_id < objects[PLAYER].inventory.len() {
+                drop_item(inventory_id, objects, game);
+                return TookTime(QUICK_ACTION_COST);
+            }
+            DidntTakeTurn
+        }
+        Action::Descend | Action::Ascend if on_portal(objects) => {
+            take_portal(objects, game, fov_map);
+            DidntTakeTurn
+        }
+        Action::Descend => {
+            // go down stairs, if the player is on them
+            let stairs = objects.iter()
+                .find(|object| object.pos() == objects[PLAYER].pos() && object.name.starts_with("stairs"))
+                .map(|object| object.name.clone());
+            let branch = stairs.as_ref()
+                .and_then(|name| Branch::all().into_iter().find(|branch| &branch.stairs_name() == name));
+            let sealed = objects.iter().any(|object| object.alive && is_guardian(&object.name));
+            match (stairs, branch) {
+                (Some(_), _) if sealed => {
+                    game.log.add("The stairs are sealed while their guardian lives.", colors::LIGHT_GREY);
+                }
+                (_, Some(branch)) => enter_branch(branch, objects, game, fov_map),
+                (Some(_), None) => next_level(objects, game, fov_map),
+                (None, None) => {}
+            }
+            DidntTakeTurn
+        }
+        Action::Ascend => {
+            // go up stairs, if the player is on them
+            let player_on_stairs = objects.iter().any(|object| {
+                object.pos() == objects[PLAYER].pos() && object.name == "up stairs"
+            });
+            if player_on_stairs && game.depth() != Depth::town() {
+                previous_level(objects, game, fov_map);
+            }
+            DidntTakeTurn
+        }
+        Action::ToggleTorch => {
+            game.torch_lit = !game.torch_lit;
+            if game.torch_lit {
+                game.log.add("You light your torch.", colors::LIGHT_YELLOW);
+            } else {
+                game.log.add("You douse your torch and creep on in the dark.", colors::LIGHT_GREY);
+            }
+            compute_fov(fov_map, objects, game);
+            TookTime(QUICK_ACTION_COST)
+        }
+        Action::Search => {
+            if search(1.0, objects, game) == 0 {
+                game.log.add("You search the walls around you, but find nothing.", colors::LIGHT_GREY);
+            }
+            TookTime(ACTION_COST)
+        }
+        Action::CloseDoor(x, y) => {
+            let (player_x, player_y) = objects[PLAYER].pos();
+            let next_to = (x - player_x).abs() <= 1 && (y - player_y).abs() <= 1 && (x, y) != (player_x, player_y);
+            if !next_to || !can_close_door(x, y, objects, &game.map) {
+                game.log.add("There is no door there you could close.", colors::RED);
+                return DidntTakeTurn;
+            }
+            game.map[x as usize][y as usize].set_door_open(false);
+            game.log.add("You close the door.", colors::LIGHT_GREY);
+            compute_fov(fov_map, objects, game);
+            TookTime(ACTION_COST)
+        }
+        Action::Disarm => {
+            // a trap the player knows about, under them or right next to them
+            let trap_id = objects.iter().position(|o| {
+                o.trap.map_or(false, |trap| !trap.hidden) && !o.removed && o.distance_to(&objects[PLAYER]) < 1.5
+            });
+            let trap_id = match trap_id {
+                Some(trap_id) => trap_id,
+                None => {
+                    game.log.add("There is no trap here to disarm.", colors::RED);
+                    return DidntTakeTurn;
+                }
+            };
+            let roll = game_rng().gen::<f32>();
+            if roll < DISARM_CHANCE {
+                let trap = objects.remove_object(trap_id, &mut game.free_slots);
+                game.log.add(format!("You disarm the {}.", trap.name), colors::LIGHT_GREEN);
+            } else if roll < DISARM_CHANCE + (1.0 - DISARM_CHANCE) / 2.0 {
+                game.log.add(format!("You set off the {}!", objects[trap_id].name), colors::RED);
+                let position = objects[PLAYER].pos();
+                trigger_trap(trap_id, PLAYER, objects, game, fov_map);
+                if objects[PLAYER].pos() != position {
+                    compute_fov(fov_map, objects, game);
+                }
+            } else {
+                game.log.add(format!("You fail to disarm the {}.", objects[trap_id].name), colors::LIGHT_GREY);
+            }
+            TookTime(ACTION_COST)
+        }
+        Action::Pray(altar_id) => {
+            let altar_near = objects.get(altar_id).map_or(false, |altar| {
+                !altar.removed && altar.name == ALTAR_NAME && altar.distance_to(&objects[PLAYER]) < 1.5
+            });
+            if !altar_near {
+                return DidntTakeTurn;
+            }
+            pray_at(altar_id, objects, game);
+            TookTime(ACTION_COST)
+        }
+        Action::Butcher(corpse_id) => {
+            if butcher(corpse_id, objects, game) { TookTime(BUTCHER_COST) } else { DidntTakeTurn }
+        }
+        Action::LevelUp(stat) => {
+            if ready_to_level_up(&objects[PLAYER], game.rules()) {
+                raise_stat(stat, objects, game);
+            }
+            DidntTakeTurn
+        }
+        Action::Buy(npc_id, index) => {
+            if buy(npc_id, index, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::Sell(npc_id, inventory_id) => {
+            if sell(npc_id, inventory_id, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::PayHealer(npc_id) => {
+            if pay_healer(npc_id, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::PayForCure(npc_id) => {
+            if pay_for_cure(npc_id, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::Deposit(npc_id, inventory_id) => {
+            if deposit(npc_id, inventory_id, objects, game) { TookTime(QUICK_ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::Withdraw(npc_id, index) => {
+            if withdraw(npc_id, index, objects, game) { TookTime(QUICK_ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::Train(npc_id, stat) => {
+            if train(npc_id, stat, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::Relearn(npc_id, index, stat) => {
+            if relearn(npc_id, index, stat, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::TakeBounty(npc_id) => {
+            if take_bounty(npc_id, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+        Action::ClaimBounty(npc_id) => {
+            if claim_bounty(npc_id, objects, game) { TookTime(ACTION_COST) } else { DidntTakeTurn }
+        }
+    }
+}
+
+/// Ask for a debug command, run it and show what came of it.
+fn debug_console(tcod: &mut Tcod, objects: &mut Vec<Object>, game: &mut Game) {
+    let command = match text_input("Debug command (try 'help'):", 40, tcod) {
+        Some(command) => command,
+        None => return,
+    };
+    let depth = game.depth();
+    match run_debug_command(&command, objects, game, &mut tcod.fov) {
+        Ok(result) => {
+            game.stats.debug_commands += 1;
+            game.log.add(result, colors::LIGHT_CYAN);
+        }
+        Err(e) => game.log.add(e, colors::RED),
+    }
+    if game.depth() != depth {
+        tcod.con.clear();
+    }
+    // the map may have changed under the player
+    render_all(tcod, objects, game, true);
+}
+
+/// The cheats behind the debug console.
+fn run_debug_command(command: &str, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap)
+                     -> Result<String, String> {
+    let words: Vec<&str> = command.split_whitespace().collect();
+    let (player_x, player_y) = objects[PLAYER].pos();
+    match &words[..] {
+        &["help"] => Ok("Commands: spawn <monster>, give <item>, teleport <x> <y>, \
+                         reveal-map, heal, descend".into()),
+        &["spawn", species] => {
+            // on the first free tile around the player
+            let free_tile = (-1..2).flat_map(|dx| (-1..2).map(move |dy| (player_x + dx, player_y + dy)))
+                .find(|&(x, y)| {
+                    x >= 0 && y >= 0 && x < CONFIG.map_width && y < CONFIG.map_height &&
+                        !is_blocked(x, y, &game.map, objects)
+                });
+            let (x, y) = try! { free_tile.ok_or("There's no room next to you.") };
+            let monster = try! { create_monster(species, x, y).ok_or(format!("There are no {}s.", species)) };
+            objects.insert_object(monster, &mut game.free_slots);
+            Ok(format!("Spawned a {}.", species))
+        }
+        &["give", name] => {
+            let kind = try! { item_kind(name).ok_or(format!("There is no item called '{}'.", name)) };
+            let item = create_item(kind, player_x, player_y);
+            let message = format!("Gave you a {}.", item.name);
+            objects[PLAYER].inventory.push(item);
+            Ok(message)
+        }
+        &["teleport", x, y] => {
+            let (x, y) = match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => (x, y),
+                _ => return Err("Teleport where?".into()),
+            };
+            if x < 0 || y < 0 || x >= CONFIG.map_width || y >= CONFIG.map_height ||
+                is_blocked(x, y, &game.map, objects) {
+                return Err(format!("Can't stand on {}, {}.", x, y));
+            }
+            objects[PLAYER].set_pos(x, y);
+            compute_fov(fov_map, objects, game);
+            Ok(format!("Teleported to {}, {}.", x, y))
+        }
+        &["reveal-map"] => {
+            for tile in game.map.iter_mut().flat_map(|column| column.iter_mut()) {
+                tile.explored = true;
+            }
+            Ok("The whole level is revealed.".into())
+        }
+        &["heal"] => {
+            let max_hp = objects[PLAYER].max_hp();
+            objects[PLAYER].heal(max_hp);
+            Ok("You are fully healed.".into())
+        }
+        &["descend"] => {
+            next_level(objects, game, fov_map);
+            Ok(format!("Went down to level {}.", game.dungeon_level))
+        }
+        _ => Err(format!("Unknown command '{}'.", command)),
+    }
+}
+
+fn level_up_xp(player: &Object, rules: &rules::Rules) -> i32 {
+    rules.level_up_base + player.level * rules.level_up_factor
+}
+
+/// see if the player's experience is enough to level-up
+fn ready_to_level_up(player: &Object, rules: &rules::Rules) -> bool {
+    player.fighter.as_ref().map_or(0, |f| f.xp) >= level_up_xp(player, rules)
+}
+
+/// level the player up, raising the given stat
+fn raise_stat(stat: Stat, objects: &mut [Object], game: &mut Game) {
+    let rules = game.rules();
+    let player = &mut objects[PLAYER];
+    let level_up_xp = level_up_xp(player, rules);
+    player.level += 1;
+    game.log.add(format!("Your battle skills grow stronger! You reached level {}!",
+                         player.level),
+                 colors::YELLOW);
+    let fighter = player.fighter.as_mut().unwrap();
+    fighter.xp -= level_up_xp;
+    add_to_stat(stat, 1, fighter, rules);
+    // the trainer can take the choice back later
+    game.level_ups.push(stat);
+}
+
+/// Raise the stat by what `times` level-ups give, or lower it if `times` is
+/// negative. Losing constitution never kills.
+fn add_to_stat(stat: Stat, times: i32, fighter: &mut Fighter, rules: &rules::Rules) {
+    match stat {
+        Stat::Constitution => {
+            fighter.base_max_hp += times * rules.level_up_hp;
+            fighter.hp = cmp::max(1, fighter.hp + times * rules.level_up_hp);
+        }
+        Stat::Strength => {
+            fighter.base_power += times * rules.level_up_power;
+        }
+        Stat::Agility => {
+            fighter.base_defense += times * rules.level_up_defense;
+        }
+    }
+}
+
+/// Ask which stat to raise, showing what it would gain.
+fn stat_menu(header: &str, player: &Object, rules: &rules::Rules, tcod: &mut Tcod) -> Option<Stat> {
+    let fighter = player.fighter.unwrap();
+    let choice = menu(
+        header,
+        &[format!("Constitution (+{} HP, from {})", rules.level_up_hp, fighter.base_max_hp),
+          format!("Strength (+{} attack, from {})", rules.level_up_power, fighter.base_power),
+          format!("Agility (+{} defense, from {})", rules.level_up_defense, fighter.base_defense)],
+        LEVEL_SCREEN_WIDTH, tcod);
+    match choice {
+        Some(0) => Some(Stat::Constitution),
+        Some(1) => Some(Stat::Strength),
+        Some(2) => Some(Stat::Agility),
+        _ => None,
+    }
+}
+
+fn level_up(objects: &mut [Object], game: &mut Game, tcod: &mut Tcod) {
+    let rules = game.rules();
+    if !ready_to_level_up(&objects[PLAYER], rules) {
+        return;
+    }
+    // it is! level up
+    let mut stat = None;
+    // keep asking until a choice is made, the game saved on closing the
+    // window asks again when it's loaded
+    while stat.is_none() && !tcod.backend.window_closed() {
+        stat = stat_menu("Level up! Choose a stat to raise:\n", &objects[PLAYER], rules, tcod);
+    };
+    if let Some(stat) = stat {
+        raise_stat(stat, objects, game);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    /// the action took this much energy
+    TookTime(i32),
+    DidntTakeTurn,
+    Exit,
+}
+
+fn player_death(player: &mut Object, game: &mut Game) {
+    // the game ended!
+    game.log.add("You died!", colors::RED);
+
+    // for added effect, transform the player into a corpse!
+    player.char = '%';
+    player.color = colors::DARK_RED;
+}
+
+fn monster_death(monster: &mut Object, game: &mut Game) {
+    // transform it into a nasty corpse! it doesn't block, can't be
+    // attacked and doesn't move
+    game.log.add(
+        format!("{} is dead!", monster.name), colors::ORANGE);
+    if is_guardian(&monster.name) {
+        game.log.add("With its guardian gone, the seal on the stairs breaks.", colors::LIGHT_YELLOW);
+    }
+    monster.char = '%';
+    monster.color = colors::DARK_RED;
+    monster.blocks = false;
+    monster.fighter = None;
+    monster.ai = None;
+    monster.name = format!("{}{}", REMAINS_PREFIX, monster.name);
+    start_decay(monster, CORPSE_DECAY_TURNS, game);
+}
+
+/// One character cell of a `Screen`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Cell {
+    ch: char,
+    fg: Color,
+    bg: Color,
+}
+
+const EMPTY_CELL: Cell = Cell { ch: ' ', fg: colors::WHITE, bg: colors::BLACK };
+
+// the cell covered by the right half of a wide glyph
+const WIDE_GLYPH_TAIL: char = '\0';
+
+/// How many cells a character takes up: 2 for the wide CJK characters and
+/// emoji, 0 for combining marks and control characters, 1 for the rest.
+fn glyph_width(ch: char) -> usize {
+    let code = ch as u32;
+    if ch.is_control() || (code >= 0x300 && code <= 0x36F) || (code >= 0x200B && code <= 0x200F) {
+        0
+    } else if (code >= 0x1100 && code <= 0x115F) || (code >= 0x2E80 && code <= 0xA4CF) ||
+        (code >= 0xAC00 && code <= 0xD7A3) || (code >= 0xF900 && code <= 0xFAFF) ||
+        (code >= 0xFE30 && code <= 0xFE4F) || (code >= 0xFF00 && code <= 0xFF60) ||
+        (code >= 0xFFE0 && code <= 0xFFE6) || (code >= 0x1F300 && code <= 0x1F64F) ||
+        (code >= 0x1F900 && code <= 0x1F9FF) || (code >= 0x20000 && code <= 0x3FFFD) {
+        2
+    } else {
+        1
+    }
+}
+
+/// the number of cells a text takes up on the screen
+fn text_width(text: &str) -> usize {
+    text.chars().map(glyph_width).sum()
+}
+
+/// A grid of cells the game draws into. A backend only has to show a
+/// finished screen, it doesn't need to know about the map or the GUI.
+#[derive(Clone)]
+struct Screen {
+    width: i32,
+    height: i32,
+    cells: Vec<Cell>,
+}
+
+impl Screen {
+    pub fn new(width: i32, height: i32) -> Self {
+        Screen {
+            width: width,
+            height: height,
+            cells: vec![EMPTY_CELL; (width * height) as usize],
+        }
+    }
+
+    pub fn clear(&mut self) {
+        for cell in self.cells.iter_mut() {
+            *cell = EMPTY_CELL;
+        }
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Cell {
+        self.cells[(y * self.width + x) as usize]
+    }
+
+    /// the cell at (x, y), or None when that's off the screen
+    fn cell_mut(&mut self, x: i32, y: i32) -> Option<&mut Cell> {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
+            return None;
+        }
+        Some(&mut self.cells[(y * self.width + x) as usize])
+    }
+
+    /// set the character and its color, keeping the background
+    pub fn put_char(&mut self, x: i32, y: i32, ch: char, fg: Color) {
+        if let Some(cell) = self.cell_mut(x, y) {
+            cell.ch = ch;
+            cell.fg = fg;
+        }
+    }
+
+    pub fn set_background(&mut self, x: i32, y: i32, bg: Color) {
+        if let Some(cell) = self.cell_mut(x, y) {
+            cell.bg = bg;
+        }
+    }
+
+    pub fn fill_background(&mut self, x: i32, y: i32, width: i32, height: i32, bg: Color) {
+        for cy in y..(y + height) {
+            for cx in x..(x + width) {
+                self.set_background(cx, cy, bg);
+            }
+        }
+    }
+
+    /// print a single line of text starting at (x, y). Wide glyphs take two
+    /// cells, combining marks are dropped as a cell can only hold one char.
+    pub fn print(&mut self, x: i32, y: i32, text: &str, fg: Color) {
+        let mut cx = x;
+        for ch in text.chars() {
+            match glyph_width(ch) {
+                0 => {}
+                1 => {
+                    self.put_char(cx, y, ch, fg);
+                    cx += 1;
+                }
+                _ => {
+                    self.put_char(cx, y, ch, fg);
+                    self.put_char(cx + 1, y, WIDE_GLYPH_TAIL, fg);
+                    cx += 2;
+                }
+            }
+        }
+    }
+
+    /// print a single line of text centered on x
+    pub fn print_centered(&mut self, x: i32, y: i32, text: &str, fg: Color) {
+        let width = text_width(text) as i32;
+        self.print(x - width / 2, y, text, fg);
+    }
+
+    /// print text wrapped to the given width and return the number of lines
+    pub fn print_wrapped(&mut self, x: i32, y: i32, width: i32, text: &str, fg: Color) -> i32 {
+        let lines = wrap_text(text, width);
+        for (i, line) in lines.iter().enumerate() {
+            self.print(x, y + i as i32, line, fg);
+        }
+        lines.len() as i32
+    }
+
+    /// copy this screen onto `dest` at (x, y). The backgrounds are blended,
+    /// `bg_alpha` being how much of this screen's background shows.
+    pub fn blit(&self, dest: &mut Screen, x: i32, y: i32, bg_alpha: f32) {
+        for sy in 0..self.height {
+            for sx in 0..self.width {
+                let src = self.get(sx, sy);
+                if let Some(cell) = dest.cell_mut(x + sx, y + sy) {
+                    cell.ch = src.ch;
+                    cell.fg = src.fg;
+                    cell.bg = colors::lerp(cell.bg, src.bg, bg_alpha);
+                }
+            }
+        }
+    }
+}
+
+/// Split text into lines no wider than `width`, breaking at spaces where
+/// possible.
+fn wrap_text(text: &str, width: i32) -> Vec<String> {
+    let width = cmp::max(width, 1) as usize;
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let line_width = text_width(&line);
+            if line_width > 0 && line_width + 1 + text_width(word) > width {
+                lines.push(line);
+                line = String::new();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+            // a word longer than the whole line gets cut
+            while text_width(&line) > width {
+                let mut head = String::new();
+                let mut tail = String::new();
+                let mut head_width = 0;
+                for ch in line.chars() {
+                    if tail.is_empty() && head_width + glyph_width(ch) <= width {
+                        head_width += glyph_width(ch);
+                        head.push(ch);
+                    } else {
+                        tail.push(ch);
+                    }
+                }
+                lines.push(head);
+                line = tail;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Shrink an image to one background color per cell, averaging each 2x2
+/// block of pixels (so a 160x100 image fills the 80x50 screen).
+fn image_to_screen(img: &tcod::image::Image) -> Screen {
+    let (width, height) = img.get_size();
+    let mut screen = Screen::new(CONFIG.screen_width, CONFIG.screen_height);
+    for y in 0..CONFIG.screen_height {
+        for x in 0..CONFIG.screen_width {
+            let (mut r, mut g, mut b, mut count) = (0u32, 0u32, 0u32, 0u32);
+            for &(px, py) in &[(x * 2, y * 2), (x * 2 + 1, y * 2), (x * 2, y * 2 + 1), (x * 2 + 1, y * 2 + 1)] {
+                if px < width && py < height {
+                    let pixel = img.get_pixel(px, py);
+                    r += pixel.r as u32;
+                    g += pixel.g as u32;
+                    b += pixel.b as u32;
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                let color = Color::new((r / count) as u8, (g / count) as u8, (b / count) as u8);
+                screen.set_background(x, y, color);
+            }
+        }
+    }
+    screen
+}
+
+/// Shows a finished `Screen` to the player.
+trait Renderer {
+    fn present(&mut self, screen: &Screen);
+    fn toggle_fullscreen(&mut self);
+}
+
+/// Where key presses and mouse events come from.
+trait Input {
+    /// the next pending event, without waiting for one
+    fn check_for_event(&mut self) -> Option<Event>;
+    fn wait_for_keypress(&mut self) -> Key;
+    fn window_closed(&self) -> bool;
+}
+
+trait Backend: Renderer + Input {}
+
+impl<T: Renderer + Input> Backend for T {}
+
+/// Draws in a libtcod window.
+struct TcodBackend {
+    root: Root,
+}
+
+impl Renderer for TcodBackend {
+    fn present(&mut self, screen: &Screen) {
+        for y in 0..screen.height {
+            for x in 0..screen.width {
+                let cell = screen.get(x, y);
+                // the font only has glyphs for the first 256 code points
+                let ch = match cell.ch {
+                    WIDE_GLYPH_TAIL => ' ',
+                    ch if (ch as u32) < 256 => ch,
+                    _ => '?',
+                };
+                self.root.put_char_ex(x, y, ch, cell.fg, cell.bg);
+            }
+        }
+        self.root.flush();
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        let fullscreen = self.root.is_fullscreen();
+        self.root.set_fullscreen(!fullscreen);
+    }
+}
+
+impl Input for TcodBackend {
+    fn check_for_event(&mut self) -> Option<Event> {
+        let flags = if CONFIG.mouse_enabled { input::KEY_PRESS | input::MOUSE } else { input::KEY_PRESS };
+        input::check_for_event(flags).map(|e| e.1)
+    }
+
+    fn wait_for_keypress(&mut self) -> Key {
+        self.root.wait_for_keypress(true)
+    }
+
+    fn window_closed(&self) -> bool {
+        self.root.window_closed()
+    }
+}
+
+/// Draws with colored text in the terminal, for when there is no window
+/// system. The terminal has to be at least CONFIG.screen_width x CONFIG.screen_height.
+struct TerminalBackend {
+    closed: bool,
+    last_frame: Instant,
+}
+
+impl TerminalBackend {
+    fn new() -> std::io::Result<Self> {
+        use crossterm::{cursor, event, terminal, ExecutableCommand};
+
+        let (columns, rows) = try! { terminal::size() };
+        if (columns as i32) < CONFIG.screen_width || (rows as i32) < CONFIG.screen_height {
+            return Err(std::io::Error::new(std::io::ErrorKind::Other, format!(
+                "the terminal is {}x{}, it needs to be at least {}x{}",
+                columns, rows, CONFIG.screen_width, CONFIG.screen_height)));
+        }
+        try! { terminal::enable_raw_mode() };
+        let mut stdout = std::io::stdout();
+        try! { stdout.execute(terminal::EnterAlternateScreen) };
+        try! { stdout.execute(cursor::Hide) };
+        if CONFIG.mouse_enabled {
+            try! { stdout.execute(event::EnableMouseCapture) };
+        }
+        Ok(TerminalBackend { closed: false, last_frame: Instant::now() })
+    }
+
+    /// turn a terminal event into the libtcod one the game understands
+    fn translate_event(&mut self, event: crossterm::event::Event) -> Option<Event> {
+        use crossterm::event::{Event as TermEvent, KeyCode as TermKey, KeyEventKind,
+                               KeyModifiers, MouseButton, MouseEventKind};
+        use tcod::input::KeyCode::*;
+
+        match event {
+            TermEvent::Key(key) => {
+                if key.kind == KeyEventKind::Release {
+                    return None;
+                }
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                let alt = key.modifiers.contains(KeyModifiers::ALT);
+                let shift = key.modifiers.contains(KeyModifiers::SHIFT);
+                if ctrl && key.code == TermKey::Char('c') {
+                    // there is no window to close, Ctrl+C quits instead
+                    self.closed = true;
+                    return Some(Event::Key(Key { code: Escape, ..Default::default() }));
+                }
+                let (code, printable) = match key.code {
+                    TermKey::Char(' ') => (Spacebar, ' '),
+                    TermKey::Char(c) => (Char, c),
+                    TermKey::Enter => (Enter, '\r'),
+                    TermKey::Esc => (Escape, '\0'),
+                    TermKey::Backspace => (Backspace, '\0'),
+                    TermKey::Tab => (Tab, '\t'),
+                    TermKey::Up => (Up, '\0'),
+                    TermKey::Down => (Down, '\0'),
+                    TermKey::Left => (Left, '\0'),
+                    TermKey::Right => (Right, '\0'),
+                    TermKey::Home => (Home, '\0'),
+                    TermKey::End => (End, '\0'),
+                    TermKey::PageUp => (PageUp, '\0'),
+                    TermKey::PageDown => (PageDown, '\0'),
+                    TermKey::F(n) if n >= 1 && n <= 12 => {
+                        let function_keys = [F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12];
+                        (function_keys[n as usize - 1], '\0')
+                    }
+                    _ => return None,
+                };
+                Some(Event::Key(Key {
+                    code: code,
+                    printable: printable,
+                    pressed: true,
+                    alt: alt,
+                    ctrl: ctrl,
+                    shift: shift,
+                    ..Default::default()
+                }))
+            }
+            TermEvent::Mouse(mouse) => {
+                let (cx, cy) = (mouse.column as isize, mouse.row as isize);
+                Some(Event::Mouse(Mouse {
+                    cx: cx,
+                    cy: cy,
+                    lbutton: mouse.kind == MouseEventKind::Down(MouseButton::Left),
+                    rbutton: mouse.kind == MouseEventKind::Down(MouseButton::Right),
+                    lbutton_pressed: mouse.kind == MouseEventKind::Up(MouseButton::Left),
+                    rbutton_pressed: mouse.kind == MouseEventKind::Up(MouseButton::Right),
+                    wheel_up: mouse.kind == MouseEventKind::ScrollUp,
+                    wheel_down: mouse.kind == MouseEventKind::ScrollDown,
+                    ..Default::default()
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Renderer for TerminalBackend {
+    fn present(&mut self, screen: &Screen) {
+        use crossterm::{cursor, style, QueueableCommand};
+
+        fn term_color(color: Color) -> style::Color {
+            style::Color::Rgb { r: color.r, g: color.g, b: color.b }
+        }
+
+        let mut stdout = std::io::stdout();
+        let mut colors = None;
+        for y in 0..screen.height {
+            stdout.queue(cursor::MoveTo(0, y as u16)).ok();
+            let mut after_wide_glyph = false;
+            for x in 0..screen.width {
+                let mut cell = screen.get(x, y);
+                if cell.ch == WIDE_GLYPH_TAIL {
+                    if after_wide_glyph {
+                        // the terminal already drew this half with the glyph
+                        after_wide_glyph = false;
+                        continue;
+                    }
+                    cell.ch = ' ';
+                }
+                after_wide_glyph = glyph_width(cell.ch) == 2;
+                let has_tail = x + 1 < screen.width && screen.get(x + 1, y).ch == WIDE_GLYPH_TAIL;
+                if after_wide_glyph && !has_tail {
+                    // the right half was drawn over, the glyph would push the row out of place
+                    cell.ch = ' ';
+                    after_wide_glyph = false;
+                }
+                // only send the colors when they change, it's a lot less output
+                if colors != Some((cell.fg, cell.bg)) {
+                    stdout.queue(style::SetForegroundColor(term_color(cell.fg))).ok();
+                    stdout.queue(style::SetBackgroundColor(term_color(cell.bg))).ok();
+                    colors = Some((cell.fg, cell.bg));
+                }
+                stdout.queue(style::Print(cell.ch)).ok();
+            }
+        }
+        stdout.flush().ok();
+
+        // the same frame limit libtcod's set_fps gives the window
+        let frame = Duration::from_millis(1000 / CONFIG.limit_fps as u64);
+        let elapsed = self.last_frame.elapsed();
+        if elapsed < frame {
+            std::thread::sleep(frame - elapsed);
+        }
+        self.last_frame = Instant::now();
+    }
+
+    fn toggle_fullscreen(&mut self) {
+        // the terminal's size is up to the terminal
+    }
+}
+
+impl Input for TerminalBackend {
+    fn check_for_event(&mut self) -> Option<Event> {
+        use crossterm::event;
+
+        while event::poll(Duration::from_millis(0)).unwrap_or(false) {
+            if let Ok(e) = event::read() {
+                if let Some(e) = self.translate_event(e) {
+                    return Some(e);
+                }
+            }
+        }
+        None
+    }
+
+    fn wait_for_keypress(&mut self) -> Key {
+        loop {
+            match crossterm::event::read() {
+                Ok(e) => {
+                    if let Some(Event::Key(key)) = self.translate_event(e) {
+                        return key;
+                    }
+                }
+                Err(_) => {
+                    self.closed = true;
+                    return Key { code: input::KeyCode::Escape, ..Default::default() };
+                }
+            }
+        }
+    }
+
+    fn window_closed(&self) -> bool {
+        self.closed
+    }
+}
+
+impl Drop for TerminalBackend {
+    fn drop(&mut self) {
+        use crossterm::{cursor, event, terminal, ExecutableCommand};
+
+        // give the player their terminal back
+        let mut stdout = std::io::stdout();
+        stdout.execute(event::DisableMouseCapture).ok();
+        stdout.execute(cursor::Show).ok();
+        stdout.execute(terminal::LeaveAlternateScreen).ok();
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+/// The part of the map that is on the screen. It follows the player and
+/// stops at the map's edges; a map no larger than the screen doesn't scroll.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Camera {
+    /// the map tile in the top left corner of the view
+    x: i32,
+    y: i32,
+}
+
+impl Camera {
+    /// Center the view on (x, y) as far as the map allows, and say whether
+    /// it moved.
+    pub fn follow(&mut self, x: i32, y: i32) -> bool {
+        let new_x = cmp::max(0, cmp::min(x - CONFIG.view_width() / 2, CONFIG.map_width - CONFIG.view_width()));
+        let new_y = cmp::max(0, cmp::min(y - CONFIG.view_height() / 2, CONFIG.map_height - CONFIG.view_height()));
+        let moved = (new_x, new_y) != (self.x, self.y);
+        self.x = new_x;
+        self.y = new_y;
+        moved
+    }
+
+    /// where the map tile (x, y) is on the screen, if it's in view
+    pub fn to_screen(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        let (screen_x, screen_y) = (x - self.x, y - self.y);
+        if screen_x >= 0 && screen_y >= 0 && screen_x < CONFIG.view_width() && screen_y < CONFIG.view_height() {
+            Some((screen_x, screen_y))
+        } else {
+            None
+        }
+    }
+
+    /// the map tile shown at (x, y) on the screen, if the map is shown there
+    pub fn to_world(&self, x: i32, y: i32) -> Option<(i32, i32)> {
+        if x >= 0 && y >= 0 && x < CONFIG.view_width() && y < CONFIG.view_height() {
+            Some((self.x + x, self.y + y))
+        } else {
+            None
+        }
+    }
+}
+
+struct Tcod {
+    backend: Box<Backend>,
+    root: Screen,
+    con: Screen,
+    panel: Screen,
+    fov: FovMap,
+    camera: Camera,
+    mouse: Mouse,
+    /// the tile the player points at with the keyboard while picking a target
+    cursor: Option<(i32, i32)>,
+    travel_target: Option<(i32, i32)>,
+    /// the levels fast travel still has to go through, the last one is
+    /// where it ends, see `travel_step`
+    travel_route: Vec<Depth>,
+    /// the stash chest the player has open, it opens again after every item
+    /// moved until they close it
+    open_stash: Option<usize>,
+    show_profiler: bool,
+    frame_times: FrameTimes,
+    save_format: SaveFormat,
+    keymap: Keymap,
+    /// the last RECENT_COMMANDS commands, oldest first, for crash reports
+    recent_commands: VecDeque<InputAction>,
+}
+
+/// Time spent in each phase of the game loop.
+#[derive(Clone, Copy, Default)]
+struct FrameTimes {
+    input: Duration,
+    fov: Duration,
+    render: Duration,
+    ai: Duration,
+}
+
+impl Tcod {
+    /// the tile the player points at: the cursor's while picking a target,
+    /// otherwise the one under the mouse
+    pub fn pointed_at(&self) -> Option<(i32, i32)> {
+        if self.cursor.is_some() || !CONFIG.mouse_enabled {
+            return self.cursor;
+        }
+        self.camera.to_world(self.mouse.cx as i32, self.mouse.cy as i32)
+    }
+
+    /// show the root screen to the player
+    fn flush(&mut self) {
+        self.backend.present(&self.root);
+    }
+}
+
+/// A challenge the player can pick for a run when they start it. It stays
+/// for the whole game and is worth points in the score.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Mutator {
+    /// the shop in town doesn't trade
+    NoShops,
+    /// rooms get up to twice as many monsters
+    DoubleMonsters,
+    /// the player's items may break when they get hurt, see FRAGILE_BREAK_CHANCE
+    FragileItems,
+    /// the torch lights less, see LOW_LIGHT_PERCENT
+    LowLight,
+}
+
+impl Mutator {
+    pub fn all() -> Vec<Mutator> {
+        vec![Mutator::NoShops, Mutator::DoubleMonsters, Mutator::FragileItems, Mutator::LowLight]
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Mutator::NoShops => "no shops",
+            Mutator::DoubleMonsters => "double monsters",
+            Mutator::FragileItems => "fragile items",
+            Mutator::LowLight => "low light",
+        }
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match *self {
+            Mutator::NoShops => "the shop in town is closed",
+            Mutator::DoubleMonsters => "twice as many monsters in the rooms",
+            Mutator::FragileItems => "what you carry may break when you're hit",
+            Mutator::LowLight => "your torch lights half as far",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Game {
+    #[serde(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    map: Map,
+    log: Messages,
+    /// the branch the player is in, `dungeon_level` counts from its top
+    branch: Branch,
+    dungeon_level: u32,
+    turn: u32,
+    /// the timed events of the current level
+    schedule: Schedule,
+    /// the smoky tiles of the current level and the turn each clears at,
+    /// see `make_smoke`
+    smoke: Vec<(i32, i32, u32)>,
+    levels: LevelCache,
+    /// the balance the game was started with
+    rule_set: rules::RuleSet,
+    /// a doused torch shows less of the level, but hides the player too
+    torch_lit: bool,
+    /// the portal to town the player opened, while it's open
+    portal: Option<Portal>,
+    /// what the player keeps in the town's stash chest. It's saved with
+    /// the game rather than on the chest, which is only the way to it.
+    stash: Vec<Object>,
+    /// the stat each of the player's level-ups raised, in order
+    level_ups: Vec<Stat>,
+    /// how many lessons the player had with the trainer, each costs more
+    trainings: u32,
+    /// what's going on in town, and the turn from which another event may
+    /// start
+    town_event: Option<TownEvent>,
+    next_town_event: u32,
+    /// the levels the player got the bonus for clearing, only once each
+    cleared_levels: Vec<Depth>,
+    /// saved after every turn, and deleted when the player dies
+    ironman: bool,
+    /// the challenges picked for this game
+    mutators: Vec<Mutator>,
+    /// what the hunter in town asks for, once the player asked for work
+    bounty: Option<Bounty>,
+    /// what the player did so far, for the score
+    stats: RunStats,
+    /// how far every tile is from the player, worked out again each turn
+    #[serde(skip_serializing, skip_deserializing)]
+    scent: ScentMap,
+    /// which object blocks each tile, worked out again each turn
+    #[serde(skip_serializing, skip_deserializing)]
+    blockers: Blockers,
+    /// the shots fired since the player's last turn, see `animate_shots`
+    #[serde(skip_serializing, skip_deserializing)]
+    shots: Vec<Projectile>,
+    /// the free slots in the objects list, see `ObjectSlots`
+    #[serde(skip_serializing, skip_deserializing)]
+    free_slots: Vec<usize>,
+}
+
+/// Something going on in town, found there on arrival now and then.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TownEvent {
+    /// the shop sells for less until the `EndFestival` event
+    Festival,
+    /// this many raiders came, the town pays for all of them once they're dead
+    Raid(u32),
+}
+
+/// A portal to town, opened with a scroll of return where the player stood.
+/// Its other end is in town, and it stays open until the player comes back
+/// through it or opens another one.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Portal {
+    depth: Depth,
+    pos: (i32, i32),
+}
+
+/// What the player did this game, counted as they go for `score`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+struct RunStats {
+    /// the difficulty of the hardest level the player got to
+    deepest: u32,
+    kills: u32,
+    /// the experience the player's kills were worth
+    kill_xp: u32,
+    gold_earned: u32,
+    // each of these breaks a conduct
+    items_used: u32,
+    purchases: u32,
+    prayers: u32,
+    debug_commands: u32,
+}
+
+/// The player's score and what it's made of, as (what, points) with the
+/// total last.
+fn score(game: &Game) -> Vec<(String, i32)> {
+    let stats = game.stats;
+    let deepest = stats.deepest as i32;
+    let mut lines = vec![
+        (format!("Got as deep as level {}", deepest), deepest * SCORE_PER_LEVEL),
+        (format!("Cleared {} levels", game.cleared_levels.len()),
+         game.cleared_levels.len() as i32 * SCORE_PER_CLEARED_LEVEL),
+        (format!("Killed {} monsters", stats.kills), stats.kill_xp as i32),
+        (format!("Earned {} gold", stats.gold_earned), stats.gold_earned as i32),
+    ];
+    let speed_bonus = (deepest * SPEED_PAR_TURNS - game.turn as i32) / SPEED_BONUS_TURNS;
+    if speed_bonus > 0 {
+        lines.push((format!("Quick, in {} turns", game.turn), speed_bonus));
+    }
+    // a conduct only counts for something once the player got anywhere
+    let conducts = [
+        (stats.items_used, "Used no items"),
+        (stats.purchases, "Bought nothing"),
+        (stats.prayers, "Never prayed"),
+    ];
+    for &(_, conduct) in conducts.iter().filter(|&&(broken, _)| broken == 0 && deepest > 0) {
+        lines.push((conduct.into(), deepest * CONDUCT_BONUS_PER_LEVEL));
+    }
+    for mutator in game.mutators.iter().filter(|_| deepest > 0) {
+        lines.push((format!("Played with {}", mutator.name()), deepest * MUTATOR_BONUS_PER_LEVEL));
+    }
+    if stats.debug_commands > 0 {
+        lines.push((format!("Cheated {} times", stats.debug_commands),
+                    -(stats.debug_commands as i32) * DEBUG_COMMAND_PENALTY));
+    }
+    let total = cmp::max(lines.iter().map(|&(_, points)| points).sum(), 0);
+    lines.push(("Score".into(), total));
+    lines
+}
+
+fn total_score(game: &Game) -> i32 {
+    score(game).last().map_or(0, |&(_, total)| total)
+}
+
+/// the mutators, for showing them to the player
+fn mutator_names(mutators: &[Mutator]) -> String {
+    if mutators.is_empty() {
+        return "none".into();
+    }
+    mutators.iter().map(|mutator| mutator.name()).collect::<Vec<_>>().join(", ")
+}
+
+/// Show what the player's score is made of, when the game is over.
+fn show_score(tcod: &mut Tcod, objects: &[Object], game: &Game) {
+    let player = &objects[PLAYER];
+    let mut text = format!("You died at character level {}, after {} turns.\n{}\n\n",
+                           player.level, game.turn, game.level_title());
+    let lines = score(game);
+    for (i, &(ref what, points)) in lines.iter().enumerate() {
+        if i == lines.len() - 1 {
+            text.push('\n');
+        }
+        text.push_str(&format!("{:<32}{:>8}\n", what, points));
+    }
+    msgbox(&text, CHARACTER_SCREEN_WIDTH + 10, tcod);
+}
+
+/// The hunter's offer: `reward` gold for `count` of the trophy called
+/// `trophy`, one bounty at a time.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Bounty {
+    trophy: String,
+    count: u32,
+    reward: u32,
+}
+
+impl Game {
+    pub fn rules(&self) -> &'static rules::Rules {
+        rules::get(self.rule_set)
+    }
+
+    /// where the player is
+    pub fn depth(&self) -> Depth {
+        Depth::new(self.branch, self.dungeon_level)
+    }
+
+    /// how the level the player is on looks
+    pub fn theme(&self) -> Theme {
+        Theme::of(self.depth())
+    }
+
+    /// what the panel calls the level the player is on
+    pub fn level_title(&self) -> String {
+        self.depth().title()
+    }
+
+    /// how far the player's light reaches
+    pub fn light_radius(&self) -> i32 {
+        let radius = if self.torch_lit {
+            CONFIG.torch_radius
+        } else {
+            cmp::min(DOUSED_TORCH_RADIUS, CONFIG.torch_radius)
+        };
+        if self.has_mutator(Mutator::LowLight) {
+            cmp::max(radius * LOW_LIGHT_PERCENT / 100, 1)
+        } else {
+            radius
+        }
+    }
+
+    pub fn has_mutator(&self, mutator: Mutator) -> bool {
+        self.mutators.contains(&mutator)
+    }
+}
+
+/// A dungeon level the player isn't on right now.
+#[derive(Serialize, Deserialize)]
+struct Level {
+    #[serde(serialize_with = "serialize_map", deserialize_with = "deserialize_map")]
+    map: Map,
+    /// everything on the level except the player
+    objects: Vec<Object>,
+    /// where the player was when they left the level
+    player_pos: (i32, i32),
+    /// its timed events, they keep their turn while the player is away
+    schedule: Schedule,
+    /// see `Game::smoke`
+    smoke: Vec<(i32, i32, u32)>,
+}
+
+/// A level in the cache, it goes into a save as a `SavedLevel`.
+enum CachedLevel {
+    Loaded(Level),
+    /// a level far away from the player, kept as its serialized form; only
+    /// until it can be written to disk
+    Stored(String),
+    /// a level far away from the player, serialized to this file. It's
+    /// saved as `Stored` so a save is complete on its own, and a save never
+    /// points at a file.
+    OnDisk(std::path::PathBuf),
+}
+
+/// How a cached level goes into a save, the same as `CachedLevel` with the
+/// levels on disk read back in. It's saved from a `&Level` and loaded as a
+/// `Level`.
+#[derive(Serialize, Deserialize)]
+enum SavedLevel<L> {
+    Loaded(L),
+    Stored(String),
+}
+
+fn serialize_levels<S: serde::Serializer>(levels: &Vec<(Depth, CachedLevel)>, serializer: S)
+                                          -> Result<S::Ok, S::Error> {
+    use serde::Serialize;
+    use serde::ser::Error;
+    let mut saved = vec![];
+    for &(depth, ref level) in levels {
+        let level = match *level {
+            CachedLevel::Loaded(ref level) => SavedLevel::Loaded(level),
+            CachedLevel::Stored(ref data) => SavedLevel::Stored(data.clone()),
+            CachedLevel::OnDisk(ref path) => {
+                let mut data = String::new();
+                try! {
+                    File::open(path).and_then(|mut file| file.read_to_string(&mut data))
+                        .map_err(|e| S::Error::custom(format!("could not read {}: {}", path.display(), e)))
+                };
+                SavedLevel::Stored(data)
+            }
+        };
+        saved.push((depth, level));
+    }
+    saved.serialize(serializer)
+}
+
+fn deserialize_levels<D: serde::Deserializer>(deserializer: D) -> Result<Vec<(Depth, CachedLevel)>, D::Error> {
+    use serde::Deserialize;
+    let saved: Vec<(Depth, SavedLevel<Level>)> = try! { Deserialize::deserialize(deserializer) };
+    Ok(saved.into_iter()
+        .map(|(depth, level)| match level {
+            SavedLevel::Loaded(level) => (depth, CachedLevel::Loaded(level)),
+            SavedLevel::Stored(data) => (depth, CachedLevel::Stored(data)),
+        })
+        .collect())
+}
+
+/// The directory a level cache writes its levels to, one for each cache so
+/// two games never share it. It's removed along with the cache.
+struct LevelFiles {
+    dir: std::path::PathBuf,
+}
+
+impl Default for LevelFiles {
+    fn default() -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let name = format!("tombs-levels-{}-{}", std::process::id(), id);
+        LevelFiles { dir: std::env::temp_dir().join(name) }
+    }
+}
+
+impl LevelFiles {
+    /// write a serialized level to its file
+    fn write(&self, depth: Depth, data: &str) -> std::io::Result<std::path::PathBuf> {
+        try! { std::fs::create_dir_all(&self.dir) };
+        let path = self.dir.join(format!("{:?}-{}.json", depth.branch, depth.level));
+        try! { File::create(&path).and_then(|mut file| file.write_all(data.as_bytes())) };
+        Ok(path)
+    }
+}
+
+impl Drop for LevelFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// The levels the player has left behind. Levels near the player stay in
+/// memory, the others are serialized to disk and only loaded again when the
+/// player comes back to them, so memory use doesn't grow with the dungeon's
+/// depth.
+#[derive(Serialize, Deserialize)]
+struct LevelCache {
+    #[serde(serialize_with = "serialize_levels", deserialize_with = "deserialize_levels")]
+    levels: Vec<(Depth, CachedLevel)>,
+    #[serde(skip_serializing, skip_deserializing)]
+    files: LevelFiles,
+}
+
+impl LevelCache {
+    pub fn new() -> Self {
+        LevelCache { levels: vec![], files: Default::default() }
+    }
+
+    pub fn store(&mut self, depth: Depth, level: Level) {
+        self.levels.retain(|&(cached, _)| cached != depth);
+        self.levels.push((depth, CachedLevel::Loaded(level)));
+    }
+
+    /// whether the player has been on the level
+    pub fn contains(&self, depth: Depth) -> bool {
+        self.levels.iter().any(|&(cached, _)| cached == depth)
+    }
+
+    /// every level the player has been on, except the one they're on
+    pub fn depths(&self) -> Vec<Depth> {
+        self.levels.iter().map(|&(depth, _)| depth).collect()
+    }
+
+    /// remove a level from the cache, loading it if it was stored away.
+    /// A level that can't be loaded is gone from the cache all the same.
+    pub fn take(&mut self, depth: Depth) -> Result<Option<Level>, String> {
+        let index = match self.levels.iter().position(|&(cached, _)| cached == depth) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let data = match self.levels.remove(index).1 {
+            CachedLevel::Loaded(level) => return Ok(Some(level)),
+            CachedLevel::Stored(data) => data,
+            CachedLevel::OnDisk(path) => {
+                let mut data = String::new();
+                let read = File::open(&path).and_then(|mut file| file.read_to_string(&mut data));
+                let _ = std::fs::remove_file(&path);
+                try! { read.map_err(|e| format!("could not read {}: {}", path.display(), e)) };
+                data
+            }
+        };
+        serde_json::from_str(&data).map(Some).map_err(|e| e.to_string())
+    }
+
+    /// write every level too far from the current one to disk, one that
+    /// can't be written stays in memory serialized
+    pub fn evict_distant(&mut self, current: Depth) {
+        for &mut (depth, ref mut slot) in self.levels.iter_mut() {
+            let stored = match *slot {
+                CachedLevel::Loaded(ref level) if depth.distance(current) > LEVEL_CACHE_RADIUS => {
+                    serde_json::to_string(level).expect("Could not store a cached level")
+                }
+                // from a loaded save
+                CachedLevel::Stored(ref data) => data.clone(),
+                _ => continue,
+            };
+            *slot = match self.files.write(depth, &stored) {
+                Ok(path) => CachedLevel::OnDisk(path),
+                Err(e) => {
+                    warn!("keeping {:?} level {} in memory, it could not be written to disk: {}",
+                          depth.branch, depth.level, e);
+                    CachedLevel::Stored(stored)
+                }
+            };
+        }
+    }
+}
+
+/// Something that happens at a given turn rather than when someone acts.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+enum TimedEvent {
+    /// the monster comes to its senses
+    EndConfusion(ObjectId),
+    /// the monster stops looking for a noise, if it hasn't found anything
+    EndInvestigation(ObjectId),
+    /// the monster stops running
+    EndFlight(ObjectId),
+    /// whatever remains are due rot away
+    Rot,
+    /// keep the number of remains in check, repeats every CLEANUP_INTERVAL turns
+    Cleanup,
+    /// the town's festival is over
+    EndFestival,
+    /// see whether the raiders in town are dead, repeats until they are
+    CheckRaid,
+    /// the smoke around (x, y) clears, see `make_smoke`
+    ClearSmoke{x: i32, y: i32, radius: i32},
+    /// the monster brings forth the minion it called up, see `call_up_minion`
+    Summon(ObjectId),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ScheduledEvent {
+    turn: u32,
+    /// events due on the same turn happen in the order they were scheduled
+    order: u64,
+    event: TimedEvent,
+}
+
+impl PartialEq for ScheduledEvent {
+    fn eq(&self, other: &Self) -> bool {
+        (self.turn, self.order) == (other.turn, other.order)
+    }
+}
+
+impl Eq for ScheduledEvent {}
+
+impl PartialOrd for ScheduledEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for ScheduledEvent {
+    // reversed, so that the heap gives out the earliest event first
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (other.turn, other.order).cmp(&(self.turn, self.order))
+    }
+}
+
+/// The world clock: everything that happens after a while is kept here,
+/// keyed on the turn it happens at, instead of every feature counting down
+/// turns of its own.
+#[derive(Serialize, Deserialize)]
+struct Schedule {
+    events: BinaryHeap<ScheduledEvent>,
+    next_order: u64,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Schedule { events: BinaryHeap::new(), next_order: 0 }
+    }
+
+    /// the schedule of a level that was just created
+    pub fn starting_at(turn: u32) -> Self {
+        let mut schedule = Schedule::new();
+        schedule.at(turn + CLEANUP_INTERVAL, TimedEvent::Cleanup);
+        schedule
+    }
+
+    pub fn at(&mut self, turn: u32, event: TimedEvent) {
+        self.events.push(ScheduledEvent { turn: turn, order: self.next_order, event: event });
+        self.next_order += 1;
+    }
+
+    /// take out the earliest event that is due by `turn`
+    pub fn pop_due(&mut self, turn: u32) -> Option<TimedEvent> {
+        if self.events.peek().map_or(false, |next| next.turn <= turn) {
+            self.events.pop().map(|scheduled| scheduled.event)
+        } else {
+            None
+        }
+    }
+}
+
+/// Make everything that is due by now happen.
+fn run_schedule(objects: &mut Vec<Object>, game: &mut Game, fov_map: &FovMap) {
+    while let Some(event) = game.schedule.pop_due(game.turn) {
+        debug!("turn {}: {:?}", game.turn, event);
+        // whoever the event is about may be gone by now, another object in
+        // their slot
+        match event {
+            TimedEvent::EndConfusion(monster) | TimedEvent::EndFlight(monster) |
+            TimedEvent::EndInvestigation(monster) if monster.find(objects).is_none() => {}
+            TimedEvent::EndConfusion(monster) => end_confusion(monster.index, objects, game),
+            TimedEvent::EndFlight(monster) => end_flight(monster.index, objects, game),
+            TimedEvent::EndInvestigation(monster) => end_investigation(monster.index, objects, game),
+            TimedEvent::Rot => remove_rotten(objects, game, fov_map),
+            TimedEvent::Cleanup => {
+                cleanup_level(objects, game, fov_map);
+                let next = game.turn + CLEANUP_INTERVAL;
+                game.schedule.at(next, TimedEvent::Cleanup);
+            }
+            TimedEvent::EndFestival => end_town_event(game),
+            TimedEvent::CheckRaid => check_raid(objects, game),
+            TimedEvent::ClearSmoke{x, y, radius} => clear_smoke(x, y, radius, game),
+            TimedEvent::Summon(summoner) => raise_minion(summoner, objects, game, fov_map),
+        }
+    }
+}
+
+trait MessageLog {
+    fn add<T: Into<String>>(&mut self, message: T, color: Color);
+}
+
+impl MessageLog for Vec<(String, Color)> {
+    fn add<T: Into<String>>(&mut self, message: T, color: Color) {
+        self.push((message.into(), color));
+    }
+}
+
+fn new_game(rule_set: rules::RuleSet) -> (Vec<Object>, Game) {
+    // create object representing the player
+    let mut player = Object::new(0, 0, '@', "player", colors::WHITE, true);
+    player.alive = true;
+    player.gold = STARTING_GOLD;
+    player.fighter = Some(Fighter{base_max_hp: 100, hp: 100, base_defense: 1, base_power: 2, xp: 0,
+                                  speed: NORMAL_SPEED, energy: ACTION_COST, morale: 100,
+                                  on_death: DeathCallback::Player});
+
+    // the list of objects with just the player
+    let mut objects = vec![player];
+    let depth = Depth::town();
+
+    let mut game = Game {
+        // generate map (at this point it's not drawn to the screen)
+        map: make_map(&mut objects, depth, rules::get(rule_set), &[]).0,
+        // create the list of game messages and their colors, starts empty
+        log: vec![],
+        branch: depth.branch,
+        dungeon_level: depth.level,
+        turn: 0,
+        schedule: Schedule::starting_at(0),
+        smoke: vec![],
+        levels: LevelCache::new(),
+        rule_set: rule_set,
+        torch_lit: true,
+        portal: None,
+        stash: vec![],
+        level_ups: vec![],
+        trainings: 0,
+        town_event: None,
+        next_town_event: 0,
+        cleared_levels: vec![],
+        ironman: false,
+        mutators: vec![],
+        bounty: None,
+        stats: Default::default(),
+        scent: Default::default(),
+        blockers: Default::default(),
+        shots: vec![],
+        free_slots: vec![],
+    };
+
+    // initial equipment: a dagger
+    let mut dagger = Object::new(0, 0, '-', "dagger", colors::SKY, false);
+    dagger.item = Some(Item::Sword);
+    dagger.equipment = Some(Equipment {
+        equipped: true,
+        slot: Slot::LeftHand,
+        max_hp_bonus: 0,
+        defense_bonus: 0,
+        power_bonus: 2,
+        luck_bonus: 0,
+    });
+    objects[PLAYER].inventory.push(dagger);
+
+    // and a dog at their side
+    let (x, y) = objects[PLAYER].pos();
+    if let Some((x, y)) = spot_beside(x, y, &objects, &game.map) {
+        objects.extend(create_monster("dog", x, y));
+    }
+
+    // a warm welcoming message!
+    game.log.add("Welcome stranger! Prepare to perish in the Tombs of the Ancient Kings.",
+                 colors::RED);
+
+    (objects, game)
+}
+
+fn initialise_fov(map: &Map, fov_map: &mut FovMap) {
+    // create the FOV map, according to the generated map
+    for y in 0..CONFIG.map_height {
+        for x in 0..CONFIG.map_width {
+            fov_map.set(x, y,
+                !map[x as usize][y as usize].block_sight,
+                !map[x as usize][y as usize].blocked);
+        }
+    }
+}
+
+/// Play until the player leaves the game. Should the game crash, it tries
+/// to save what it can and writes a crash report before giving up.
+fn play_game(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod, slot: usize) {
+    let played = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| run_game(objects, game, tcod, slot)));
+    if let Err(panic) = played {
+        // whatever was going on may have left the game half-changed, but
+        // that beats losing it altogether
+        let saved = if game.ironman {
+            Ok("the ironman journal has every turn up to the crash".to_string())
+        } else {
+            let path = autosave_path(slot);
+            let format = tcod.save_format;
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| save_game(objects, game, format, &path)))
+                .map_err(|_| "saving crashed too".to_string())
+                .and_then(|saved| saved.map_err(|e| e.to_string()))
+                .map(|()| format!("saved to {}", path.display()))
+        };
+        match saved {
+            Ok(ref how) => info!("emergency save: {}", how),
+            Err(ref e) => error!("the emergency save failed: {}", e),
+        }
+        if let Err(e) = write_crash_report(objects, game, tcod, &saved) {
+            error!("could not write the crash report: {}", e);
+        }
+        std::panic::resume_unwind(panic);
+    }
+}
+
+/// Write down what went wrong and what the game was up to for whoever
+/// fixes it: the panic with its backtrace, the seed, where the player was
+/// and what they did last.
+fn write_crash_report(objects: &[Object], game: &Game, tcod: &Tcod, saved: &Result<String, String>)
+                      -> std::io::Result<()> {
+    let panic = PANIC_REPORT.with(|report| report.borrow().clone()).unwrap_or("no panic message".into());
+    let seed = SEED.with(|seed| seed.get()).map_or("unknown".to_string(), |seed| seed.to_string());
+    let commands: Vec<String> = tcod.recent_commands.iter().map(|command| format!("{:?}", command)).collect();
+    let depth = game.depth();
+    let report = format!("Tombs of the Ancient Kings {} crashed.
+
+Seed: {}
+Rules: {}
+Turn: {}
+Level: {:?} {}
+Player: {} at {:?}
+Emergency save: {}
+Last commands: {}
+
+{}
+", env!("CARGO_PKG_VERSION"), seed, game.rule_set.name(), game.turn, depth.branch, depth.level,
+                         objects[PLAYER].name, objects[PLAYER].pos(),
+                         match *saved { Ok(ref how) => how.clone(), Err(ref e) => format!("failed, {}", e) },
+                         commands.join(", "), panic);
+    let mut file = try! { File::create(CRASH_REPORT_PATH) };
+    file.write_all(report.as_bytes())
+}
+
+/// Remember what a panic said and where for the crash report, and put it
+/// in the log. In a window it's printed as usual, in the terminal it would
+/// only garble the screen so `play` tells the player once it's restored.
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        error!("the game crashed: {}", info);
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        PANIC_REPORT.with(|report| *report.borrow_mut() = Some(format!("{}\n\nBacktrace:\n{}", info, backtrace)));
+        if !options().terminal {
+            default_hook(info);
+        }
+    }));
+}
+
+fn run_game(objects: &mut Vec<Object>, game: &mut Game, tcod: &mut Tcod, slot: usize) {
+    // force FOV "recompute" first time through the game loop
+    let mut previous_player_position = (-1, -1);
+
+    let mut key = Default::default();
+    let mut last_autosave = (game.depth(), game.turn);
+    // an ironman game is deleted as soon as the player dies
+    let mut dead = false;
+    // and every game shows its score when the player dies
+    let mut score_shown = !objects[PLAYER].alive;
+    if game.ironman {
+        let started = save_game(objects, game, tcod.save_format, &slot_path(slot))
+            .and_then(|()| start_journal(game, &journal_path(slot)));
+        if let Err(e) = started {
+            game.log.add(format!("Could not save: {}", e), colors::RED);
+        }
+    }
+
+    loop {
+        let input_start = Instant::now();
+        match tcod.backend.check_for_event() {
+            Some(Event::Mouse(m)) => {
+                tcod.mouse = m;
+                if CONFIG.mouse_action(&m) == MouseAction::Examine {
+                    if let Some((x, y)) = tcod.camera.to_world(m.cx as i32, m.cy as i32) {
+                        examine(x, y, objects, game, &tcod.fov);
+                    }
+                }
+            }
+            Some(Event::Key(k)) => key = k,
+            _ => key = Default::default(),
+        }
+        let mut input_time = input_start.elapsed();
+
+        // render the screen
+        let render_start = Instant::now();
+        let fov_recompute = previous_player_position != (objects[PLAYER].pos());
+        render_all(tcod, &objects, game, fov_recompute);
+
+        tcod.flush();
+        tcod.frame_times.render = render_start.elapsed() - tcod.frame_times.fov;
+
+        // level up if needed
+        level_up(objects, game, tcod);
+
+        // erase all objects at their old locations, before they move
+        for object in objects.iter_mut() {
+            object.clear(&mut tcod.con, &tcod.camera)
+        }
+
+        // handle keys and exit game if needed
+        previous_player_position = objects[PLAYER].pos();
+        let input_start = Instant::now();
+        let travelling = tcod.travel_target.is_some() || !tcod.travel_route.is_empty();
+        let player_action = if key.code == input::KeyCode::NoKey && travelling {
+            travel_step(tcod, objects, game)
+        } else if key.code == input::KeyCode::NoKey && tcod.open_stash.is_some() {
+            reopen_stash(tcod, objects, game)
+        } else {
+            // any key press interrupts auto-travel
+            if key.code != input::KeyCode::NoKey {
+                tcod.travel_target = None;
+                tcod.travel_route.clear();
+            }
+            match tcod.keymap.action(key) {
+                Some(input) => {
+                    if tcod.recent_commands.len() == RECENT_COMMANDS {
+                        tcod.recent_commands.pop_front();
+                    }
+                    tcod.recent_commands.push_back(input);
+                    handle_keys(input, tcod, objects, game)
+                }
+                None => PlayerAction::DidntTakeTurn,
+            }
+        };
+        input_time += input_start.elapsed();
+        tcod.frame_times.input = input_time;
+        if player_action == PlayerAction::Exit && game.ironman && !objects[PLAYER].alive {
+            // already gone for good
+            break;
+        }
+        if player_action == PlayerAction::Exit && save_on_exit(objects, game, tcod, slot) {
+            break;
+        }
+
+        // let monstars take their turns
+        let turn = game.turn;
+        if let (true, PlayerAction::TookTime(cost)) = (objects[PLAYER].alive, player_action) {
+            let ai_start = Instant::now();
+            end_player_turn(cost, objects, game, &mut tcod.fov);
+            tcod.frame_times.ai = ai_start.elapsed();
+            animate_shots(tcod, objects, game);
+            // doors may have opened or closed even if the player stayed put
+            previous_player_position = (-1, -1);
+        }
+        if !objects[PLAYER].alive && !score_shown {
+            render_all(tcod, objects, game, false);
+            show_score(tcod, objects, game);
+            score_shown = true;
+        }
+
+        // save a checkpoint on each new level and every so often; an
+        // ironman game saves over itself, with each turn in between in its
+        // journal, and is gone once the player dies
+        let (autosave_depth, autosave_turn) = last_autosave;
+        let checkpoint = game.depth() != autosave_depth || game.turn >= autosave_turn + AUTOSAVE_INTERVAL;
+        if game.ironman && !objects[PLAYER].alive {
+            if !dead {
+                clear_slot(slot);
+                dead = true;
+            }
+        } else if game.ironman {
+            let saved = if checkpoint {
+                last_autosave = (game.depth(), game.turn);
+                save_game(objects, game, tcod.save_format, &slot_path(slot))
+                    .and_then(|()| start_journal(game, &journal_path(slot)))
+            } else if game.turn != turn {
+                append_to_journal(objects, game, tcod.save_format, &journal_path(slot))
+            } else {
+                Ok(())
+            };
+            if let Err(e) = saved {
+                game.log.add(format!("Could not save: {}", e), colors::RED);
+            }
+        } else if checkpoint {
+            if let Err(e) = save_game(objects, game, tcod.save_format, &autosave_path(slot)) {
+                game.log.add(format!("Could not autosave: {}", e), colors::RED);
+            }
+            last_autosave = (game.depth(), game.turn);
+        }
+
+        // the window may have been closed in the middle of a menu or while
+        // picking a target, which gave up on what they were asking for:
+        // the game is saved as if the player had quit
+        if tcod.backend.window_closed() {
+            if !dead {
+                save_on_exit(objects, game, tcod, slot);
+            }
+            break;
+        }
+    }
+}
+
+/// Save the game in its slot on the way out, its checkpoint and journal
+/// aren't needed then. If it can't be saved the player is asked whether to
+/// quit anyway, returns whether to.
+fn save_on_exit(objects: &[Object], game: &Game, tcod: &mut Tcod, slot: usize) -> bool {
+    match save_game(objects, game, tcod.save_format, &slot_path(slot)) {
+        Ok(()) => {
+            // the proper save is the newest now
+            let _ = std::fs::remove_file(autosave_path(slot));
+            let _ = std::fs::remove_file(journal_path(slot));
+            true
+        }
+        Err(e) if tcod.backend.window_closed() => {
+            // nobody left to ask, the last checkpoint will have to do
+            error!("could not save the game as the window closed: {}", e);
+            true
+        }
+        Err(e) => {
+            let question = format!("\nCould not save the game: {}\n", e);
+            let choices = &["Keep playing", "Quit without saving"];
+            menu(&question, choices, 40, tcod) == Some(1)
+        }
+    }
+}
+
+/// Show the shots the monsters fired on their turns fly, one after the
+/// other, over the tiles the player can see.
+fn animate_shots(tcod: &mut Tcod, objects: &[Object], game: &mut Game) {
+    let shots = std::mem::replace(&mut game.shots, vec![]);
+    for shot in &shots {
+        for &(x, y) in &shot.path {
+            if !tcod.fov.is_in_fov(x, y) {
+                continue;
+            }
+            render_all(tcod, objects, game, false);
+            if let Some((screen_x, screen_y)) = tcod.camera.to_screen(x, y) {
+                tcod.root.put_char(screen_x, screen_y, shot.glyph, shot.color);
+            }
+            tcod.flush();
+            std::thread::sleep(Duration::from_millis(SHOT_FRAME_MS));
+        }
+    }
+    game.shots = shots;
+}
+
+/// Let the rest of the world catch up after the player took a turn.
+fn end_player_turn(cost: i32, objects: &mut Vec<Object>, game: &mut Game, fov_map: &mut FovMap) {
+    // the last turn's shots were shown, if there's a screen to show them
+    game.shots.clear();
+    // monsters act until the player can act again
+    if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+        fighter.energy -= cost;
+    }
+    terrain_effects(PLAYER, objects, game, fov_map);
+    // the smoke the player made hides them before the monsters get to look
+    compute_fov(fov_map, objects, game);
+    // whoever lost their leader to the player's action runs before it's their turn
+    check_morale(objects, game, fov_map);
+    check_nerve(objects, game, fov_map);
+    let hp = objects[PLAYER].fighter.map_or(0, |f| f.hp);
+    advance_time(objects, game, fov_map);
+    if game.has_mutator(Mutator::FragileItems) && objects[PLAYER].fighter.map_or(0, |f| f.hp) < hp {
+        break_fragile_item(objects, game);
+    }
+    drop_loot_of_the_dead(objects, game);
+    // the player may notice a secret door without looking for it
+    search(PASSIVE_SEARCH_CHANCE, objects, game);
+
+    game.turn += 1;
+    debug!("turn {} on level {}: the player is at {:?} with {} hp",
+           game.turn, game.dungeon_level, objects[PLAYER].pos(), objects[PLAYER].fighter.map_or(0, |f| f.hp));
+    suffer_poison(objects, game);
+    run_schedule(objects, game, fov_map);
+}
+
+/// Maybe break one of the items the player carries but doesn't wear or
+/// wield, they were hurt with the fragile items mutator on.
+fn break_fragile_item(objects: &mut [Object], game: &mut Game) {
+    let fragile: Vec<usize> = (0..objects[PLAYER].inventory.len())
+        .filter(|&index| objects[PLAYER].inventory[index].equipment.is_none())
+        .collect();
+    if fragile.is_empty() || game_rng().gen::<f32>() >= FRAGILE_BREAK_CHANCE {
+        return;
+    }
+    let index = fragile[game_rng().gen_range(0, fragile.len())];
+    let item = objects[PLAYER].inventory.remove(index);
+    game.log.add(format!("Your {} breaks!", item.name), colors::LIGHT_RED);
+}
+
+/// The game without a window: it is driven by `Action`s rather than key
+/// presses, so bots, tests or a server can play it.
+struct Simulation {
+    objects: Vec<Object>,
+    game: Game,
+    fov: FovMap,
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        let (objects, game) = new_game(rules::RuleSet::latest());
+        let mut fov = FovMap::new(CONFIG.map_width, CONFIG.map_height);
+        initialise_fov(&game.map, &mut fov);
+        let mut simulation = Simulation { objects: objects, game: game, fov: fov };
+        compute_fov(&mut simulation.fov, &simulation.objects, &mut simulation.game);
+        simulation
+    }
+
+    /// perform the player's action and let the world react to it
+    pub fn step(&mut self, action: Action) -> PlayerAction {
+        let player_action = perform_action(action, &mut self.objects, &mut self.game, &mut self.fov);
+        if let (true, PlayerAction::TookTime(cost)) = (self.objects[PLAYER].alive, player_action) {
+            end_player_turn(cost, &mut self.objects, &mut self.game, &mut self.fov);
+        }
+        compute_fov(&mut self.fov, &self.objects, &mut self.game);
+        player_action
+    }
+
+    pub fn player_alive(&self) -> bool {
+        self.objects[PLAYER].alive
+    }
+
+    /// A small level drawn as text, to see what the monsters do on it: '#'
+    /// is a wall, '.' the floor, '@' the player and any other character the
+    /// species `legend` gives it. Everything off the drawing is rock, and
+    /// nothing else is on the level.
+    #[cfg(test)]
+    pub fn from_ascii(rows: &[&str], legend: &[(char, &str)]) -> Result<Self, String> {
+        let (mut objects, mut game) = new_game(rules::RuleSet::latest());
+        objects.truncate(PLAYER + 1);
+        game.branch = Branch::Main;
+        game.dungeon_level = 1;
+        game.map = vec![vec![Tile::wall(); CONFIG.map_height as usize]; CONFIG.map_width as usize];
+        for (y, row) in rows.iter().enumerate() {
+            for (x, symbol) in row.chars().enumerate() {
+                let (x, y) = (x as i32, y as i32);
+                if x >= CONFIG.map_width || y >= CONFIG.map_height {
+                    return Err(format!("({}, {}) is off the map", x, y));
+                }
+                if symbol != '#' {
+                    game.map[x as usize][y as usize] = Tile::empty();
+                }
+                match symbol {
+                    '#' | '.' => {}
+                    '@' => objects[PLAYER].set_pos(x, y),
+                    _ => {
+                        let monster = legend.iter()
+                            .find(|&&(glyph, _)| glyph == symbol)
+                            .and_then(|&(_, species)| create_monster(species, x, y));
+                        match monster {
+                            Some(monster) => objects.push(monster),
+                            None => return Err(format!("there's no monster for '{}'", symbol)),
+                        }
+                    }
+                }
+            }
+        }
+        let mut fov = FovMap::new(CONFIG.map_width, CONFIG.map_height);
+        initialise_fov(&game.map, &mut fov);
+        let mut simulation = Simulation { objects: objects, game: game, fov: fov };
+        compute_fov(&mut simulation.fov, &simulation.objects, &mut simulation.game);
+        Ok(simulation)
+    }
+
+    /// let the monsters have `turns` turns while the player stands still
+    #[cfg(test)]
+    pub fn wait(&mut self, turns: u32) {
+        for _ in 0..turns {
+            if !self.player_alive() {
+                break;
+            }
+            self.step(Action::Wait);
+        }
+    }
+
+    /// the ids of the living monsters of a species, in the order the
+    /// drawing has them: left to right, top to bottom
+    #[cfg(test)]
+    pub fn monsters(&self, species: &str) -> Vec<usize> {
+        let mut ids: Vec<usize> = (PLAYER + 1..self.objects.len())
+            .filter(|&id| self.objects[id].alive && self.objects[id].name == species)
+            .collect();
+        ids.sort_by_key(|&id| (self.objects[id].y, self.objects[id].x));
+        ids
+    }
+
+    #[cfg(test)]
+    pub fn hp(&self, id: usize) -> i32 {
+        self.objects[id].fighter.map_or(0, |f| f.hp)
+    }
+}
+
+/// A very simple bot: levels up, fights whatever it sees, picks up what it
+/// stands on, takes the stairs and otherwise wanders around.
+fn bot_action(simulation: &Simulation) -> Action {
+    let objects = &simulation.objects;
+    let player = &objects[PLAYER];
+    if ready_to_level_up(player, simulation.game.rules()) {
+        return Action::LevelUp(Stat::Strength);
+    }
+    let closest_monster = objects.iter()
+        .filter(|o| o.fighter.is_some() && o.ai.is_some() && o.is_hostile() && simulation.fov.is_in_fov(o.x, o.y))
+        .min_by_key(|o| (player.distance_to(o) * 100.0) as i32);
+    if let Some(monster) = closest_monster {
+        let dx = (monster.x - player.x).signum();
+        let dy = (monster.y - player.y).signum();
+        return Action::MoveOrAttack(dx, dy);
+    }
+    let here: Vec<_> = objects.iter().skip(1).filter(|o| o.pos() == player.pos()).collect();
+    if here.iter().any(|o| o.item.is_some()) && player.inventory.len() < INVENTORY_SIZE {
+        return Action::PickUp;
+    }
+    if here.iter().any(|o| o.name == "stairs") {
+        return Action::Descend;
+    }
+    let mut rng = game_rng();
+    Action::MoveOrAttack(rng.gen_range(-1, 2), rng.gen_range(-1, 2))
+}
+
+/// Check the game's data the way the game would read it and return
+/// everything that is wrong, so mistakes show up before playing.
+fn validate_data() -> Vec<String> {
+    let mut problems = Config::check(&options().config_path);
+    problems.extend(Keymap::check(KEYMAP_PATH));
+
+    // scripts have to compile and declare a targeting the game knows
+    let scripts = std::fs::read_dir("scripts").into_iter()
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("rhai"));
+    for path in scripts {
+        let name = path.file_stem().and_then(|name| name.to_str()).unwrap_or("").to_string();
+        let checked = EFFECTS.with(|effects| {
+            effects.load_script(&name).and_then(|ast| effects.script_targeting(&ast))
+        });
+        if let Err(e) = checked {
+            problems.push(format!("{}: {}", path.display(), e));
+        }
+    }
+
+    // spawn tables go up by level, something can always spawn and every
+    // species exists
+    let mut monsters = vec![];
+    let mut items = vec![];
+    for set in rules::RuleSet::all() {
+        let rules = rules::get(set);
+        let mut tables: Vec<(String, &[Transition])> = vec![
+            ("the monsters per room".into(), &rules.max_monsters),
+            ("the items per room".into(), &rules.max_items),
+        ];
+        tables.extend(rules.monster_chances.iter()
+            .map(|&(ref species, ref table)| (format!("the {}", species), &table[..])));
+        tables.extend(rules.item_chances.iter().map(|&(item, ref table)| (format!("{:?} items", item), &table[..])));
+        tables.extend(rules.packs.iter().map(|pack| (format!("packs of the {}", pack.leader), &pack.chance[..])));
+        tables.push(("the traps per room".into(), &rules.max_traps));
+        tables.extend(rules.trap_chances.iter().map(|&(kind, ref table)| (format!("{}s", kind.name()), &table[..])));
+        for branch in &rules.branches {
+            tables.extend(branch.monster_chances.iter()
+                .map(|&(ref species, ref table)| {
+                    (format!("the {} in the {:?}", species, branch.branch), &table[..])
+                }));
+        }
+        for &(ref name, table) in &tables {
+            if table.windows(2).any(|pair| pair[0].level >= pair[1].level) {
+                problems.push(format!("{} rules, spawn table for {}: levels have to go up", set.name(), name));
+            }
+        }
+        let levels: Vec<u32> = tables.iter().flat_map(|&(_, table)| table.iter().map(|t| t.level)).collect();
+        for &level in levels.iter().chain(&[1]) {
+            if rules.monster_chances.iter().all(|&(_, ref table)| from_dungeon_level(table, level) == 0) {
+                problems.push(format!("{} rules: no monster can spawn on level {}", set.name(), level));
+            }
+            if rules.item_chances.iter().all(|&(_, ref table)| from_dungeon_level(table, level) == 0) {
+                problems.push(format!("{} rules: no item can spawn on level {}", set.name(), level));
+            }
+            if from_dungeon_level(&rules.max_traps, level) > 0 &&
+                rules.trap_chances.iter().all(|&(_, ref table)| from_dungeon_level(table, level) == 0) {
+                problems.push(format!("{} rules: traps but no kind of trap on level {}", set.name(), level));
+            }
+        }
+
+        // every branch has its own stairs and monsters on each of its levels
+        for (i, branch) in rules.branches.iter().enumerate() {
+            if branch.branch == Branch::Main || branch.levels == 0 {
+                problems.push(format!("{} rules: the {:?} can't be a branch", set.name(), branch.branch));
+            }
+            if rules.branches[..i].iter().any(|other| other.entry_level == branch.entry_level) {
+                problems.push(format!("{} rules: two branches start on level {}", set.name(), branch.entry_level));
+            }
+            if boss_of(Depth::new(Branch::Main, branch.entry_level), rules).is_some() {
+                problems.push(format!("{} rules: the {:?} starts in a boss's arena", set.name(), branch.branch));
+            }
+            for level in 1..branch.levels + 1 {
+                let difficulty = rules.difficulty(Depth::new(branch.branch, level));
+                if branch.monster_chances.iter().all(|&(_, ref table)| from_dungeon_level(table, difficulty) == 0) {
+                    problems.push(format!("{} rules: no monster can spawn on level {} of the {:?}",
+                                          set.name(), level, branch.branch));
+                }
+            }
+        }
+
+        let pack_species = rules.packs.iter().flat_map(|pack| vec![&pack.leader[..], &pack.follower[..]]);
+        let branch_species = rules.branches.iter()
+            .flat_map(|branch| branch.monster_chances.iter().map(|&(ref species, _)| &species[..]));
+        let species = rules.monster_chances.iter().map(|&(ref species, _)| &species[..])
+            .chain(pack_species)
+            .chain(branch_species);
+        for species in species {
+            match create_monster(species, 0, 0) {
+                Some(monster) => monsters.push(monster),
+                None => problems.push(format!("{} rules: there is no monster called '{}'", set.name(), species)),
+            }
+        }
+        items.extend(rules.item_chances.iter().map(|&(item, _)| create_item(item, 0, 0)));
+    }
+    monsters.extend(create_monster("swarm of flies", 0, 0));
+    for boss in BOSSES.iter().chain(MINI_BOSSES.iter().map(|boss| &boss.species)) {
+        match create_monster(boss, 0, 0) {
+            Some(monster) => monsters.push(monster),
+            None => problems.push(format!("there is no monster called '{}' to be a boss", boss)),
+        }
+    }
+    // every branch is themed from its top, and the themes go down it
+    for branch in Branch::all() {
+        let themes: Vec<&Theme> = THEMES.iter().filter(|theme| theme.branch == branch).collect();
+        let top = if branch == Branch::Main { 0 } else { 1 };
+        if themes.first().map_or(true, |theme| theme.from_level > top) {
+            problems.push(format!("the {} has no theme for level {}", branch.title(), top));
+        }
+        for pair in themes.windows(2).filter(|pair| pair[0].from_level >= pair[1].from_level) {
+            problems.push(format!("the {} theme has to start below the {} one", pair[1].name, pair[0].name));
+        }
+    }
+    // trophies come from species that exist, one for each at most
+    match load_trophies(TROPHIES_PATH) {
+        Ok(trophies) => for (i, trophy) in trophies.iter().enumerate() {
+            if create_monster(&trophy.species, 0, 0).is_none() {
+                problems.push(format!("there is no monster called '{}' to cut a {} from",
+                                      trophy.species, trophy.name));
+            }
+            if trophies[..i].iter().any(|other| other.species == trophy.species) {
+                problems.push(format!("the {} have more than one trophy", trophy.species));
+            }
+            if trophy.chance == 0 || trophy.chance > 100 {
+                problems.push(format!("the chance of a {} has to be from 1 to 100", trophy.name));
+            }
+        },
+        Err(e) => problems.push(e),
+    }
+
+    // the same monster or item from several rule sets only counts once
+    monsters.sort_by_key(|monster| monster.name.clone());
+    monsters.dedup_by_key(|monster| monster.name.clone());
+    items.sort_by_key(|item| item.name.clone());
+    items.dedup_by_key(|item| item.name.clone());
+
+    // everything refers to effects that exist
+    let carried = monsters.iter().flat_map(|monster| monster.inventory.iter());
+    for object in monsters.iter().chain(&items).chain(carried) {
+        if let Some(ref effect) = object.effect {
+            if !EFFECTS.with(|effects| effects.contains(effect)) {
+                problems.push(format!("the {} uses the effect '{}', which doesn't exist", object.name, effect));
+            }
+        }
+    }
+
+    // monsters have to be told apart from each other and from the things
+    // lying around
+    let others: Vec<(char, String)> = items.iter().map(|item| (item.char, item.name.clone()))
+        .chain(Some(('@', "player".to_string())))
+        .chain(Some(('>', "stairs".to_string())))
+        .chain(Some(('<', "up stairs".to_string())))
+        .chain(Some(('^', "traps".to_string())))
+        .collect();
+    for (i, monster) in monsters.iter().enumerate() {
+        let same_glyph = monsters[i + 1..].iter().map(|other| (other.char, other.name.clone()))
+            .chain(others.iter().cloned())
+            .filter(|&(char, _)| char == monster.char);
+        for (char, name) in same_glyph {
+            problems.push(format!("the {} and the {} both look like '{}'", monster.name, name, char));
+        }
+    }
+
+    // prefabs only put things that exist on their tiles
+    let (prefabs, prefab_problems) = mapgen::load_prefabs(PREFAB_DIR);
+    problems.extend(prefab_problems);
+    for prefab in &prefabs {
+        for &(symbol, ref placement) in &prefab.legend {
+            let exists = match *placement {
+                mapgen::Placement::Monster(ref species) => create_monster(species, 0, 0).is_some(),
+                mapgen::Placement::Item(ref name) => item_kind(name).is_some(),
+                mapgen::Placement::Npc(_) | mapgen::Placement::Decoration(_) => true,
+            };
+            if !exists {
+                problems.push(format!("prefab '{}': there is nothing like {:?} for '{}'",
+                                      prefab.name, placement, symbol));
+            }
+        }
+    }
+    // and each mini-boss has a room of its own to guard
+    for boss in MINI_BOSSES {
+        let in_it = mapgen::Placement::Monster(boss.species.into());
+        let guarded = prefabs.iter()
+            .find(|prefab| prefab.name == boss.prefab)
+            .map(|prefab| prefab.legend.iter().any(|&(_, ref placement)| *placement == in_it));
+        match guarded {
+            Some(true) => {}
+            Some(false) => problems.push(format!("prefab '{}': the {} isn't in it", boss.prefab, boss.species)),
+            None => problems.push(format!("there is no prefab '{}' for the {}", boss.prefab, boss.species)),
+        }
+    }
+
+    // the map generator makes levels that can be finished with these
+    // settings, at every depth
+    for seed in 0..MAPGEN_CHECK_SEEDS {
+        seed_rng(seed);
+        let level = seed as u32 % 10 + 1;
+        if let Err(e) = mapgen::check_connected(&mapgen::generate(level, &prefabs, None, 1)) {
+            problems.push(format!("the map made with seed {} for level {}: {}", seed, level, e));
+        }
+        if let Err(e) = mapgen::check_connected(&mapgen::generate_caves(level, 1)) {
+            problems.push(format!("the caves made with seed {} for level {}: {}", seed, level, e));
+        }
+    }
+    if let Err(e) = mapgen::check_connected(&mapgen::generate_arena()) {
+        problems.push(format!("the boss arena: {}", e));
+    }
+    if let Err(e) = mapgen::check_connected(&mapgen::generate_town()) {
+        problems.push(format!("the town: {}", e));
+    }
+    problems
+}
+
+/// Let the bot play a game without opening a window, printing the log.
+fn run_headless(max_turns: u32) {
+    let mut simulation = Simulation::new();
+    let mut printed_messages = 0;
+    let mut steps = 0;
+    while simulation.player_alive() && simulation.game.turn < max_turns && steps < max_turns * 10 {
+        let action = bot_action(&simulation);
+        simulation.step(action);
+        steps += 1;
+        for &(ref msg, _) in &simulation.game.log[printed_messages..] {
+            println!("[turn {}] {}", simulation.game.turn, msg);
+        }
+        printed_messages = simulation.game.log.len();
+    }
+    let player = &simulation.objects[PLAYER];
+    println!("Finished after {} turns: dungeon level {}, character level {}, {} HP, score {}.",
+             simulation.game.turn, simulation.game.dungeon_level, player.level,
+             player.fighter.map_or(0, |f| f.hp), total_score(&simulation.game));
+}
+
+/// How the game is written to disk. Loading works with either.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SaveFormat {
+    /// readable, handy when debugging
+    Json,
+    /// compact binary (bincode)
+    Binary,
+}
+
+// saves live in SAVE_DIR, one file per slot
+const SAVE_DIR: &'static str = "saves";
+const SAVE_SLOTS: usize = 5;
+const MAX_NAME_LENGTH: usize = 20;
+// the game also saves itself every this many turns and on every new level
+const AUTOSAVE_INTERVAL: u32 = 100;
+// in between those an ironman game appends each turn to its journal, which
+// starts with a line: "<JOURNAL_MAGIC> <save version> <turn> <depth>" of the
+// save it goes with
+const JOURNAL_MAGIC: &'static str = "TOMBS-JOURNAL";
+
+// every save starts with a line: "<SAVE_MAGIC> <save version> <game version>",
+// followed by what the slot list shows: "<saved at> <dungeon level> <name>"
+const SAVE_MAGIC: &'static str = "TOMBS-SAVE";
+// bump this whenever a change breaks loading saves, and teach `upgrade_save`
+// how to bring the previous version up to date
+const SAVE_VERSION: u32 = 31;
+
+/// Why a saved game could not be loaded.
+#[derive(Debug)]
+enum LoadError {
+    NoSave,
+    /// the save is from an older version of the game; it may load after
+    /// `upgrade_save`
+    Outdated { save_version: u32, game_version: String },
+    /// the save is from a newer version of the game
+    TooNew { save_version: u32, game_version: String },
+    /// the save's levels are of another size than config.toml asks for
+    MapSize { width: usize, height: usize },
+    Broken(String),
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            LoadError::NoSave => write!(f, "No saved game to load."),
+            LoadError::Outdated { save_version, ref game_version } => {
+                write!(f, "The save is from an older version of the game ({}, save format {}).",
+                       game_version, save_version)
+            }
+            LoadError::TooNew { save_version, ref game_version } => {
+                write!(f, "The save is from a newer version of the game ({}, save format {}).",
+                       game_version, save_version)
+            }
+            LoadError::MapSize { width, height } => {
+                write!(f, "The save's levels are {}x{}, but config.toml asks for {}x{}. Set the map's \
+                           width and height back to load it.",
+                       width, height, CONFIG.map_width, CONFIG.map_height)
+            }
+            LoadError::Broken(ref reason) => write!(f, "The save can't be loaded: {}", reason),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn description(&self) -> &str {
+        "could not load the saved game"
+    }
+}
+
+fn slot_path(slot: usize) -> std::path::PathBuf {
+    std::path::Path::new(SAVE_DIR).join(format!("slot{}.sav", slot + 1))
+}
+
+/// where the game saves itself while it's being played
+fn autosave_path(slot: usize) -> std::path::PathBuf {
+    std::path::Path::new(SAVE_DIR).join(format!("slot{}.autosave", slot + 1))
+}
+
+/// where an ironman game keeps the turns played since it was last saved
+fn journal_path(slot: usize) -> std::path::PathBuf {
+    std::path::Path::new(SAVE_DIR).join(format!("slot{}.journal", slot + 1))
+}
+
+/// The autosave of a slot, if it's newer than the game saved on exit. That
+/// means the game didn't get to exit properly the last time.
+fn newer_autosave(slot: usize) -> Option<std::path::PathBuf> {
+    let modified = |path: &std::path::Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    let autosave = autosave_path(slot);
+    match (modified(&autosave), modified(&slot_path(slot))) {
+        (Some(autosaved), Some(saved)) if autosaved > saved => Some(autosave),
+        (Some(_), None) => Some(autosave),
+        _ => None,
+    }
+}
+
+/// remove everything saved in a slot
+fn clear_slot(slot: usize) {
+    let _ = std::fs::remove_file(slot_path(slot));
+    let _ = std::fs::remove_file(autosave_path(slot));
+    let _ = std::fs::remove_file(journal_path(slot));
+}
+
+/// What the slot list shows about a saved game.
+struct SaveSummary {
+    name: String,
+    dungeon_level: u32,
+    /// seconds since the Unix epoch
+    saved_at: u64,
+}
+
+impl SaveSummary {
+    /// e.g. "Rodney, dungeon level 3, saved 2 hours ago"
+    fn describe(&self) -> String {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(self.saved_at);
+        let ago = now.saturating_sub(self.saved_at);
+        let when = if ago < 60 {
+            "just now".to_string()
+        } else if ago < 3600 {
+            format!("{} min ago", ago / 60)
+        } else if ago < 86400 {
+            format!("{} hours ago", ago / 3600)
+        } else {
+            format!("{} days ago", ago / 86400)
+        };
+        format!("{}, dungeon level {}, saved {}", self.name, self.dungeon_level, when)
+    }
+}
+
+/// read the summary from the header of the newest save in a slot, without
+/// loading the game. None if the slot is empty.
+fn read_save_summary(slot: usize) -> Option<SaveSummary> {
+    use std::io::BufRead;
+
+    let path = newer_autosave(slot).unwrap_or(slot_path(slot));
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return None,
+    };
+    let mut header = String::new();
+    let _ = std::io::BufReader::new(file).read_line(&mut header);
+    let fields: Vec<&str> = header.trim().splitn(6, ' ').collect();
+    if fields.len() == 6 && fields[0] == SAVE_MAGIC {
+        Some(SaveSummary {
+            name: fields[5].to_string(),
+            dungeon_level: fields[4].parse().unwrap_or(0),
+            saved_at: fields[3].parse().unwrap_or(0),
+        })
+    } else {
+        // a save without a summary, from an older version
+        Some(SaveSummary { name: "unknown".into(), dungeon_level: 0, saved_at: 0 })
+    }
+}
+
+/// Move a save from before there were slots into the first slot.
+fn adopt_old_save() {
+    if std::path::Path::new("savegame").exists() && read_save_summary(0).is_none() {
+        let _ = std::fs::create_dir_all(SAVE_DIR);
+        let _ = std::fs::rename("savegame", slot_path(0));
+    }
+}
+
+/// Why the game could not be saved. Whatever was saved before is left as it
+/// was.
+#[derive(Debug)]
+enum SaveError {
+    /// the game couldn't be turned into the save format
+    Encode(String),
+    /// e.g. the disk is full or the directory isn't writable
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            SaveError::Encode(ref reason) => write!(f, "{}", reason),
+            SaveError::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for SaveError {
+    fn description(&self) -> &str {
+        "could not save the game"
+    }
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+fn encode_game(objects: &[Object], game: &Game, format: SaveFormat) -> Result<Vec<u8>, SaveError> {
+    match format {
+        SaveFormat::Json => serde_json::to_vec(&(objects, game)).map_err(|e| e.to_string()),
+        SaveFormat::Binary => {
+            bincode::serialize(&(objects, game), bincode::Infinite).map_err(|e| e.to_string())
+        }
+    }.map_err(SaveError::Encode)
+}
+
+/// the game saved in either format, JSON is an array and bincode never
+/// starts with a '['
+fn decode_game(data: &[u8]) -> Result<(Vec<Object>, Game), String> {
+    if data.first() == Some(&b'[') {
+        serde_json::from_slice(data).map_err(|e| e.to_string())
+    } else {
+        bincode::deserialize(data).map_err(|e| e.to_string())
+    }
+}
+
+fn save_game(objects: &[Object], game: &Game, format: SaveFormat, path: &std::path::Path)
+             -> Result<(), SaveError> {
+    let saved_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs()).unwrap_or(0);
+    let header = format!("{} {} {} {} {} {}\n", SAVE_MAGIC, SAVE_VERSION, env!("CARGO_PKG_VERSION"),
+                         saved_at, game.dungeon_level, objects[PLAYER].name);
+    let payload = try! { encode_game(objects, game, format) };
+
+    // the header stays readable so the slot list doesn't have to unpack the game
+    let mut encoder = flate2::write::GzEncoder::new(header.into_bytes(), flate2::Compression::Default);
+    try! { encoder.write_all(&payload) };
+    let save_data = try! { encoder.finish() };
+
+    // write everything to a temporary file first and only then replace the
+    // old save, so a failed save can't leave a half-written file behind
+    try! { std::fs::create_dir_all(SAVE_DIR) };
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = std::path::PathBuf::from(temp_path);
+    let written = File::create(&temp_path).and_then(|mut file| {
+        try! { file.write_all(&save_data) };
+        file.sync_all()
+    });
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(SaveError::Io(e));
+    }
+    try! { std::fs::rename(&temp_path, path) };
+    info!("saved turn {} on level {} to {} ({} bytes)",
+          game.turn, game.dungeon_level, path.display(), save_data.len());
+    Ok(())
+}
+
+/// Split a save into its version, the version of the game that wrote it and
+/// the saved data. Saves from before there was a header are version 0.
+fn read_save_header(save_data: &[u8]) -> (u32, String, &[u8]) {
+    if save_data.starts_with(SAVE_MAGIC.as_bytes()) {
+        if let Some(end) = save_data.iter().position(|&b| b == b'\n') {
+            let header = String::from_utf8_lossy(&save_data[..end]).into_owned();
+            let mut fields = header.split(' ').skip(1);  // ignore the summary
+            let save_version = fields.next().and_then(|v| v.parse().ok()).unwrap_or(0);
+            let game_version = fields.next().unwrap_or("unknown").to_string();
+            return (save_version, game_version, &save_data[end + 1..]);
+        }
+    }
+    (0, "unknown".into(), save_data)
+}
+
+/// Load the saved game. A save from an older version is only loaded when
+/// `upgrade` is set, and it may not be quite the game that was saved.
+fn load_game(path: &std::path::Path, upgrade: bool) -> Result<(Vec<Object>, Game), LoadError> {
+    let mut save_data = vec![];
+    let mut file = try! { File::open(path).map_err(|_| LoadError::NoSave) };
+    try! { file.read_to_end(&mut save_data).map_err(|e| LoadError::Broken(e.to_string())) };
+
+    let (save_version, game_version, data) = read_save_header(&save_data);
+    let unpacked;
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        // gzip compressed
+        let mut payload = vec![];
+        try! {
+            flate2::read::GzDecoder::new(data)
+                .and_then(|mut decoder| decoder.read_to_end(&mut payload))
+                .map_err(|e| LoadError::Broken(e.to_string()))
+        };
+        unpacked = payload;
+        &unpacked[..]
+    } else {
+        data
+    };
+    if save_version > SAVE_VERSION {
+        return Err(LoadError::TooNew { save_version: save_version, game_version: game_version });
+    }
+    if save_version < SAVE_VERSION && !upgrade {
+        return Err(LoadError::Outdated { save_version: save_version, game_version: game_version });
+    }
+
+    // a JSON save is an array, bincode never starts with a '['
+    let (objects, game) = if data.first() == Some(&b'[') {
+        let mut save: serde_json::Value = try! {
+            serde_json::from_slice(data).map_err(|e| LoadError::Broken(e.to_string()))
+        };
+        for version in save_version..SAVE_VERSION {
+            try! { upgrade_save(version, &mut save).map_err(LoadError::Broken) };
+        }
+        try! { serde_json::from_value::<(Vec<Object>, Game)>(save).map_err(|e| LoadError::Broken(e.to_string())) }
+    } else if save_version < SAVE_VERSION {
+        return Err(LoadError::Broken("old binary saves can't be upgraded".into()));
+    } else {
+        try! { bincode::deserialize::<(Vec<Object>, Game)>(data).map_err(|e| LoadError::Broken(e.to_string())) }
+    };
+    // the whole game is played on maps of one size, the one the config
+    // had when it started
+    let (width, height) = (game.map.len(), game.map.first().map_or(0, |column| column.len()));
+    if width as i32 != CONFIG.map_width || height as i32 != CONFIG.map_height {
+        return Err(LoadError::MapSize { width: width, height: height });
+    }
+    Ok((objects, game))
+}
+
+/// What the save tool does with the saved games.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SaveCommand {
+    /// show what each slot has
+    List,
+    /// try to load every save and tell which won't
+    Check,
+    /// bring the saves from older versions up to date, keeping the old files
+    Upgrade,
+    /// remove what the game has no more use for: half-written saves,
+    /// autosaves older than their slot's save and journals without a save
+    Prune,
+}
+
+impl SaveCommand {
+    pub fn parse(command: &str) -> Option<Self> {
+        match command {
+            "list" => Some(SaveCommand::List),
+            "check" => Some(SaveCommand::Check),
+            "upgrade" => Some(SaveCommand::Upgrade),
+            "prune" => Some(SaveCommand::Prune),
+            _ => None,
+        }
+    }
+}
+
+/// Do what the save tool was asked to, printing what was done. Returns
+/// whether everything went well.
+pub fn run_save_tool(command: SaveCommand) -> bool {
+    let mut fine = true;
+    for slot in 0..SAVE_SLOTS {
+        let saves: Vec<std::path::PathBuf> = vec![slot_path(slot), autosave_path(slot)].into_iter()
+            .filter(|path| path.exists())
+            .collect();
+        match command {
+            SaveCommand::List => match read_save_summary(slot) {
+                Some(summary) if !saves.is_empty() => println!("Slot {}: {}", slot + 1, summary.describe()),
+                _ => println!("Slot {}: empty", slot + 1),
+            },
+            SaveCommand::Prune => {
+                let mut leftovers = vec![];
+                if newer_autosave(slot).is_none() && saves.len() == 2 {
+                    leftovers.push(autosave_path(slot));
+                }
+                if saves.is_empty() && journal_path(slot).exists() {
+                    leftovers.push(journal_path(slot));
+                }
+                for path in leftovers {
+                    match std::fs::remove_file(&path) {
+                        Ok(()) => println!("Removed {}", path.display()),
+                        Err(e) => {
+                            println!("Could not remove {}: {}", path.display(), e);
+                            fine = false;
+                        }
+                    }
+                }
+                continue;
+            }
+            SaveCommand::Check | SaveCommand::Upgrade => {}
+        }
+        for path in &saves {
+            let header = File::open(path).and_then(|mut file| {
+                let mut data = vec![];
+                file.read_to_end(&mut data).map(|_| data)
+            });
+            let (save_version, game_version) = match header {
+                Ok(ref data) => {
+                    let (save_version, game_version, _) = read_save_header(data);
+                    (save_version, game_version)
+                }
+                Err(e) => {
+                    println!("  {}: can't be read: {}", path.display(), e);
+                    fine = false;
+                    continue;
+                }
+            };
+            if command == SaveCommand::List {
+                println!("  {}: save format {}, from version {}", path.display(), save_version, game_version);
+                continue;
+            }
+            match (command, load_game(path, false)) {
+                (_, Ok(_)) => println!("  {}: fine", path.display()),
+                (SaveCommand::Check, Err(LoadError::Outdated { .. })) => match load_game(path, true) {
+                    Ok(_) => println!("  {}: from an older version, `save-tool upgrade` brings it up to date",
+                                      path.display()),
+                    Err(e) => {
+                        println!("  {}: from an older version and can't be upgraded: {}", path.display(), e);
+                        fine = false;
+                    }
+                },
+                (SaveCommand::Upgrade, Err(LoadError::Outdated { .. })) => {
+                    let mut backup = path.as_os_str().to_owned();
+                    backup.push(format!(".v{}", save_version));
+                    let upgraded = load_game(path, true)
+                        .map_err(|e| e.to_string())
+                        .and_then(|(objects, game)| {
+                            try! { std::fs::copy(path, &backup).map_err(|e| e.to_string()) };
+                            save_game(&objects, &game, SaveFormat::Json, path).map_err(|e| e.to_string())
+                        });
+                    match upgraded {
+                        Ok(()) => println!("  {}: upgraded from save format {}, the old one is in {}",
+                                           path.display(), save_version, backup.to_string_lossy()),
+                        Err(e) => {
+                            println!("  {}: could not be upgraded: {}", path.display(), e);
+                            fine = false;
+                        }
+                    }
+                }
+                (_, Err(e)) => {
+                    println!("  {}: {}", path.display(), e);
+                    fine = false;
+                }
+            }
+        }
+    }
+    // a save the game never got to finish writing
+    if command == SaveCommand::Prune {
+        let temporary = std::fs::read_dir(SAVE_DIR).into_iter()
+            .flat_map(|entries| entries.filter_map(|entry| entry.ok()))
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|ext| ext.to_str()) == Some("tmp"));
+        for path in temporary {
+            match std::fs::remove_file(&path) {
+                Ok(()) => println!("Removed {}", path.display()),
+                Err(e) => {
+                    println!("Could not remove {}: {}", path.display(), e);
+                    fine = false;
+                }
+            }
+        }
+    }
+    fine
+}
+
+/// the line an ironman game's journal starts with, it only goes with the
+/// save written at this turn and depth
+fn journal_header(game: &Game) -> String {
+    let depth = game.depth();
+    format!("{} {} {} {:?} {}\n", JOURNAL_MAGIC, SAVE_VERSION, game.turn, depth.branch, depth.level)
+}
+
+/// Start an ironman game's journal over, right after the whole game was
+/// saved. Until then whatever journal there was still goes with the save
+/// before, and is left alone when the game is loaded.
+fn start_journal(game: &Game, path: &std::path::Path) -> Result<(), SaveError> {
+    let mut file = try! { File::create(path) };
+    try! { file.write_all(journal_header(game).as_bytes()) };
+    try! { file.sync_all() };
+    Ok(())
+}
+
+/// Append the state after a turn to an ironman game's journal, which is
+/// far quicker than saving the whole game: the objects and the game without
+/// the levels left behind, those only change when the whole game is saved.
+/// Each record is its length and then the compressed state, a crash
+/// halfway through leaves a short last record that's skipped on loading.
+fn append_to_journal(objects: &[Object], game: &mut Game, format: SaveFormat, path: &std::path::Path)
+                     -> Result<(), SaveError> {
+    let levels = std::mem::replace(&mut game.levels, LevelCache::new());
+    let payload = encode_game(objects, game, format);
+    game.levels = levels;
+    let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::Fast);
+    try! { encoder.write_all(&try! { payload }) };
+    let record = try! { encoder.finish() };
+
+    let length = record.len() as u32;
+    let mut file = try! { std::fs::OpenOptions::new().append(true).open(path) };
+    try! { file.write_all(&[length as u8, (length >> 8) as u8, (length >> 16) as u8, (length >> 24) as u8]) };
+    try! { file.write_all(&record) };
+    try! { file.sync_data() };
+    Ok(())
+}
+
+/// Bring a loaded ironman game up to the last turn in its journal. There's
+/// no going back to the save, what was played since counts.
+fn replay_journal(path: &std::path::Path, objects: &mut Vec<Object>, game: &mut Game) {
+    let mut journal = vec![];
+    if File::open(path).and_then(|mut file| file.read_to_end(&mut journal)).is_err() {
+        return;
+    }
+    let header = journal_header(game);
+    if !journal.starts_with(header.as_bytes()) {
+        // from before the game was last saved
+        return;
+    }
+    let mut records = &journal[header.len()..];
+    let mut last = None;
+    while records.len() >= 4 {
+        let length = records[..4].iter().rev().fold(0, |length, &byte| (length << 8) | byte as usize);
+        if records.len() < 4 + length {
+            warn!("the last turn in the journal {} was cut short", path.display());
+            break;
+        }
+        let mut data = vec![];
+        let decoded = flate2::read::GzDecoder::new(&records[4..4 + length])
+            .and_then(|mut decoder| decoder.read_to_end(&mut data))
+            .map_err(|e| e.to_string())
+            .and_then(|_| decode_game(&data));
+        match decoded {
+            Ok(state) => last = Some(state),
+            Err(e) => warn!("skipping a broken turn in the journal {}: {}", path.display(), e),
+        }
+        records = &records[4 + length..];
+    }
+    if let Some((journal_objects, journal_game)) = last {
+        info!("replayed the journal {} up to turn {}", path.display(), journal_game.turn);
+        let levels = std::mem::replace(&mut game.levels, LevelCache::new());
+        *game = journal_game;
+        game.levels = levels;
+        *objects = journal_objects;
+    }
+}
+
+/// Bring a JSON save from `version` to `version + 1`, filling in whatever
+/// the newer version has that the older one didn't.
+fn upgrade_save(version: u32, save: &mut serde_json::Value) -> Result<(), String> {
+    use serde_json::Value;
+
+    fn set_default(object: &mut serde_json::Map<String, Value>, key: &str, value: Value) {
+        if object.get(key).is_none() {
+            object.insert(key.into(), value);
+        }
+    }
+
+    type Upgrade<'a> = Fn(&mut serde_json::Map<String, Value>) -> Result<(), String> + 'a;
+
+    /// run `upgrade` on every level left behind
+    fn upgrade_levels(save: &mut Value, upgrade: &Upgrade) -> Result<(), String> {
+        let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+        let levels = parts[1].as_object_mut()
+            .and_then(|game| game.get_mut("levels"))
+            .and_then(|levels| levels.as_object_mut())
+            .and_then(|levels| levels.get_mut("levels"))
+            .and_then(|levels| levels.as_array_mut());
+        for level in levels.into_iter().flat_map(|levels| levels.iter_mut()) {
+            // a level and where it is since saves know about branches
+            let level = match *level {
+                Value::Array(ref mut pair) if pair.len() == 2 => &mut pair[1],
+                ref mut level => level,
+            };
+            let cached = match level.as_object_mut() {
+                Some(cached) => cached,
+                None => continue,  // never visited
+            };
+            if let Some(loaded) = cached.get_mut("Loaded").and_then(|level| level.as_object_mut()) {
+                try! { upgrade(loaded) };
+            }
+            let stored = cached.get("Stored").and_then(|data| data.as_str()).map(|data| data.to_string());
+            if let Some(data) = stored {
+                let mut stored: Value = try! { serde_json::from_str(&data).map_err(|e| e.to_string()) };
+                try! { upgrade(try! { stored.as_object_mut().ok_or("broken level") }) };
+                cached.insert("Stored".into(), Value::String(try! {
+                    serde_json::to_string(&stored).map_err(|e| e.to_string())
+                }));
+            }
+        }
+        Ok(())
+    }
+
+    /// run `upgrade` on every object in the save: the ones on the current
+    /// level, in inventories, in the stash and on the levels left behind
+    fn upgrade_objects(save: &mut Value, upgrade: &Upgrade) -> Result<(), String> {
+        fn upgrade_object(object: &mut Value, upgrade: &Upgrade) -> Result<(), String> {
+            let object = try! { object.as_object_mut().ok_or("broken object") };
+            try! { upgrade(object) };
+            if let Some(inventory) = object.get_mut("inventory").and_then(|i| i.as_array_mut()) {
+                for item in inventory.iter_mut() {
+                    try! { upgrade_object(item, upgrade) };
+                }
+            }
+            Ok(())
+        }
+
+        {
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            for object in try! { parts[0].as_array_mut().ok_or("no objects in the save") } {
+                try! { upgrade_object(object, upgrade) };
+            }
+            let stash = parts[1].as_object_mut()
+                .and_then(|game| game.get_mut("stash"))
+                .and_then(|stash| stash.as_array_mut());
+            for object in stash.into_iter().flat_map(|stash| stash.iter_mut()) {
+                try! { upgrade_object(object, upgrade) };
+            }
+        }
+        upgrade_levels(save, &|level| {
+            let objects = level.get_mut("objects").and_then(|objects| objects.as_array_mut());
+            for object in objects.into_iter().flat_map(|objects| objects.iter_mut()) {
+                try! { upgrade_object(object, upgrade) };
+            }
+            Ok(())
+        })
+    }
+
+    /// the schedule of a level that only has remains rotting on it
+    fn remains_schedule(objects: Option<&Value>, turn: u32) -> Result<Value, String> {
+        let mut schedule = Schedule::starting_at(turn);
+        let objects = objects.and_then(|objects| objects.as_array());
+        for object in objects.into_iter().flat_map(|objects| objects.iter()) {
+            let rots_at = object.as_object().and_then(|object| object.get("rots_at"))
+                .and_then(|rots_at| rots_at.as_u64());
+            if let Some(rots_at) = rots_at {
+                schedule.at(rots_at as u32, TimedEvent::Rot);
+            }
+        }
+        serde_json::to_value(schedule).map_err(|e| e.to_string())
+    }
+
+    let to_value = |n: i32| serde_json::to_value(n).map_err(|e| e.to_string());
+    match version {
+        0 => {
+            let parts = match save.as_array_mut() {
+                Some(parts) if parts.len() == 2 => parts,
+                _ => return Err("not a saved game".into()),
+            };
+            let (objects_part, game_part) = parts.split_at_mut(1);
+            let objects = try! { objects_part[0].as_array_mut().ok_or("no objects in the save") };
+            let game = try! { game_part[0].as_object_mut().ok_or("no game in the save") };
+
+            // the player's inventory used to live in the game
+            if let Some(inventory) = game.remove("inventory") {
+                if let Some(player) = objects.get_mut(PLAYER).and_then(|p| p.as_object_mut()) {
+                    player.insert("inventory".into(), inventory);
+                }
+            }
+
+            // objects got an inventory, scripts, decay, free slots and speed,
+            // the items the player carries too
+            fn add_fields(object: &mut serde_json::Map<String, Value>) {
+                set_default(object, "script", Value::Null);
+                set_default(object, "inventory", Value::Array(vec![]));
+                set_default(object, "decay", Value::Null);
+                set_default(object, "removed", Value::Bool(false));
+            }
+            for (id, object) in objects.iter_mut().enumerate() {
+                let object = try! { object.as_object_mut().ok_or("broken object") };
+                add_fields(object);
+                if let Some(fighter) = object.get_mut("fighter").and_then(|f| f.as_object_mut()) {
+                    set_default(fighter, "speed", try! { to_value(NORMAL_SPEED) });
+                    let energy = if id == PLAYER { ACTION_COST } else { 0 };
+                    set_default(fighter, "energy", try! { to_value(energy) });
+                }
+                let items = object.get_mut("inventory").and_then(|items| items.as_array_mut());
+                for item in items.into_iter().flat_map(|items| items.iter_mut()) {
+                    add_fields(try! { item.as_object_mut().ok_or("broken item") });
+                }
+            }
+
+            // the map is saved packed, and the game knows the turn and old levels
+            let map: Map = try! {
+                serde_json::from_value(game.remove("map").unwrap_or(Value::Null))
+                    .map_err(|e| format!("broken map: {}", e))
+            };
+            game.insert("map".into(), try! {
+                serde_json::to_value(PackedMap::pack(&map)).map_err(|e| e.to_string())
+            });
+            set_default(game, "turn", try! { to_value(0) });
+            set_default(game, "levels", try! {
+                serde_json::to_value(LevelCache::new()).map_err(|e| e.to_string())
+            });
+            Ok(())
+        }
+        1 => {
+            // objects name their effect by id, scripts were the only effects
+            // that had one before
+            upgrade_objects(save, &|object| {
+                let script = object.remove("script").unwrap_or(Value::Null);
+                let effect = match object.get("item").and_then(|item| item.as_str()) {
+                    Some("Heal") => Value::String("heal".into()),
+                    Some("Lightning") => Value::String("lightning".into()),
+                    Some("Confuse") => Value::String("confuse".into()),
+                    Some("Fireball") => Value::String("fireball".into()),
+                    _ => script,
+                };
+                object.insert("effect".into(), effect);
+                Ok(())
+            })
+        }
+        2 => {
+            // items can have charges
+            upgrade_objects(save, &|object| {
+                set_default(object, "charges", Value::Null);
+                Ok(())
+            })
+        }
+        3 => {
+            // timed things went to the schedule: remains know the turn they
+            // rot at and confusion wears off by an event, so the monsters
+            // confused in the old save come to their senses right away
+            let turn = try! {
+                save.as_array().and_then(|parts| parts.get(1))
+                    .and_then(|game| game.as_object()).and_then(|game| game.get("turn"))
+                    .and_then(|turn| turn.as_u64()).ok_or("no turn in the save")
+            } as u32;
+            try! { upgrade_objects(save, &|object| {
+                let decay = object.remove("decay").and_then(|decay| decay.as_i64());
+                let rots_at = match decay {
+                    Some(turns) => try! {
+                        serde_json::to_value(turn + cmp::max(turns, 0) as u32).map_err(|e| e.to_string())
+                    },
+                    None => Value::Null,
+                };
+                object.insert("rots_at".into(), rots_at);
+                loop {
+                    let previous_ai = object.get("ai").and_then(|ai| ai.as_object())
+                        .and_then(|ai| ai.get("Confused")).and_then(|confused| confused.as_object())
+                        .and_then(|confused| confused.get("previous_ai")).cloned();
+                    match previous_ai {
+                        Some(previous_ai) => object.insert("ai".into(), previous_ai),
+                        None => break,
+                    };
+                }
+                Ok(())
+            }) };
+            try! { upgrade_levels(save, &|level| {
+                let schedule = try! { remains_schedule(level.get("objects"), turn) };
+                level.insert("schedule".into(), schedule);
+                Ok(())
+            }) };
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let schedule = try! { remains_schedule(parts.get(0), turn) };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            game.insert("schedule".into(), schedule);
+            Ok(())
+        }
+        4 => {
+            // games record their rule set, everything before played by the classic rules
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "rule_set", try! {
+                serde_json::to_value(rules::RuleSet::Classic).map_err(|e| e.to_string())
+            });
+            Ok(())
+        }
+        5 => {
+            // torches could only be put out after this
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "torch_lit", Value::Bool(true));
+            Ok(())
+        }
+        6 => {
+            // monsters have a faction, nobody came in a pack yet
+            upgrade_objects(save, &|object| {
+                let name = object.get("name").and_then(|name| name.as_str()).unwrap_or("").to_string();
+                set_default(object, "faction", try! {
+                    serde_json::to_value(faction(&name)).map_err(|e| e.to_string())
+                });
+                set_default(object, "pack", Value::Null);
+                Ok(())
+            })
+        }
+        7 => {
+            // traps and poison came along
+            upgrade_objects(save, &|object| {
+                set_default(object, "trap", Value::Null);
+                set_default(object, "poison", try! { to_value(0) });
+                Ok(())
+            })
+        }
+        8 => {
+            // stairs down are drawn as '>' now that there are stairs up
+            upgrade_objects(save, &|object| {
+                if object.get("name").and_then(|name| name.as_str()) == Some("stairs") {
+                    object.insert("char".into(), Value::String(">".into()));
+                }
+                Ok(())
+            })
+        }
+        9 => {
+            // every level was in the main dungeon, the level cache was
+            // indexed by dungeon level - 1
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "branch", try! { serde_json::to_value(Branch::Main).map_err(|e| e.to_string()) });
+            let levels = game.get_mut("levels").and_then(|levels| levels.as_object_mut());
+            let levels = try! { levels.ok_or("no level cache in the save") };
+            let old = match levels.remove("levels") {
+                Some(Value::Array(old)) => old,
+                _ => vec![],
+            };
+            let mut new = vec![];
+            for (index, level) in old.into_iter().enumerate() {
+                if level.is_null() {
+                    continue;  // never visited
+                }
+                let depth = Depth::new(Branch::Main, index as u32 + 1);
+                new.push(Value::Array(vec![
+                    try! { serde_json::to_value(depth).map_err(|e| e.to_string()) },
+                    level,
+                ]));
+            }
+            levels.insert("levels".into(), Value::Array(new));
+            Ok(())
+        }
+        10 => {
+            // the town's folk and gold came along, games started before
+            // have no town above their first level
+            upgrade_objects(save, &|object| {
+                set_default(object, "service", Value::Null);
+                set_default(object, "gold", try! { to_value(0) });
+                Ok(())
+            })
+        }
+        11 => {
+            // how creatures move came along, only the vermin could fly
+            upgrade_objects(save, &|object| {
+                let name = object.get("name").and_then(|name| name.as_str()).unwrap_or("").to_string();
+                let mut movement = Movement::walking();
+                if name == "bat" || name == "swarm of flies" {
+                    movement = Movement::flying();
+                }
+                set_default(object, "movement", try! {
+                    serde_json::to_value(movement).map_err(|e| e.to_string())
+                });
+                Ok(())
+            })
+        }
+        12 => {
+            // creatures learned to swim, none of the old ones can
+            upgrade_objects(save, &|object| {
+                if let Some(movement) = object.get_mut("movement").and_then(|movement| movement.as_object_mut()) {
+                    set_default(movement, "swims", Value::Bool(false));
+                }
+                Ok(())
+            })
+        }
+        13 => {
+            // portals to town came along
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "portal", Value::Null);
+            Ok(())
+        }
+        14 => {
+            // the stash's items moved from the chest in town to the game
+            let items = RefCell::new(vec![]);
+            try! { upgrade_objects(save, &|object| {
+                if object.get("service").and_then(|service| service.as_str()) == Some("Stash") {
+                    if let Some(Value::Array(inventory)) = object.insert("inventory".into(), Value::Array(vec![])) {
+                        items.borrow_mut().extend(inventory);
+                    }
+                }
+                Ok(())
+            }) };
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "stash", Value::Array(items.into_inner()));
+            Ok(())
+        }
+        15 => {
+            // the trainer came to town, older towns stay without one and
+            // the level-ups made before aren't known
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "level_ups", Value::Array(vec![]));
+            set_default(game, "trainings", try! { to_value(0) });
+            Ok(())
+        }
+        16 => {
+            // nothing was going on in town yet
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "town_event", Value::Null);
+            set_default(game, "next_town_event", try! { to_value(0) });
+            Ok(())
+        }
+        17 => {
+            // no level was cleared for a bonus yet
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "cleared_levels", Value::Array(vec![]));
+            Ok(())
+        }
+        18 => {
+            // everyone started out with no luck at all
+            upgrade_objects(save, &|object| {
+                set_default(object, "base_luck", try! { to_value(0) });
+                if let Some(equipment) = object.get_mut("equipment").and_then(|e| e.as_object_mut()) {
+                    set_default(equipment, "luck_bonus", try! { to_value(0) });
+                }
+                Ok(())
+            })
+        }
+        19 => {
+            // there was no ironman mode yet
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "ironman", Value::Bool(false));
+            Ok(())
+        }
+        20 => {
+            // everything filled a single tile
+            upgrade_objects(save, &|object| {
+                set_default(object, "size", try! { to_value(1) });
+                Ok(())
+            })
+        }
+        21 => {
+            // monsters didn't call out when they spotted the player
+            upgrade_objects(save, &|object| {
+                set_default(object, "alerted", Value::Bool(false));
+                Ok(())
+            })
+        }
+        22 => {
+            // doors came along, and the monsters that know how to open them
+            upgrade_objects(save, &|object| {
+                let name = object.get("name").and_then(|name| name.as_str()).unwrap_or("").to_string();
+                if let Some(movement) = object.get_mut("movement").and_then(|movement| movement.as_object_mut()) {
+                    movement.insert("opens_doors".into(), Value::Bool(opens_doors(&name)));
+                }
+                Ok(())
+            })
+        }
+        23 => {
+            // nobody had asked the hunter for work yet
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "bounty", Value::Null);
+            Ok(())
+        }
+        24 => {
+            // nothing was counted for the score, the level the player is on
+            // is the best guess at how far they got
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            let level = game.get("dungeon_level").and_then(|level| level.as_u64()).unwrap_or(0) as u32;
+            let stats = RunStats { deepest: level, ..Default::default() };
+            set_default(game, "stats", try! { serde_json::to_value(stats).map_err(|e| e.to_string()) });
+            Ok(())
+        }
+        25 => {
+            // monsters fought to the death, the ones already around still do
+            upgrade_objects(save, &|object| {
+                if let Some(fighter) = object.get_mut("fighter").and_then(|fighter| fighter.as_object_mut()) {
+                    fighter.insert("morale".into(), try! { to_value(100) });
+                }
+                Ok(())
+            })
+        }
+        26 => {
+            // the folk in town were the only ones not out to fight the player
+            upgrade_objects(save, &|object| {
+                let friendly = object.get("service").map_or(false, |service| !service.is_null());
+                let disposition = if friendly { Disposition::Friendly } else { Disposition::Hostile };
+                set_default(object, "disposition", try! {
+                    serde_json::to_value(disposition).map_err(|e| e.to_string())
+                });
+                Ok(())
+            })
+        }
+        27 => {
+            // there were no mutators to play with
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "mutators", Value::Array(vec![]));
+            Ok(())
+        }
+        28 => {
+            // confusion and flight only ended by their events, which missed
+            // them under another state: whoever is confused or fleeing in
+            // the old save gets over it right away
+            fn add_until(ai: &mut Value, turn: &Value) {
+                for (state, fields) in ai.as_object_mut().into_iter().flat_map(|ai| ai.iter_mut()) {
+                    if let Some(fields) = fields.as_object_mut() {
+                        if state == "Confused" || state == "Fleeing" {
+                            set_default(fields, "until", turn.clone());
+                        }
+                        if let Some(previous_ai) = fields.get_mut("previous_ai") {
+                            add_until(previous_ai, turn);
+                        }
+                    }
+                }
+            }
+            let turn = try! {
+                save.as_array().and_then(|parts| parts.get(1))
+                    .and_then(|game| game.as_object()).and_then(|game| game.get("turn"))
+                    .cloned().ok_or("no turn in the save")
+            };
+            upgrade_objects(save, &|object| {
+                if let Some(ai) = object.get_mut("ai") {
+                    add_until(ai, &turn);
+                }
+                Ok(())
+            })
+        }
+        29 => {
+            // the timed events and the companions' orders kept the index
+            // of the object they're about, now it's that and the
+            // generation of its slot. No slot was reused before.
+            fn object_id(index: &Value) -> Result<Value, String> {
+                let index = try! { index.as_u64().ok_or("broken object id") };
+                serde_json::to_value(ObjectId { index: index as usize, generation: 0 }).map_err(|e| e.to_string())
+            }
+            fn upgrade_schedule(schedule: Option<&mut Value>) -> Result<(), String> {
+                let events = schedule.and_then(|schedule| schedule.as_object_mut())
+                    .and_then(|schedule| schedule.get_mut("events"))
+                    .and_then(|events| events.as_array_mut());
+                for scheduled in events.into_iter().flat_map(|events| events.iter_mut()) {
+                    let event = scheduled.as_object_mut()
+                        .and_then(|scheduled| scheduled.get_mut("event"))
+                        .and_then(|event| event.as_object_mut());
+                    for (_, id) in event.into_iter().flat_map(|event| event.iter_mut()) {
+                        if id.is_u64() {
+                            *id = try! { object_id(id) };
+                        }
+                    }
+                }
+                Ok(())
+            }
+            fn upgrade_orders(ai: &mut Value) -> Result<(), String> {
+                for (key, value) in ai.as_object_mut().into_iter().flat_map(|ai| ai.iter_mut()) {
+                    if key == "Attack" && value.is_u64() {
+                        *value = try! { object_id(value) };
+                    } else {
+                        try! { upgrade_orders(value) };
+                    }
+                }
+                Ok(())
+            }
+            try! {
+                upgrade_objects(save, &|object| {
+                    set_default(object, "generation", try! { to_value(0) });
+                    match object.get_mut("ai") {
+                        Some(ai) => upgrade_orders(ai),
+                        None => Ok(()),
+                    }
+                })
+            };
+            try! { upgrade_levels(save, &|level| upgrade_schedule(level.get_mut("schedule"))) };
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            upgrade_schedule(parts[1].as_object_mut().and_then(|game| game.get_mut("schedule")))
+        }
+        30 => {
+            // the smoke didn't keep track of when it clears, it goes with
+            // the first cloud to clear around it
+            try! { upgrade_levels(save, &|level| {
+                set_default(level, "smoke", Value::Array(vec![]));
+                Ok(())
+            }) };
+            let parts = try! { save.as_array_mut().ok_or("not a saved game") };
+            let game = try! { parts[1].as_object_mut().ok_or("no game in the save") };
+            set_default(game, "smoke", Value::Array(vec![]));
+            Ok(())
+        }
+        _ => Err(format!("don't know how to upgrade a version {} save", version)),
+    }
+}
+
+/// Let the bot play on the first level of a new game until a key is
+/// pressed, it dies or ATTRACT_TURNS have gone by.
+fn attract_mode(tcod: &mut Tcod) {
+    let mut simulation = Simulation::new();
+    {
+        let Simulation { ref mut objects, ref mut game, ref mut fov } = simulation;
+        descend_to(Depth::new(Branch::Main, 1), objects, game, fov);
+    }
+    let mut depth = None;
+    while simulation.player_alive() && simulation.game.turn < ATTRACT_TURNS {
+        if depth != Some(simulation.game.depth()) {
+            depth = Some(simulation.game.depth());
+            initialise_fov(&simulation.game.map, &mut tcod.fov);
+            tcod.con.clear();
+        }
+        render_all(tcod, &simulation.objects, &mut simulation.game, true);
+        tcod.root.print_centered(CONFIG.screen_width / 2, 0, "DEMO - press any key", colors::LIGHT_YELLOW);
+        tcod.flush();
+        let frame = Duration::from_millis(ATTRACT_FRAME_MS);
+        if wait_for_keypress_within(frame, tcod).is_some() || tcod.backend.window_closed() {
+            break;
+        }
+        let action = bot_action(&simulation);
+        simulation.step(action);
+    }
+    tcod.con.clear();
+    // a game started with --seed is the same with or without the demo
+    if let Some(seed) = options().seed {
+        seed_rng(seed);
+    }
+}
+
+fn main_menu(tcod: &mut Tcod) {
+    let img = tcod::image::Image::from_file("menu_background.png")
+        .ok().expect("Background image not found");
+    let background = image_to_screen(&img);
+    adopt_old_save();
+
+    while !tcod.backend.window_closed() {
+        // show the background image
+        background.blit(&mut tcod.root, 0, 0, 1.0);
+
+        tcod.root.print_centered(CONFIG.screen_width/2, CONFIG.screen_height/2 - 4,
+                                 "TOMBS OF THE ANCIENT KINGS", colors::LIGHT_YELLOW);
+        tcod.root.print_centered(CONFIG.screen_width/2, CONFIG.screen_height - 2,
+                                 "By Yours Truly", colors::LIGHT_YELLOW);
+
+        // show options and wait for the player's choice, or show off the
+        // game while they take their time
+        let choices = &["Play a new game", "Load game", "Key bindings", "Quit"];
+        let choice = match menu_within("", choices, 24, Some(Duration::from_secs(ATTRACT_IDLE_SECS)), tcod) {
+            Some(choice) => choice,
+            None => {
+                attract_mode(tcod);
+                continue;
+            }
+        };
+
+        match choice {
+            Some(0) => {  // new game
+                let slot = match choose_slot("Choose a slot for the new game:", tcod) {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                if read_save_summary(slot).is_some() {
+                    let question = "\nThis slot already has a saved game. Overwrite it?\n";
+                    if menu(question, &["Yes", "No"], 30, tcod) != Some(0) {
+                        continue;
+                    }
+                    clear_slot(slot);
+                }
+                let name = match text_input("What is your name, stranger?", MAX_NAME_LENGTH, tcod) {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let rule_sets = rules::RuleSet::all();
+                let options: Vec<String> = rule_sets.iter()
+                    .map(|set| format!("{}: {}", set.name(), set.describe()))
+                    .collect();
+                let rule_set = match menu("\nWhich rules do you want to play by?\n", &options, 50, tcod) {
+                    Some(choice) => rule_sets[choice],
+                    None => continue,
+                };
+                let question = "\nPlay in ironman mode? The game saves after every turn, there's \
+                                no going back to an earlier save, and dying deletes it.\n";
+                let ironman = match menu(question, &["No", "Yes, ironman"], 40, tcod) {
+                    Some(choice) => choice == 1,
+                    None => continue,
+                };
+                let mutators = match choose_mutators(tcod) {
+                    Some(mutators) => mutators,
+                    None => continue,
+                };
+                let (mut objects, mut game) = new_game(rule_set);
+                game.ironman = ironman;
+                game.mutators = mutators;
+                if !name.is_empty() {
+                    objects[PLAYER].name = name;
+                }
+                initialise_fov(&game.map, &mut tcod.fov);
+                // unexplored areas start black (which is the default background color)
+                tcod.con.clear();
+                play_game(&mut objects, &mut game, tcod, slot);
+            }
+            Some(1) => {  // load game
+                let slot = match choose_slot("Choose a saved game:", tcod) {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                if read_save_summary(slot).is_none() {
+                    msgbox("\nThis slot is empty.\n", 24, tcod);
+                    continue;
+                }
+                match menu("", &["Load", "Delete", "Cancel"], 24, tcod) {
+                    Some(0) => {}
+                    Some(1) => {
+                        let question = "\nDelete this saved game for good?\n";
+                        if menu(question, &["Yes", "No"], 30, tcod) == Some(0) {
+                            clear_slot(slot);
+                        }
+                        continue;
+                    }
+                    _ => continue,
+                }
+                load_and_play(slot, tcod);
+            }
+            Some(2) => {
+                key_bindings_screen(tcod);
+            }
+            Some(3) => {  // quit
+                break;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Let the player switch mutators on and off for a new game, until they
+/// start it. Returns `None` if they cancelled.
+fn choose_mutators(tcod: &mut Tcod) -> Option<Vec<Mutator>> {
+    let all = Mutator::all();
+    let mut chosen = vec![];
+    loop {
+        let mut options = vec!["Start the game".to_string()];
+        options.extend(all.iter().map(|mutator| {
+            let mark = if chosen.contains(mutator) { 'x' } else { ' ' };
+            format!("[{}] {}: {}", mark, mutator.name(), mutator.describe())
+        }));
+        let header = "\nAny mutators for this game? Each one is worth more points.\n";
+        match menu(header, &options, 60, tcod) {
+            Some(0) => return Some(all.into_iter().filter(|mutator| chosen.contains(mutator)).collect()),
+            Some(choice) => {
+                let mutator = all[choice - 1];
+                match chosen.iter().position(|&picked| picked == mutator) {
+                    Some(index) => {
+                        chosen.remove(index);
+                    }
+                    None => chosen.push(mutator),
+                }
+            }
+            None => return None,
+        }
+    }
+}
+
+/// Load the game saved in a slot (or its checkpoint) and play it.
+fn load_and_play(slot: usize, tcod: &mut Tcod) {
+    let mut path = slot_path(slot);
+    if let Some(autosave) = newer_autosave(slot) {
+        let question = "\nThe game didn't exit properly last time, but it saved a \
+                        checkpoint. Continue from the checkpoint?\n";
+        if menu(question, &["Yes", "No, load the last save"], 36, tcod) == Some(0) {
+            path = autosave;
+        }
+    }
+    let loaded = match load_game(&path, false) {
+        Err(LoadError::Outdated { .. }) => {
+            let question = "\nThe save is from an older version of the game. \
+                            Try to upgrade it?\n";
+            match menu(question, &["Yes", "No"], 30, tcod) {
+                Some(0) => load_game(&path, true),
+                _ => return,
+            }
+        }
+        result => result,
+    };
+    match loaded {
+        Ok((mut objects, mut game)) => {
+            if game.ironman {
+                replay_journal(&journal_path(slot), &mut objects, &mut game);
+            }
+            game.free_slots = free_slots(&objects);
+            initialise_fov(&game.map, &mut tcod.fov);
+            tcod.con.clear();
+            play_game(&mut objects, &mut game, tcod, slot);
+        }
+        Err(e) => {
+            msgbox(&format!("\n{}\n", e), 30, tcod);
+        }
+    }
+}
+
+/// List the save slots with what's in them and let the player pick one.
+fn choose_slot(header: &str, tcod: &mut Tcod) -> Option<usize> {
+    let options: Vec<String> = (0..SAVE_SLOTS).map(|slot| {
+        match read_save_summary(slot) {
+            Some(summary) => format!("Slot {}: {}", slot + 1, summary.describe()),
+            None => format!("Slot {}: empty", slot + 1),
+        }
+    }).collect();
+    menu(header, &options, 60, tcod)
+}
+
+/// Let the player type a line of text. Enter accepts it, Escape cancels.
+fn text_input(header: &str, max_length: usize, tcod: &mut Tcod) -> Option<String> {
+    use tcod::input::KeyCode::{Backspace, Enter, Escape};
+
+    // leave room for a name made of wide glyphs
+    let width = cmp::max(text_width(header), max_length * 2 + 1) as i32;
+    let mut text = String::new();
+    loop {
+        let mut window = Screen::new(width, 2);
+        window.print(0, 0, header, colors::WHITE);
+        window.print(0, 1, &format!("{}_", text), colors::LIGHT_YELLOW);
+        window.blit(&mut tcod.root, CONFIG.screen_width / 2 - width / 2, CONFIG.screen_height / 2 - 1, 0.7);
+        tcod.flush();
+
+        let key = tcod.backend.wait_for_keypress();
+        match key.code {
+            Enter => return Some(text.trim().to_string()),
+            Escape => return None,
+            Backspace => {
+                text.pop();
+            }
+            _ if glyph_width(key.printable) > 0 && text.chars().count() < max_length => {
+                text.push(key.printable);
+            }
+            _ => {}
+        }
+        if tcod.backend.window_closed() {
+            return None;
+        }
+    }
+}
+
+/// Send the log to game.log, moving the older logs out of the way once it
+/// gets too big. `--debug` logs every turn, attack and AI decision too.
+fn init_logging() -> Result<(), Box<Error>> {
+    let too_big = std::fs::metadata(LOG_PATH).map(|m| m.len() > LOG_MAX_SIZE).unwrap_or(false);
+    if too_big {
+        for n in (1..LOG_FILES).rev() {
+            let _ = std::fs::rename(format!("{}.{}", LOG_PATH, n), format!("{}.{}", LOG_PATH, n + 1));
+        }
+        try! { std::fs::rename(LOG_PATH, format!("{}.1", LOG_PATH)) };
+    }
+    let started = Instant::now();
+    try! {
+        fern::Dispatch::new()
+            .format(move |out, message, record| {
+                let elapsed = started.elapsed();
+                out.finish(format_args!("[{:>5}.{:03}] {:<5} {}", elapsed.as_secs(),
+                                        elapsed.subsec_nanos() / 1_000_000, record.level(), message))
+            })
+            .level(if options().debug { log::LevelFilter::Debug } else { log::LevelFilter::Info })
+            .chain(try! { fern::log_file(LOG_PATH) })
+            .apply()
+    };
+    Ok(())
+}
+
+/// Run the game the way the command line asked for, see
+/// src/bin/part-13-adventure-gear.rs.
+pub fn play(parsed: Options) {
+    OPTIONS.set(parsed).ok().expect("Something read the options before they were parsed");
+    let options = options();
+    // read the config now so any complaints about it show up before the
+    // terminal is taken over
+    lazy_static::initialize(&CONFIG);
+    // nothing would spawn without the spawn tables
+    if let Some(problem) = rules::problem() {
+        eprintln!("{}", problem);
+        std::process::exit(1);
+    }
+    if let Err(e) = init_logging() {
+        eprintln!("Playing without a log file: {}", e);
+    }
+    info!("starting version {} with {:?}", env!("CARGO_PKG_VERSION"),
+          std::env::args().skip(1).collect::<Vec<_>>());
+    install_panic_hook();
+    // without a seed pick one, so a crash report can tell which it was
+    let seed = options.seed.unwrap_or_else(rand::random);
+    info!("using the seed {}", seed);
+    seed_rng(seed);
+
+    if options.validate_data {
+        let problems = validate_data();
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if problems.is_empty() {
+            println!("All the game's data looks fine.");
+        } else {
+            println!("{} problem(s) found.", problems.len());
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if options.headless {
+        // no window at all, let the bot play
+        run_headless(1000);
+        return;
+    }
+
+    // like the config, before the terminal is taken over
+    let keymap = Keymap::load(KEYMAP_PATH);
+    if std::panic::catch_unwind(move || play_in_window(keymap)).is_err() {
+        // the window is gone and the terminal back to normal by now
+        eprintln!("Sorry, the game crashed! Whatever could be saved was, load the game to carry on.");
+        eprintln!("What went wrong is in {} and {}, please send them along with a bug report.",
+                  CRASH_REPORT_PATH, LOG_PATH);
+        std::process::exit(1);
+    }
+}
+
+/// Open the game's window (or take over the terminal) and show the main menu.
+fn play_in_window(keymap: Keymap) {
+    let backend: Box<Backend> = if options().terminal {
+        // draw with plain text in the terminal instead of opening a window
+        match TerminalBackend::new() {
+            Ok(backend) => Box::new(backend),
+            Err(e) => {
+                println!("Could not use the terminal: {}", e);
+                return;
+            }
+        }
+    } else {
+        let root = Root::initializer()
+            .font(&options().font, FontLayout::Tcod)
+            .font_type(FontType::Greyscale)
+            .size(CONFIG.screen_width, CONFIG.screen_height)
+            .title("Rust/libtcod tutorial")
+            .init();
+        tcod::system::set_fps(CONFIG.limit_fps);
+        Box::new(TcodBackend { root: root })
+    };
+
+    let mut tcod = Tcod {
+        backend: backend,
+        root: Screen::new(CONFIG.screen_width, CONFIG.screen_height),
+        con: Screen::new(CONFIG.view_width(), CONFIG.view_height()),
+        panel: Screen::new(CONFIG.screen_width, PANEL_HEIGHT),
+        fov: FovMap::new(CONFIG.map_width, CONFIG.map_height),
+        camera: Default::default(),
+        mouse: Default::default(),
+        cursor: None,
+        travel_target: None,
+        travel_route: vec![],
+        open_stash: None,
+        show_profiler: options().debug,
+        frame_times: Default::default(),
+        save_format: if options().binary_saves {
+            SaveFormat::Binary
+        } else {
+            SaveFormat::Json
+        },
+        keymap: keymap,
+        recent_commands: VecDeque::new(),
+    };
+    if options().fullscreen {
+        tcod.backend.toggle_fullscreen();
+    }
+
+    if let Some(slot) = options().load_slot {
+        load_and_play(slot, &mut tcod);
+    }
+    main_menu(&mut tcod);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_put_by_the_start() {
+        let mut problems = vec![];
+        for seed in 0..MAPGEN_CHECK_SEEDS / 5 {
+            seed_rng(seed);
+            let set = rules::RuleSet::all()[seed % rules::RuleSet::all().len()];
+            let depth = Depth::new(Branch::Main, seed as u32 % 15 + 1);
+            let mut objects = vec![Object::new(0, 0, '@', "player", colors::WHITE, true)];
+            make_map(&mut objects, depth, rules::get(set), &[]);
+            let start = objects[PLAYER].pos();
+            let crowding = objects[PLAYER + 1..].iter()
+                .filter(|o| near_start(o.x, o.y, &objects) && !(o.name == "up stairs" && o.pos() == start));
+            for object in crowding {
+                let what = format!("the {} level {} made with seed {}", set.name(), depth.level, seed);
+                problems.push(format!("{} has a {} at {:?}, the player starts at {:?}",
+                                      what, object.name, object.pos(), start));
+            }
+        }
+        assert!(problems.is_empty(), "{}", problems.join("\n"));
+    }
+
+    #[test]
+    fn the_stairs_lead_onto_stairs() {
+        seed_rng(0);
+        let (mut objects, mut game) = new_game(rules::RuleSet::latest());
+        let mut fov = FovMap::new(CONFIG.map_width, CONFIG.map_height);
+        let on = |name: &str, objects: &[Object]| {
+            objects.iter().any(|o| o.name == name && o.pos() == objects[PLAYER].pos())
+        };
+        next_level(&mut objects, &mut game, &mut fov);
+        assert!(on("up stairs", &objects), "came down to {:?}", objects[PLAYER].pos());
+
+        // off to town through a portal away from the stairs, and back down them
+        let away = (0..CONFIG.map_width)
+            .flat_map(|x| (0..CONFIG.map_height).map(move |y| (x, y)))
+            .find(|&(x, y)| !is_blocked(x, y, &game.map, &objects) && objects[PLAYER].distance(x, y) > 5.0)
+            .unwrap();
+        objects[PLAYER].set_pos(away.0, away.1);
+        game.portal = Some(Portal { depth: game.depth(), pos: away });
+        take_portal(&mut objects, &mut game, &mut fov);
+        assert_eq!(game.depth(), Depth::town());
+        next_level(&mut objects, &mut game, &mut fov);
+        assert!(on("up stairs", &objects), "came back down to {:?}", objects[PLAYER].pos());
+
+        previous_level(&mut objects, &mut game, &mut fov);
+        assert!(on("stairs", &objects), "went back up to {:?}", objects[PLAYER].pos());
+    }
+
+    /// Play out a fight on a small map, failing with what went wrong. Every
+    /// fight starts from the same seed, so the same code always plays it out
+    /// the same way.
+    fn play_out(rows: &[&str], legend: &[(char, &str)], play: &Fn(&mut Simulation) -> Result<(), String>) {
+        seed_rng(0);
+        if let Err(e) = Simulation::from_ascii(rows, legend).and_then(|mut simulation| play(&mut simulation)) {
+            panic!("{}", e);
+        }
+    }
+
+    #[test]
+    fn monsters_chase_the_player() {
+        play_out(&[
+            "##########",
+            "#o.......#",
+            "#........#",
+            "#...#..@.#",
+            "##########",
+        ], &[('o', "orc")], &|simulation| {
+            simulation.wait(8);
+            let orc = simulation.monsters("orc")[0];
+            if simulation.objects[orc].distance_to(&simulation.objects[PLAYER]) >= 2.0 {
+                return Err(format!("the orc is still at {:?}", simulation.objects[orc].pos()));
+            }
+            if simulation.hp(PLAYER) == 100 {
+                return Err("the orc never hit the player".into());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn wounded_monsters_flee() {
+        play_out(&[
+            "############",
+            "#..........#",
+            "#.@o.......#",
+            "#..........#",
+            "############",
+        ], &[('o', "orc")], &|simulation| {
+            let orc = simulation.monsters("orc")[0];
+            simulation.objects[orc].fighter.as_mut().unwrap().hp = 2;
+            simulation.wait(3);
+            if !simulation.objects[orc].statuses().contains(&Status::Fleeing) {
+                return Err(format!("the wounded orc is {:?}", simulation.objects[orc].ai));
+            }
+            if simulation.objects[orc].distance_to(&simulation.objects[PLAYER]) < 3.0 {
+                return Err("the wounded orc didn't get away".into());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn confusion_wears_off() {
+        play_out(&[
+            "#######",
+            "#.....#",
+            "#.@.o.#",
+            "#.....#",
+            "#######",
+        ], &[('o', "orc")], &|simulation| {
+            let orc = simulation.monsters("orc")[0];
+            let ai = simulation.objects[orc].ai.take().unwrap();
+            let turn = simulation.game.turn + 3;
+            simulation.objects[orc].ai = Some(Ai::Confused{until: turn, previous_ai: Box::new(ai)});
+            simulation.game.schedule.at(turn, TimedEvent::EndConfusion(ObjectId::of(orc, &simulation.objects)));
+            simulation.wait(5);
+            if simulation.objects[orc].ai != Some(Ai::Basic) {
+                return Err(format!("the orc is {:?}", simulation.objects[orc].ai));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn confusion_and_flight_wear_off_inside_each_other() {
+        fn confused(until: u32, previous_ai: Ai) -> Ai {
+            Ai::Confused{until: until, previous_ai: Box::new(previous_ai)}
+        }
+        fn fleeing(until: u32, previous_ai: Ai) -> Ai {
+            Ai::Fleeing{until: until, previous_ai: Box::new(previous_ai)}
+        }
+        // a confused orc that turned to flee, and a fleeing one that got confused
+        let states: [fn(u32) -> Ai; 2] = [
+            |turn| fleeing(turn + 5, confused(turn + 3, Ai::Basic)),
+            |turn| confused(turn + 3, fleeing(turn + 5, Ai::Basic)),
+        ];
+        for state in states.iter() {
+            play_out(&[
+                "#######",
+                "#.....#",
+                "#.@.o.#",
+                "#.....#",
+                "#######",
+            ], &[('o', "orc")], &|simulation| {
+                let orc = simulation.monsters("orc")[0];
+                let turn = simulation.game.turn;
+                simulation.objects[orc].ai = Some(state(turn));
+                let id = ObjectId::of(orc, &simulation.objects);
+                simulation.game.schedule.at(turn + 3, TimedEvent::EndConfusion(id));
+                simulation.game.schedule.at(turn + 5, TimedEvent::EndFlight(id));
+                simulation.wait(8);
+                if simulation.objects[orc].ai != Some(Ai::Basic) {
+                    return Err(format!("the orc that was {:?} is {:?}", state(turn), simulation.objects[orc].ai));
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[test]
+    fn overlapping_smoke_clears_with_the_last_cloud() {
+        play_out(&[
+            "#######",
+            "#@....#",
+            "#.....#",
+            "#######",
+        ], &[], &|simulation| {
+            make_smoke(4, 1, 1, 4, &mut simulation.game);
+            simulation.wait(2);
+            make_smoke(4, 1, 1, 10, &mut simulation.game);
+            simulation.wait(5);
+            if !simulation.game.map[4][1].is_smoky() {
+                return Err("the first cloud to clear took the second one along".into());
+            }
+            simulation.wait(8);
+            if simulation.game.map[4][1].is_smoky() {
+                return Err("the second cloud never cleared".into());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn packs_come_from_all_sides() {
+        play_out(&[
+            "###########",
+            "#.........#",
+            "#.........#",
+            "#....@....#",
+            "#.........#",
+            "#.........#",
+            "#jjj......#",
+            "###########",
+        ], &[('j', "jackal")], &|simulation| {
+            let jackals = simulation.monsters("jackal");
+            for (i, &id) in jackals.iter().enumerate() {
+                simulation.objects[id].pack = Some(if i == 0 { PackRole::Leader(0) } else { PackRole::Follower(0) });
+            }
+            simulation.wait(6);
+            let player = simulation.objects[PLAYER].pos();
+            let sides: Vec<(i32, i32)> = jackals.iter()
+                .map(|&id| simulation.objects[id].pos())
+                .map(|(x, y)| ((x - player.0).signum(), (y - player.1).signum()))
+                .collect();
+            if sides.iter().all(|&side| side == sides[0]) {
+                return Err(format!("the jackals all came from {:?}", sides[0]));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn monsters_pick_up_gear() {
+        play_out(&[
+            "##########",
+            "#o.....#@#",
+            "#......#.#",
+            "##########",
+        ], &[('o', "orc")], &|simulation| {
+            let orc = simulation.monsters("orc")[0];
+            simulation.objects[orc].inventory.clear();
+            simulation.objects.push(create_item(Item::Shield, 4, 2));
+            simulation.wait(6);
+            let orc = &simulation.objects[orc];
+            if get_equipped_in_slot(Slot::LeftHand, &orc.inventory).is_none() {
+                return Err(format!("the orc is at {:?} with {:?}", orc.pos(), orc.get_all_equipped()));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn nests_summon_a_few() {
+        play_out(&[
+            "#########",
+            "#.......#",
+            "#.@...N.#",
+            "#.......#",
+            "#########",
+        ], &[('N', "gnat nest")], &|simulation| {
+            let nest = simulation.monsters("gnat nest")[0];
+            simulation.wait(40);
+            let gnats = simulation.monsters("cloud of gnats").len();
+            if gnats == 0 || gnats > Summons::of("gnat nest").unwrap().most {
+                return Err(format!("the nest released {} clouds of gnats", gnats));
+            }
+            if simulation.objects[nest].pos() != (6, 2) {
+                return Err(format!("the nest moved to {:?}", simulation.objects[nest].pos()));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn summons_die_with_the_summoner() {
+        play_out(&[
+            "#########",
+            "#.......#",
+            "#.@...N.#",
+            "#.......#",
+            "#########",
+        ], &[('N', "gnat nest")], &|simulation| {
+            let nest = simulation.monsters("gnat nest")[0];
+            let turn = simulation.game.turn + 1;
+            simulation.game.schedule.at(turn, TimedEvent::Summon(ObjectId::of(nest, &simulation.objects)));
+            simulation.objects.remove_object(nest, &mut simulation.game.free_slots);
+            // another nest in the old one's slot, one that never calls up any of its own
+            let mut other = create_monster("gnat nest", 1, 1).unwrap();
+            other.ai = None;
+            simulation.objects.insert_object(other, &mut simulation.game.free_slots);
+            simulation.wait(2);
+            match simulation.monsters("cloud of gnats").len() {
+                0 => Ok(()),
+                gnats => Err(format!("{} clouds of gnats came out of the other nest", gnats)),
+            }
+        });
+    }
+
+    #[test]
+    fn companions_fight_by_the_player() {
+        play_out(&[
+            "#########",
+            "#@d...o.#",
+            "#########",
+        ], &[('d', "dog"), ('o', "orc")], &|simulation| {
+            let (dog, orc) = (simulation.monsters("dog")[0], simulation.monsters("orc")[0]);
+            simulation.objects[orc].inventory.clear();
+            simulation.wait(12);
+            if simulation.objects[orc].alive && simulation.hp(orc) == simulation.objects[orc].max_hp() {
+                return Err("the dog never went for the orc".into());
+            }
+            if simulation.objects[dog].distance_to(&simulation.objects[PLAYER]) > COMPANION_LEASH + 1.0 {
+                return Err(format!("the dog strayed to {:?}", simulation.objects[dog].pos()));
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn companions_forget_a_foe_that_is_gone() {
+        play_out(&[
+            "#######",
+            "#@d..o#",
+            "#######",
+            "#.#####",
+            "#######",
+        ], &[('d', "dog"), ('o', "orc")], &|simulation| {
+            let (dog, orc) = (simulation.monsters("dog")[0], simulation.monsters("orc")[0]);
+            let order = Order::Attack(ObjectId::of(orc, &simulation.objects));
+            simulation.objects[dog].ai = Some(Ai::Companion{order: order});
+            simulation.objects.remove_object(orc, &mut simulation.game.free_slots);
+            // another orc in the old one's slot, walled in where the dog can't see it
+            let walled_in = create_monster("orc", 1, 3).unwrap();
+            if simulation.objects.insert_object(walled_in, &mut simulation.game.free_slots) != orc {
+                return Err("the orc's slot wasn't reused".into());
+            }
+            simulation.wait(1);
+            match simulation.objects[dog].ai {
+                Some(Ai::Companion{order: Order::Follow}) => Ok(()),
+                ref ai => Err(format!("the dog's still after the other orc: {:?}", ai)),
+            }
+        });
+    }
+
+    #[test]
+    fn neutral_monsters_leave_the_player_be() {
+        play_out(&[
+            "#######",
+            "#.....#",
+            "#.@B..#",
+            "#.....#",
+            "#######",
+        ], &[('B', "giant beetle")], &|simulation| {
+            let beetle = simulation.monsters("giant beetle")[0];
+            simulation.wait(5);
+            if simulation.hp(PLAYER) != 100 {
+                return Err("the beetle bit the player unprovoked".into());
+            }
+            simulation.step(Action::MoveOrAttack(1, 0));
+            if simulation.hp(beetle) != simulation.objects[beetle].max_hp() {
+                return Err("bumping into the beetle attacked it".into());
+            }
+            simulation.step(Action::Attack(1, 0));
+            simulation.wait(5);
+            if simulation.objects[beetle].alive && simulation.hp(PLAYER) == 100 {
+                return Err("the beetle never bit back".into());
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn cached_levels_fail_to_load_without_a_panic() {
+        let depth = Depth::new(Branch::Main, 2);
+        // a save can't make the game read some file
+        let saved = format!("{{\"levels\":[[{},{{\"OnDisk\":\"level.json\"}}]]}}",
+                            serde_json::to_string(&depth).unwrap());
+        assert!(serde_json::from_str::<LevelCache>(&saved).is_err());
+
+        let mut cache = LevelCache::new();
+        cache.levels.push((depth, CachedLevel::Stored("{".into())));
+        assert!(cache.take(depth).is_err());
+        assert!(!cache.contains(depth));
+    }
+
+    /// a save file with the given format version, in the temporary
+    /// directory; version 0 saves had no header
+    fn write_save(name: &str, version: u32, data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("tombs-{}-{}.sav", name, std::process::id()));
+        let mut save = vec![];
+        if version > 0 {
+            save.extend(format!("{} {} test\n", SAVE_MAGIC, version).into_bytes());
+        }
+        save.extend_from_slice(data);
+        File::create(&path).and_then(|mut file| file.write_all(&save)).unwrap();
+        path
+    }
+
+    #[test]
+    fn saves_with_another_map_size_are_turned_down() {
+        let (objects, mut game) = new_game(rules::RuleSet::latest());
+        game.map = vec![vec![Tile::wall(); 10]; 20];
+        let path = write_save("map-size", SAVE_VERSION, &encode_game(&objects, &game, SaveFormat::Json).unwrap());
+        let loaded = load_game(&path, false);
+        let _ = std::fs::remove_file(&path);
+        match loaded {
+            Err(LoadError::MapSize { width: 20, height: 10 }) => {}
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("a 20x10 map loaded"),
+        }
+    }
+
+    #[test]
+    fn version_0_saves_are_upgraded() {
+        let mut save: serde_json::Value = std::fs::File::open("tests/saves/version-0.json")
+            .map_err(|e| e.to_string())
+            .and_then(|file| serde_json::from_reader(file).map_err(|e| e.to_string()))
+            .unwrap();
+        // the fixture leaves out the map, back then a struct for every tile
+        let wall: serde_json::Value = serde_json::from_str(
+            r#"{"blocked": true, "explored": false, "block_sight": true}"#).unwrap();
+        let column = serde_json::Value::Array(vec![wall; CONFIG.map_height as usize]);
+        let map = serde_json::Value::Array(vec![column; CONFIG.map_width as usize]);
+        save.as_array_mut().unwrap()[1].as_object_mut().unwrap().insert("map".into(), map);
+        let path = write_save("version-0", 0, &serde_json::to_vec(&save).unwrap());
+        let outdated = load_game(&path, false);
+        let loaded = load_game(&path, true);
+        let _ = std::fs::remove_file(&path);
+        match outdated {
+            Err(LoadError::Outdated { save_version: 0, .. }) => {}
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("a version 0 save loaded without an upgrade"),
+        }
+        let (objects, game) = loaded.unwrap_or_else(|e| panic!("{}", e));
+
+        let player = &objects[PLAYER];
+        let fighter = player.fighter.unwrap();
+        assert_eq!((fighter.hp, fighter.speed, fighter.energy, fighter.morale),
+                   (80, NORMAL_SPEED, ACTION_COST, 100));
+        assert_eq!(player.inventory.len(), 1);
+        assert!(player.inventory[0].name == "dagger" && player.inventory[0].equipment.unwrap().equipped);
+        let orc = &objects[1];
+        assert_eq!(orc.ai, Some(Ai::Basic));
+        assert_eq!((orc.faction, orc.disposition, orc.generation),
+                   (Some(Faction::Greenskins), Disposition::Hostile, 0));
+        assert_eq!(objects[2].effect, Some("heal".into()));
+        assert_eq!(objects[3].char, '>');
+
+        assert_eq!((game.branch, game.dungeon_level, game.turn, game.stats.deepest), (Branch::Main, 2, 0, 2));
+        assert_eq!(game.rule_set, rules::RuleSet::Classic);
+        assert!(game.torch_lit && !game.ironman && game.levels.depths().is_empty());
+        assert_eq!((game.map.len(), game.map[0].len()), (CONFIG.map_width as usize, CONFIG.map_height as usize));
+        assert_eq!(game.log.len(), 1);
+    }
+
+    #[test]
+    fn saves_from_halfway_are_upgraded() {
+        // made by the game at save version 14: the town two levels up is
+        // stored away, the level above is still loaded and the orc is
+        // confused until an event ends it
+        let (objects, mut game) = load_game(std::path::Path::new("tests/saves/version-14.sav"), true)
+            .unwrap_or_else(|e| panic!("{}", e));
+        let orc = ObjectId::of(1, &objects);
+        assert_eq!(objects[1].ai, Some(Ai::Confused{until: 120, previous_ai: Box::new(Ai::Basic)}));
+        let events: Vec<TimedEvent> = game.schedule.events.iter().map(|scheduled| scheduled.event.clone()).collect();
+        assert!(events.contains(&TimedEvent::EndConfusion(orc)), "{:?}", events);
+        assert_eq!((objects[PLAYER].base_luck, objects[PLAYER].size), (0, 1));
+        assert_eq!(game.stats.deepest, 3);
+        assert!(game.level_ups.is_empty() && game.mutators.is_empty() && game.bounty.is_none());
+        // the stash moved out of the chest in town, stored away as it was
+        assert_eq!(game.stash.iter().map(|item| &item.name[..]).collect::<Vec<_>>(), ["healing potion"]);
+
+        let town = game.levels.take(Depth::town()).unwrap().expect("the town wasn't cached");
+        assert_eq!(town.objects.len(), 1);
+        assert!(town.objects[0].name == "stash chest" && town.objects[0].inventory.is_empty());
+        assert_eq!(town.objects[0].disposition, Disposition::Friendly);
+        let above = game.levels.take(Depth::new(Branch::Main, 2)).unwrap().expect("level 2 wasn't cached");
+        let troll = &above.objects[0];
+        assert_eq!(troll.fighter.map(|f| f.morale), Some(100));
+        assert_eq!(troll.movement.opens_doors, opens_doors("troll"));
+    }
+
+    #[test]
+    fn saves_load_the_game_that_was_saved() {
+        let (mut objects, mut game) = new_game(rules::RuleSet::latest());
+        let mut fov = FovMap::new(CONFIG.map_width, CONFIG.map_height);
+        // far enough down for the first levels to go to disk
+        for level in 1..5 {
+            change_level(Depth::new(Branch::Main, level), &mut objects, &mut game, &mut fov);
+        }
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let saved = encode_game(&objects, &game, format).unwrap();
+            let path = write_save("round-trip", SAVE_VERSION, &saved);
+            let loaded = load_game(&path, false);
+            let _ = std::fs::remove_file(&path);
+            let (objects, game) = loaded.unwrap_or_else(|e| panic!("{}", e));
+            assert!(encode_game(&objects, &game, format).unwrap() == saved, "{:?} saves changed", format);
+        }
+    }
+
+    #[test]
+    fn a_journal_cut_short_replays_up_to_the_last_whole_turn() {
+        let (mut objects, mut game) = new_game(rules::RuleSet::latest());
+        let path = std::env::temp_dir().join(format!("tombs-journal-{}", std::process::id()));
+        let saved_turn = game.turn;
+        start_journal(&game, &path).unwrap();
+        for turn in 1..4 {
+            game.turn = saved_turn + turn;
+            append_to_journal(&objects, &mut game, SaveFormat::Binary, &path).unwrap();
+        }
+        // the game crashed halfway through writing the third turn
+        let length = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new().write(true).open(&path).and_then(|file| file.set_len(length - 10)).unwrap();
+        game.turn = saved_turn;
+        replay_journal(&path, &mut objects, &mut game);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(game.turn, saved_turn + 2);
+    }
+}